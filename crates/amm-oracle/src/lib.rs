@@ -0,0 +1,63 @@
+//! CPI client for mock-amm's `get_price` oracle view.
+//!
+//! Consumers (vault-core USD caps, lending markets) read pool prices through this
+//! crate without depending on the AMM program. `tests/abi.rs` checks the
+//! discriminator and return layout against mock-amm.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::program::{get_return_data, invoke};
+
+declare_id!("8TN4YaBrKm5WZAcFTxzEBTA1i8AXxwnnYWTFxYF5PsSU");
+
+/// Anchor discriminator of `get_price`: first 8 bytes of sha256("global:get_price")
+pub const GET_PRICE: [u8; 8] = [238, 38, 193, 106, 228, 32, 210, 33];
+
+/// One in the Q64.64 fixed-point format of [`PoolPrice`]
+pub const Q64: u128 = 1 << 64;
+
+/// Prices of a pool's `mint_a` in `mint_b`, Q64.64
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolPrice {
+    /// Marginal price at the current reserves, before fees
+    pub spot_price: u128,
+    /// Time-weighted average of the spot price over `twap_window`
+    pub twap_price: u128,
+    /// Seconds covered by `twap_price`; 0 for a pool created this second
+    pub twap_window: i64,
+}
+
+/// `get_price` for `pool`; after invoking, read the result with [`read_price`]
+pub fn get_price_instruction(pool: Pubkey) -> Instruction {
+    Instruction {
+        program_id: ID,
+        accounts: vec![AccountMeta::new_readonly(pool, false)],
+        data: GET_PRICE.to_vec(),
+    }
+}
+
+/// CPI into `get_price` and decode the result
+pub fn get_price<'info>(
+    amm_program: &AccountInfo<'info>,
+    pool: &AccountInfo<'info>,
+) -> Result<PoolPrice> {
+    require_keys_eq!(amm_program.key(), ID, ErrorCode::InvalidProgramId);
+    invoke(
+        &get_price_instruction(pool.key()),
+        &[pool.clone(), amm_program.clone()],
+    )?;
+    read_price()
+}
+
+/// Decode the price left in return data by a `get_price` CPI
+pub fn read_price() -> Result<PoolPrice> {
+    let (program_id, data) = get_return_data().ok_or(ErrorCode::InstructionDidNotDeserialize)?;
+    require_keys_eq!(program_id, ID, ErrorCode::InvalidProgramId);
+    PoolPrice::try_from_slice(&data).map_err(|_| error!(ErrorCode::InstructionDidNotDeserialize))
+}
+
+/// `amount` of `mint_a` valued in `mint_b` at `price`, rounded down
+pub fn quote_a_in_b(amount: u64, price: u128) -> Option<u64> {
+    let value = (amount as u128).checked_mul(price)? >> 64;
+    u64::try_from(value).ok()
+}
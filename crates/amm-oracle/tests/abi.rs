@@ -0,0 +1,39 @@
+//! ABI stability checks: amm-oracle must encode exactly what mock-amm decodes.
+
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::{AnchorSerialize, Discriminator, InstructionData, ToAccountMetas};
+
+#[test]
+fn program_id_matches() {
+    assert_eq!(amm_oracle::ID, mock_amm::ID);
+}
+
+#[test]
+fn get_price_abi() {
+    assert_eq!(
+        amm_oracle::GET_PRICE,
+        mock_amm::instruction::GetPrice::DISCRIMINATOR
+    );
+    let pool = Pubkey::new_unique();
+    let ix = amm_oracle::get_price_instruction(pool);
+    assert_eq!(
+        ix.accounts,
+        mock_amm::accounts::Quote { pool }.to_account_metas(None)
+    );
+    assert_eq!(ix.data, mock_amm::instruction::GetPrice {}.data());
+}
+
+#[test]
+fn pool_price_layout_matches() {
+    let price = mock_amm::PoolPrice {
+        spot_price: 3 << 63,
+        twap_price: 1 << 64,
+        twap_window: 1_800,
+    };
+    let decoded: amm_oracle::PoolPrice =
+        anchor_lang::AnchorDeserialize::try_from_slice(&price.try_to_vec().unwrap()).unwrap();
+    assert_eq!(decoded.spot_price, price.spot_price);
+    assert_eq!(decoded.twap_price, price.twap_price);
+    assert_eq!(decoded.twap_window, price.twap_window);
+    assert_eq!(amm_oracle::quote_a_in_b(10, decoded.spot_price), Some(15));
+}
@@ -0,0 +1,219 @@
+//! Callback interface for vault-core flash loans.
+//!
+//! After sending the loan, `flash_loan` invokes the borrower's callback program with
+//! [`ON_FLASH_LOAN`] followed by Borsh-encoded [`OnFlashLoanArgs`]. The accounts are
+//! the fixed [`OnFlashLoanAccounts`] in declaration order, then whatever remaining
+//! accounts the borrower passed to `flash_loan`. Before returning, the callback must
+//! move `amount + fee` back into `vault_token_account` (see [`repay`]).
+//!
+//! Vaults that allow PDA borrowers also lend to the callback program's own
+//! [`borrower_pda`] without its signature. The callback then repays with
+//! [`repay_signed`] and the PDA's seeds.
+//!
+//! Anchor borrowers can declare the callback as a regular instruction,
+//! `on_flash_loan(ctx, amount: u64, fee: u64, initiator: Pubkey)`, whose discriminator
+//! is [`ON_FLASH_LOAN`]. Other programs implement [`FlashLoanReceiver`] and route the
+//! instruction through [`process`].
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::program::{invoke, invoke_signed};
+
+/// SPL Token program id (kept local so the interface doesn't pull in anchor-spl)
+pub const TOKEN_PROGRAM_ID: Pubkey = pubkey!("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+
+/// Anchor discriminator of `on_flash_loan`: first 8 bytes of sha256("global:on_flash_loan")
+pub const ON_FLASH_LOAN: [u8; 8] = [195, 212, 238, 236, 80, 204, 73, 167];
+
+/// Seed of [`borrower_pda`]
+pub const BORROWER_SEED: &[u8] = b"flash_borrower";
+
+/// The one PDA of `callback_program` that may borrow without signing
+pub fn borrower_pda(callback_program: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[BORROWER_SEED], callback_program)
+}
+
+/// Loan terms passed to the callback
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OnFlashLoanArgs {
+    /// Tokens lent; already in `borrower_token_account`
+    pub amount: u64,
+    /// Owed on top of `amount`
+    pub fee: u64,
+    /// Signer of the outer `flash_loan`
+    pub initiator: Pubkey,
+}
+
+impl OnFlashLoanArgs {
+    /// Tokens the vault expects back. vault-core never lends more than it holds, so
+    /// this cannot overflow for a real loan.
+    pub fn repayment(&self) -> u64 {
+        self.amount.saturating_add(self.fee)
+    }
+}
+
+/// Accounts every callback receives, in this order, ahead of the borrower's own
+#[derive(Clone)]
+pub struct OnFlashLoanAccounts<'info> {
+    /// Vault token account to repay into (writable)
+    pub vault_token_account: AccountInfo<'info>,
+    /// Where the loan was sent (writable)
+    pub borrower_token_account: AccountInfo<'info>,
+    /// The `flash_loan` signer, still a signer inside the callback, or the callback
+    /// program's [`borrower_pda`]
+    pub borrower: AccountInfo<'info>,
+    pub token_program: AccountInfo<'info>,
+}
+
+impl<'info> OnFlashLoanAccounts<'info> {
+    pub const LEN: usize = 4;
+
+    pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+        let mut metas = account_metas(
+            self.vault_token_account.key(),
+            self.borrower_token_account.key(),
+            self.borrower.key(),
+        );
+        metas[2].is_signer = self.borrower.is_signer;
+        metas
+    }
+
+    pub fn to_account_infos(&self) -> Vec<AccountInfo<'info>> {
+        vec![
+            self.vault_token_account.clone(),
+            self.borrower_token_account.clone(),
+            self.borrower.clone(),
+            self.token_program.clone(),
+        ]
+    }
+
+    /// Split the fixed accounts off the front of a callback's account list, returning
+    /// the borrower's remaining accounts. A non-signing borrower is only accepted as
+    /// `program_id`'s [`borrower_pda`].
+    pub fn split<'a>(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'info>],
+    ) -> Result<(Self, &'a [AccountInfo<'info>])> {
+        if accounts.len() < Self::LEN {
+            return err!(ErrorCode::AccountNotEnoughKeys);
+        }
+        let (fixed, remaining) = accounts.split_at(Self::LEN);
+        if !fixed[2].is_signer && fixed[2].key() != borrower_pda(program_id).0 {
+            return err!(ErrorCode::AccountNotSigner);
+        }
+        if fixed[3].key() != TOKEN_PROGRAM_ID {
+            return err!(ErrorCode::InvalidProgramId);
+        }
+        Ok((
+            Self {
+                vault_token_account: fixed[0].clone(),
+                borrower_token_account: fixed[1].clone(),
+                borrower: fixed[2].clone(),
+                token_program: fixed[3].clone(),
+            },
+            remaining,
+        ))
+    }
+}
+
+/// Implemented by borrowers that don't use Anchor's dispatch
+pub trait FlashLoanReceiver {
+    fn on_flash_loan<'info>(
+        accounts: OnFlashLoanAccounts<'info>,
+        args: OnFlashLoanArgs,
+        remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<()>;
+}
+
+/// Decode a callback instruction and hand it to `R`
+pub fn process<'info, R: FlashLoanReceiver>(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo<'info>],
+    data: &[u8],
+) -> Result<()> {
+    let args = data
+        .strip_prefix(&ON_FLASH_LOAN)
+        .ok_or_else(|| error!(ErrorCode::InstructionFallbackNotFound))?;
+    let args = OnFlashLoanArgs::try_from_slice(args)
+        .map_err(|_| error!(ErrorCode::InstructionDidNotDeserialize))?;
+    let (fixed, remaining) = OnFlashLoanAccounts::split(program_id, accounts)?;
+    R::on_flash_loan(fixed, args, remaining)
+}
+
+/// The fixed callback accounts, in [`OnFlashLoanAccounts`] order
+pub fn account_metas(
+    vault_token_account: Pubkey,
+    borrower_token_account: Pubkey,
+    borrower: Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(vault_token_account, false),
+        AccountMeta::new(borrower_token_account, false),
+        AccountMeta::new(borrower, true),
+        AccountMeta::new_readonly(TOKEN_PROGRAM_ID, false),
+    ]
+}
+
+/// Build the callback instruction for `program_id`. `accounts` starts with
+/// [`account_metas`], followed by the borrower's own accounts.
+pub fn instruction(
+    program_id: Pubkey,
+    args: &OnFlashLoanArgs,
+    accounts: Vec<AccountMeta>,
+) -> Instruction {
+    let mut data = ON_FLASH_LOAN.to_vec();
+    // Serializing plain integers/keys into a Vec cannot fail
+    args.serialize(&mut data).expect("serialize callback args");
+    Instruction {
+        program_id,
+        accounts,
+        data,
+    }
+}
+
+/// CPI into a borrower's callback. Used by vault-core; signer privileges of the outer
+/// transaction (the borrower) carry through.
+pub fn invoke_on_flash_loan<'info>(
+    callback_program: &AccountInfo<'info>,
+    accounts: OnFlashLoanAccounts<'info>,
+    args: &OnFlashLoanArgs,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<()> {
+    let mut metas = accounts.to_account_metas();
+    metas.extend(remaining_accounts.iter().map(|acc| AccountMeta {
+        pubkey: acc.key(),
+        is_signer: acc.is_signer,
+        is_writable: acc.is_writable,
+    }));
+    let ix = instruction(callback_program.key(), args, metas);
+    let mut infos = accounts.to_account_infos();
+    infos.extend(remaining_accounts.iter().cloned());
+    infos.push(callback_program.clone());
+    invoke(&ix, &infos).map_err(Into::into)
+}
+
+/// Return `amount + fee` from the borrower's token account to the vault
+pub fn repay(accounts: &OnFlashLoanAccounts, args: &OnFlashLoanArgs) -> Result<()> {
+    repay_signed(accounts, args, &[])
+}
+
+/// [`repay`] for a [`borrower_pda`] borrower, signing with its seeds
+pub fn repay_signed(
+    accounts: &OnFlashLoanAccounts,
+    args: &OnFlashLoanArgs,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    // SPL Token `Transfer`: tag 3 followed by the amount
+    let mut data = vec![3];
+    data.extend_from_slice(&args.repayment().to_le_bytes());
+    let ix = Instruction {
+        program_id: TOKEN_PROGRAM_ID,
+        accounts: vec![
+            AccountMeta::new(accounts.borrower_token_account.key(), false),
+            AccountMeta::new(accounts.vault_token_account.key(), false),
+            AccountMeta::new_readonly(accounts.borrower.key(), true),
+        ],
+        data,
+    };
+    invoke_signed(&ix, &accounts.to_account_infos(), signer_seeds).map_err(Into::into)
+}
@@ -0,0 +1,51 @@
+//! The callback encoding must match what an Anchor `on_flash_loan` instruction decodes.
+
+use anchor_lang::prelude::Pubkey;
+use anchor_lang::{Discriminator, InstructionData, ToAccountMetas};
+use flash_loan_interface::{account_metas, instruction, OnFlashLoanArgs, ON_FLASH_LOAN};
+
+#[test]
+fn discriminator_matches_anchor() {
+    assert_eq!(
+        ON_FLASH_LOAN,
+        mock_flash_borrower::instruction::OnFlashLoan::DISCRIMINATOR
+    );
+}
+
+#[test]
+fn callback_abi() {
+    let (vault_token_account, borrower_token_account, borrower) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    let args = OnFlashLoanArgs {
+        amount: 1_000,
+        fee: 9,
+        initiator: borrower,
+    };
+    let ix = instruction(
+        mock_flash_borrower::ID,
+        &args,
+        account_metas(vault_token_account, borrower_token_account, borrower),
+    );
+    let expected = mock_flash_borrower::accounts::OnFlashLoan {
+        vault_token_account,
+        borrower_token_account,
+        borrower,
+        token_program: flash_loan_interface::TOKEN_PROGRAM_ID,
+    };
+    // The mock also takes its PDA as borrower, so its struct can't demand a signer
+    let mut expected_metas = expected.to_account_metas(None);
+    expected_metas[2].is_signer = true;
+    assert_eq!(ix.accounts, expected_metas);
+    assert_eq!(
+        ix.data,
+        mock_flash_borrower::instruction::OnFlashLoan {
+            amount: 1_000,
+            fee: 9,
+            initiator: borrower,
+        }
+        .data()
+    );
+}
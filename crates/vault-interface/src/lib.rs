@@ -0,0 +1,799 @@
+//! Stable CPI interface for vault-core.
+//!
+//! Third-party programs (lending markets, liquid wrappers, strategies) can build
+//! vault-core instructions from this crate without depending on the program itself.
+//! Discriminators, account orders and argument encodings here are the vault ABI;
+//! `tests/abi.rs` checks them against the program so accidental breaks fail CI.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::program::{get_return_data, invoke_signed};
+use anchor_lang::solana_program::{system_program, sysvar};
+
+declare_id!("A4nGMAE6j5xty4a5PALzz7nYnWQcB59mYcLptZMoYkfN");
+
+/// SPL Token program id (kept local so the interface doesn't pull in anchor-spl)
+pub const TOKEN_PROGRAM_ID: Pubkey = pubkey!("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+
+/// Token-2022 program id; reward mints may be owned by either token program
+pub const TOKEN_2022_PROGRAM_ID: Pubkey = pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+
+/// Associated Token Account program id
+pub const ASSOCIATED_TOKEN_PROGRAM_ID: Pubkey =
+    pubkey!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
+
+/// Anchor instruction discriminators: first 8 bytes of sha256("global:<name>")
+pub mod discriminator {
+    pub const DEPOSIT: [u8; 8] = [242, 35, 198, 137, 82, 225, 242, 182];
+    pub const DEPOSIT_WITH_DELEGATE: [u8; 8] = [3, 68, 84, 188, 129, 107, 195, 217];
+    pub const WITHDRAW: [u8; 8] = [183, 18, 70, 156, 148, 109, 161, 34];
+    pub const CLAIM_REWARDS: [u8; 8] = [4, 144, 132, 71, 116, 23, 151, 80];
+    pub const FUND_REWARDS: [u8; 8] = [114, 64, 163, 112, 175, 167, 19, 121];
+    pub const FLASH_LOAN: [u8; 8] = [239, 246, 59, 224, 139, 20, 175, 14];
+    pub const UPDATE_REWARDS: [u8; 8] = [188, 38, 124, 42, 87, 77, 176, 90];
+    pub const DONATE: [u8; 8] = [121, 186, 218, 211, 73, 70, 196, 180];
+    pub const GET_EXCHANGE_RATE: [u8; 8] = [153, 76, 17, 194, 170, 215, 89, 142];
+}
+
+/// Scale of the rate returned by `get_exchange_rate`
+pub const EXCHANGE_RATE_PRECISION: u128 = 1_000_000_000_000;
+
+/// PDA derivations used by vault-core
+pub mod pda {
+    use super::*;
+
+    pub fn vault(token_mint: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[b"vault", token_mint.as_ref()], &ID)
+    }
+
+    pub fn vault_authority(token_mint: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[b"vault", token_mint.as_ref(), b"authority"], &ID)
+    }
+
+    pub fn user_position(vault: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[b"position", vault.as_ref(), user.as_ref()], &ID)
+    }
+
+    pub fn position_index(vault: &Pubkey, index: u64) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[b"position_index", vault.as_ref(), &index.to_le_bytes()],
+            &ID,
+        )
+    }
+
+    /// Share history written by every deposit, withdrawal and compound
+    pub fn user_checkpoints(vault: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[b"checkpoints", vault.as_ref(), user.as_ref()], &ID)
+    }
+
+    pub fn protocol_config() -> (Pubkey, u8) {
+        Pubkey::find_program_address(&[b"protocol_config"], &ID)
+    }
+
+    /// Seed appended to a vault's and its authority's seeds: empty for generation 0,
+    /// so [`vault`] and [`vault_authority`] are the generation-0 addresses
+    pub fn generation_seed(generation: &u8) -> &[u8] {
+        if *generation == 0 {
+            &[]
+        } else {
+            std::slice::from_ref(generation)
+        }
+    }
+}
+
+/// Fields of vault-core's zero-copy `Vault` account read straight from its data
+pub mod state {
+    use super::*;
+
+    /// `Vault` account discriminator
+    pub const VAULT_DISCRIMINATOR: &[u8] = b"vault_v6";
+
+    /// Offset of `Vault::generation` in the account data, discriminator included
+    pub const VAULT_GENERATION_OFFSET: usize = 976;
+
+    /// Generation of `vault`, a `Vault` owned by `vault_program`; needed to derive the
+    /// authority of any vault past generation 0 (see [`pda::generation_seed`])
+    pub fn vault_generation(vault: &AccountInfo, vault_program: &Pubkey) -> Result<u8> {
+        require_keys_eq!(
+            *vault.owner,
+            *vault_program,
+            ErrorCode::AccountOwnedByWrongProgram
+        );
+        let data = vault.try_borrow_data()?;
+        require!(
+            data.starts_with(VAULT_DISCRIMINATOR),
+            ErrorCode::AccountDiscriminatorMismatch
+        );
+        data.get(VAULT_GENERATION_OFFSET)
+            .copied()
+            .ok_or_else(|| error!(ErrorCode::AccountDidNotDeserialize))
+    }
+}
+
+/// Account lists in the exact order vault-core expects them
+pub mod accounts {
+    use super::*;
+
+    pub struct Deposit {
+        pub vault: Pubkey,
+        pub user_position: Pubkey,
+        pub user: Pubkey,
+        pub user_token_account: Pubkey,
+        pub vault_token_account: Pubkey,
+        pub vault_authority: Pubkey,
+        /// [`pda::user_checkpoints`]
+        pub user_checkpoints: Pubkey,
+    }
+
+    impl Deposit {
+        pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+            vec![
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new(self.user_position, false),
+                AccountMeta::new(self.user, true),
+                AccountMeta::new(self.user_token_account, false),
+                AccountMeta::new(self.vault_token_account, false),
+                AccountMeta::new_readonly(self.vault_authority, false),
+                AccountMeta::new_readonly(TOKEN_PROGRAM_ID, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new(self.user_checkpoints, false),
+            ]
+        }
+    }
+
+    /// `user_token_account` must have approved `vault_authority` as its delegate for at
+    /// least the amount; `payer` covers a new position
+    pub struct DepositWithDelegate {
+        pub vault: Pubkey,
+        pub user_position: Pubkey,
+        pub user: Pubkey,
+        pub payer: Pubkey,
+        pub user_token_account: Pubkey,
+        pub vault_token_account: Pubkey,
+        pub vault_authority: Pubkey,
+        /// [`pda::user_checkpoints`]
+        pub user_checkpoints: Pubkey,
+    }
+
+    impl DepositWithDelegate {
+        pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+            vec![
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new(self.user_position, false),
+                AccountMeta::new_readonly(self.user, false),
+                AccountMeta::new(self.payer, true),
+                AccountMeta::new(self.user_token_account, false),
+                AccountMeta::new(self.vault_token_account, false),
+                AccountMeta::new_readonly(self.vault_authority, false),
+                AccountMeta::new_readonly(TOKEN_PROGRAM_ID, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new(self.user_checkpoints, false),
+            ]
+        }
+    }
+
+    pub struct Withdraw {
+        pub vault: Pubkey,
+        pub user_position: Pubkey,
+        pub user: Pubkey,
+        pub user_token_account: Pubkey,
+        pub vault_token_account: Pubkey,
+        pub vault_authority: Pubkey,
+        /// [`pda::user_checkpoints`]
+        pub user_checkpoints: Pubkey,
+    }
+
+    impl Withdraw {
+        pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+            vec![
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new(self.user_position, false),
+                AccountMeta::new(self.user, true),
+                AccountMeta::new(self.user_token_account, false),
+                AccountMeta::new(self.vault_token_account, false),
+                AccountMeta::new_readonly(self.vault_authority, false),
+                AccountMeta::new_readonly(TOKEN_PROGRAM_ID, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new_readonly(sysvar::clock::ID, false),
+                AccountMeta::new(self.user_checkpoints, false),
+            ]
+        }
+    }
+
+    /// `user_reward_token_account` must be the user's reward-mint ATA; it is
+    /// created (paid by `user`) if it doesn't exist yet
+    pub struct ClaimRewards {
+        pub vault: Pubkey,
+        pub user_position: Pubkey,
+        pub user: Pubkey,
+        pub reward_mint: Pubkey,
+        pub user_reward_token_account: Pubkey,
+        pub reward_vault: Pubkey,
+        pub vault_authority: Pubkey,
+        /// Owner of the reward mint: [`TOKEN_PROGRAM_ID`] or [`TOKEN_2022_PROGRAM_ID`]
+        pub reward_token_program: Pubkey,
+    }
+
+    impl ClaimRewards {
+        pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+            vec![
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new(self.user_position, false),
+                AccountMeta::new(self.user, true),
+                AccountMeta::new_readonly(self.reward_mint, false),
+                AccountMeta::new(self.user_reward_token_account, false),
+                AccountMeta::new(self.reward_vault, false),
+                AccountMeta::new_readonly(self.vault_authority, false),
+                AccountMeta::new_readonly(self.reward_token_program, false),
+                AccountMeta::new_readonly(sysvar::clock::ID, false),
+                AccountMeta::new_readonly(ASSOCIATED_TOKEN_PROGRAM_ID, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+            ]
+        }
+    }
+
+    pub struct FundRewards {
+        pub vault: Pubkey,
+        pub funder: Pubkey,
+        pub funder_token_account: Pubkey,
+        pub reward_vault: Pubkey,
+        pub reward_mint: Pubkey,
+        /// Owner of the reward mint: [`TOKEN_PROGRAM_ID`] or [`TOKEN_2022_PROGRAM_ID`]
+        pub reward_token_program: Pubkey,
+    }
+
+    impl FundRewards {
+        pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+            vec![
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new(self.funder, true),
+                AccountMeta::new(self.funder_token_account, false),
+                AccountMeta::new(self.reward_vault, false),
+                AccountMeta::new_readonly(self.reward_mint, false),
+                AccountMeta::new_readonly(self.reward_token_program, false),
+                AccountMeta::new_readonly(pda::protocol_config().0, false),
+            ]
+        }
+    }
+
+    pub struct UpdateRewards {
+        pub vault: Pubkey,
+    }
+
+    impl UpdateRewards {
+        pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+            vec![AccountMeta::new(self.vault, false)]
+        }
+    }
+
+    pub struct Donate {
+        pub vault: Pubkey,
+        pub donor: Pubkey,
+        pub donor_token_account: Pubkey,
+        pub vault_token_account: Pubkey,
+        pub vault_authority: Pubkey,
+    }
+
+    pub struct GetExchangeRate {
+        pub vault: Pubkey,
+        pub vault_token_account: Pubkey,
+        pub vault_authority: Pubkey,
+    }
+
+    impl GetExchangeRate {
+        pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+            vec![
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new_readonly(self.vault_token_account, false),
+                AccountMeta::new_readonly(self.vault_authority, false),
+            ]
+        }
+    }
+
+    impl Donate {
+        pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+            vec![
+                AccountMeta::new_readonly(self.vault, false),
+                AccountMeta::new_readonly(self.donor, true),
+                AccountMeta::new(self.donor_token_account, false),
+                AccountMeta::new(self.vault_token_account, false),
+                AccountMeta::new_readonly(self.vault_authority, false),
+                AccountMeta::new_readonly(TOKEN_PROGRAM_ID, false),
+            ]
+        }
+    }
+
+    /// The borrower's own callback accounts are appended after these; vault-core passes
+    /// them to `on_flash_loan` after the fixed accounts from `flash-loan-interface`
+    pub struct FlashLoan {
+        pub vault: Pubkey,
+        pub vault_token_account: Pubkey,
+        pub reward_vault: Pubkey,
+        pub vault_authority: Pubkey,
+        pub borrower: Pubkey,
+        pub borrower_token_account: Pubkey,
+        pub callback_program: Pubkey,
+    }
+
+    impl FlashLoan {
+        pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+            vec![
+                AccountMeta::new(self.vault, false),
+                AccountMeta::new(self.vault_token_account, false),
+                AccountMeta::new_readonly(self.reward_vault, false),
+                AccountMeta::new_readonly(self.vault_authority, false),
+                AccountMeta::new(self.borrower, true),
+                AccountMeta::new(self.borrower_token_account, false),
+                AccountMeta::new_readonly(self.callback_program, false),
+                AccountMeta::new_readonly(TOKEN_PROGRAM_ID, false),
+                AccountMeta::new_readonly(system_program::ID, false),
+                AccountMeta::new_readonly(sysvar::instructions::ID, false),
+            ]
+        }
+    }
+}
+
+/// Typed instruction builders
+pub mod instruction {
+    use super::*;
+
+    fn build(
+        discriminator: [u8; 8],
+        args: &impl AnchorSerialize,
+        accounts: Vec<AccountMeta>,
+    ) -> Instruction {
+        let mut data = discriminator.to_vec();
+        // Serializing plain integers/bytes into a Vec cannot fail
+        args.serialize(&mut data)
+            .expect("serialize instruction args");
+        Instruction {
+            program_id: ID,
+            accounts,
+            data,
+        }
+    }
+
+    /// `proof` is the depositor's Merkle allowlist proof; empty for open vaults
+    pub fn deposit(
+        accounts: &accounts::Deposit,
+        amount: u64,
+        min_shares_out: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Instruction {
+        build(
+            discriminator::DEPOSIT,
+            &(amount, min_shares_out, proof),
+            accounts.to_account_metas(),
+        )
+    }
+
+    /// `deposit` pulled by `payer` through the user's approval of the vault authority
+    pub fn deposit_with_delegate(
+        accounts: &accounts::DepositWithDelegate,
+        amount: u64,
+        min_shares_out: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Instruction {
+        build(
+            discriminator::DEPOSIT_WITH_DELEGATE,
+            &(amount, min_shares_out, proof),
+            accounts.to_account_metas(),
+        )
+    }
+
+    pub fn withdraw(
+        accounts: &accounts::Withdraw,
+        shares: u64,
+        min_tokens_out: u64,
+    ) -> Instruction {
+        build(
+            discriminator::WITHDRAW,
+            &(shares, min_tokens_out),
+            accounts.to_account_metas(),
+        )
+    }
+
+    pub fn claim_rewards(accounts: &accounts::ClaimRewards) -> Instruction {
+        build(
+            discriminator::CLAIM_REWARDS,
+            &(),
+            accounts.to_account_metas(),
+        )
+    }
+
+    pub fn fund_rewards(
+        accounts: &accounts::FundRewards,
+        amount: u64,
+        reward_rate: u64,
+    ) -> Instruction {
+        build(
+            discriminator::FUND_REWARDS,
+            &(amount, reward_rate),
+            accounts.to_account_metas(),
+        )
+    }
+
+    /// Accrue rewards so `acc_reward_per_share` is current before reading the vault
+    pub fn update_rewards(accounts: &accounts::UpdateRewards) -> Instruction {
+        build(
+            discriminator::UPDATE_REWARDS,
+            &(),
+            accounts.to_account_metas(),
+        )
+    }
+
+    /// Raise the share price without minting shares (e.g. protocol fee sharing)
+    pub fn donate(accounts: &accounts::Donate, amount: u64) -> Instruction {
+        build(discriminator::DONATE, &amount, accounts.to_account_metas())
+    }
+
+    /// Tokens per share scaled by [`EXCHANGE_RATE_PRECISION`]; after invoking, read it
+    /// with [`read_exchange_rate`]
+    pub fn get_exchange_rate(accounts: &accounts::GetExchangeRate) -> Instruction {
+        build(
+            discriminator::GET_EXCHANGE_RATE,
+            &(),
+            accounts.to_account_metas(),
+        )
+    }
+
+    pub fn flash_loan(
+        accounts: &accounts::FlashLoan,
+        amount: u64,
+        callback_accounts: Vec<AccountMeta>,
+    ) -> Instruction {
+        let mut metas = accounts.to_account_metas();
+        metas.extend(callback_accounts);
+        build(discriminator::FLASH_LOAN, &amount, metas)
+    }
+}
+
+/// Typed CPIs for on-chain callers handed vault-core accounts as a slice, such as a
+/// router's remaining accounts. Each struct names one instruction's accounts in the
+/// [`accounts`] order and `from_slice` takes the first `LEN` of the slice, so callers
+/// check and invoke accounts by name instead of by index.
+///
+/// `invoke` calls whichever program `vault_program` is, so other deployments of
+/// vault-core work too; callers pin it (a `Program<VaultCore>` account, an allow-list).
+pub mod cpi {
+    use super::*;
+
+    fn for_program(mut ix: Instruction, vault_program: &AccountInfo) -> Instruction {
+        ix.program_id = vault_program.key();
+        ix
+    }
+
+    pub struct Deposit<'a, 'info> {
+        pub vault: &'a AccountInfo<'info>,
+        pub user_position: &'a AccountInfo<'info>,
+        pub user: &'a AccountInfo<'info>,
+        pub user_token_account: &'a AccountInfo<'info>,
+        pub vault_token_account: &'a AccountInfo<'info>,
+        pub vault_authority: &'a AccountInfo<'info>,
+        pub token_program: &'a AccountInfo<'info>,
+        pub system_program: &'a AccountInfo<'info>,
+        pub user_checkpoints: &'a AccountInfo<'info>,
+    }
+
+    impl<'a, 'info> Deposit<'a, 'info> {
+        pub const LEN: usize = 9;
+
+        pub fn from_slice(accounts: &'a [AccountInfo<'info>]) -> Result<Self> {
+            match accounts {
+                [vault, user_position, user, user_token_account, vault_token_account, vault_authority, token_program, system_program, user_checkpoints, ..] => {
+                    Ok(Self {
+                        vault,
+                        user_position,
+                        user,
+                        user_token_account,
+                        vault_token_account,
+                        vault_authority,
+                        token_program,
+                        system_program,
+                        user_checkpoints,
+                    })
+                }
+                _ => err!(ErrorCode::AccountNotEnoughKeys),
+            }
+        }
+
+        pub fn keys(&self) -> accounts::Deposit {
+            accounts::Deposit {
+                vault: self.vault.key(),
+                user_position: self.user_position.key(),
+                user: self.user.key(),
+                user_token_account: self.user_token_account.key(),
+                vault_token_account: self.vault_token_account.key(),
+                vault_authority: self.vault_authority.key(),
+                user_checkpoints: self.user_checkpoints.key(),
+            }
+        }
+
+        /// See [`instruction::deposit`]
+        pub fn invoke(
+            &self,
+            vault_program: &AccountInfo<'info>,
+            amount: u64,
+            min_shares_out: u64,
+            proof: Vec<[u8; 32]>,
+            signer_seeds: &[&[&[u8]]],
+        ) -> Result<()> {
+            invoke_vault(
+                &for_program(
+                    instruction::deposit(&self.keys(), amount, min_shares_out, proof),
+                    vault_program,
+                ),
+                &[
+                    self.vault.clone(),
+                    self.user_position.clone(),
+                    self.user.clone(),
+                    self.user_token_account.clone(),
+                    self.vault_token_account.clone(),
+                    self.vault_authority.clone(),
+                    self.token_program.clone(),
+                    self.system_program.clone(),
+                    self.user_checkpoints.clone(),
+                    vault_program.clone(),
+                ],
+                signer_seeds,
+            )
+        }
+    }
+
+    pub struct DepositWithDelegate<'a, 'info> {
+        pub vault: &'a AccountInfo<'info>,
+        pub user_position: &'a AccountInfo<'info>,
+        pub user: &'a AccountInfo<'info>,
+        pub payer: &'a AccountInfo<'info>,
+        pub user_token_account: &'a AccountInfo<'info>,
+        pub vault_token_account: &'a AccountInfo<'info>,
+        pub vault_authority: &'a AccountInfo<'info>,
+        pub token_program: &'a AccountInfo<'info>,
+        pub system_program: &'a AccountInfo<'info>,
+        pub user_checkpoints: &'a AccountInfo<'info>,
+    }
+
+    impl<'a, 'info> DepositWithDelegate<'a, 'info> {
+        pub const LEN: usize = 10;
+
+        pub fn from_slice(accounts: &'a [AccountInfo<'info>]) -> Result<Self> {
+            match accounts {
+                [vault, user_position, user, payer, user_token_account, vault_token_account, vault_authority, token_program, system_program, user_checkpoints, ..] => {
+                    Ok(Self {
+                        vault,
+                        user_position,
+                        user,
+                        payer,
+                        user_token_account,
+                        vault_token_account,
+                        vault_authority,
+                        token_program,
+                        system_program,
+                        user_checkpoints,
+                    })
+                }
+                _ => err!(ErrorCode::AccountNotEnoughKeys),
+            }
+        }
+
+        pub fn keys(&self) -> accounts::DepositWithDelegate {
+            accounts::DepositWithDelegate {
+                vault: self.vault.key(),
+                user_position: self.user_position.key(),
+                user: self.user.key(),
+                payer: self.payer.key(),
+                user_token_account: self.user_token_account.key(),
+                vault_token_account: self.vault_token_account.key(),
+                vault_authority: self.vault_authority.key(),
+                user_checkpoints: self.user_checkpoints.key(),
+            }
+        }
+
+        /// See [`instruction::deposit_with_delegate`]
+        pub fn invoke(
+            &self,
+            vault_program: &AccountInfo<'info>,
+            amount: u64,
+            min_shares_out: u64,
+            proof: Vec<[u8; 32]>,
+            signer_seeds: &[&[&[u8]]],
+        ) -> Result<()> {
+            invoke_vault(
+                &for_program(
+                    instruction::deposit_with_delegate(&self.keys(), amount, min_shares_out, proof),
+                    vault_program,
+                ),
+                &[
+                    self.vault.clone(),
+                    self.user_position.clone(),
+                    self.user.clone(),
+                    self.payer.clone(),
+                    self.user_token_account.clone(),
+                    self.vault_token_account.clone(),
+                    self.vault_authority.clone(),
+                    self.token_program.clone(),
+                    self.system_program.clone(),
+                    self.user_checkpoints.clone(),
+                    vault_program.clone(),
+                ],
+                signer_seeds,
+            )
+        }
+    }
+
+    pub struct Withdraw<'a, 'info> {
+        pub vault: &'a AccountInfo<'info>,
+        pub user_position: &'a AccountInfo<'info>,
+        pub user: &'a AccountInfo<'info>,
+        pub user_token_account: &'a AccountInfo<'info>,
+        pub vault_token_account: &'a AccountInfo<'info>,
+        pub vault_authority: &'a AccountInfo<'info>,
+        pub token_program: &'a AccountInfo<'info>,
+        pub system_program: &'a AccountInfo<'info>,
+        pub clock: &'a AccountInfo<'info>,
+        pub user_checkpoints: &'a AccountInfo<'info>,
+    }
+
+    impl<'a, 'info> Withdraw<'a, 'info> {
+        pub const LEN: usize = 10;
+
+        pub fn from_slice(accounts: &'a [AccountInfo<'info>]) -> Result<Self> {
+            match accounts {
+                [vault, user_position, user, user_token_account, vault_token_account, vault_authority, token_program, system_program, clock, user_checkpoints, ..] => {
+                    Ok(Self {
+                        vault,
+                        user_position,
+                        user,
+                        user_token_account,
+                        vault_token_account,
+                        vault_authority,
+                        token_program,
+                        system_program,
+                        clock,
+                        user_checkpoints,
+                    })
+                }
+                _ => err!(ErrorCode::AccountNotEnoughKeys),
+            }
+        }
+
+        pub fn keys(&self) -> accounts::Withdraw {
+            accounts::Withdraw {
+                vault: self.vault.key(),
+                user_position: self.user_position.key(),
+                user: self.user.key(),
+                user_token_account: self.user_token_account.key(),
+                vault_token_account: self.vault_token_account.key(),
+                vault_authority: self.vault_authority.key(),
+                user_checkpoints: self.user_checkpoints.key(),
+            }
+        }
+
+        /// See [`instruction::withdraw`]
+        pub fn invoke(
+            &self,
+            vault_program: &AccountInfo<'info>,
+            shares: u64,
+            min_tokens_out: u64,
+            signer_seeds: &[&[&[u8]]],
+        ) -> Result<()> {
+            invoke_vault(
+                &for_program(
+                    instruction::withdraw(&self.keys(), shares, min_tokens_out),
+                    vault_program,
+                ),
+                &[
+                    self.vault.clone(),
+                    self.user_position.clone(),
+                    self.user.clone(),
+                    self.user_token_account.clone(),
+                    self.vault_token_account.clone(),
+                    self.vault_authority.clone(),
+                    self.token_program.clone(),
+                    self.system_program.clone(),
+                    self.clock.clone(),
+                    self.user_checkpoints.clone(),
+                    vault_program.clone(),
+                ],
+                signer_seeds,
+            )
+        }
+    }
+
+    pub struct ClaimRewards<'a, 'info> {
+        pub vault: &'a AccountInfo<'info>,
+        pub user_position: &'a AccountInfo<'info>,
+        pub user: &'a AccountInfo<'info>,
+        pub reward_mint: &'a AccountInfo<'info>,
+        pub user_reward_token_account: &'a AccountInfo<'info>,
+        pub reward_vault: &'a AccountInfo<'info>,
+        pub vault_authority: &'a AccountInfo<'info>,
+        pub reward_token_program: &'a AccountInfo<'info>,
+        pub clock: &'a AccountInfo<'info>,
+        pub associated_token_program: &'a AccountInfo<'info>,
+        pub system_program: &'a AccountInfo<'info>,
+    }
+
+    impl<'a, 'info> ClaimRewards<'a, 'info> {
+        pub const LEN: usize = 11;
+
+        pub fn from_slice(accounts: &'a [AccountInfo<'info>]) -> Result<Self> {
+            match accounts {
+                [vault, user_position, user, reward_mint, user_reward_token_account, reward_vault, vault_authority, reward_token_program, clock, associated_token_program, system_program, ..] => {
+                    Ok(Self {
+                        vault,
+                        user_position,
+                        user,
+                        reward_mint,
+                        user_reward_token_account,
+                        reward_vault,
+                        vault_authority,
+                        reward_token_program,
+                        clock,
+                        associated_token_program,
+                        system_program,
+                    })
+                }
+                _ => err!(ErrorCode::AccountNotEnoughKeys),
+            }
+        }
+
+        pub fn keys(&self) -> accounts::ClaimRewards {
+            accounts::ClaimRewards {
+                vault: self.vault.key(),
+                user_position: self.user_position.key(),
+                user: self.user.key(),
+                reward_mint: self.reward_mint.key(),
+                user_reward_token_account: self.user_reward_token_account.key(),
+                reward_vault: self.reward_vault.key(),
+                vault_authority: self.vault_authority.key(),
+                reward_token_program: self.reward_token_program.key(),
+            }
+        }
+
+        /// See [`instruction::claim_rewards`]
+        pub fn invoke(
+            &self,
+            vault_program: &AccountInfo<'info>,
+            signer_seeds: &[&[&[u8]]],
+        ) -> Result<()> {
+            invoke_vault(
+                &for_program(instruction::claim_rewards(&self.keys()), vault_program),
+                &[
+                    self.vault.clone(),
+                    self.user_position.clone(),
+                    self.user.clone(),
+                    self.reward_mint.clone(),
+                    self.user_reward_token_account.clone(),
+                    self.reward_vault.clone(),
+                    self.vault_authority.clone(),
+                    self.reward_token_program.clone(),
+                    self.clock.clone(),
+                    self.associated_token_program.clone(),
+                    self.system_program.clone(),
+                    vault_program.clone(),
+                ],
+                signer_seeds,
+            )
+        }
+    }
+}
+
+/// CPI into vault-core with an instruction from [`instruction`].
+///
+/// `account_infos` must contain every account referenced by the instruction
+/// (order doesn't matter). Pass `signer_seeds` when a PDA is the user/funder.
+pub fn invoke_vault<'info>(
+    ix: &Instruction,
+    account_infos: &[AccountInfo<'info>],
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    invoke_signed(ix, account_infos, signer_seeds).map_err(Into::into)
+}
+
+/// Decode the rate left in return data by a `get_exchange_rate` CPI
+pub fn read_exchange_rate() -> Result<u128> {
+    let (program_id, data) = get_return_data().ok_or(ErrorCode::InstructionDidNotDeserialize)?;
+    require_keys_eq!(program_id, ID, ErrorCode::InvalidProgramId);
+    u128::try_from_slice(&data).map_err(|_| error!(ErrorCode::InstructionDidNotDeserialize))
+}
@@ -0,0 +1,483 @@
+//! ABI stability checks: vault-interface must encode exactly what vault-core decodes.
+
+use anchor_lang::prelude::{AccountInfo, AccountMeta, Pubkey};
+use anchor_lang::{Discriminator, InstructionData, ToAccountMetas};
+use vault_interface::{accounts, cpi, discriminator, instruction};
+
+fn key() -> Pubkey {
+    Pubkey::new_unique()
+}
+
+#[test]
+fn program_id_matches() {
+    assert_eq!(vault_interface::ID, vault_core::ID);
+}
+
+#[test]
+fn discriminators_match() {
+    assert_eq!(
+        discriminator::DEPOSIT,
+        vault_core::instruction::Deposit::DISCRIMINATOR
+    );
+    assert_eq!(
+        discriminator::DEPOSIT_WITH_DELEGATE,
+        vault_core::instruction::DepositWithDelegate::DISCRIMINATOR
+    );
+    assert_eq!(
+        discriminator::WITHDRAW,
+        vault_core::instruction::Withdraw::DISCRIMINATOR
+    );
+    assert_eq!(
+        discriminator::CLAIM_REWARDS,
+        vault_core::instruction::ClaimRewards::DISCRIMINATOR
+    );
+    assert_eq!(
+        discriminator::FUND_REWARDS,
+        vault_core::instruction::FundRewards::DISCRIMINATOR
+    );
+    assert_eq!(
+        discriminator::FLASH_LOAN,
+        vault_core::instruction::FlashLoan::DISCRIMINATOR
+    );
+    assert_eq!(
+        discriminator::UPDATE_REWARDS,
+        vault_core::instruction::UpdateRewards::DISCRIMINATOR
+    );
+    assert_eq!(
+        discriminator::DONATE,
+        vault_core::instruction::Donate::DISCRIMINATOR
+    );
+    assert_eq!(
+        discriminator::GET_EXCHANGE_RATE,
+        vault_core::instruction::GetExchangeRate::DISCRIMINATOR
+    );
+}
+
+#[test]
+fn pdas_match() {
+    let mint = key();
+    let (vault, _) = vault_interface::pda::vault(&mint);
+    let user = key();
+    assert_eq!(
+        vault,
+        Pubkey::find_program_address(&[b"vault", mint.as_ref()], &vault_core::ID).0
+    );
+    assert_eq!(
+        vault_interface::pda::user_position(&vault, &user).0,
+        Pubkey::find_program_address(
+            &[b"position", vault.as_ref(), user.as_ref()],
+            &vault_core::ID
+        )
+        .0
+    );
+    assert_eq!(
+        vault_interface::pda::position_index(&vault, 3).0,
+        Pubkey::find_program_address(
+            &[b"position_index", vault.as_ref(), &3u64.to_le_bytes()],
+            &vault_core::ID
+        )
+        .0
+    );
+    for generation in [0u8, 2] {
+        assert_eq!(
+            vault_interface::pda::generation_seed(&generation),
+            vault_core::generation_seed(&generation)
+        );
+    }
+}
+
+#[test]
+fn vault_layout_matches() {
+    assert_eq!(
+        vault_interface::state::VAULT_DISCRIMINATOR,
+        vault_core::Vault::DISCRIMINATOR
+    );
+    assert_eq!(
+        vault_interface::state::VAULT_GENERATION_OFFSET,
+        8 + std::mem::offset_of!(vault_core::Vault, generation)
+    );
+
+    let vault = key();
+    let mut lamports = 0;
+    let mut data = vault_core::Vault::DISCRIMINATOR.to_vec();
+    data.resize(8 + std::mem::size_of::<vault_core::Vault>(), 0);
+    data[vault_interface::state::VAULT_GENERATION_OFFSET] = 2;
+    let info = AccountInfo::new(
+        &vault,
+        false,
+        false,
+        &mut lamports,
+        &mut data,
+        &vault_core::ID,
+        false,
+        0,
+    );
+    assert_eq!(
+        vault_interface::state::vault_generation(&info, &vault_core::ID).unwrap(),
+        2
+    );
+    assert!(vault_interface::state::vault_generation(&info, &key()).is_err());
+}
+
+#[test]
+fn deposit_abi() {
+    let a = accounts::Deposit {
+        vault: key(),
+        user_position: key(),
+        user: key(),
+        user_token_account: key(),
+        vault_token_account: key(),
+        vault_authority: key(),
+        user_checkpoints: key(),
+    };
+    let ix = instruction::deposit(&a, 42, 40, vec![[7u8; 32]]);
+    let expected = vault_core::accounts::Deposit {
+        vault: a.vault,
+        user_position: a.user_position,
+        user: a.user,
+        user_token_account: a.user_token_account,
+        vault_token_account: a.vault_token_account,
+        vault_authority: a.vault_authority,
+        token_program: vault_interface::TOKEN_PROGRAM_ID,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        user_checkpoints: Some(a.user_checkpoints),
+    };
+    assert_eq!(ix.accounts, expected.to_account_metas(None));
+    assert_eq!(
+        ix.data,
+        vault_core::instruction::Deposit {
+            amount: 42,
+            min_shares_out: 40,
+            proof: vec![[7u8; 32]],
+        }
+        .data()
+    );
+}
+
+#[test]
+fn deposit_with_delegate_abi() {
+    let a = accounts::DepositWithDelegate {
+        vault: key(),
+        user_position: key(),
+        user: key(),
+        payer: key(),
+        user_token_account: key(),
+        vault_token_account: key(),
+        vault_authority: key(),
+        user_checkpoints: key(),
+    };
+    let ix = instruction::deposit_with_delegate(&a, 42, 40, vec![[7u8; 32]]);
+    let expected = vault_core::accounts::DepositWithDelegate {
+        vault: a.vault,
+        user_position: a.user_position,
+        user: a.user,
+        payer: a.payer,
+        user_token_account: a.user_token_account,
+        vault_token_account: a.vault_token_account,
+        vault_authority: a.vault_authority,
+        token_program: vault_interface::TOKEN_PROGRAM_ID,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        user_checkpoints: Some(a.user_checkpoints),
+    };
+    assert_eq!(ix.accounts, expected.to_account_metas(None));
+    assert_eq!(
+        ix.data,
+        vault_core::instruction::DepositWithDelegate {
+            amount: 42,
+            min_shares_out: 40,
+            proof: vec![[7u8; 32]],
+        }
+        .data()
+    );
+}
+
+#[test]
+fn withdraw_abi() {
+    let a = accounts::Withdraw {
+        vault: key(),
+        user_position: key(),
+        user: key(),
+        user_token_account: key(),
+        vault_token_account: key(),
+        vault_authority: key(),
+        user_checkpoints: key(),
+    };
+    let ix = instruction::withdraw(&a, 7, 5);
+    let expected = vault_core::accounts::Withdraw {
+        vault: a.vault,
+        user_position: a.user_position,
+        user: a.user,
+        user_token_account: a.user_token_account,
+        vault_token_account: a.vault_token_account,
+        vault_authority: a.vault_authority,
+        token_program: vault_interface::TOKEN_PROGRAM_ID,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        clock: anchor_lang::solana_program::sysvar::clock::ID,
+        user_checkpoints: Some(a.user_checkpoints),
+    };
+    assert_eq!(ix.accounts, expected.to_account_metas(None));
+    assert_eq!(
+        ix.data,
+        vault_core::instruction::Withdraw {
+            shares: 7,
+            min_tokens_out: 5,
+        }
+        .data()
+    );
+}
+
+#[test]
+fn claim_rewards_abi() {
+    let a = accounts::ClaimRewards {
+        vault: key(),
+        user_position: key(),
+        user: key(),
+        reward_mint: key(),
+        user_reward_token_account: key(),
+        reward_vault: key(),
+        vault_authority: key(),
+        reward_token_program: vault_interface::TOKEN_2022_PROGRAM_ID,
+    };
+    let ix = instruction::claim_rewards(&a);
+    let expected = vault_core::accounts::ClaimRewards {
+        vault: a.vault,
+        user_position: a.user_position,
+        user: a.user,
+        reward_mint: a.reward_mint,
+        user_reward_token_account: a.user_reward_token_account,
+        reward_vault: a.reward_vault,
+        vault_authority: a.vault_authority,
+        reward_token_program: a.reward_token_program,
+        clock: anchor_lang::solana_program::sysvar::clock::ID,
+        associated_token_program: vault_interface::ASSOCIATED_TOKEN_PROGRAM_ID,
+        system_program: anchor_lang::solana_program::system_program::ID,
+    };
+    assert_eq!(ix.accounts, expected.to_account_metas(None));
+    assert_eq!(ix.data, vault_core::instruction::ClaimRewards {}.data());
+}
+
+#[test]
+fn fund_rewards_abi() {
+    let a = accounts::FundRewards {
+        vault: key(),
+        funder: key(),
+        funder_token_account: key(),
+        reward_vault: key(),
+        reward_mint: key(),
+        reward_token_program: vault_interface::TOKEN_PROGRAM_ID,
+    };
+    let ix = instruction::fund_rewards(&a, 1_000, 10);
+    let expected = vault_core::accounts::FundRewards {
+        vault: a.vault,
+        funder: a.funder,
+        funder_token_account: a.funder_token_account,
+        reward_vault: a.reward_vault,
+        reward_mint: a.reward_mint,
+        reward_token_program: a.reward_token_program,
+        protocol_config: vault_interface::pda::protocol_config().0,
+    };
+    assert_eq!(ix.accounts, expected.to_account_metas(None));
+    assert_eq!(
+        ix.data,
+        vault_core::instruction::FundRewards {
+            amount: 1_000,
+            reward_rate: 10
+        }
+        .data()
+    );
+}
+
+#[test]
+fn flash_loan_abi() {
+    let a = accounts::FlashLoan {
+        vault: key(),
+        vault_token_account: key(),
+        reward_vault: key(),
+        vault_authority: key(),
+        borrower: key(),
+        borrower_token_account: key(),
+        callback_program: key(),
+    };
+    let ix = instruction::flash_loan(&a, 500, vec![]);
+    let expected = vault_core::accounts::FlashLoan {
+        vault: a.vault,
+        vault_token_account: a.vault_token_account,
+        reward_vault: a.reward_vault,
+        vault_authority: a.vault_authority,
+        borrower: a.borrower,
+        borrower_token_account: a.borrower_token_account,
+        callback_program: a.callback_program,
+        token_program: vault_interface::TOKEN_PROGRAM_ID,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        instructions_sysvar: anchor_lang::solana_program::sysvar::instructions::ID,
+    };
+    // vault-core also lends to non-signing PDA borrowers; a CPI caller always signs
+    let mut expected_metas = expected.to_account_metas(None);
+    expected_metas[4].is_signer = true;
+    assert_eq!(ix.accounts, expected_metas);
+    assert_eq!(
+        ix.data,
+        vault_core::instruction::FlashLoan { amount: 500 }.data()
+    );
+}
+
+#[test]
+fn update_rewards_abi() {
+    let a = accounts::UpdateRewards { vault: key() };
+    let ix = instruction::update_rewards(&a);
+    let expected = vault_core::accounts::UpdateRewards { vault: a.vault };
+    assert_eq!(ix.accounts, expected.to_account_metas(None));
+    assert_eq!(ix.data, vault_core::instruction::UpdateRewards {}.data());
+}
+
+#[test]
+fn donate_abi() {
+    let a = accounts::Donate {
+        vault: key(),
+        donor: key(),
+        donor_token_account: key(),
+        vault_token_account: key(),
+        vault_authority: key(),
+    };
+    let ix = instruction::donate(&a, 500);
+    let expected = vault_core::accounts::Donate {
+        vault: a.vault,
+        donor: a.donor,
+        donor_token_account: a.donor_token_account,
+        vault_token_account: a.vault_token_account,
+        vault_authority: a.vault_authority,
+        token_program: vault_interface::TOKEN_PROGRAM_ID,
+    };
+    assert_eq!(ix.accounts, expected.to_account_metas(None));
+    assert_eq!(
+        ix.data,
+        vault_core::instruction::Donate { amount: 500 }.data()
+    );
+}
+
+#[test]
+fn get_exchange_rate_abi() {
+    let a = accounts::GetExchangeRate {
+        vault: key(),
+        vault_token_account: key(),
+        vault_authority: key(),
+    };
+    let ix = instruction::get_exchange_rate(&a);
+    let expected = vault_core::accounts::GetExchangeRate {
+        vault: a.vault,
+        vault_token_account: a.vault_token_account,
+        vault_authority: a.vault_authority,
+    };
+    assert_eq!(ix.accounts, expected.to_account_metas(None));
+    assert_eq!(ix.data, vault_core::instruction::GetExchangeRate {}.data());
+    assert_eq!(
+        vault_interface::EXCHANGE_RATE_PRECISION,
+        vault_core::EXCHANGE_RATE_PRECISION
+    );
+}
+
+/// `AccountInfo`s for `metas`, in order
+fn with_infos<R>(metas: &[AccountMeta], f: impl FnOnce(&[AccountInfo]) -> R) -> R {
+    let keys: Vec<Pubkey> = metas.iter().map(|m| m.pubkey).collect();
+    let mut lamports = vec![0u64; metas.len()];
+    let mut data = vec![Vec::<u8>::new(); metas.len()];
+    let owner = Pubkey::default();
+    let infos: Vec<AccountInfo> = keys
+        .iter()
+        .zip(metas)
+        .zip(lamports.iter_mut().zip(data.iter_mut()))
+        .map(|((key, meta), (lamports, data))| {
+            AccountInfo::new(
+                key,
+                meta.is_signer,
+                meta.is_writable,
+                lamports,
+                data,
+                &owner,
+                false,
+                0,
+            )
+        })
+        .collect();
+    f(&infos)
+}
+
+#[test]
+fn cpi_slices_follow_abi() {
+    let deposit = vault_core::accounts::Deposit {
+        vault: key(),
+        user_position: key(),
+        user: key(),
+        user_token_account: key(),
+        vault_token_account: key(),
+        vault_authority: key(),
+        token_program: vault_interface::TOKEN_PROGRAM_ID,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        user_checkpoints: Some(key()),
+    }
+    .to_account_metas(None);
+    with_infos(&deposit, |infos| {
+        let parsed = cpi::Deposit::from_slice(infos).unwrap();
+        assert_eq!(parsed.keys().to_account_metas(), deposit);
+        assert_eq!(cpi::Deposit::LEN, deposit.len());
+        assert!(cpi::Deposit::from_slice(&infos[1..]).is_err());
+    });
+
+    let withdraw = vault_core::accounts::Withdraw {
+        vault: key(),
+        user_position: key(),
+        user: key(),
+        user_token_account: key(),
+        vault_token_account: key(),
+        vault_authority: key(),
+        token_program: vault_interface::TOKEN_PROGRAM_ID,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        clock: anchor_lang::solana_program::sysvar::clock::ID,
+        user_checkpoints: Some(key()),
+    }
+    .to_account_metas(None);
+    let deposit_with_delegate = vault_core::accounts::DepositWithDelegate {
+        vault: key(),
+        user_position: key(),
+        user: key(),
+        payer: key(),
+        user_token_account: key(),
+        vault_token_account: key(),
+        vault_authority: key(),
+        token_program: vault_interface::TOKEN_PROGRAM_ID,
+        system_program: anchor_lang::solana_program::system_program::ID,
+        user_checkpoints: Some(key()),
+    }
+    .to_account_metas(None);
+    with_infos(&deposit_with_delegate, |infos| {
+        let parsed = cpi::DepositWithDelegate::from_slice(infos).unwrap();
+        assert_eq!(parsed.keys().to_account_metas(), deposit_with_delegate);
+        assert_eq!(cpi::DepositWithDelegate::LEN, deposit_with_delegate.len());
+    });
+
+    with_infos(&withdraw, |infos| {
+        let parsed = cpi::Withdraw::from_slice(infos).unwrap();
+        assert_eq!(parsed.keys().to_account_metas(), withdraw);
+        assert_eq!(cpi::Withdraw::LEN, withdraw.len());
+    });
+
+    let claim = vault_core::accounts::ClaimRewards {
+        vault: key(),
+        user_position: key(),
+        user: key(),
+        reward_mint: key(),
+        user_reward_token_account: key(),
+        reward_vault: key(),
+        vault_authority: key(),
+        reward_token_program: vault_interface::TOKEN_2022_PROGRAM_ID,
+        clock: anchor_lang::solana_program::sysvar::clock::ID,
+        associated_token_program: vault_interface::ASSOCIATED_TOKEN_PROGRAM_ID,
+        system_program: anchor_lang::solana_program::system_program::ID,
+    }
+    .to_account_metas(None);
+    with_infos(&claim, |infos| {
+        let parsed = cpi::ClaimRewards::from_slice(infos).unwrap();
+        assert_eq!(parsed.keys().to_account_metas(), claim);
+        assert_eq!(cpi::ClaimRewards::LEN, claim.len());
+    });
+}
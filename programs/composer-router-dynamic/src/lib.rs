@@ -1,7 +1,11 @@
 use anchor_lang::prelude::*;
-use anchor_lang::solana_program::program::invoke_signed;
-use anchor_spl::token::{Token, TokenAccount};
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::invoke;
+use anchor_spl::associated_token::{self, get_associated_token_address_with_program_id};
+use anchor_spl::token::{self, Token, TokenAccount};
+use anchor_spl::token_2022;
 use solana_program::hash;
+use vault_interface::{pda as vault_pda, state as vault_state};
 
 declare_id!("Df2vmmXUbtYRyPRiXdyFWYf2PiwYQo5vMAxTbHz2WH1y");
 
@@ -41,10 +45,43 @@ pub mod composer_router_dynamic {
         Ok(())
     }
 
+    /// Record how to call and validate an allow-listed swap program's swap instruction:
+    /// its 8-byte `discriminator` and which of its swap accounts play which role.
+    /// Registering a program again replaces its layout.
+    pub fn register_adapter(
+        ctx: Context<RegisterAdapter>,
+        program_id: Pubkey,
+        discriminator: [u8; 8],
+        account_roles: AccountRoles,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.config.swap_programs.contains(&program_id),
+            RouterError::SwapProgramNotAllowlisted
+        );
+        let count = account_roles.account_count;
+        require!(
+            [account_roles.signer, account_roles.token_in, account_roles.token_out]
+                .into_iter()
+                .chain(account_roles.pool_authority)
+                .all(|index| index < count)
+                && account_roles.token_in != account_roles.token_out,
+            RouterError::InvalidAccountRoles
+        );
+
+        let adapter = &mut ctx.accounts.adapter;
+        adapter.program_id = program_id;
+        adapter.discriminator = discriminator;
+        adapter.account_roles = account_roles;
+        adapter.bump = ctx.bumps.adapter;
+
+        Ok(())
+    }
+
     /// Deposit → Swap → Stake workflow
     /// 
     /// This instruction atomically executes:
-    /// 1. Swaps input tokens for output tokens via CPI to swap_program
+    /// 1. Swaps input tokens for output tokens via CPI to swap_program, as laid out by
+    ///    its registered `SwapAdapter`
     /// 2. Deposits output tokens into vault via CPI to vault_program
     /// 
     /// Account layout:
@@ -55,33 +92,49 @@ pub mod composer_router_dynamic {
     /// - input_token_account (mut): User's token account for input tokens (token A)
     /// - output_token_account (mut): User's token account for output tokens (token B, receives swap output)
     /// - swap_program: Program ID to CPI to for swap (must be in allowlist)
+    /// - adapter: swap_program's `SwapAdapter` (see `register_adapter`)
     /// - vault_program: vault-core program ID to CPI to for deposit
     /// - token_program: SPL Token program
     /// - system_program: System program
     /// 
     /// Remaining accounts (variable, passed through to CPIs):
     /// 
-    /// First 8 accounts - Swap instruction accounts (for mock-amm swap):
-    /// - [0] pool: AMM Pool account
+    /// First `account_roles.account_count` accounts - Swap instruction accounts, in the
+    /// swap program's order. The adapter's roles are validated: `signer` must be the
+    /// user, `token_in` input_token_account, `token_out` output_token_account, and
+    /// `pool_authority`, if set, the pair's pool authority PDA. For mock-amm (11):
+    /// - [0] pool (mut): AMM Pool account
     /// - [1] user: User signer (same as fixed accounts user)
     /// - [2] user_token_in: Must match input_token_account (validated)
     /// - [3] user_token_out: Must match output_token_account (validated)
     /// - [4] vault_a: Pool's token A vault
     /// - [5] vault_b: Pool's token B vault
     /// - [6] pool_authority: Pool's PDA authority
-    /// - [7] token_program: SPL Token program
+    /// - [7] mint_a: Pool's token A mint
+    /// - [8] mint_b: Pool's token B mint
+    /// - [9] token_program_a: Token program of mint_a (SPL Token or Token-2022)
+    /// - [10] token_program_b: Token program of mint_b
     /// 
-    /// Next 8 accounts - Vault deposit instruction accounts:
-    /// - [8] vault: Vault account (must match output_token_account mint)
-    /// - [9] user_position: User's position PDA in vault
-    /// - [10] user: User signer (same as fixed accounts user)
-    /// - [11] user_token_account: Must match output_token_account (validated)
-    /// - [12] vault_token_account: Vault's token account
-    /// - [13] vault_authority: Vault's PDA authority
-    /// - [14] token_program: SPL Token program
-    /// - [15] system_program: System program
+    /// Next 9 accounts - Vault deposit instruction accounts:
+    /// - [+0] vault: Vault account (must match output_token_account mint)
+    /// - [+1] user_position: User's position PDA in vault
+    /// - [+2] user: User signer (same as fixed accounts user)
+    /// - [+3] user_token_account: Must match output_token_account (validated)
+    /// - [+4] vault_token_account: Vault's token account
+    /// - [+5] vault_authority: Vault's PDA authority
+    /// - [+6] token_program: SPL Token program
+    /// - [+7] system_program: System program
+    /// - [+8] user_checkpoints (mut): User's share checkpoint PDA in vault
     /// 
-    /// Total: 16 remaining accounts required
+    /// Total: `account_count` + 9 remaining accounts required (20 for mock-amm)
+    ///
+    /// A `vault_deposit_amount` of 0 deposits exactly what the swap paid out.
+    ///
+    /// `fee_tier` selects which of the pair's pools the swap accounts belong to. The
+    /// route fails with `DeadlineExceeded` once the clock passes `deadline` (unix time).
+    ///
+    /// Returns the swap output and shares minted as a `RouteResult` in return data.
+    #[allow(clippy::too_many_arguments)]
     pub fn deposit_swap_stake(
         ctx: Context<DepositSwapStake>,
         swap_amount_in: u64,
@@ -89,10 +142,15 @@ pub mod composer_router_dynamic {
         vault_deposit_amount: u64,
         expected_input_mint: Pubkey,
         expected_output_mint: Pubkey,
-    ) -> Result<()> {
+        fee_tier: u16,
+        deadline: i64,
+    ) -> Result<RouteResult> {
+        require!(
+            Clock::get()?.unix_timestamp <= deadline,
+            RouterError::DeadlineExceeded
+        );
         require!(swap_amount_in > 0, RouterError::InvalidAmount);
         require!(min_amount_out > 0, RouterError::InvalidAmount);
-        require!(vault_deposit_amount > 0, RouterError::InvalidAmount);
 
         let config = &ctx.accounts.config;
 
@@ -128,195 +186,300 @@ pub mod composer_router_dynamic {
             RouterError::InsufficientBalance
         );
 
-        // 5. CPI to swap program
-        // For mock-amm swap instruction:
-        // Accounts: pool, user, user_token_in, user_token_out, vault_a, vault_b, pool_authority, token_program (8 accounts)
-        // Instruction: swap(amount_in: u64, min_amount_out: u64)
-        
-        // Calculate Anchor instruction discriminator: first 8 bytes of sha256("global:swap")
-        let swap_discriminator = hash::hash(b"global:swap").to_bytes()[..8].to_vec();
-        
-        let mut swap_ix_data = swap_discriminator;
-        swap_ix_data.extend_from_slice(&swap_amount_in.to_le_bytes());
-        swap_ix_data.extend_from_slice(&min_amount_out.to_le_bytes());
+        // 5. Validate the route accounts before any CPI
+        // The swap takes the adapter's account_count accounts; vault deposit needs 9:
+        // vault, user_position, user, user_token_account, vault_token_account,
+        // vault_authority, token_program, system_program, user_checkpoints
+        const VAULT_DEPOSIT_ACCOUNT_COUNT: usize = 9;
+        let roles = ctx.accounts.adapter.account_roles;
 
-        // Extract swap accounts from remaining_accounts
-        // mock-amm swap needs 8 accounts: pool, user, user_token_in, user_token_out, vault_a, vault_b, pool_authority, token_program
-        const MOCK_AMM_SWAP_ACCOUNT_COUNT: usize = 8;
-        
-        if ctx.remaining_accounts.len() < MOCK_AMM_SWAP_ACCOUNT_COUNT {
-            return Err(RouterError::InsufficientAccounts.into());
-        }
+        let legs = split_route(
+            ctx.remaining_accounts,
+            &[roles.account_count as usize, VAULT_DEPOSIT_ACCOUNT_COUNT],
+        )?;
+        let (swap_accounts, vault_accounts) = (legs[0], legs[1]);
 
-        let swap_accounts: Vec<_> = ctx.remaining_accounts
-            .iter()
-            .take(MOCK_AMM_SWAP_ACCOUNT_COUNT)
-            .collect();
+        // The user signs the swap, which moves input_token_account →
+        // output_token_account, and the deposit stakes from output_token_account
+        require!(
+            swap_accounts[roles.signer as usize].key() == ctx.accounts.user.key(),
+            RouterError::InvalidTokenAccountOwner
+        );
+        require!(
+            swap_accounts[roles.token_in as usize].key() == ctx.accounts.input_token_account.key(),
+            RouterError::InvalidTokenAccount
+        );
+        require!(
+            swap_accounts[roles.token_out as usize].key()
+                == ctx.accounts.output_token_account.key(),
+            RouterError::InvalidTokenAccount
+        );
+        require!(
+            vault_accounts[3].key() == ctx.accounts.output_token_account.key(),
+            RouterError::InvalidTokenAccount
+        );
 
-        // Validate swap accounts match expected token accounts
-        // Account 2 should be user_token_in (input_token_account)
-        // Account 3 should be user_token_out (output_token_account)
-        if swap_accounts.len() >= 4 {
-            require!(
-                swap_accounts[2].key() == ctx.accounts.input_token_account.key(),
-                RouterError::InvalidMint
+        // The pool and vault authorities are PDAs of the swap and vault programs; the
+        // router never signs for them, it only checks the route points at the pair's
+        // pool (seeds: [b"pool", mint_a, mint_b, fee_tier (LE), b"authority"], mints
+        // smaller first) when the adapter has one, and the output mint's vault
+        // (seeds: [b"vault", mint, b"authority", generation seed], the generation read
+        // from the vault account)
+        if let Some(index) = roles.pool_authority {
+            let (mint1, mint2) = if expected_input_mint < expected_output_mint {
+                (expected_input_mint, expected_output_mint)
+            } else {
+                (expected_output_mint, expected_input_mint)
+            };
+            let (pool_authority_pda, _) = Pubkey::find_program_address(
+                &[
+                    b"pool",
+                    mint1.as_ref(),
+                    mint2.as_ref(),
+                    &fee_tier.to_le_bytes(),
+                    b"authority",
+                ],
+                ctx.accounts.swap_program.key,
             );
             require!(
-                swap_accounts[3].key() == ctx.accounts.output_token_account.key(),
-                RouterError::InvalidMint
+                swap_accounts[index as usize].key() == pool_authority_pda,
+                RouterError::InvalidPoolAuthority
             );
         }
-
-        // Derive pool_authority PDA for signing
-        // Pool authority seeds: [b"pool", mint_a, mint_b, b"authority"]
-        // Mints must be in deterministic order (smaller first)
-        let (mint1, mint2) = if expected_input_mint < expected_output_mint {
-            (expected_input_mint, expected_output_mint)
-        } else {
-            (expected_output_mint, expected_input_mint)
-        };
-        
-        msg!("swap_program: {}", ctx.accounts.swap_program.key);
-        let (pool_authority_pda, pool_authority_bump) = Pubkey::find_program_address(
+        let generation =
+            vault_state::vault_generation(&vault_accounts[0], ctx.accounts.vault_program.key)?;
+        let (vault_authority_pda, _) = Pubkey::find_program_address(
             &[
-                b"pool",
-                mint1.as_ref(),
-                mint2.as_ref(),
+                b"vault",
+                expected_output_mint.as_ref(),
                 b"authority",
+                vault_pda::generation_seed(&generation),
             ],
-            ctx.accounts.swap_program.key,
+            ctx.accounts.vault_program.key,
         );
-        msg!("pool_authority_pda: {}", pool_authority_pda);
-        msg!("pool_authority_bump: {}", pool_authority_bump);
-        
-        // Verify the pool_authority account matches
         require!(
-            swap_accounts[6].key() == pool_authority_pda,
-            RouterError::InvalidMint
+            vault_accounts[5].key() == vault_authority_pda,
+            RouterError::InvalidVaultAuthority
         );
 
-        // Build swap instruction
-        let swap_ix = anchor_lang::solana_program::instruction::Instruction {
-            program_id: ctx.accounts.swap_program.key(),
-            accounts: swap_accounts.iter().map(|acc| {
-                anchor_lang::solana_program::instruction::AccountMeta {
-                    pubkey: acc.key(),
-                    is_signer: acc.is_signer,
-                    is_writable: acc.is_writable,
-                }
-            }).collect(),
-            data: swap_ix_data,
-        };
+        // 6. CPI to swap program
+        // Instruction: swap(amount_in: u64, min_amount_out: u64, limit_price: Option<u128>)
+        // under the adapter's discriminator
+        let mut swap_ix_data = ctx.accounts.adapter.discriminator.to_vec();
+        swap_ix_data.extend_from_slice(&swap_amount_in.to_le_bytes());
+        swap_ix_data.extend_from_slice(&min_amount_out.to_le_bytes());
+        // limit_price: None (Borsh option tag 0); min_amount_out is the route's bound
+        swap_ix_data.push(0);
 
-        // Use invoke_signed to allow swap program to sign with pool_authority PDA
-        let swap_account_infos: Vec<_> = swap_accounts.iter().map(|acc| (*acc).clone()).collect();
-        let pool_authority_seeds = &[
-            b"pool",
-            mint1.as_ref(),
-            mint2.as_ref(),
-            b"authority",
-            &[pool_authority_bump],
-        ];
-        invoke_signed(
-            &swap_ix,
-            &swap_account_infos,
-            &[&pool_authority_seeds[..]],
+        let output_before = ctx.accounts.output_token_account.amount;
+        invoke(
+            &passthrough_instruction(ctx.accounts.swap_program.key(), swap_accounts, swap_ix_data),
+            swap_accounts,
         )?;
 
-        // 6. Reload output token account to verify swap happened
+        // 7. Reload output token account to verify swap happened
         ctx.accounts.output_token_account.reload()?;
+        let swapped = ctx
+            .accounts
+            .output_token_account
+            .amount
+            .checked_sub(output_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        let deposit_amount = if vault_deposit_amount == 0 {
+            swapped
+        } else {
+            vault_deposit_amount
+        };
 
-        // Validate that vault deposit will use the output token account
-        // The vault deposit's user_token_account (4th account in remaining_accounts after swap accounts)
-        // should be the output_token_account
-        if ctx.remaining_accounts.len() > MOCK_AMM_SWAP_ACCOUNT_COUNT + 3 {
-            let vault_user_token_account = &ctx.remaining_accounts[MOCK_AMM_SWAP_ACCOUNT_COUNT + 3];
-            require!(
-                vault_user_token_account.key() == ctx.accounts.output_token_account.key(),
-                RouterError::InvalidMint
-            );
-        }
-
-        // 7. CPI to vault-core deposit
-        // Vault deposit instruction: deposit(amount: u64)
-        // Accounts: vault, user_position, user, user_token_account, vault_token_account, vault_authority, token_program, system_program (8 accounts)
-        
+        // 8. CPI to vault-core deposit
+        // Instruction: deposit(amount: u64, min_shares_out: u64, proof: Vec<[u8; 32]>)
         // Calculate Anchor instruction discriminator: first 8 bytes of sha256("global:deposit")
-        let deposit_discriminator = hash::hash(b"global:deposit").to_bytes()[..8].to_vec();
-        
-        let mut vault_ix_data = deposit_discriminator;
-        vault_ix_data.extend_from_slice(&vault_deposit_amount.to_le_bytes());
-
-        // Extract vault accounts from remaining_accounts (after swap accounts)
-        // Vault deposit needs: vault, user_position, user, user_token_account, vault_token_account, vault_authority, token_program, system_program
-        const VAULT_DEPOSIT_ACCOUNT_COUNT: usize = 8;
-        
-        if ctx.remaining_accounts.len() < MOCK_AMM_SWAP_ACCOUNT_COUNT + VAULT_DEPOSIT_ACCOUNT_COUNT {
-            return Err(RouterError::InsufficientAccounts.into());
-        }
+        let mut vault_ix_data = hash::hash(b"global:deposit").to_bytes()[..8].to_vec();
+        vault_ix_data.extend_from_slice(&deposit_amount.to_le_bytes());
+        // No share floor: the swap's min_amount_out already bounds the route
+        vault_ix_data.extend_from_slice(&0u64.to_le_bytes());
+        // Empty allowlist proof (Borsh u32 length prefix): routes only target open vaults
+        vault_ix_data.extend_from_slice(&0u32.to_le_bytes());
+
+        let shares_before = position_shares(&vault_accounts[1], ctx.accounts.vault_program.key)?;
+        invoke(
+            &passthrough_instruction(ctx.accounts.vault_program.key(), vault_accounts, vault_ix_data),
+            vault_accounts,
+        )?;
+        let shares_after = position_shares(&vault_accounts[1], ctx.accounts.vault_program.key)?;
 
-        let vault_accounts: Vec<_> = ctx.remaining_accounts
-            .iter()
-            .skip(MOCK_AMM_SWAP_ACCOUNT_COUNT)
-            .take(VAULT_DEPOSIT_ACCOUNT_COUNT)
-            .collect();
+        Ok(RouteResult {
+            amount_out: swapped,
+            shares_minted: shares_after.saturating_sub(shares_before),
+        })
+    }
 
-        // Derive vault_authority PDA for signing
-        // Vault authority seeds: [b"vault", token_mint, b"authority"]
-        let (vault_authority_pda, vault_authority_bump) = Pubkey::find_program_address(
-            &[
-                b"vault",
-                expected_output_mint.as_ref(),
-                b"authority",
-            ],
-            ctx.accounts.vault_program.key,
+    /// Generic route executor
+    ///
+    /// Walks `plan.steps` in order, each over the next remaining accounts:
+    /// - `Invoke` CPIs the step's program with its raw instruction data over the next
+    ///   `account_count` accounts, passed through with the caller's signer and
+    ///   writable flags. `program_index` points at the step's program among the
+    ///   remaining accounts (usually listed after every step's accounts, and reusable
+    ///   across steps); each must be allow-listed in `config`, and the router itself
+    ///   can't be a step.
+    /// - `CreateAta` idempotently creates an associated token account, so a route can
+    ///   open a first-time user's output or reward account before the legs that need
+    ///   it (see `RouteStep::CreateAta` for its accounts)
+    ///
+    /// Remaining accounts: step 0's accounts, step 1's accounts, ..., then the programs.
+    ///
+    /// The router signs nothing, so a step can only do what the transaction's own
+    /// signers already authorize; amounts and slippage bounds live in the steps' data.
+    /// The route fails with `DeadlineExceeded` once the clock passes `deadline`.
+    pub fn execute_route(
+        ctx: Context<ExecuteRoute>,
+        plan: RoutePlan,
+        deadline: i64,
+    ) -> Result<()> {
+        require!(
+            Clock::get()?.unix_timestamp <= deadline,
+            RouterError::DeadlineExceeded
         );
-        msg!("vault_authority_pda: {}", vault_authority_pda);
-        msg!("vault_authority_bump: {}", vault_authority_bump);
-        
-        // Verify the vault_authority account matches (5th account in vault_accounts)
         require!(
-            vault_accounts[5].key() == vault_authority_pda,
-            RouterError::InvalidMint
+            !plan.steps.is_empty() && plan.steps.len() <= RoutePlan::MAX_STEPS,
+            RouterError::InvalidRoutePlan
         );
 
-        // Build vault deposit instruction
-        let vault_ix = anchor_lang::solana_program::instruction::Instruction {
-            program_id: ctx.accounts.vault_program.key(),
-            accounts: vault_accounts.iter().map(|acc| {
-                anchor_lang::solana_program::instruction::AccountMeta {
-                    pubkey: acc.key(),
-                    is_signer: acc.is_signer,
-                    is_writable: acc.is_writable,
+        let counts: Vec<usize> = plan.steps.iter().map(RouteStep::account_count).collect();
+        let legs = split_route(ctx.remaining_accounts, &counts)?;
+
+        for (step, accounts) in plan.steps.into_iter().zip(legs) {
+            let (program_index, data) = match step {
+                RouteStep::Invoke {
+                    program_index,
+                    data,
+                    ..
+                } => (program_index, data),
+                RouteStep::CreateAta => {
+                    create_ata(accounts)?;
+                    continue;
                 }
-            }).collect(),
-            data: vault_ix_data,
-        };
-        msg!("check2");
-        // Use invoke_signed to allow vault program to sign with vault_authority PDA
-        let vault_account_infos: Vec<_> = vault_accounts.iter().map(|acc| (*acc).clone()).collect();
-        //msg!("vault_authority: {}", vault_authority_pda);
-        msg!("expected_output_mint: {}", expected_output_mint);
-        msg!("vault_program: {}", ctx.accounts.vault_program.key());
-        let vault_authority_seeds = &[
-            b"vault",
-            expected_output_mint.as_ref(),
-            b"authority",
-            //&[vault_authority_bump],
-        ];
-        invoke_signed(
-            &vault_ix,
-            &vault_account_infos,
-            &[&vault_authority_seeds[..]],
-        )?;
+            };
+            let program = ctx
+                .remaining_accounts
+                .get(program_index as usize)
+                .ok_or(RouterError::InsufficientAccounts)?;
+            require!(
+                program.executable && program.key() != crate::ID,
+                RouterError::InvalidRoutePlan
+            );
+            require!(
+                ctx.accounts.config.swap_programs.contains(program.key),
+                RouterError::SwapProgramNotAllowlisted
+            );
+
+            invoke(
+                &passthrough_instruction(program.key(), accounts, data),
+                &[accounts, std::slice::from_ref(program)].concat(),
+            )?;
+        }
 
         Ok(())
     }
 }
 
+/// `RouteStep::CreateAta`: idempotently create the owner's associated token account
+/// for the mint, under the leg's token program (SPL Token or Token-2022). Rent is paid
+/// by the leg's payer, the user or a sponsor covering onboarding costs.
+fn create_ata(accounts: &[AccountInfo]) -> Result<()> {
+    let [payer, associated_token_account, owner, mint, system_program, token_program, associated_token_program] =
+        accounts
+    else {
+        return err!(RouterError::InsufficientAccounts);
+    };
+    require!(
+        *token_program.key == token::ID || *token_program.key == token_2022::ID,
+        RouterError::InvalidTokenProgram
+    );
+    require_keys_eq!(*mint.owner, *token_program.key, RouterError::InvalidMint);
+    require_keys_eq!(
+        *associated_token_program.key,
+        associated_token::ID,
+        RouterError::InvalidRoutePlan
+    );
+    let expected_ata =
+        get_associated_token_address_with_program_id(owner.key, mint.key, token_program.key);
+    require_keys_eq!(
+        associated_token_account.key(),
+        expected_ata,
+        RouterError::InvalidAssociatedTokenAccount
+    );
+
+    associated_token::create_idempotent(CpiContext::new(
+        associated_token_program.clone(),
+        associated_token::Create {
+            payer: payer.clone(),
+            associated_token: associated_token_account.clone(),
+            authority: owner.clone(),
+            mint: mint.clone(),
+            system_program: system_program.clone(),
+            token_program: token_program.clone(),
+        },
+    ))
+}
+
+/// Slice `accounts` into consecutive legs of `counts` accounts each
+fn split_route<'a, 'info>(
+    accounts: &'a [AccountInfo<'info>],
+    counts: &[usize],
+) -> Result<Vec<&'a [AccountInfo<'info>]>> {
+    let mut rest = accounts;
+    let mut legs = Vec::with_capacity(counts.len());
+    for &count in counts {
+        require!(rest.len() >= count, RouterError::InsufficientAccounts);
+        let (leg, tail) = rest.split_at(count);
+        legs.push(leg);
+        rest = tail;
+    }
+    Ok(legs)
+}
+
+/// Offset of `shares` (u128) in a vault-core `UserPosition`: after the discriminator,
+/// user, vault, legacy shares, reward debt, keeper flag, version, delegate, stake
+/// timestamp and rewards owed
+const POSITION_SHARES_OFFSET: usize = 154;
+
+/// Shares of the vault-core position at `info`; zero until its first deposit creates it
+fn position_shares(info: &AccountInfo, vault_program: &Pubkey) -> Result<u128> {
+    if info.data_is_empty() {
+        return Ok(0);
+    }
+    require_keys_eq!(*info.owner, *vault_program, ErrorCode::AccountOwnedByWrongProgram);
+    let data = info.try_borrow_data()?;
+    let shares = data
+        .get(POSITION_SHARES_OFFSET..POSITION_SHARES_OFFSET + 16)
+        .ok_or(ErrorCode::AccountDidNotDeserialize)?;
+    Ok(u128::from_le_bytes(shares.try_into().unwrap()))
+}
+
+/// Instruction for `program_id` over `accounts` as passed in, keeping the caller's
+/// signer and writable flags
+fn passthrough_instruction(program_id: Pubkey, accounts: &[AccountInfo], data: Vec<u8>) -> Instruction {
+    Instruction {
+        program_id,
+        accounts: accounts
+            .iter()
+            .map(|acc| AccountMeta {
+                pubkey: acc.key(),
+                is_signer: acc.is_signer,
+                is_writable: acc.is_writable,
+            })
+            .collect(),
+        data,
+    }
+}
+
 #[account]
 pub struct RouterConfig {
     pub authority: Pubkey,
+    /// Programs the router may CPI: the swap program of `deposit_swap_stake` and
+    /// every step program of `execute_route`
     pub swap_programs: Vec<Pubkey>,
     pub bump: u8,
 }
@@ -329,6 +492,43 @@ impl RouterConfig {
         1; // bump
 }
 
+/// How `deposit_swap_stake` calls and validates one swap program
+#[account]
+pub struct SwapAdapter {
+    pub program_id: Pubkey,
+    /// Discriminator of the program's swap instruction
+    pub discriminator: [u8; 8],
+    pub account_roles: AccountRoles,
+    pub bump: u8,
+}
+
+impl SwapAdapter {
+    pub const LEN: usize = 8 + // discriminator
+        32 + // program_id
+        8 + // swap discriminator
+        AccountRoles::LEN +
+        1; // bump
+}
+
+/// Indices, among a swap's accounts, of the accounts the router validates
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccountRoles {
+    /// Accounts the swap instruction takes
+    pub account_count: u8,
+    /// The user signing the swap
+    pub signer: u8,
+    /// The user's token account the swap pays from
+    pub token_in: u8,
+    /// The user's token account the swap pays into
+    pub token_out: u8,
+    /// The pool's mock-amm-style authority PDA, for programs that have one
+    pub pool_authority: Option<u8>,
+}
+
+impl AccountRoles {
+    pub const LEN: usize = 4 + 2;
+}
+
 #[derive(Accounts)]
 pub struct InitializeRouterConfig<'info> {
     #[account(
@@ -359,6 +559,31 @@ pub struct UpdateRouterConfig<'info> {
     pub authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+#[instruction(program_id: Pubkey)]
+pub struct RegisterAdapter<'info> {
+    #[account(
+        seeds = [b"router_config"],
+        bump = config.bump,
+        has_one = authority @ RouterError::Unauthorized
+    )]
+    pub config: Account<'info, RouterConfig>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = SwapAdapter::LEN,
+        seeds = [b"adapter", program_id.as_ref()],
+        bump
+    )]
+    pub adapter: Account<'info, SwapAdapter>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct DepositSwapStake<'info> {
     #[account(
@@ -379,6 +604,12 @@ pub struct DepositSwapStake<'info> {
     /// CHECK: Swap program to CPI to
     pub swap_program: UncheckedAccount<'info>,
 
+    #[account(
+        seeds = [b"adapter", swap_program.key().as_ref()],
+        bump = adapter.bump
+    )]
+    pub adapter: Account<'info, SwapAdapter>,
+
     /// CHECK: Vault program to CPI to
     pub vault_program: UncheckedAccount<'info>,
 
@@ -386,6 +617,66 @@ pub struct DepositSwapStake<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct ExecuteRoute<'info> {
+    #[account(
+        seeds = [b"router_config"],
+        bump = config.bump
+    )]
+    pub config: Account<'info, RouterConfig>,
+}
+
+/// Steps `execute_route` runs in order
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RoutePlan {
+    pub steps: Vec<RouteStep>,
+}
+
+impl RoutePlan {
+    pub const MAX_STEPS: usize = 8;
+}
+
+/// One leg of a `RoutePlan`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub enum RouteStep {
+    /// CPI to an allow-listed program
+    Invoke {
+        /// Remaining account index of the program to invoke
+        program_index: u8,
+        /// Remaining accounts the step consumes, in instruction order
+        account_count: u8,
+        /// Raw instruction data, discriminator included
+        data: Vec<u8>,
+    },
+    /// Idempotently create an associated token account. Consumes
+    /// `RouteStep::CREATE_ATA_ACCOUNTS` accounts: payer (signer, mut),
+    /// associated_token_account (mut), owner, mint, system_program, token_program
+    /// (SPL Token or Token-2022, the mint's owner) and associated_token_program.
+    CreateAta,
+}
+
+impl RouteStep {
+    pub const CREATE_ATA_ACCOUNTS: usize = 7;
+
+    /// Remaining accounts the step consumes
+    pub fn account_count(&self) -> usize {
+        match self {
+            RouteStep::Invoke { account_count, .. } => *account_count as usize,
+            RouteStep::CreateAta => Self::CREATE_ATA_ACCOUNTS,
+        }
+    }
+}
+
+/// What the route executed, set as the instruction's return data so clients read it
+/// from the simulation or confirmed transaction
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RouteResult {
+    /// Tokens the swap paid out to the user
+    pub amount_out: u64,
+    /// Vault shares credited to the user's position
+    pub shares_minted: u128,
+}
+
 #[error_code]
 pub enum RouterError {
     #[msg("Invalid amount")]
@@ -404,4 +695,20 @@ pub enum RouterError {
     Unauthorized,
     #[msg("Insufficient accounts provided")]
     InsufficientAccounts,
+    #[msg("Invalid associated token account")]
+    InvalidAssociatedTokenAccount,
+    #[msg("Route deadline has passed")]
+    DeadlineExceeded,
+    #[msg("Invalid token account")]
+    InvalidTokenAccount,
+    #[msg("Invalid pool authority")]
+    InvalidPoolAuthority,
+    #[msg("Invalid vault authority")]
+    InvalidVaultAuthority,
+    #[msg("Invalid route plan")]
+    InvalidRoutePlan,
+    #[msg("Account role index out of range")]
+    InvalidAccountRoles,
+    #[msg("Token program must be SPL Token or Token-2022")]
+    InvalidTokenProgram,
 }
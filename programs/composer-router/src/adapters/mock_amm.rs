@@ -0,0 +1,115 @@
+//! mock-amm `swap`
+//!
+//! Swap accounts, in order:
+//! - [0] pool (mut): AMM Pool account
+//! - [1] user: Swap signer
+//! - [2] user_token_in: The route's input token account (validated)
+//! - [3] user_token_out: The route's output token account (validated)
+//! - [4] vault_a: Pool's token A vault
+//! - [5] vault_b: Pool's token B vault
+//! - [6] pool_authority: Pool's PDA authority (validated)
+//! - [7] mint_a: Pool's token A mint
+//! - [8] mint_b: Pool's token B mint
+//! - [9] token_program_a: Token program of mint_a (SPL Token or Token-2022)
+//! - [10] token_program_b: Token program of mint_b
+
+use anchor_lang::prelude::*;
+
+pub use ::mock_amm::program::MockAmm as Dex;
+
+use super::SwapRoute;
+use crate::{check_route, RouteCheck, RouterError};
+
+pub const SWAP_ACCOUNTS: usize = 11;
+
+/// Index of the swap's signer among the swap accounts
+pub const SWAP_USER: usize = 1;
+
+/// mock-amm pool authority of the pair's pool at `fee_tier`, mints in either order
+pub fn pool_authority(amm_program: &Pubkey, mint_x: Pubkey, mint_y: Pubkey, fee_tier: u16) -> Pubkey {
+    let (mint_a, mint_b) = ::mock_amm::pda::sorted_mints(mint_x, mint_y);
+    Pubkey::find_program_address(
+        &[
+            b"pool",
+            mint_a.as_ref(),
+            mint_b.as_ref(),
+            &fee_tier.to_le_bytes(),
+            b"authority",
+        ],
+        amm_program,
+    )
+    .0
+}
+
+/// Check the swap accounts move `route.token_in` to `route.token_out` through the
+/// pair's pool; `offset` is their position among the remaining accounts
+pub fn check_swap_accounts(
+    amm_program: &Pubkey,
+    swap_accounts: &[AccountInfo],
+    offset: usize,
+    route: &SwapRoute,
+) -> Result<()> {
+    check_route(
+        swap_accounts[2].key() == route.token_in,
+        RouteCheck::SwapUserTokenIn,
+        Some((offset + 2) as u8),
+        route.token_in,
+        swap_accounts[2].key(),
+        RouterError::InvalidTokenAccount,
+    )?;
+    check_route(
+        swap_accounts[3].key() == route.token_out,
+        RouteCheck::SwapUserTokenOut,
+        Some((offset + 3) as u8),
+        route.token_out,
+        swap_accounts[3].key(),
+        RouterError::InvalidTokenAccount,
+    )?;
+
+    // The pool authority belongs to mock-amm, which signs for it; the router only
+    // checks the route points at the pair's pool
+    let pool_authority_pda = pool_authority(
+        amm_program,
+        route.mint_in,
+        route.mint_out,
+        route.fee_tier,
+    );
+    check_route(
+        swap_accounts[6].key() == pool_authority_pda,
+        RouteCheck::PoolAuthority,
+        Some((offset + 6) as u8),
+        pool_authority_pda,
+        swap_accounts[6].key(),
+        RouterError::InvalidPoolAuthority,
+    )
+}
+
+/// CPI to mock-amm `swap`, which reads the direction off `user_token_in`'s mint.
+/// `signer_seeds` sign for a PDA swap user (`execute_intent`'s intent authority).
+pub fn swap<'info>(
+    amm_program: AccountInfo<'info>,
+    swap_accounts: &[AccountInfo<'info>],
+    _route: &SwapRoute,
+    amount_in: u64,
+    min_amount_out: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let ctx_swap = CpiContext::new_with_signer(
+        amm_program,
+        ::mock_amm::cpi::accounts::Swap {
+            pool: swap_accounts[0].to_account_info(),
+            user: swap_accounts[1].to_account_info(),
+            user_token_in: swap_accounts[2].to_account_info(),
+            user_token_out: swap_accounts[3].to_account_info(),
+            vault_a: swap_accounts[4].to_account_info(),
+            vault_b: swap_accounts[5].to_account_info(),
+            pool_authority: swap_accounts[6].to_account_info(),
+            mint_a: swap_accounts[7].to_account_info(),
+            mint_b: swap_accounts[8].to_account_info(),
+            token_program_a: swap_accounts[9].to_account_info(),
+            token_program_b: swap_accounts[10].to_account_info(),
+        },
+        signer_seeds,
+    );
+    ::mock_amm::cpi::swap(ctx_swap, amount_in, min_amount_out, None)
+}
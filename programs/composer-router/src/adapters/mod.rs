@@ -0,0 +1,31 @@
+//! DEX adapters: the account mapping and CPI of each swap program the router can route
+//! through, so the route logic in `lib.rs` stays DEX-agnostic.
+//!
+//! Every adapter exposes the same surface: `Dex` (the program type routes take as
+//! `amm_program`), `SWAP_ACCOUNTS`, `SWAP_USER`, `check_swap_accounts` and `swap`.
+//! `dex` is the adapter routes use: mock-amm by default, Orca Whirlpools with the
+//! `whirlpool` feature (e.g. for devnet builds against real liquidity). Routes that
+//! need more than a swap, like `zap_lp_and_stake`, use the mock-amm adapter directly.
+
+use anchor_lang::prelude::*;
+
+pub mod mock_amm;
+#[cfg(feature = "whirlpool")]
+pub mod whirlpool;
+
+#[cfg(not(feature = "whirlpool"))]
+pub use self::mock_amm as dex;
+#[cfg(feature = "whirlpool")]
+pub use self::whirlpool as dex;
+
+/// The swap leg a route expects, checked against the leg's accounts by the adapter
+pub struct SwapRoute {
+    /// User's token account the swap pays from
+    pub token_in: Pubkey,
+    /// User's token account the swap pays into
+    pub token_out: Pubkey,
+    pub mint_in: Pubkey,
+    pub mint_out: Pubkey,
+    /// Pool fee tier; adapters whose pools aren't keyed by it ignore it
+    pub fee_tier: u16,
+}
@@ -0,0 +1,164 @@
+//! Orca Whirlpools `swap` (exact input, no price limit)
+//!
+//! Swap accounts, in order:
+//! - [0] token_program: SPL Token program
+//! - [1] token_authority: Swap signer
+//! - [2] whirlpool (mut): The pair's Whirlpool (validated against the route's mints)
+//! - [3] token_owner_account_a (mut): User's account of the pool's token A (validated)
+//! - [4] token_vault_a (mut): Pool's token A vault (validated)
+//! - [5] token_owner_account_b (mut): User's account of the pool's token B (validated)
+//! - [6] token_vault_b (mut): Pool's token B vault (validated)
+//! - [7] tick_array_0 (mut): Tick arrays the swap crosses, in swap direction
+//! - [8] tick_array_1 (mut)
+//! - [9] tick_array_2 (mut)
+//! - [10] oracle (mut): The Whirlpool's oracle PDA
+//!
+//! Which of the user's accounts is the input depends on the swap direction: the route's
+//! input mint is token A for an A → B swap. The pool is picked by the swap accounts, so
+//! the route's fee tier is ignored.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::invoke_signed;
+
+use super::SwapRoute;
+use crate::{check_route, RouteCheck, RouterError};
+
+declare_id!("whirLbMiicVdio4qvUfM5KAg6Ct8VwpYzGff3uctyCc");
+
+/// The Whirlpools program, for `Program<'info, Dex>`
+#[derive(Clone)]
+pub struct Dex;
+
+impl Id for Dex {
+    fn id() -> Pubkey {
+        ID
+    }
+}
+
+pub const SWAP_ACCOUNTS: usize = 11;
+
+/// Index of the swap's signer among the swap accounts
+pub const SWAP_USER: usize = 1;
+
+const SWAP_DISCRIMINATOR: [u8; 8] = [248, 198, 158, 145, 225, 117, 135, 200];
+
+// Whirlpool account: discriminator, whirlpools_config, bump, tick_spacing,
+// fee_tier_index_seed, fee_rate, protocol_fee_rate, liquidity, sqrt_price,
+// tick_current_index, protocol_fee_owed_a/b, then the fields below
+const TOKEN_MINT_A_OFFSET: usize = 101;
+const TOKEN_VAULT_A_OFFSET: usize = 133;
+const TOKEN_MINT_B_OFFSET: usize = 181;
+const TOKEN_VAULT_B_OFFSET: usize = 213;
+
+// Bounds of Whirlpools' sqrt price, i.e. no price limit in either direction
+const MIN_SQRT_PRICE_X64: u128 = 4_295_048_016;
+const MAX_SQRT_PRICE_X64: u128 = 79_226_673_515_401_279_992_447_579_055;
+
+fn read_pubkey(data: &[u8], offset: usize) -> Result<Pubkey> {
+    let bytes: [u8; 32] = data
+        .get(offset..offset + 32)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(ErrorCode::AccountDidNotDeserialize)?;
+    Ok(Pubkey::new_from_array(bytes))
+}
+
+/// Check the swap accounts move `route.token_in` to `route.token_out` through a
+/// Whirlpool of the pair; `offset` is their position among the remaining accounts
+pub fn check_swap_accounts(
+    amm_program: &Pubkey,
+    swap_accounts: &[AccountInfo],
+    offset: usize,
+    route: &SwapRoute,
+) -> Result<()> {
+    let pool = &swap_accounts[2];
+    require_keys_eq!(*pool.owner, *amm_program, ErrorCode::AccountOwnedByWrongProgram);
+    let data = pool.try_borrow_data()?;
+    let (mint_a, mint_b) = (
+        read_pubkey(&data, TOKEN_MINT_A_OFFSET)?,
+        read_pubkey(&data, TOKEN_MINT_B_OFFSET)?,
+    );
+    let a_to_b = route.mint_in == mint_a;
+    let expected_mint_b = if a_to_b { route.mint_out } else { route.mint_in };
+    check_route(
+        (a_to_b || route.mint_out == mint_a) && mint_b == expected_mint_b,
+        RouteCheck::PoolMint,
+        Some((offset + 2) as u8),
+        expected_mint_b,
+        mint_b,
+        RouterError::InvalidPool,
+    )?;
+    for (index, field_offset) in [(4, TOKEN_VAULT_A_OFFSET), (6, TOKEN_VAULT_B_OFFSET)] {
+        let expected = read_pubkey(&data, field_offset)?;
+        check_route(
+            swap_accounts[index].key() == expected,
+            RouteCheck::PoolVault,
+            Some((offset + index) as u8),
+            expected,
+            swap_accounts[index].key(),
+            RouterError::InvalidPool,
+        )?;
+    }
+
+    let (token_in, token_out) = if a_to_b { (3, 5) } else { (5, 3) };
+    check_route(
+        swap_accounts[token_in].key() == route.token_in,
+        RouteCheck::SwapUserTokenIn,
+        Some((offset + token_in) as u8),
+        route.token_in,
+        swap_accounts[token_in].key(),
+        RouterError::InvalidTokenAccount,
+    )?;
+    check_route(
+        swap_accounts[token_out].key() == route.token_out,
+        RouteCheck::SwapUserTokenOut,
+        Some((offset + token_out) as u8),
+        route.token_out,
+        swap_accounts[token_out].key(),
+        RouterError::InvalidTokenAccount,
+    )
+}
+
+/// CPI to Whirlpools `swap`, exact `amount_in` of `route.mint_in` for at least
+/// `min_amount_out`. `signer_seeds` sign for a PDA token authority (`execute_intent`'s
+/// intent authority).
+pub fn swap<'info>(
+    amm_program: AccountInfo<'info>,
+    swap_accounts: &[AccountInfo<'info>],
+    route: &SwapRoute,
+    amount_in: u64,
+    min_amount_out: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let a_to_b = {
+        let pool = swap_accounts[2].try_borrow_data()?;
+        route.mint_in == read_pubkey(&pool, TOKEN_MINT_A_OFFSET)?
+    };
+    let sqrt_price_limit = if a_to_b {
+        MIN_SQRT_PRICE_X64
+    } else {
+        MAX_SQRT_PRICE_X64
+    };
+
+    let mut data = SWAP_DISCRIMINATOR.to_vec();
+    (amount_in, min_amount_out, sqrt_price_limit, true, a_to_b).serialize(&mut data)?;
+    let accounts = swap_accounts[..SWAP_ACCOUNTS]
+        .iter()
+        .enumerate()
+        .map(|(index, info)| AccountMeta {
+            pubkey: info.key(),
+            is_signer: index == SWAP_USER,
+            is_writable: index >= 2,
+        })
+        .collect();
+    invoke_signed(
+        &Instruction {
+            program_id: amm_program.key(),
+            accounts,
+            data,
+        },
+        &[&swap_accounts[..SWAP_ACCOUNTS], &[amm_program]].concat(),
+        signer_seeds,
+    )?;
+    Ok(())
+}
@@ -1,7 +1,18 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{Token, TokenAccount};
+use anchor_lang::solana_program::sysvar::instructions::{
+    load_current_index_checked, load_instruction_at_checked,
+};
+use anchor_lang::system_program;
+use anchor_spl::associated_token::{self, get_associated_token_address, AssociatedToken};
+use anchor_spl::token::{self, spl_token, Mint, Token, TokenAccount};
 use mock_amm::program::MockAmm;
 use vault_core::program::VaultCore;
+use vault_interface::cpi as vault_cpi;
+use vault_interface::{pda as vault_pda, state as vault_state};
+
+use adapters::{dex, SwapRoute};
+
+pub mod adapters;
 
 declare_id!("5bw3v7LUaXn3pRmgXUPpeneYu9My3AhF7EemUNmmVLUQ");
 
@@ -9,185 +20,2209 @@ declare_id!("5bw3v7LUaXn3pRmgXUPpeneYu9My3AhF7EemUNmmVLUQ");
 pub mod composer_router {
     use super::*;
 
+    /// Create the router's `RouterState`; the signer becomes its authority
+    pub fn initialize_router_state(
+        ctx: Context<InitializeRouterState>,
+        vault_programs: Vec<Pubkey>,
+    ) -> Result<()> {
+        require!(
+            vault_programs.len() <= RouterState::MAX_VAULT_PROGRAMS,
+            RouterError::TooManyPrograms
+        );
+
+        let state = &mut ctx.accounts.router_state;
+        state.authority = ctx.accounts.authority.key();
+        state.paused = false;
+        state.vault_programs = vault_programs;
+        state.fee_bps = 0;
+        state.referral_share_bps = 0;
+        state.bump = ctx.bumps.router_state;
+
+        Ok(())
+    }
+
+    /// Replace the vault programs `deposit_swap_stake` may deposit into
+    pub fn update_vault_programs(
+        ctx: Context<UpdateRouterState>,
+        vault_programs: Vec<Pubkey>,
+    ) -> Result<()> {
+        require!(
+            vault_programs.len() <= RouterState::MAX_VAULT_PROGRAMS,
+            RouterError::TooManyPrograms
+        );
+
+        ctx.accounts.router_state.vault_programs = vault_programs;
+
+        Ok(())
+    }
+
+    /// Pause or resume `deposit_swap_stake`
+    pub fn set_paused(ctx: Context<UpdateRouterState>, paused: bool) -> Result<()> {
+        ctx.accounts.router_state.paused = paused;
+        Ok(())
+    }
+
+    /// Set the router fee, in bps of the swap output, and the share of it credited to
+    /// the route's referrer, in bps of the fee. The fee is also capped by vault-core's
+    /// protocol bound `max_router_fee_bps`.
+    ///
+    /// Every route that stakes what the user swapped takes the fee from the tokens it
+    /// stakes and credits referrers: `deposit_swap_stake`, `deposit_sol_swap_stake`,
+    /// `claim_swap_restake`, `zap_lp_and_stake` and `batch_swap_stake`. Exits
+    /// (`withdraw_swap_return`) are free, and so is `execute_intent`, whose user only
+    /// approves the vault authority on the output account, leaving the router nothing
+    /// to take the fee with.
+    pub fn set_fees(ctx: Context<SetFees>, fee_bps: u16, referral_share_bps: u16) -> Result<()> {
+        require!(
+            fee_bps <= RouterState::MAX_FEE_BPS && referral_share_bps <= BPS_DENOMINATOR,
+            RouterError::InvalidFee
+        );
+        let bounds = vault_core::ProtocolConfig::load(&ctx.accounts.protocol_config)?;
+        require!(
+            fee_bps <= bounds.max_router_fee_bps,
+            RouterError::ExceedsProtocolBound
+        );
+
+        let state = &mut ctx.accounts.router_state;
+        state.fee_bps = fee_bps;
+        state.referral_share_bps = referral_share_bps;
+
+        Ok(())
+    }
+
+    /// Create the signer's `Referral` for `mint`, which routes into that mint credit
+    pub fn register_referral(ctx: Context<RegisterReferral>) -> Result<()> {
+        let referral = &mut ctx.accounts.referral;
+        referral.referrer = ctx.accounts.referrer.key();
+        referral.mint = ctx.accounts.mint.key();
+        referral.routed_volume = 0;
+        referral.claimable = 0;
+        referral.bump = ctx.bumps.referral;
+
+        Ok(())
+    }
+
+    /// Pay the referrer's claimable fee share out of the referral vault
+    pub fn claim_referral_fees(ctx: Context<ClaimReferralFees>) -> Result<()> {
+        let amount = ctx.accounts.referral.claimable;
+        require!(amount > 0, RouterError::InvalidAmount);
+        ctx.accounts.referral.claimable = 0;
+
+        let seeds: &[&[u8]] = &[b"router_state", &[ctx.accounts.router_state.bump]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                token::Transfer {
+                    from: ctx.accounts.referral_vault.to_account_info(),
+                    to: ctx.accounts.referrer_token_account.to_account_info(),
+                    authority: ctx.accounts.router_state.to_account_info(),
+                },
+                &[seeds],
+            ),
+            amount,
+        )
+    }
+
     /// Deposit → Swap → Stake workflow
     ///
     /// This instruction atomically executes:
     /// 1. Swaps input tokens for output tokens via CPI to mock-amm
-    /// 2. Deposits output tokens into vault via CPI to vault-core
+    /// 2. Deposits output tokens into vault via CPI to vault-core: `vault_deposit_amount`,
+    ///    or exactly what the swap paid out when it is 0
     /// ///
     /// Account layout:
     ///
     /// Fixed accounts (defined in DepositSwapStake struct, in order):
     /// - user (signer, mut): The user executing the transaction
-    /// - config: RouterConfig account (validates swap_program is allowlisted)
+    /// - router_state: RouterState account (route fails with `RouterPaused` while paused)
     /// - input_token_account (mut): User's token account for input tokens (token A)
     /// - output_token_account (mut): User's token account for output tokens (token B, receives swap output)
-    /// - swap_program: Swap program to CPI to for swap
-    /// - vault_program: vault-core program to CPI to for deposit
+    /// - amm_program: mock-amm program to CPI to for swap
+    /// - vault_program: vault-core program to CPI to for deposit (must be allow-listed in
+    ///   router_state)
     /// - token_program: SPL Token program
     /// - system_program: System program
+    /// - fee_token_account (optional, mut): Router authority's account of the output
+    ///   mint, receives the router fee; required while `fee_bps` is non-zero
+    /// - referral (optional, mut): Referrer's `Referral` for the output mint
+    /// - referral_vault (optional, mut): Router-owned account of the output mint holding
+    ///   referrer balances; required with `referral`
     ///
     /// Remaining accounts (variable, passed through to CPIs):
     ///
-    /// First 8 accounts - Swap instruction accounts (for mock-amm swap):
-    /// - [0] pool: AMM Pool account
+    /// First 11 accounts - Swap instruction accounts (for mock-amm swap; with the
+    /// `whirlpool` feature, Whirlpools `swap` as laid out in `adapters::whirlpool`):
+    /// - [0] pool (mut): AMM Pool account
     /// - [1] user: User signer (same as fixed accounts user)
     /// - [2] user_token_in: Must match input_token_account (validated)
     /// - [3] user_token_out: Must match output_token_account (validated)
     /// - [4] vault_a: Pool's token A vault
     /// - [5] vault_b: Pool's token B vault
     /// - [6] pool_authority: Pool's PDA authority
-    /// - [7] token_program: SPL Token program
-    ///
-    /// Next 8 accounts - Vault deposit instruction accounts:
-    /// - [8] vault: Vault account (must match output_token_account mint)
-    /// - [9] user_position: User's position PDA in vault
-    /// - [10] user: User signer (same as fixed accounts user)
-    /// - [11] user_token_account: Must match output_token_account (validated)
-    /// - [12] vault_token_account: Vault's token account
-    /// - [13] vault_authority: Vault's PDA authority
-    /// - [14] token_program: SPL Token program
-    /// - [15] system_program: System program
-    ///
-    /// Total: 16 remaining accounts required
+    /// - [7] mint_a: Pool's token A mint
+    /// - [8] mint_b: Pool's token B mint
+    /// - [9] token_program_a: Token program of mint_a (SPL Token or Token-2022)
+    /// - [10] token_program_b: Token program of mint_b
+    ///
+    /// Next 9 accounts - Vault deposit instruction accounts:
+    /// - [11] vault: Vault account (must match output_token_account mint)
+    /// - [12] user_position: User's position PDA in vault
+    /// - [13] user: User signer (same as fixed accounts user)
+    /// - [14] user_token_account: Must match output_token_account (validated)
+    /// - [15] vault_token_account: Vault's token account
+    /// - [16] vault_authority: Vault's PDA authority
+    /// - [17] token_program: SPL Token program
+    /// - [18] system_program: System program
+    /// - [19] user_checkpoints (mut): User's share checkpoint PDA in vault
+    ///
+    /// Total: 20 remaining accounts required
+    ///
+    /// `fee_tier` selects which of the pair's pools the swap accounts belong to. The
+    /// route fails with `DeadlineExceeded` once the clock passes `deadline` (unix time).
+    ///
+    /// The route fails with `SharesBelowMinimum` unless the user's position gained at
+    /// least `min_shares_out` shares from the deposit.
+    ///
+    /// The router fee (`RouterState::fee_bps` of the swap output, see `take_router_fee`)
+    /// needs the optional fee accounts; a `referral` credits its referrer's share.
+    ///
+    /// Returns the swap output and shares minted as a `RouteResult` in return data.
+    ///
+    /// A rejected route emits `RouteValidationFailed` (check + remaining account index)
+    /// before returning the error.
+    #[allow(clippy::too_many_arguments)]
     pub fn deposit_swap_stake<'c: 'info, 'info>(
         ctx: Context<'_, '_, 'c, 'info, DepositSwapStake<'info>>,
         swap_amount_in: u64,
         min_amount_out: u64,
         vault_deposit_amount: u64,
+        min_shares_out: u64,
         expected_input_mint: Pubkey,
         expected_output_mint: Pubkey,
-    ) -> Result<()> {
+        fee_tier: u16,
+        deadline: i64,
+    ) -> Result<RouteResult> {
+        let state = &ctx.accounts.router_state;
+        require!(!state.paused, RouterError::RouterPaused);
+        require!(
+            state.vault_programs.contains(ctx.accounts.vault_program.key),
+            RouterError::VaultProgramNotAllowlisted
+        );
+        check_deadline(deadline)?;
         require!(swap_amount_in > 0, RouterError::InvalidAmount);
         require!(min_amount_out > 0, RouterError::InvalidAmount);
-        require!(vault_deposit_amount > 0, RouterError::InvalidAmount);
+
+        let user_key = ctx.accounts.user.key();
+        let input = &ctx.accounts.input_token_account;
+        let output = &ctx.accounts.output_token_account;
+        let output_before = output.amount;
 
         // Validate token account authorities
+        check_route(
+            input.owner == user_key,
+            RouteCheck::InputTokenOwner,
+            None,
+            user_key,
+            input.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            output.owner == user_key,
+            RouteCheck::OutputTokenOwner,
+            None,
+            user_key,
+            output.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+
+        check_route(
+            input.mint == expected_input_mint,
+            RouteCheck::InputMint,
+            None,
+            expected_input_mint,
+            input.mint,
+            RouterError::InvalidMint,
+        )?;
+        check_route(
+            output.mint == expected_output_mint,
+            RouteCheck::OutputMint,
+            None,
+            expected_output_mint,
+            output.mint,
+            RouterError::InvalidMint,
+        )?;
+
+        // Validate user has sufficient balance
         require!(
-            ctx.accounts.input_token_account.owner == ctx.accounts.user.key(),
-            RouterError::InvalidTokenAccountOwner
+            input.amount >= swap_amount_in,
+            RouterError::InsufficientBalance
         );
 
-        require!(
-            ctx.accounts.output_token_account.owner == ctx.accounts.user.key(),
-            RouterError::InvalidTokenAccountOwner
+        if ctx.remaining_accounts.len() < ROUTE_ACCOUNTS {
+            emit!(RouteValidationFailed {
+                check: RouteCheck::RemainingAccountsLength,
+                account_index: Some(ctx.remaining_accounts.len() as u8),
+                expected: Pubkey::default(),
+                actual: Pubkey::default(),
+            });
+            return err!(RouterError::InsufficientAccounts);
+        }
+
+        let (swap_accounts, vault_accounts) =
+            ctx.remaining_accounts[..ROUTE_ACCOUNTS].split_at(SWAP_ACCOUNTS);
+        let deposit = vault_cpi::Deposit::from_slice(vault_accounts)?;
+
+        // Validate the swap accounts move input_token_account to output_token_account
+        // through the pair's pool
+        let route = SwapRoute {
+            token_in: ctx.accounts.input_token_account.key(),
+            token_out: ctx.accounts.output_token_account.key(),
+            mint_in: expected_input_mint,
+            mint_out: expected_output_mint,
+            fee_tier,
+        };
+        dex::check_swap_accounts(ctx.accounts.amm_program.key, swap_accounts, 0, &route)?;
+        check_route(
+            deposit.user_token_account.key() == ctx.accounts.output_token_account.key(),
+            RouteCheck::VaultUserTokenAccount,
+            Some(14),
+            ctx.accounts.output_token_account.key(),
+            deposit.user_token_account.key(),
+            RouterError::InvalidTokenAccount,
+        )?;
+
+        // The vault authority belongs to vault-core, which signs for it; the router
+        // only checks the route points at the output mint's vault
+        let generation = vault_state::vault_generation(deposit.vault, ctx.accounts.vault_program.key)?;
+        let (vault_authority_pda, _) = Pubkey::find_program_address(
+            &[
+                b"vault",
+                expected_output_mint.as_ref(),
+                b"authority",
+                vault_pda::generation_seed(&generation),
+            ],
+            ctx.accounts.vault_program.key,
         );
+        check_route(
+            deposit.vault_authority.key() == vault_authority_pda,
+            RouteCheck::VaultAuthority,
+            Some(16),
+            vault_authority_pda,
+            deposit.vault_authority.key(),
+            RouterError::InvalidVaultAuthority,
+        )?;
+
+        // 1. CPI to the DEX adapter's swap
+        dex::swap(
+            ctx.accounts.amm_program.to_account_info(),
+            swap_accounts,
+            &route,
+            swap_amount_in,
+            min_amount_out,
+            &[],
+        )?;
 
+        // 2. Reload output token account to verify swap happened
+        ctx.accounts.output_token_account.reload()?;
+        let swapped = ctx
+            .accounts
+            .output_token_account
+            .amount
+            .checked_sub(output_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        // Keep what's left after the router fee
+        let swapped = take_router_fee(
+            &ctx.accounts.router_state,
+            &ctx.accounts.user,
+            &ctx.accounts.output_token_account,
+            &ctx.accounts.token_program,
+            ctx.accounts.fee_token_account.as_ref(),
+            ctx.accounts.referral.as_mut(),
+            ctx.accounts.referral_vault.as_ref(),
+            swapped,
+        )?;
+        let deposit_amount = if vault_deposit_amount == 0 {
+            swapped
+        } else {
+            vault_deposit_amount
+        };
+
+        // 3. CPI to vault-core deposit
+        let shares_before = position_shares(deposit.user_position, ctx.accounts.vault_program.key)?;
+        deposit.invoke(
+            &ctx.accounts.vault_program.to_account_info(),
+            deposit_amount,
+            0,
+            Vec::new(),
+            &[],
+        )?;
+        let shares_minted = check_shares_out(
+            deposit.user_position,
+            ctx.accounts.vault_program.key,
+            shares_before,
+            min_shares_out,
+        )?;
+        Ok(RouteResult {
+            amount_out: swapped,
+            shares_minted,
+        })
+    }
+
+    /// SOL → Swap → Stake workflow, `deposit_swap_stake` for users holding only SOL
+    ///
+    /// This instruction atomically executes:
+    /// 1. Creates the user's WSOL associated token account and wraps `lamports_in`
+    ///    into it
+    /// 2. Swaps all of it for the output mint via CPI to mock-amm
+    /// 3. Deposits exactly what the swap paid out into the vault via CPI to vault-core
+    /// 4. Closes the WSOL account, returning its rent to the user
+    ///
+    /// The WSOL account must not exist beforehand, so WSOL the user already holds is
+    /// never unwrapped by the close.
+    ///
+    /// Account layout:
+    ///
+    /// Fixed accounts (defined in DepositSolSwapStake struct, in order):
+    /// - user (signer, mut): The user executing the transaction
+    /// - router_state: RouterState account (route fails with `RouterPaused` while paused)
+    /// - wsol_token_account (mut): User's WSOL associated token account (created and closed here)
+    /// - native_mint: The WSOL mint
+    /// - output_token_account (mut): User's token account for output tokens (receives swap output)
+    /// - amm_program: mock-amm program to CPI to for swap
+    /// - vault_program: vault-core program to CPI to for deposit (must be allow-listed in
+    ///   router_state)
+    /// - token_program: SPL Token program
+    /// - associated_token_program: Associated Token program
+    /// - system_program: System program
+    /// - fee_token_account, referral, referral_vault (optional): As in `deposit_swap_stake`
+    ///
+    /// Remaining accounts: the 20 accounts of `deposit_swap_stake`, with
+    /// wsol_token_account as the swap's user_token_in [2]
+    ///
+    /// `fee_tier` selects which of the pair's pools the swap accounts belong to. The
+    /// route fails with `DeadlineExceeded` once the clock passes `deadline` (unix time).
+    ///
+    /// The route fails with `SharesBelowMinimum` unless the user's position gained at
+    /// least `min_shares_out` shares from the deposit.
+    ///
+    /// Returns the swap output and shares minted as a `RouteResult` in return data.
+    ///
+    /// A rejected route emits `RouteValidationFailed` (check + remaining account index)
+    /// before returning the error.
+    pub fn deposit_sol_swap_stake<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, DepositSolSwapStake<'info>>,
+        lamports_in: u64,
+        min_amount_out: u64,
+        min_shares_out: u64,
+        expected_output_mint: Pubkey,
+        fee_tier: u16,
+        deadline: i64,
+    ) -> Result<RouteResult> {
+        let state = &ctx.accounts.router_state;
+        require!(!state.paused, RouterError::RouterPaused);
         require!(
-            ctx.accounts.input_token_account.mint == expected_input_mint,
-            RouterError::InvalidMint
+            state.vault_programs.contains(ctx.accounts.vault_program.key),
+            RouterError::VaultProgramNotAllowlisted
         );
-
+        check_deadline(deadline)?;
+        require!(lamports_in > 0, RouterError::InvalidAmount);
+        require!(min_amount_out > 0, RouterError::InvalidAmount);
         require!(
-            ctx.accounts.output_token_account.mint == expected_output_mint,
+            expected_output_mint != spl_token::native_mint::ID,
             RouterError::InvalidMint
         );
 
-        // Validate user has sufficient balance
+        let user_key = ctx.accounts.user.key();
+        let wsol = ctx.accounts.wsol_token_account.key();
+        let output = &ctx.accounts.output_token_account;
+        let output_before = output.amount;
+
+        let expected_wsol = get_associated_token_address(&user_key, &spl_token::native_mint::ID);
+        check_route(
+            wsol == expected_wsol,
+            RouteCheck::WsolTokenAccount,
+            None,
+            expected_wsol,
+            wsol,
+            RouterError::InvalidTokenAccount,
+        )?;
+        check_route(
+            output.owner == user_key,
+            RouteCheck::OutputTokenOwner,
+            None,
+            user_key,
+            output.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            output.mint == expected_output_mint,
+            RouteCheck::OutputMint,
+            None,
+            expected_output_mint,
+            output.mint,
+            RouterError::InvalidMint,
+        )?;
+
+        if ctx.remaining_accounts.len() < ROUTE_ACCOUNTS {
+            emit!(RouteValidationFailed {
+                check: RouteCheck::RemainingAccountsLength,
+                account_index: Some(ctx.remaining_accounts.len() as u8),
+                expected: Pubkey::default(),
+                actual: Pubkey::default(),
+            });
+            return err!(RouterError::InsufficientAccounts);
+        }
+        let (swap_accounts, vault_accounts) =
+            ctx.remaining_accounts[..ROUTE_ACCOUNTS].split_at(SWAP_ACCOUNTS);
+        let deposit = vault_cpi::Deposit::from_slice(vault_accounts)?;
+
+        let route = SwapRoute {
+            token_in: wsol,
+            token_out: output.key(),
+            mint_in: spl_token::native_mint::ID,
+            mint_out: expected_output_mint,
+            fee_tier,
+        };
+        dex::check_swap_accounts(ctx.accounts.amm_program.key, swap_accounts, 0, &route)?;
+        check_route(
+            deposit.user_token_account.key() == output.key(),
+            RouteCheck::VaultUserTokenAccount,
+            Some(14),
+            output.key(),
+            deposit.user_token_account.key(),
+            RouterError::InvalidTokenAccount,
+        )?;
+        let generation = vault_state::vault_generation(deposit.vault, ctx.accounts.vault_program.key)?;
+        let (vault_authority_pda, _) = Pubkey::find_program_address(
+            &[
+                b"vault",
+                expected_output_mint.as_ref(),
+                b"authority",
+                vault_pda::generation_seed(&generation),
+            ],
+            ctx.accounts.vault_program.key,
+        );
+        check_route(
+            deposit.vault_authority.key() == vault_authority_pda,
+            RouteCheck::VaultAuthority,
+            Some(16),
+            vault_authority_pda,
+            deposit.vault_authority.key(),
+            RouterError::InvalidVaultAuthority,
+        )?;
+
+        // 1. Create the WSOL account and wrap the lamports
+        let ctx_create = CpiContext::new(
+            ctx.accounts.associated_token_program.to_account_info(),
+            associated_token::Create {
+                payer: ctx.accounts.user.to_account_info(),
+                associated_token: ctx.accounts.wsol_token_account.to_account_info(),
+                authority: ctx.accounts.user.to_account_info(),
+                mint: ctx.accounts.native_mint.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+                token_program: ctx.accounts.token_program.to_account_info(),
+            },
+        );
+        associated_token::create(ctx_create)?;
+        let ctx_fund = CpiContext::new(
+            ctx.accounts.system_program.to_account_info(),
+            system_program::Transfer {
+                from: ctx.accounts.user.to_account_info(),
+                to: ctx.accounts.wsol_token_account.to_account_info(),
+            },
+        );
+        system_program::transfer(ctx_fund, lamports_in)?;
+        let ctx_sync = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::SyncNative {
+                account: ctx.accounts.wsol_token_account.to_account_info(),
+            },
+        );
+        token::sync_native(ctx_sync)?;
+
+        // 2. Swap all of it
+        dex::swap(
+            ctx.accounts.amm_program.to_account_info(),
+            swap_accounts,
+            &route,
+            lamports_in,
+            min_amount_out,
+            &[],
+        )?;
+
+        // 3. Deposit exactly what the swap paid out
+        ctx.accounts.output_token_account.reload()?;
+        let swapped = ctx
+            .accounts
+            .output_token_account
+            .amount
+            .checked_sub(output_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        // Less the router fee
+        let swapped = take_router_fee(
+            &ctx.accounts.router_state,
+            &ctx.accounts.user,
+            &ctx.accounts.output_token_account,
+            &ctx.accounts.token_program,
+            ctx.accounts.fee_token_account.as_ref(),
+            ctx.accounts.referral.as_mut(),
+            ctx.accounts.referral_vault.as_ref(),
+            swapped,
+        )?;
+        let shares_before = position_shares(deposit.user_position, ctx.accounts.vault_program.key)?;
+        deposit.invoke(
+            &ctx.accounts.vault_program.to_account_info(),
+            swapped,
+            0,
+            Vec::new(),
+            &[],
+        )?;
+        let shares_minted = check_shares_out(
+            deposit.user_position,
+            ctx.accounts.vault_program.key,
+            shares_before,
+            min_shares_out,
+        )?;
+
+        // 4. Close the now empty WSOL account
+        let ctx_close = CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            token::CloseAccount {
+                account: ctx.accounts.wsol_token_account.to_account_info(),
+                destination: ctx.accounts.user.to_account_info(),
+                authority: ctx.accounts.user.to_account_info(),
+            },
+        );
+        token::close_account(ctx_close)?;
+        Ok(RouteResult {
+            amount_out: swapped,
+            shares_minted,
+        })
+    }
+
+    /// Unstake → Swap → Return workflow, the reverse of `deposit_swap_stake`
+    ///
+    /// This instruction atomically executes:
+    /// 1. Withdraws `shares` from the vault via CPI to vault-core
+    /// 2. Swaps every token the withdrawal paid out back to the return mint via CPI to
+    ///    mock-amm, failing unless at least `min_amount_out` reaches the user
+    ///
+    /// Account layout:
+    ///
+    /// Fixed accounts (defined in WithdrawSwapReturn struct, in order):
+    /// - user (signer, mut): The user executing the transaction
+    /// - router_state: RouterState account (route fails with `RouterPaused` while paused)
+    /// - staked_token_account (mut): User's token account for the vault token (receives the withdrawal)
+    /// - return_token_account (mut): User's token account for the return mint (receives swap output)
+    /// - amm_program: mock-amm program to CPI to for swap
+    /// - vault_program: vault-core program to CPI to for withdraw (must be allow-listed in
+    ///   router_state)
+    /// - token_program: SPL Token program
+    /// - system_program: System program
+    ///
+    /// Remaining accounts (variable, passed through to CPIs):
+    ///
+    /// First 10 accounts - Vault withdraw instruction accounts:
+    /// - [0] vault (mut): Vault account (must match staked_token_account mint)
+    /// - [1] user_position (mut): User's position PDA in vault
+    /// - [2] user: User signer (same as fixed accounts user)
+    /// - [3] user_token_account: Must match staked_token_account (validated)
+    /// - [4] vault_token_account: Vault's token account
+    /// - [5] vault_authority: Vault's PDA authority
+    /// - [6] token_program: SPL Token program
+    /// - [7] system_program: System program
+    /// - [8] clock: Clock sysvar
+    /// - [9] user_checkpoints (mut): User's share checkpoint PDA in vault
+    ///
+    /// Next 11 accounts - Swap instruction accounts (for mock-amm swap):
+    /// - [10] pool (mut): AMM Pool account
+    /// - [11] user: User signer (same as fixed accounts user)
+    /// - [12] user_token_in: Must match staked_token_account (validated)
+    /// - [13] user_token_out: Must match return_token_account (validated)
+    /// - [14] vault_a: Pool's token A vault
+    /// - [15] vault_b: Pool's token B vault
+    /// - [16] pool_authority: Pool's PDA authority
+    /// - [17] mint_a: Pool's token A mint
+    /// - [18] mint_b: Pool's token B mint
+    /// - [19] token_program_a: Token program of mint_a (SPL Token or Token-2022)
+    /// - [20] token_program_b: Token program of mint_b
+    ///
+    /// Total: 21 remaining accounts required
+    ///
+    /// `fee_tier` selects which of the pair's pools the swap accounts belong to. The
+    /// route fails with `DeadlineExceeded` once the clock passes `deadline` (unix time).
+    ///
+    /// Returns the swap output as a `RouteResult` in return data, with no shares minted.
+    ///
+    /// A rejected route emits `RouteValidationFailed` (check + remaining account index)
+    /// before returning the error.
+    pub fn withdraw_swap_return<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, WithdrawSwapReturn<'info>>,
+        shares: u64,
+        min_amount_out: u64,
+        expected_staked_mint: Pubkey,
+        expected_return_mint: Pubkey,
+        fee_tier: u16,
+        deadline: i64,
+    ) -> Result<RouteResult> {
+        let state = &ctx.accounts.router_state;
+        require!(!state.paused, RouterError::RouterPaused);
         require!(
-            ctx.accounts.input_token_account.amount >= swap_amount_in,
-            RouterError::InsufficientBalance
+            state.vault_programs.contains(ctx.accounts.vault_program.key),
+            RouterError::VaultProgramNotAllowlisted
         );
+        check_deadline(deadline)?;
+        require!(shares > 0, RouterError::InvalidAmount);
+        require!(min_amount_out > 0, RouterError::InvalidAmount);
+
+        let user_key = ctx.accounts.user.key();
+        let staked = &ctx.accounts.staked_token_account;
+        let returned = &ctx.accounts.return_token_account;
+
+        check_route(
+            staked.owner == user_key,
+            RouteCheck::InputTokenOwner,
+            None,
+            user_key,
+            staked.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            returned.owner == user_key,
+            RouteCheck::OutputTokenOwner,
+            None,
+            user_key,
+            returned.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            staked.mint == expected_staked_mint,
+            RouteCheck::InputMint,
+            None,
+            expected_staked_mint,
+            staked.mint,
+            RouterError::InvalidMint,
+        )?;
+        check_route(
+            returned.mint == expected_return_mint,
+            RouteCheck::OutputMint,
+            None,
+            expected_return_mint,
+            returned.mint,
+            RouterError::InvalidMint,
+        )?;
 
-        // 1. CPI to mock-amm swap
-        let swap_accounts: Vec<_> = ctx
-            .remaining_accounts
-            .iter()
-            .take(8)
-            .collect::<Vec<&AccountInfo>>();
+        if ctx.remaining_accounts.len() < RETURN_ROUTE_ACCOUNTS {
+            emit!(RouteValidationFailed {
+                check: RouteCheck::RemainingAccountsLength,
+                account_index: Some(ctx.remaining_accounts.len() as u8),
+                expected: Pubkey::default(),
+                actual: Pubkey::default(),
+            });
+            return err!(RouterError::InsufficientAccounts);
+        }
+        let (withdraw_accounts, swap_accounts) = ctx.remaining_accounts[..RETURN_ROUTE_ACCOUNTS]
+            .split_at(RETURN_ROUTE_ACCOUNTS - SWAP_ACCOUNTS);
+        let withdraw = vault_cpi::Withdraw::from_slice(withdraw_accounts)?;
 
-        let vault_accounts = ctx
-            .remaining_accounts
-            .iter()
-            .skip(8)
-            .take(8)
-            .collect::<Vec<_>>();
+        check_route(
+            withdraw.user_token_account.key() == staked.key(),
+            RouteCheck::VaultUserTokenAccount,
+            Some(3),
+            staked.key(),
+            withdraw.user_token_account.key(),
+            RouterError::InvalidTokenAccount,
+        )?;
+        let route = SwapRoute {
+            token_in: staked.key(),
+            token_out: returned.key(),
+            mint_in: expected_staked_mint,
+            mint_out: expected_return_mint,
+            fee_tier,
+        };
+        dex::check_swap_accounts(ctx.accounts.amm_program.key, swap_accounts, RETURN_ROUTE_ACCOUNTS - SWAP_ACCOUNTS, &route)?;
+
+        let generation = vault_state::vault_generation(withdraw.vault, ctx.accounts.vault_program.key)?;
+        let (vault_authority_pda, _) = Pubkey::find_program_address(
+            &[
+                b"vault",
+                expected_staked_mint.as_ref(),
+                b"authority",
+                vault_pda::generation_seed(&generation),
+            ],
+            ctx.accounts.vault_program.key,
+        );
+        check_route(
+            withdraw.vault_authority.key() == vault_authority_pda,
+            RouteCheck::VaultAuthority,
+            Some(5),
+            vault_authority_pda,
+            withdraw.vault_authority.key(),
+            RouterError::InvalidVaultAuthority,
+        )?;
+
+        // 1. CPI to vault-core withdraw; the swap leg carries the slippage bound
+        let balance_before = staked.amount;
+        let return_before = returned.amount;
+        withdraw.invoke(&ctx.accounts.vault_program.to_account_info(), shares, 0, &[])?;
+
+        // 2. Swap exactly what the withdrawal paid out
+        ctx.accounts.staked_token_account.reload()?;
+        let withdrawn = ctx
+            .accounts
+            .staked_token_account
+            .amount
+            .checked_sub(balance_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        require!(withdrawn > 0, RouterError::InvalidAmount);
+        dex::swap(
+            ctx.accounts.amm_program.to_account_info(),
+            swap_accounts,
+            &route,
+            withdrawn,
+            min_amount_out,
+            &[],
+        )?;
+
+        ctx.accounts.return_token_account.reload()?;
+        let returned = ctx
+            .accounts
+            .return_token_account
+            .amount
+            .checked_sub(return_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        Ok(RouteResult {
+            amount_out: returned,
+            shares_minted: 0,
+        })
+    }
 
-        // Validate swap accounts match expected token accounts
-        // Account 2 should be user_token_in (input_token_account)
-        // Account 3 should be user_token_out (output_token_account)
+    /// Claim → Swap → Compound workflow
+    ///
+    /// This instruction atomically executes:
+    /// 1. Claims the position's vault rewards via CPI to vault-core
+    /// 2. Swaps everything the claim paid out for the vault's stake token via CPI to
+    ///    mock-amm, failing unless at least `min_amount_out` comes back
+    /// 3. Deposits the swap output back into the same vault via CPI to vault-core,
+    ///    failing with `SharesBelowMinimum` unless it adds at least `min_shares_out`
+    ///    shares to the position
+    ///
+    /// vault-core only pays rewards to the position owner, so `user` must sign; a
+    /// keeper compounds with a transaction the owner has signed.
+    ///
+    /// Account layout:
+    ///
+    /// Fixed accounts (defined in ClaimSwapRestake struct, in order):
+    /// - user (signer, mut): The position owner
+    /// - router_state: RouterState account (route fails with `RouterPaused` while paused)
+    /// - reward_token_account (mut): User's reward mint ATA (receives the claim)
+    /// - stake_token_account (mut): User's token account for the vault token (receives swap output)
+    /// - amm_program: mock-amm program to CPI to for swap
+    /// - vault_program: vault-core program to CPI to for claim and deposit (must be
+    ///   allow-listed in router_state)
+    /// - token_program: SPL Token program
+    /// - system_program: System program
+    /// - fee_token_account, referral, referral_vault (optional): As in `deposit_swap_stake`,
+    ///   in the stake mint
+    ///
+    /// Remaining accounts (variable, passed through to CPIs):
+    ///
+    /// First 11 accounts - Vault claim_rewards instruction accounts:
+    /// - [0] vault (mut): Vault account
+    /// - [1] user_position (mut): User's position PDA in vault
+    /// - [2] user: User signer (same as fixed accounts user)
+    /// - [3] reward_mint: The vault's reward mint
+    /// - [4] user_reward_token_account: Must match reward_token_account (validated)
+    /// - [5] reward_vault (mut): Vault's reward token account
+    /// - [6] vault_authority: Vault's PDA authority
+    /// - [7] reward_token_program: Token program of the reward mint
+    /// - [8] clock: Clock sysvar
+    /// - [9] associated_token_program: Associated Token program
+    /// - [10] system_program: System program
+    ///
+    /// Next 11 accounts - Swap instruction accounts (for mock-amm swap):
+    /// - [11] pool (mut): AMM Pool account
+    /// - [12] user: User signer (same as fixed accounts user)
+    /// - [13] user_token_in: Must match reward_token_account (validated)
+    /// - [14] user_token_out: Must match stake_token_account (validated)
+    /// - [15] vault_a: Pool's token A vault
+    /// - [16] vault_b: Pool's token B vault
+    /// - [17] pool_authority: Pool's PDA authority
+    /// - [18] mint_a: Pool's token A mint
+    /// - [19] mint_b: Pool's token B mint
+    /// - [20] token_program_a: Token program of mint_a (SPL Token or Token-2022)
+    /// - [21] token_program_b: Token program of mint_b
+    ///
+    /// Next 9 accounts - Vault deposit instruction accounts:
+    /// - [22] vault: Must match the claim's vault (validated)
+    /// - [23] user_position: User's position PDA in vault
+    /// - [24] user: User signer (same as fixed accounts user)
+    /// - [25] user_token_account: Must match stake_token_account (validated)
+    /// - [26] vault_token_account: Vault's token account
+    /// - [27] vault_authority: Vault's PDA authority
+    /// - [28] token_program: SPL Token program
+    /// - [29] system_program: System program
+    /// - [30] user_checkpoints (mut): User's share checkpoint PDA in vault
+    ///
+    /// Total: 31 remaining accounts required
+    ///
+    /// `fee_tier` selects which of the pair's pools the swap accounts belong to. The
+    /// route fails with `DeadlineExceeded` once the clock passes `deadline` (unix time).
+    ///
+    /// Returns the swap output and shares minted as a `RouteResult` in return data.
+    ///
+    /// A rejected route emits `RouteValidationFailed` (check + remaining account index)
+    /// before returning the error.
+    pub fn claim_swap_restake<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, ClaimSwapRestake<'info>>,
+        min_amount_out: u64,
+        min_shares_out: u64,
+        expected_reward_mint: Pubkey,
+        expected_stake_mint: Pubkey,
+        fee_tier: u16,
+        deadline: i64,
+    ) -> Result<RouteResult> {
+        let state = &ctx.accounts.router_state;
+        require!(!state.paused, RouterError::RouterPaused);
         require!(
-            ctx.remaining_accounts[2].key() == ctx.accounts.input_token_account.key(),
-            RouterError::InvalidMint
+            state.vault_programs.contains(ctx.accounts.vault_program.key),
+            RouterError::VaultProgramNotAllowlisted
         );
+        check_deadline(deadline)?;
+        require!(min_amount_out > 0, RouterError::InvalidAmount);
         require!(
-            ctx.remaining_accounts[3].key() == ctx.accounts.output_token_account.key(),
+            expected_reward_mint != expected_stake_mint,
             RouterError::InvalidMint
         );
 
-        let mut seeds = vec![
-            b"pool",
-            expected_input_mint.as_ref(),
-            expected_output_mint.as_ref(),
-            b"authority",
-        ];
+        let user_key = ctx.accounts.user.key();
+        let reward = &ctx.accounts.reward_token_account;
+        let stake = &ctx.accounts.stake_token_account;
+
+        check_route(
+            reward.owner == user_key,
+            RouteCheck::InputTokenOwner,
+            None,
+            user_key,
+            reward.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            stake.owner == user_key,
+            RouteCheck::OutputTokenOwner,
+            None,
+            user_key,
+            stake.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            reward.mint == expected_reward_mint,
+            RouteCheck::InputMint,
+            None,
+            expected_reward_mint,
+            reward.mint,
+            RouterError::InvalidMint,
+        )?;
+        check_route(
+            stake.mint == expected_stake_mint,
+            RouteCheck::OutputMint,
+            None,
+            expected_stake_mint,
+            stake.mint,
+            RouterError::InvalidMint,
+        )?;
 
-        let (pool_authority_pda, pool_authority_bump) =
-            Pubkey::find_program_address(&seeds, &ctx.accounts.amm_program.key());
+        if ctx.remaining_accounts.len() < RESTAKE_ROUTE_ACCOUNTS {
+            emit!(RouteValidationFailed {
+                check: RouteCheck::RemainingAccountsLength,
+                account_index: Some(ctx.remaining_accounts.len() as u8),
+                expected: Pubkey::default(),
+                actual: Pubkey::default(),
+            });
+            return err!(RouterError::InsufficientAccounts);
+        }
+        let (claim_accounts, rest) = ctx.remaining_accounts.split_at(CLAIM_ACCOUNTS);
+        let (swap_accounts, rest) = rest.split_at(SWAP_ACCOUNTS);
+        let claim = vault_cpi::ClaimRewards::from_slice(claim_accounts)?;
+        let deposit = vault_cpi::Deposit::from_slice(rest)?;
+
+        check_route(
+            claim.user_reward_token_account.key() == reward.key(),
+            RouteCheck::ClaimRewardTokenAccount,
+            Some(4),
+            reward.key(),
+            claim.user_reward_token_account.key(),
+            RouterError::InvalidTokenAccount,
+        )?;
+        let route = SwapRoute {
+            token_in: reward.key(),
+            token_out: stake.key(),
+            mint_in: expected_reward_mint,
+            mint_out: expected_stake_mint,
+            fee_tier,
+        };
+        dex::check_swap_accounts(ctx.accounts.amm_program.key, swap_accounts, CLAIM_ACCOUNTS, &route)?;
+        check_route(
+            deposit.vault.key() == claim.vault.key(),
+            RouteCheck::RestakeVault,
+            Some(22),
+            claim.vault.key(),
+            deposit.vault.key(),
+            RouterError::InvalidVault,
+        )?;
+        check_route(
+            deposit.user_token_account.key() == stake.key(),
+            RouteCheck::VaultUserTokenAccount,
+            Some(25),
+            stake.key(),
+            deposit.user_token_account.key(),
+            RouterError::InvalidTokenAccount,
+        )?;
+        // The vault's token is the stake mint, so its authority pins the vault
+        let generation = vault_state::vault_generation(deposit.vault, ctx.accounts.vault_program.key)?;
+        let (vault_authority_pda, _) = Pubkey::find_program_address(
+            &[
+                b"vault",
+                expected_stake_mint.as_ref(),
+                b"authority",
+                vault_pda::generation_seed(&generation),
+            ],
+            ctx.accounts.vault_program.key,
+        );
+        check_route(
+            deposit.vault_authority.key() == vault_authority_pda,
+            RouteCheck::VaultAuthority,
+            Some(27),
+            vault_authority_pda,
+            deposit.vault_authority.key(),
+            RouterError::InvalidVaultAuthority,
+        )?;
 
-        // Verify the pool_authority account matches
+        // 1. CPI to vault-core claim_rewards
+        let reward_before = reward.amount;
+        let stake_before = stake.amount;
+        claim.invoke(&ctx.accounts.vault_program.to_account_info(), &[])?;
+
+        // 2. Swap exactly what the claim paid out
+        ctx.accounts.reward_token_account.reload()?;
+        let claimed = ctx
+            .accounts
+            .reward_token_account
+            .amount
+            .checked_sub(reward_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        require!(claimed > 0, RouterError::InvalidAmount);
+        dex::swap(
+            ctx.accounts.amm_program.to_account_info(),
+            swap_accounts,
+            &route,
+            claimed,
+            min_amount_out,
+            &[],
+        )?;
+
+        // 3. Deposit exactly what the swap paid out
+        ctx.accounts.stake_token_account.reload()?;
+        let swapped = ctx
+            .accounts
+            .stake_token_account
+            .amount
+            .checked_sub(stake_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        // Less the router fee
+        let swapped = take_router_fee(
+            &ctx.accounts.router_state,
+            &ctx.accounts.user,
+            &ctx.accounts.stake_token_account,
+            &ctx.accounts.token_program,
+            ctx.accounts.fee_token_account.as_ref(),
+            ctx.accounts.referral.as_mut(),
+            ctx.accounts.referral_vault.as_ref(),
+            swapped,
+        )?;
+        let shares_before = position_shares(deposit.user_position, ctx.accounts.vault_program.key)?;
+        deposit.invoke(
+            &ctx.accounts.vault_program.to_account_info(),
+            swapped,
+            0,
+            Vec::new(),
+            &[],
+        )?;
+        let shares_minted = check_shares_out(
+            deposit.user_position,
+            ctx.accounts.vault_program.key,
+            shares_before,
+            min_shares_out,
+        )?;
+        Ok(RouteResult {
+            amount_out: swapped,
+            shares_minted,
+        })
+    }
+
+    /// Zap → LP → Stake workflow
+    ///
+    /// This instruction atomically executes:
+    /// 1. Swaps half of `amount_in` for the pair's other token via CPI to mock-amm
+    /// 2. Adds the unswapped half and exactly what the swap paid out as liquidity via
+    ///    CPI to mock-amm `add_liquidity`, failing below `min_lp_out` LP tokens
+    /// 3. Deposits exactly the LP tokens minted into the vault whose stake mint is the
+    ///    pool's LP mint via CPI to vault-core
+    ///
+    /// Account layout:
+    ///
+    /// Fixed accounts (defined in ZapLpAndStake struct, in order):
+    /// - user (signer, mut): The user executing the transaction
+    /// - router_state: RouterState account (route fails with `RouterPaused` while paused)
+    /// - input_token_account (mut): User's token account for the input mint
+    /// - other_token_account (mut): User's token account for the pair's other mint (receives swap output)
+    /// - lp_token_account (mut): User's token account for the pool's LP mint
+    /// - amm_program: mock-amm program to CPI to for swap and add_liquidity
+    /// - vault_program: vault-core program to CPI to for deposit (must be allow-listed in
+    ///   router_state)
+    /// - token_program: SPL Token program
+    /// - system_program: System program
+    /// - fee_token_account, referral, referral_vault (optional): As in `deposit_swap_stake`,
+    ///   in the LP mint; the fee is taken from the LP tokens minted
+    ///
+    /// Remaining accounts (variable, passed through to CPIs):
+    ///
+    /// First 11 accounts - Swap instruction accounts (for mock-amm swap):
+    /// - [0] pool (mut): AMM Pool account
+    /// - [1] user: User signer (same as fixed accounts user)
+    /// - [2] user_token_in: Must match input_token_account (validated)
+    /// - [3] user_token_out: Must match other_token_account (validated)
+    /// - [4] vault_a: Pool's token A vault
+    /// - [5] vault_b: Pool's token B vault
+    /// - [6] pool_authority: Pool's PDA authority
+    /// - [7] mint_a: Pool's token A mint
+    /// - [8] mint_b: Pool's token B mint
+    /// - [9] token_program_a: Token program of mint_a (SPL Token or Token-2022)
+    /// - [10] token_program_b: Token program of mint_b
+    ///
+    /// Next 14 accounts - Add liquidity instruction accounts (same pool):
+    /// - [11] pool (mut): Must match the swap pool (validated)
+    /// - [12] user: User signer (same as fixed accounts user)
+    /// - [13] user_token_a: User's account for mint_a, input or other (validated)
+    /// - [14] user_token_b: User's account for mint_b, input or other (validated)
+    /// - [15] user_lp_token_account: Must match lp_token_account (validated)
+    /// - [16] vault_a: Pool's token A vault
+    /// - [17] vault_b: Pool's token B vault
+    /// - [18] lp_mint (mut): Pool's LP mint
+    /// - [19] pool_authority: Pool's PDA authority
+    /// - [20] mint_a: Pool's token A mint
+    /// - [21] mint_b: Pool's token B mint
+    /// - [22] token_program: LP mint's token program
+    /// - [23] token_program_a: Token program of mint_a
+    /// - [24] token_program_b: Token program of mint_b
+    ///
+    /// Next 9 accounts - Vault deposit instruction accounts:
+    /// - [25] vault: Vault account (must match lp_token_account mint)
+    /// - [26] user_position: User's position PDA in vault
+    /// - [27] user: User signer (same as fixed accounts user)
+    /// - [28] user_token_account: Must match lp_token_account (validated)
+    /// - [29] vault_token_account: Vault's token account
+    /// - [30] vault_authority: Vault's PDA authority
+    /// - [31] token_program: SPL Token program
+    /// - [32] system_program: System program
+    /// - [33] user_checkpoints (mut): User's share checkpoint PDA in vault
+    ///
+    /// Total: 34 remaining accounts required
+    ///
+    /// `fee_tier` selects which of the pair's pools the swap accounts belong to. The
+    /// route fails with `DeadlineExceeded` once the clock passes `deadline` (unix time).
+    ///
+    /// The route fails with `SharesBelowMinimum` unless the user's position gained at
+    /// least `min_shares_out` shares from the deposit.
+    ///
+    /// Returns the swap output and shares minted as a `RouteResult` in return data.
+    ///
+    /// A rejected route emits `RouteValidationFailed` (check + remaining account index)
+    /// before returning the error.
+    #[allow(clippy::too_many_arguments)]
+    pub fn zap_lp_and_stake<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, ZapLpAndStake<'info>>,
+        amount_in: u64,
+        min_amount_out: u64,
+        min_lp_out: u64,
+        min_shares_out: u64,
+        expected_input_mint: Pubkey,
+        expected_other_mint: Pubkey,
+        fee_tier: u16,
+        deadline: i64,
+    ) -> Result<RouteResult> {
+        let state = &ctx.accounts.router_state;
+        require!(!state.paused, RouterError::RouterPaused);
+        require!(
+            state.vault_programs.contains(ctx.accounts.vault_program.key),
+            RouterError::VaultProgramNotAllowlisted
+        );
+        check_deadline(deadline)?;
+        // Both halves must be non-zero
+        require!(amount_in >= 2, RouterError::InvalidAmount);
+        require!(min_amount_out > 0, RouterError::InvalidAmount);
         require!(
-            swap_accounts[6].key() == pool_authority_pda,
+            expected_input_mint != expected_other_mint,
             RouterError::InvalidMint
         );
 
-        let bump = [pool_authority_bump];
-        seeds.push(&bump);
-        let pool_authority_seeds = [&seeds[..]];
+        let user_key = ctx.accounts.user.key();
+        let input = &ctx.accounts.input_token_account;
+        let other = &ctx.accounts.other_token_account;
+        let lp = &ctx.accounts.lp_token_account;
+
+        for (account, check) in [
+            (input, RouteCheck::InputTokenOwner),
+            (other, RouteCheck::OutputTokenOwner),
+            (lp, RouteCheck::LpTokenOwner),
+        ] {
+            check_route(
+                account.owner == user_key,
+                check,
+                None,
+                user_key,
+                account.owner,
+                RouterError::InvalidTokenAccountOwner,
+            )?;
+        }
+        check_route(
+            input.mint == expected_input_mint,
+            RouteCheck::InputMint,
+            None,
+            expected_input_mint,
+            input.mint,
+            RouterError::InvalidMint,
+        )?;
+        check_route(
+            other.mint == expected_other_mint,
+            RouteCheck::OutputMint,
+            None,
+            expected_other_mint,
+            other.mint,
+            RouterError::InvalidMint,
+        )?;
+        require!(input.amount >= amount_in, RouterError::InsufficientBalance);
+
+        if ctx.remaining_accounts.len() < ZAP_ROUTE_ACCOUNTS {
+            emit!(RouteValidationFailed {
+                check: RouteCheck::RemainingAccountsLength,
+                account_index: Some(ctx.remaining_accounts.len() as u8),
+                expected: Pubkey::default(),
+                actual: Pubkey::default(),
+            });
+            return err!(RouterError::InsufficientAccounts);
+        }
+        let (swap_accounts, rest) =
+            ctx.remaining_accounts.split_at(adapters::mock_amm::SWAP_ACCOUNTS);
+        let (liquidity_accounts, rest) = rest.split_at(LIQUIDITY_ACCOUNTS);
+        let deposit = vault_cpi::Deposit::from_slice(rest)?;
+
+        // add_liquidity takes the pool's tokens in mint order
+        let (mint_a, _) = mock_amm::pda::sorted_mints(expected_input_mint, expected_other_mint);
+        let input_is_a = mint_a == expected_input_mint;
+        let (token_a, token_b) = if input_is_a {
+            (input.key(), other.key())
+        } else {
+            (other.key(), input.key())
+        };
+
+        let route = SwapRoute {
+            token_in: input.key(),
+            token_out: other.key(),
+            mint_in: expected_input_mint,
+            mint_out: expected_other_mint,
+            fee_tier,
+        };
+        adapters::mock_amm::check_swap_accounts(
+            ctx.accounts.amm_program.key,
+            swap_accounts,
+            0,
+            &route,
+        )?;
+        for (index, expected, check) in [
+            (13, token_a, RouteCheck::LiquidityUserToken),
+            (14, token_b, RouteCheck::LiquidityUserToken),
+            (15, lp.key(), RouteCheck::LiquidityUserToken),
+            (28, lp.key(), RouteCheck::VaultUserTokenAccount),
+        ] {
+            let actual = ctx.remaining_accounts[index].key();
+            check_route(
+                actual == expected,
+                check,
+                Some(index as u8),
+                expected,
+                actual,
+                RouterError::InvalidTokenAccount,
+            )?;
+        }
+        // The swap pool is pinned by its authority, the liquidity pool by it
+        check_route(
+            liquidity_accounts[0].key() == swap_accounts[0].key(),
+            RouteCheck::LiquidityPool,
+            Some(11),
+            swap_accounts[0].key(),
+            liquidity_accounts[0].key(),
+            RouterError::InvalidPool,
+        )?;
+        // mock-amm ties the LP account to the pool's LP mint, so the vault's token is it
+        let generation = vault_state::vault_generation(deposit.vault, ctx.accounts.vault_program.key)?;
+        let (vault_authority_pda, _) = Pubkey::find_program_address(
+            &[
+                b"vault",
+                lp.mint.as_ref(),
+                b"authority",
+                vault_pda::generation_seed(&generation),
+            ],
+            ctx.accounts.vault_program.key,
+        );
+        check_route(
+            deposit.vault_authority.key() == vault_authority_pda,
+            RouteCheck::VaultAuthority,
+            Some(30),
+            vault_authority_pda,
+            deposit.vault_authority.key(),
+            RouterError::InvalidVaultAuthority,
+        )?;
+
+        // 1. Swap half the input
+        let other_before = other.amount;
+        let lp_before = lp.amount;
+        let swap_amount = amount_in / 2;
+        adapters::mock_amm::swap(
+            ctx.accounts.amm_program.to_account_info(),
+            swap_accounts,
+            &route,
+            swap_amount,
+            min_amount_out,
+            &[],
+        )?;
 
-        let ctx_swap = CpiContext::new(
+        // 2. Add the other half and exactly what the swap paid out as liquidity
+        ctx.accounts.other_token_account.reload()?;
+        let swapped = ctx
+            .accounts
+            .other_token_account
+            .amount
+            .checked_sub(other_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        let kept = amount_in - swap_amount;
+        let (amount_a, amount_b) = if input_is_a {
+            (kept, swapped)
+        } else {
+            (swapped, kept)
+        };
+        let ctx_liquidity = CpiContext::new(
             ctx.accounts.amm_program.to_account_info(),
-            mock_amm::cpi::accounts::Swap {
-                pool: swap_accounts[0].to_account_info(),
-                user: swap_accounts[1].to_account_info(),
-                user_token_in: swap_accounts[2].to_account_info(),
-                user_token_out: swap_accounts[3].to_account_info(),
-                vault_a: swap_accounts[4].to_account_info(),
-                vault_b: swap_accounts[5].to_account_info(),
-                pool_authority: swap_accounts[6].to_account_info(),
-                token_program: swap_accounts[7].to_account_info(),
+            mock_amm::cpi::accounts::AddLiquidity {
+                pool: liquidity_accounts[0].to_account_info(),
+                user: liquidity_accounts[1].to_account_info(),
+                user_token_a: liquidity_accounts[2].to_account_info(),
+                user_token_b: liquidity_accounts[3].to_account_info(),
+                user_lp_token_account: liquidity_accounts[4].to_account_info(),
+                vault_a: liquidity_accounts[5].to_account_info(),
+                vault_b: liquidity_accounts[6].to_account_info(),
+                lp_mint: liquidity_accounts[7].to_account_info(),
+                pool_authority: liquidity_accounts[8].to_account_info(),
+                mint_a: liquidity_accounts[9].to_account_info(),
+                mint_b: liquidity_accounts[10].to_account_info(),
+                token_program: liquidity_accounts[11].to_account_info(),
+                token_program_a: liquidity_accounts[12].to_account_info(),
+                token_program_b: liquidity_accounts[13].to_account_info(),
             },
-        )
-        .with_signer(&pool_authority_seeds);
-        mock_amm::cpi::swap(ctx_swap, swap_amount_in, min_amount_out)?;
+        );
+        mock_amm::cpi::add_liquidity(ctx_liquidity, amount_a, amount_b, min_lp_out)?;
 
-        // 2. Reload output token account to verify swap happened
+        // 3. Stake exactly the LP tokens minted, less the router fee
+        ctx.accounts.lp_token_account.reload()?;
+        let minted = ctx
+            .accounts
+            .lp_token_account
+            .amount
+            .checked_sub(lp_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        let minted = take_router_fee(
+            &ctx.accounts.router_state,
+            &ctx.accounts.user,
+            &ctx.accounts.lp_token_account,
+            &ctx.accounts.token_program,
+            ctx.accounts.fee_token_account.as_ref(),
+            ctx.accounts.referral.as_mut(),
+            ctx.accounts.referral_vault.as_ref(),
+            minted,
+        )?;
+        let shares_before = position_shares(deposit.user_position, ctx.accounts.vault_program.key)?;
+        deposit.invoke(
+            &ctx.accounts.vault_program.to_account_info(),
+            minted,
+            0,
+            Vec::new(),
+            &[],
+        )?;
+        let shares_minted = check_shares_out(
+            deposit.user_position,
+            ctx.accounts.vault_program.key,
+            shares_before,
+            min_shares_out,
+        )?;
+        Ok(RouteResult {
+            amount_out: swapped,
+            shares_minted,
+        })
+    }
+
+    /// Keeper-executed Deposit → Swap → Stake: runs a `RouteIntent` the user signed
+    /// off-chain, so the user needs no transaction (or SOL) of their own
+    ///
+    /// The user signs `intent_message(intent)` once and approves two delegates up front
+    /// (SPL `approve`): the router's intent authority PDA on the input account for at
+    /// least `amount_in`, and the output mint's vault authority on the output account.
+    /// The keeper submits an ed25519 program instruction verifying that signature
+    /// immediately before this one, then this instruction:
+    /// 1. Checks the signature, `expiry` (as `DeadlineExceeded`) and that the intent's
+    ///    nonce is unused, recording it in an `IntentReceipt` paid by the keeper
+    /// 2. Swaps `amount_in` via CPI to mock-amm, signed by the intent authority
+    /// 3. Deposits exactly what the swap paid out into the user's position via CPI to
+    ///    vault-core `deposit_with_delegate`, paid by the keeper if the position is new
+    ///
+    /// Like `deposit_swap_stake`, fails with `RouterPaused` while the router is paused
+    /// and requires `intent.vault_program` to be allow-listed in router_state.
+    ///
+    /// Account layout:
+    ///
+    /// Fixed accounts (defined in ExecuteIntent struct, in order):
+    /// - keeper (signer, mut): Submits the intent and pays for the receipt
+    /// - router_state: RouterState account
+    /// - intent_receipt (mut): `[b"intent", user, nonce]`, created here
+    /// - intent_authority: The router's `[b"intent_authority"]` PDA
+    /// - input_token_account (mut): User's input account, delegated to intent_authority
+    /// - output_token_account (mut): User's output account, delegated to the vault authority
+    /// - instructions_sysvar: Instructions sysvar (holds the ed25519 instruction)
+    /// - amm_program: mock-amm program to CPI to for swap
+    /// - vault_program: vault-core program to CPI to for deposit (must be intent.vault_program)
+    /// - token_program: SPL Token program
+    /// - system_program: System program
+    ///
+    /// Remaining accounts (variable, passed through to CPIs):
+    ///
+    /// First 11 accounts - Swap instruction accounts (for mock-amm swap):
+    /// - [0] pool (mut): AMM Pool account
+    /// - [1] user (mut): Must match intent_authority (validated)
+    /// - [2] user_token_in: Must match input_token_account (validated)
+    /// - [3] user_token_out: Must match output_token_account (validated)
+    /// - [4] vault_a: Pool's token A vault
+    /// - [5] vault_b: Pool's token B vault
+    /// - [6] pool_authority: Pool's PDA authority
+    /// - [7] mint_a: Pool's token A mint
+    /// - [8] mint_b: Pool's token B mint
+    /// - [9] token_program_a: Token program of mint_a (SPL Token or Token-2022)
+    /// - [10] token_program_b: Token program of mint_b
+    ///
+    /// Next 10 accounts - Vault deposit_with_delegate instruction accounts:
+    /// - [11] vault: Vault account (must match output_token_account mint)
+    /// - [12] user_position: User's position PDA in vault
+    /// - [13] user: Must match intent.user (validated)
+    /// - [14] payer: Must match keeper (validated)
+    /// - [15] user_token_account: Must match output_token_account (validated)
+    /// - [16] vault_token_account: Vault's token account
+    /// - [17] vault_authority: Vault's PDA authority
+    /// - [18] token_program: SPL Token program
+    /// - [19] system_program: System program
+    /// - [20] user_checkpoints (mut): Beneficiary's share checkpoint PDA in vault
+    ///
+    /// Total: 21 remaining accounts required
+    ///
+    /// Returns the swap output and shares minted as a `RouteResult` in return data.
+    ///
+    /// A rejected route emits `RouteValidationFailed` (check + remaining account index)
+    /// before returning the error.
+    pub fn execute_intent<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, ExecuteIntent<'info>>,
+        intent: RouteIntent,
+    ) -> Result<RouteResult> {
+        let state = &ctx.accounts.router_state;
+        require!(!state.paused, RouterError::RouterPaused);
+        require_keys_eq!(
+            ctx.accounts.vault_program.key(),
+            intent.vault_program,
+            RouterError::InvalidVaultProgram
+        );
+        require!(
+            state.vault_programs.contains(&intent.vault_program),
+            RouterError::VaultProgramNotAllowlisted
+        );
+        check_deadline(intent.expiry)?;
+        verify_intent_signature(
+            &ctx.accounts.instructions_sysvar,
+            &intent.user,
+            &intent_message(&intent)?,
+        )?;
+        require!(intent.amount_in > 0, RouterError::InvalidAmount);
+        require!(intent.min_amount_out > 0, RouterError::InvalidAmount);
+
+        let authority_key = ctx.accounts.intent_authority.key();
+        let input = &ctx.accounts.input_token_account;
+        let output = &ctx.accounts.output_token_account;
+        let output_before = output.amount;
+
+        check_route(
+            input.owner == intent.user,
+            RouteCheck::InputTokenOwner,
+            None,
+            intent.user,
+            input.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            output.owner == intent.user,
+            RouteCheck::OutputTokenOwner,
+            None,
+            intent.user,
+            output.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            input.mint == intent.input_mint,
+            RouteCheck::InputMint,
+            None,
+            intent.input_mint,
+            input.mint,
+            RouterError::InvalidMint,
+        )?;
+        check_route(
+            output.mint == intent.output_mint,
+            RouteCheck::OutputMint,
+            None,
+            intent.output_mint,
+            output.mint,
+            RouterError::InvalidMint,
+        )?;
+        let delegate: Option<Pubkey> = input.delegate.into();
+        require!(
+            delegate == Some(authority_key) && input.delegated_amount >= intent.amount_in,
+            RouterError::DelegateNotApproved
+        );
+        require!(
+            input.amount >= intent.amount_in,
+            RouterError::InsufficientBalance
+        );
+
+        if ctx.remaining_accounts.len() < INTENT_ROUTE_ACCOUNTS {
+            emit!(RouteValidationFailed {
+                check: RouteCheck::RemainingAccountsLength,
+                account_index: Some(ctx.remaining_accounts.len() as u8),
+                expected: Pubkey::default(),
+                actual: Pubkey::default(),
+            });
+            return err!(RouterError::InsufficientAccounts);
+        }
+        let (swap_accounts, vault_accounts) =
+            ctx.remaining_accounts[..INTENT_ROUTE_ACCOUNTS].split_at(SWAP_ACCOUNTS);
+        let deposit = vault_cpi::DepositWithDelegate::from_slice(vault_accounts)?;
+
+        let route = SwapRoute {
+            token_in: input.key(),
+            token_out: output.key(),
+            mint_in: intent.input_mint,
+            mint_out: intent.output_mint,
+            fee_tier: intent.fee_tier,
+        };
+        dex::check_swap_accounts(ctx.accounts.amm_program.key, swap_accounts, 0, &route)?;
+        for (index, expected, actual, check) in [
+            (
+                dex::SWAP_USER as u8,
+                authority_key,
+                swap_accounts[dex::SWAP_USER].key(),
+                RouteCheck::IntentAuthority,
+            ),
+            (13, intent.user, deposit.user.key(), RouteCheck::IntentUser),
+            (14, ctx.accounts.keeper.key(), deposit.payer.key(), RouteCheck::IntentPayer),
+            (15, output.key(), deposit.user_token_account.key(), RouteCheck::VaultUserTokenAccount),
+        ] {
+            check_route(
+                actual == expected,
+                check,
+                Some(index),
+                expected,
+                actual,
+                RouterError::InvalidTokenAccount,
+            )?;
+        }
+        let generation = vault_state::vault_generation(deposit.vault, ctx.accounts.vault_program.key)?;
+        let (vault_authority_pda, _) = Pubkey::find_program_address(
+            &[
+                b"vault",
+                intent.output_mint.as_ref(),
+                b"authority",
+                vault_pda::generation_seed(&generation),
+            ],
+            ctx.accounts.vault_program.key,
+        );
+        check_route(
+            deposit.vault_authority.key() == vault_authority_pda,
+            RouteCheck::VaultAuthority,
+            Some(17),
+            vault_authority_pda,
+            deposit.vault_authority.key(),
+            RouterError::InvalidVaultAuthority,
+        )?;
+
+        // 1. The receipt's `init` already rejected a reused nonce
+        ctx.accounts.intent_receipt.executed_at = Clock::get()?.unix_timestamp;
+
+        // 2. Swap as the intent authority, through the user's approval
+        dex::swap(
+            ctx.accounts.amm_program.to_account_info(),
+            swap_accounts,
+            &route,
+            intent.amount_in,
+            intent.min_amount_out,
+            &[&[INTENT_AUTHORITY_SEED, &[ctx.bumps.intent_authority]]],
+        )?;
+
+        // 3. Deposit exactly what the swap paid out, through the vault approval
         ctx.accounts.output_token_account.reload()?;
+        let swapped = ctx
+            .accounts
+            .output_token_account
+            .amount
+            .checked_sub(output_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        let shares_before = position_shares(deposit.user_position, ctx.accounts.vault_program.key)?;
+        deposit.invoke(
+            &ctx.accounts.vault_program.to_account_info(),
+            swapped,
+            0,
+            Vec::new(),
+            &[],
+        )?;
+        let shares_minted = check_shares_out(
+            deposit.user_position,
+            ctx.accounts.vault_program.key,
+            shares_before,
+            intent.min_shares_out,
+        )?;
+        Ok(RouteResult {
+            amount_out: swapped,
+            shares_minted,
+        })
+    }
 
-        let mut seeds = vec![b"vault", expected_output_mint.as_ref(), b"authority"];
+    /// Deposit → Swap → Stake into several positions: swaps once, then splits the
+    /// output across one vault deposit per `allocations` entry
+    ///
+    /// Each leg names its own vault-core program (any allow-listed deployment, so
+    /// different vaults of the output mint) and beneficiary. Every leg deposits through
+    /// vault-core `deposit_with_delegate` with the user as payer:
+    /// - A leg depositing from the user's own output_token_account: the router approves
+    ///   the leg's vault authority for the leg amount, replacing any existing approval
+    /// - A leg for another beneficiary: the router first transfers the leg amount to the
+    ///   beneficiary's token account, which must have approved the leg's vault authority
+    ///   as delegate (SPL `approve`) beforehand
+    ///
+    /// `allocations` split the swap output, net of the router fee, in basis points and must sum to 10_000; the
+    /// last leg also takes the rounding remainder. Each leg fails with
+    /// `SharesBelowMinimum` unless its position gained `min_shares_out` shares.
+    ///
+    /// Account layout:
+    ///
+    /// Fixed accounts (defined in BatchSwapStake struct, in order):
+    /// - user (signer, mut): The user executing the transaction, pays for new positions
+    /// - router_state: RouterState account (route fails with `RouterPaused` while paused)
+    /// - input_token_account (mut): User's token account for input tokens
+    /// - output_token_account (mut): User's token account for output tokens, receives the swap output
+    /// - amm_program: mock-amm program to CPI to for swap
+    /// - token_program: SPL Token program
+    /// - system_program: System program
+    /// - fee_token_account, referral, referral_vault (optional): As in `deposit_swap_stake`
+    ///
+    /// Remaining accounts (variable, passed through to CPIs):
+    ///
+    /// First 11 accounts - Swap instruction accounts, as in `deposit_swap_stake`
+    ///
+    /// Then 11 accounts per allocation, leg `i` starting at `11 + 11 * i`:
+    /// - [+0] vault_program: vault-core program of the leg (must be allow-listed)
+    /// - [+1] vault: Vault account (must match output_token_account mint)
+    /// - [+2] user_position: Beneficiary's position PDA in vault
+    /// - [+3] user: Beneficiary; the user for legs from output_token_account
+    /// - [+4] payer: Must match user (validated)
+    /// - [+5] user_token_account: output_token_account, or the beneficiary's token account
+    /// - [+6] vault_token_account: Vault's token account
+    /// - [+7] vault_authority: Vault's PDA authority (validated)
+    /// - [+8] token_program: SPL Token program
+    /// - [+9] system_program: System program
+    /// - [+10] user_checkpoints (mut): Beneficiary's share checkpoint PDA in vault
+    ///
+    /// Total: 11 + 11 * allocations.len() remaining accounts required
+    ///
+    /// Returns one `RouteResult` per leg in return data: the leg's share of the swap
+    /// output and the shares it minted.
+    ///
+    /// A rejected route emits `RouteValidationFailed` (check + remaining account index)
+    /// before returning the error.
+    #[allow(clippy::too_many_arguments)]
+    pub fn batch_swap_stake<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, BatchSwapStake<'info>>,
+        swap_amount_in: u64,
+        min_amount_out: u64,
+        allocations: Vec<Allocation>,
+        expected_input_mint: Pubkey,
+        expected_output_mint: Pubkey,
+        fee_tier: u16,
+        deadline: i64,
+    ) -> Result<Vec<RouteResult>> {
+        let state = &ctx.accounts.router_state;
+        require!(!state.paused, RouterError::RouterPaused);
+        check_deadline(deadline)?;
+        require!(swap_amount_in > 0, RouterError::InvalidAmount);
+        require!(min_amount_out > 0, RouterError::InvalidAmount);
+        require!(
+            !allocations.is_empty()
+                && allocations.iter().all(|allocation| allocation.bps > 0)
+                && allocations.iter().map(|allocation| allocation.bps as u32).sum::<u32>()
+                    == BPS_DENOMINATOR as u32,
+            RouterError::InvalidAllocation
+        );
 
-        let (vault_authority_pda, vault_authority_bump) =
-            Pubkey::find_program_address(&seeds, ctx.accounts.vault_program.key);
+        let user_key = ctx.accounts.user.key();
+        let input = &ctx.accounts.input_token_account;
+        let output = &ctx.accounts.output_token_account;
+        let output_before = output.amount;
 
-        // Verify the vault_authority account matches
+        check_route(
+            input.owner == user_key,
+            RouteCheck::InputTokenOwner,
+            None,
+            user_key,
+            input.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            output.owner == user_key,
+            RouteCheck::OutputTokenOwner,
+            None,
+            user_key,
+            output.owner,
+            RouterError::InvalidTokenAccountOwner,
+        )?;
+        check_route(
+            input.mint == expected_input_mint,
+            RouteCheck::InputMint,
+            None,
+            expected_input_mint,
+            input.mint,
+            RouterError::InvalidMint,
+        )?;
+        check_route(
+            output.mint == expected_output_mint,
+            RouteCheck::OutputMint,
+            None,
+            expected_output_mint,
+            output.mint,
+            RouterError::InvalidMint,
+        )?;
         require!(
-            vault_accounts[5].key() == vault_authority_pda,
-            RouterError::InvalidMint
+            input.amount >= swap_amount_in,
+            RouterError::InsufficientBalance
         );
 
-        let bump = [vault_authority_bump];
-        seeds.push(&bump);
-        let vault_authority_seeds = [&seeds[..]];
+        let route_accounts = SWAP_ACCOUNTS + BATCH_LEG_ACCOUNTS * allocations.len();
+        if ctx.remaining_accounts.len() < route_accounts {
+            emit!(RouteValidationFailed {
+                check: RouteCheck::RemainingAccountsLength,
+                account_index: Some(ctx.remaining_accounts.len() as u8),
+                expected: Pubkey::default(),
+                actual: Pubkey::default(),
+            });
+            return err!(RouterError::InsufficientAccounts);
+        }
+        let (swap_accounts, leg_accounts) =
+            ctx.remaining_accounts[..route_accounts].split_at(SWAP_ACCOUNTS);
 
-        // 3. CPI to vault-core deposit
-        let ctx_deposit = CpiContext::new(
-            ctx.accounts.vault_program.to_account_info(),
-            vault_core::cpi::accounts::Deposit {
-                vault: vault_accounts[0].to_account_info(),
-                user_position: vault_accounts[1].to_account_info(),
-                user: vault_accounts[2].to_account_info(),
-                user_token_account: vault_accounts[3].to_account_info(),
-                vault_token_account: vault_accounts[4].to_account_info(),
-                vault_authority: vault_accounts[5].to_account_info(),
-                token_program: vault_accounts[6].to_account_info(),
-                system_program: vault_accounts[7].to_account_info(),
+        let route = SwapRoute {
+            token_in: input.key(),
+            token_out: output.key(),
+            mint_in: expected_input_mint,
+            mint_out: expected_output_mint,
+            fee_tier,
+        };
+        dex::check_swap_accounts(ctx.accounts.amm_program.key, swap_accounts, 0, &route)?;
+
+        // Validate every leg before moving any tokens
+        let mut legs = Vec::with_capacity(allocations.len());
+        for (leg, accounts) in leg_accounts.chunks(BATCH_LEG_ACCOUNTS).enumerate() {
+            let base = SWAP_ACCOUNTS + BATCH_LEG_ACCOUNTS * leg;
+            let (vault_program, deposit_accounts) = accounts.split_first().unwrap();
+            require!(vault_program.executable, RouterError::InvalidVaultProgram);
+            require!(
+                state.vault_programs.contains(vault_program.key),
+                RouterError::VaultProgramNotAllowlisted
+            );
+            let deposit = vault_cpi::DepositWithDelegate::from_slice(deposit_accounts)?;
+            check_route(
+                deposit.payer.key() == user_key,
+                RouteCheck::BatchPayer,
+                Some((base + 4) as u8),
+                user_key,
+                deposit.payer.key(),
+                RouterError::InvalidTokenAccount,
+            )?;
+            let generation = vault_state::vault_generation(deposit.vault, vault_program.key)?;
+            let (vault_authority_pda, _) = Pubkey::find_program_address(
+                &[
+                    b"vault",
+                    expected_output_mint.as_ref(),
+                    b"authority",
+                    vault_pda::generation_seed(&generation),
+                ],
+                vault_program.key,
+            );
+            check_route(
+                deposit.vault_authority.key() == vault_authority_pda,
+                RouteCheck::VaultAuthority,
+                Some((base + 7) as u8),
+                vault_authority_pda,
+                deposit.vault_authority.key(),
+                RouterError::InvalidVaultAuthority,
+            )?;
+            legs.push((vault_program, deposit));
+        }
+
+        // 1. CPI to the DEX adapter's swap
+        dex::swap(
+            ctx.accounts.amm_program.to_account_info(),
+            swap_accounts,
+            &route,
+            swap_amount_in,
+            min_amount_out,
+            &[],
+        )?;
+
+        // 2. Split exactly what the swap paid out, after the router fee
+        ctx.accounts.output_token_account.reload()?;
+        let swapped = ctx
+            .accounts
+            .output_token_account
+            .amount
+            .checked_sub(output_before)
+            .ok_or(RouterError::InvalidAmount)?;
+        let swapped = take_router_fee(
+            &ctx.accounts.router_state,
+            &ctx.accounts.user,
+            &ctx.accounts.output_token_account,
+            &ctx.accounts.token_program,
+            ctx.accounts.fee_token_account.as_ref(),
+            ctx.accounts.referral.as_mut(),
+            ctx.accounts.referral_vault.as_ref(),
+            swapped,
+        )?;
+        let output_info = ctx.accounts.output_token_account.to_account_info();
+        let token_program = ctx.accounts.token_program.to_account_info();
+        let mut results = Vec::with_capacity(legs.len());
+        let mut allocated = 0u64;
+        for (index, ((vault_program, deposit), allocation)) in
+            legs.iter().zip(&allocations).enumerate()
+        {
+            let amount = if index + 1 == allocations.len() {
+                swapped - allocated
+            } else {
+                (swapped as u128 * allocation.bps as u128 / BPS_DENOMINATOR as u128) as u64
+            };
+            allocated += amount;
+
+            // 3. Hand the leg amount to its vault authority: approve it on the user's
+            // own account, or move it into the beneficiary's pre-approved account
+            if deposit.user_token_account.key() == output_info.key() {
+                token::approve(
+                    CpiContext::new(
+                        token_program.clone(),
+                        token::Approve {
+                            to: output_info.clone(),
+                            delegate: deposit.vault_authority.clone(),
+                            authority: ctx.accounts.user.to_account_info(),
+                        },
+                    ),
+                    amount,
+                )?;
+            } else {
+                token::transfer(
+                    CpiContext::new(
+                        token_program.clone(),
+                        token::Transfer {
+                            from: output_info.clone(),
+                            to: deposit.user_token_account.clone(),
+                            authority: ctx.accounts.user.to_account_info(),
+                        },
+                    ),
+                    amount,
+                )?;
+            }
+
+            // 4. CPI to the leg's vault-core deposit_with_delegate
+            let shares_before = position_shares(deposit.user_position, vault_program.key)?;
+            deposit.invoke(vault_program, amount, 0, Vec::new(), &[])?;
+            let shares_minted = check_shares_out(
+                deposit.user_position,
+                vault_program.key,
+                shares_before,
+                allocation.min_shares_out,
+            )?;
+            results.push(RouteResult {
+                amount_out: amount,
+                shares_minted,
+            });
+        }
+        Ok(results)
+    }
+}
+
+/// Swap remaining accounts of the active DEX adapter
+pub const SWAP_ACCOUNTS: usize = dex::SWAP_ACCOUNTS;
+
+/// Swap (11) + vault deposit (9) remaining accounts
+pub const ROUTE_ACCOUNTS: usize = 20;
+
+/// Vault withdraw (10) + swap (11) remaining accounts of `withdraw_swap_return`
+pub const RETURN_ROUTE_ACCOUNTS: usize = 21;
+
+/// vault-core claim_rewards remaining accounts
+pub const CLAIM_ACCOUNTS: usize = vault_cpi::ClaimRewards::LEN;
+
+/// Claim (11) + swap (11) + vault deposit (9) remaining accounts of `claim_swap_restake`
+pub const RESTAKE_ROUTE_ACCOUNTS: usize = 31;
+
+/// mock-amm add_liquidity remaining accounts
+pub const LIQUIDITY_ACCOUNTS: usize = 14;
+
+/// Swap (11) + add liquidity (14) + vault deposit (9) remaining accounts of
+/// `zap_lp_and_stake`
+pub const ZAP_ROUTE_ACCOUNTS: usize = 34;
+
+/// Swap (11) + vault deposit_with_delegate (10) remaining accounts of `execute_intent`
+pub const INTENT_ROUTE_ACCOUNTS: usize = 21;
+
+/// Vault program (1) + vault deposit_with_delegate (10) remaining accounts per
+/// `batch_swap_stake` leg
+pub const BATCH_LEG_ACCOUNTS: usize = 1 + vault_cpi::DepositWithDelegate::LEN;
+
+/// `Allocation::bps` of every leg sum to this
+pub const BPS_DENOMINATOR: u16 = 10_000;
+
+/// Seed of the PDA users approve as delegate on their input account for intents
+pub const INTENT_AUTHORITY_SEED: &[u8] = b"intent_authority";
+
+/// Native ed25519 signature verification program
+pub const ED25519_PROGRAM_ID: Pubkey = pubkey!("Ed25519SigVerify111111111111111111111111111");
+
+/// Prefix of the message a user signs for `execute_intent`, see `intent_message`
+pub const INTENT_DOMAIN: &[u8] = b"composer-router intent";
+
+/// What the user signs: `INTENT_DOMAIN`, this program's id, then the Borsh-encoded
+/// intent, so a signature can't be replayed against another program or message type
+pub fn intent_message(intent: &RouteIntent) -> Result<Vec<u8>> {
+    let mut message = [INTENT_DOMAIN, crate::ID.as_ref()].concat();
+    intent.serialize(&mut message)?;
+    Ok(message)
+}
+
+/// Require the instruction just before this one to be an ed25519 program instruction
+/// verifying one signature by `user` over `message`, all read from its own data
+fn verify_intent_signature(
+    instructions_sysvar: &AccountInfo,
+    user: &Pubkey,
+    message: &[u8],
+) -> Result<()> {
+    let current = load_current_index_checked(instructions_sysvar)? as usize;
+    require!(current > 0, RouterError::InvalidIntentSignature);
+    let ix = load_instruction_at_checked(current - 1, instructions_sysvar)?;
+    require_keys_eq!(
+        ix.program_id,
+        ED25519_PROGRAM_ID,
+        RouterError::InvalidIntentSignature
+    );
+
+    // Signature count and padding, then the offsets: signature, its instruction index,
+    // public key, its instruction index, message, message size, its instruction index
+    let data = &ix.data;
+    require!(
+        data.len() >= 16 && data[0] == 1,
+        RouterError::InvalidIntentSignature
+    );
+    let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    // u16::MAX: the ed25519 instruction's own data
+    require!(
+        read_u16(4) == u16::MAX && read_u16(8) == u16::MAX && read_u16(14) == u16::MAX,
+        RouterError::InvalidIntentSignature
+    );
+    let key_offset = read_u16(6) as usize;
+    let (message_offset, message_len) = (read_u16(10) as usize, read_u16(12) as usize);
+    require!(
+        data.get(key_offset..key_offset + 32) == Some(user.as_ref())
+            && data.get(message_offset..message_offset + message_len) == Some(message),
+        RouterError::InvalidIntentSignature
+    );
+    Ok(())
+}
+
+/// Take `RouterState::fee_bps` of `swapped` from the user's output account and return
+/// the rest. The fee goes to `fee_token_account`, except the referrer's
+/// `referral_share_bps` of it, which moves to `referral_vault` and is credited to
+/// `referral` along with the routed volume.
+#[allow(clippy::too_many_arguments)]
+fn take_router_fee<'info>(
+    state: &RouterState,
+    user: &Signer<'info>,
+    output: &Account<'info, TokenAccount>,
+    token_program: &Program<'info, Token>,
+    fee_token_account: Option<&Account<'info, TokenAccount>>,
+    referral: Option<&mut Account<'info, Referral>>,
+    referral_vault: Option<&Account<'info, TokenAccount>>,
+    swapped: u64,
+) -> Result<u64> {
+    let fee = (swapped as u128 * state.fee_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+    let mut protocol_fee = fee;
+    if let Some(referral) = referral {
+        require!(referral.mint == output.mint, RouterError::InvalidMint);
+        require_keys_neq!(referral.referrer, user.key(), RouterError::InvalidReferrer);
+        let vault = referral_vault.ok_or(RouterError::FeeAccountRequired)?;
+        require!(vault.mint == output.mint, RouterError::InvalidMint);
+
+        let share =
+            (fee as u128 * state.referral_share_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+        referral.routed_volume = referral
+            .routed_volume
+            .checked_add(swapped as u128)
+            .ok_or(RouterError::MathOverflow)?;
+        referral.claimable = referral
+            .claimable
+            .checked_add(share)
+            .ok_or(RouterError::MathOverflow)?;
+        protocol_fee -= share;
+        transfer_fee(user, output, vault, token_program, share)?;
+    }
+    if protocol_fee > 0 {
+        let fee_account = fee_token_account.ok_or(RouterError::FeeAccountRequired)?;
+        require!(fee_account.mint == output.mint, RouterError::InvalidMint);
+        transfer_fee(user, output, fee_account, token_program, protocol_fee)?;
+    }
+    Ok(swapped - fee)
+}
+
+fn transfer_fee<'info>(
+    user: &Signer<'info>,
+    from: &Account<'info, TokenAccount>,
+    to: &Account<'info, TokenAccount>,
+    token_program: &Program<'info, Token>,
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+    token::transfer(
+        CpiContext::new(
+            token_program.to_account_info(),
+            token::Transfer {
+                from: from.to_account_info(),
+                to: to.to_account_info(),
+                authority: user.to_account_info(),
             },
-        )
-        .with_signer(&vault_authority_seeds);
-        vault_core::cpi::deposit(ctx_deposit, vault_deposit_amount)?;
+        ),
+        amount,
+    )
+}
 
-        Ok(())
+/// Shares of the vault-core position at `info`, owned by `vault_program`; zero until its
+/// first deposit creates it
+fn position_shares(info: &AccountInfo, vault_program: &Pubkey) -> Result<u128> {
+    if info.data_is_empty() {
+        return Ok(0);
     }
+    require_keys_eq!(*info.owner, *vault_program, ErrorCode::AccountOwnedByWrongProgram);
+    let position = vault_core::UserPosition::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+    Ok(position.shares)
+}
+
+/// Shares the deposit credited the position; fails the route below `min_shares_out`
+fn check_shares_out(
+    position: &AccountInfo,
+    vault_program: &Pubkey,
+    shares_before: u128,
+    min_shares_out: u64,
+) -> Result<u128> {
+    let credited = position_shares(position, vault_program)?.saturating_sub(shares_before);
+    require!(
+        credited >= min_shares_out as u128,
+        RouterError::SharesBelowMinimum
+    );
+    Ok(credited)
+}
+
+/// Reject routes submitted after `deadline` (unix time), i.e. on stale quotes
+fn check_deadline(deadline: i64) -> Result<()> {
+    require!(
+        Clock::get()?.unix_timestamp <= deadline,
+        RouterError::DeadlineExceeded
+    );
+    Ok(())
+}
+
+/// Emit `RouteValidationFailed` and return `error` unless `ok`
+fn check_route(
+    ok: bool,
+    check: RouteCheck,
+    account_index: Option<u8>,
+    expected: Pubkey,
+    actual: Pubkey,
+    error: RouterError,
+) -> Result<()> {
+    if !ok {
+        emit!(RouteValidationFailed {
+            check,
+            account_index,
+            expected,
+            actual,
+        });
+        return Err(error.into());
+    }
+    Ok(())
+}
+
+#[account]
+pub struct RouterState {
+    pub authority: Pubkey,
+    pub paused: bool,
+    pub vault_programs: Vec<Pubkey>,
+    /// Router fee, in bps of the swap output of `deposit_swap_stake` and
+    /// `batch_swap_stake`, the routes that take it
+    pub fee_bps: u16,
+    /// Share of the router fee credited to the route's referrer, in bps of the fee
+    pub referral_share_bps: u16,
+    pub bump: u8,
+}
+
+impl RouterState {
+    pub const MAX_VAULT_PROGRAMS: usize = 10;
+    pub const MAX_FEE_BPS: u16 = 100;
+    pub const LEN: usize = 8 + // discriminator
+        32 + // authority
+        1 + // paused
+        4 + (32 * Self::MAX_VAULT_PROGRAMS) + // Vec<Pubkey> max size
+        2 + // fee_bps
+        2 + // referral_share_bps
+        1; // bump
+}
+
+/// A referrer's routed volume and claimable fee share in one mint
+#[account]
+pub struct Referral {
+    pub referrer: Pubkey,
+    pub mint: Pubkey,
+    /// Output, before fees, of the routes that named this referral
+    pub routed_volume: u128,
+    /// Fee share held for the referrer in the mint's referral vault
+    pub claimable: u64,
+    pub bump: u8,
+}
+
+impl Referral {
+    pub const LEN: usize = 8 + 32 + 32 + 16 + 8 + 1;
+}
+
+#[derive(Accounts)]
+pub struct InitializeRouterState<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = RouterState::LEN,
+        seeds = [b"router_state"],
+        bump
+    )]
+    pub router_state: Account<'info, RouterState>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateRouterState<'info> {
+    #[account(
+        mut,
+        seeds = [b"router_state"],
+        bump = router_state.bump,
+        has_one = authority @ RouterError::Unauthorized
+    )]
+    pub router_state: Account<'info, RouterState>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"router_state"],
+        bump = router_state.bump,
+        has_one = authority @ RouterError::Unauthorized
+    )]
+    pub router_state: Account<'info, RouterState>,
+
+    pub authority: Signer<'info>,
+
+    /// CHECK: vault-core's protocol bounds PDA, may be uninitialized (see
+    /// `vault_core::ProtocolConfig::load`)
+    #[account(seeds = [b"protocol_config"], bump, seeds::program = VaultCore::id())]
+    pub protocol_config: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterReferral<'info> {
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+
+    pub mint: Account<'info, Mint>,
+
+    #[account(
+        init,
+        payer = referrer,
+        space = Referral::LEN,
+        seeds = [b"referral", referrer.key().as_ref(), mint.key().as_ref()],
+        bump
+    )]
+    pub referral: Account<'info, Referral>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralFees<'info> {
+    pub referrer: Signer<'info>,
+
+    #[account(seeds = [b"router_state"], bump = router_state.bump)]
+    pub router_state: Account<'info, RouterState>,
+
+    #[account(
+        mut,
+        seeds = [b"referral", referrer.key().as_ref(), referral.mint.as_ref()],
+        bump = referral.bump,
+        has_one = referrer @ RouterError::Unauthorized
+    )]
+    pub referral: Account<'info, Referral>,
+
+    #[account(
+        mut,
+        constraint = referral_vault.owner == router_state.key() @ RouterError::InvalidFeeAccount,
+        constraint = referral_vault.mint == referral.mint @ RouterError::InvalidMint
+    )]
+    pub referral_vault: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = referrer_token_account.mint == referral.mint @ RouterError::InvalidMint
+    )]
+    pub referrer_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
@@ -195,16 +2230,337 @@ pub struct DepositSwapStake<'info> {
     #[account(mut)]
     pub user: Signer<'info>,
 
+    #[account(seeds = [b"router_state"], bump = router_state.bump)]
+    pub router_state: Account<'info, RouterState>,
+
+    #[account(mut)]
+    pub input_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub output_token_account: Account<'info, TokenAccount>,
+
+    pub amm_program: Program<'info, dex::Dex>,
+    /// CHECK: vault-core or another deployment of it, allow-listed in `router_state`
+    #[account(executable)]
+    pub vault_program: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        mut,
+        constraint = fee_token_account.owner == router_state.authority @ RouterError::InvalidFeeAccount
+    )]
+    pub fee_token_account: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub referral: Option<Account<'info, Referral>>,
+
+    #[account(
+        mut,
+        constraint = referral_vault.owner == router_state.key() @ RouterError::InvalidFeeAccount
+    )]
+    pub referral_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct DepositSolSwapStake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(seeds = [b"router_state"], bump = router_state.bump)]
+    pub router_state: Account<'info, RouterState>,
+
+    /// CHECK: Validated against the user's WSOL ATA and created by the ATA program
+    #[account(mut)]
+    pub wsol_token_account: UncheckedAccount<'info>,
+
+    #[account(address = spl_token::native_mint::ID)]
+    pub native_mint: Account<'info, Mint>,
+
+    #[account(mut)]
+    pub output_token_account: Account<'info, TokenAccount>,
+
+    pub amm_program: Program<'info, dex::Dex>,
+    /// CHECK: vault-core or another deployment of it, allow-listed in `router_state`
+    #[account(executable)]
+    pub vault_program: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        mut,
+        constraint = fee_token_account.owner == router_state.authority @ RouterError::InvalidFeeAccount
+    )]
+    pub fee_token_account: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub referral: Option<Account<'info, Referral>>,
+
+    #[account(
+        mut,
+        constraint = referral_vault.owner == router_state.key() @ RouterError::InvalidFeeAccount
+    )]
+    pub referral_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct BatchSwapStake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(seeds = [b"router_state"], bump = router_state.bump)]
+    pub router_state: Account<'info, RouterState>,
+
     #[account(mut)]
     pub input_token_account: Account<'info, TokenAccount>,
 
     #[account(mut)]
     pub output_token_account: Account<'info, TokenAccount>,
 
+    pub amm_program: Program<'info, dex::Dex>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        mut,
+        constraint = fee_token_account.owner == router_state.authority @ RouterError::InvalidFeeAccount
+    )]
+    pub fee_token_account: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub referral: Option<Account<'info, Referral>>,
+
+    #[account(
+        mut,
+        constraint = referral_vault.owner == router_state.key() @ RouterError::InvalidFeeAccount
+    )]
+    pub referral_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(intent: RouteIntent)]
+pub struct ExecuteIntent<'info> {
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    #[account(seeds = [b"router_state"], bump = router_state.bump)]
+    pub router_state: Account<'info, RouterState>,
+
+    #[account(
+        init,
+        payer = keeper,
+        space = IntentReceipt::LEN,
+        seeds = [b"intent", intent.user.as_ref(), &intent.nonce.to_le_bytes()],
+        bump
+    )]
+    pub intent_receipt: Account<'info, IntentReceipt>,
+
+    /// CHECK: PDA approved as delegate on input_token_account; signs the swap
+    #[account(seeds = [INTENT_AUTHORITY_SEED], bump)]
+    pub intent_authority: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub input_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub output_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: instructions sysvar, address-checked; read by `verify_intent_signature`
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions_sysvar: UncheckedAccount<'info>,
+
+    pub amm_program: Program<'info, dex::Dex>,
+    /// CHECK: must be intent.vault_program, allow-listed in `router_state`
+    #[account(executable)]
+    pub vault_program: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawSwapReturn<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(seeds = [b"router_state"], bump = router_state.bump)]
+    pub router_state: Account<'info, RouterState>,
+
+    #[account(mut)]
+    pub staked_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub return_token_account: Account<'info, TokenAccount>,
+
+    pub amm_program: Program<'info, dex::Dex>,
+    /// CHECK: vault-core or another deployment of it, allow-listed in `router_state`
+    #[account(executable)]
+    pub vault_program: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimSwapRestake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(seeds = [b"router_state"], bump = router_state.bump)]
+    pub router_state: Account<'info, RouterState>,
+
+    #[account(mut)]
+    pub reward_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub stake_token_account: Account<'info, TokenAccount>,
+
+    pub amm_program: Program<'info, dex::Dex>,
+    /// CHECK: vault-core or another deployment of it, allow-listed in `router_state`
+    #[account(executable)]
+    pub vault_program: UncheckedAccount<'info>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        mut,
+        constraint = fee_token_account.owner == router_state.authority @ RouterError::InvalidFeeAccount
+    )]
+    pub fee_token_account: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub referral: Option<Account<'info, Referral>>,
+
+    #[account(
+        mut,
+        constraint = referral_vault.owner == router_state.key() @ RouterError::InvalidFeeAccount
+    )]
+    pub referral_vault: Option<Account<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+pub struct ZapLpAndStake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(seeds = [b"router_state"], bump = router_state.bump)]
+    pub router_state: Account<'info, RouterState>,
+
+    #[account(mut)]
+    pub input_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub other_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub lp_token_account: Account<'info, TokenAccount>,
+
     pub amm_program: Program<'info, MockAmm>,
-    pub vault_program: Program<'info, VaultCore>,
+    /// CHECK: vault-core or another deployment of it, allow-listed in `router_state`
+    #[account(executable)]
+    pub vault_program: UncheckedAccount<'info>,
     pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
+
+    #[account(
+        mut,
+        constraint = fee_token_account.owner == router_state.authority @ RouterError::InvalidFeeAccount
+    )]
+    pub fee_token_account: Option<Account<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub referral: Option<Account<'info, Referral>>,
+
+    #[account(
+        mut,
+        constraint = referral_vault.owner == router_state.key() @ RouterError::InvalidFeeAccount
+    )]
+    pub referral_vault: Option<Account<'info, TokenAccount>>,
+}
+
+/// A Deposit → Swap → Stake route the user signed off-chain for `execute_intent`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RouteIntent {
+    /// Signer; owns both token accounts and the position
+    pub user: Pubkey,
+    pub input_mint: Pubkey,
+    pub output_mint: Pubkey,
+    /// vault-core deployment to deposit into
+    pub vault_program: Pubkey,
+    pub amount_in: u64,
+    pub min_amount_out: u64,
+    pub min_shares_out: u64,
+    pub fee_tier: u16,
+    /// Unix time after which the intent can no longer execute
+    pub expiry: i64,
+    /// Unique among the user's intents; each nonce executes at most once
+    pub nonce: u64,
+}
+
+/// Marks a user's intent nonce as executed
+#[account]
+pub struct IntentReceipt {
+    pub executed_at: i64,
+}
+
+impl IntentReceipt {
+    pub const LEN: usize = 8 + 8;
+}
+
+/// One `batch_swap_stake` leg
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    /// Share of the swap output, in basis points
+    pub bps: u16,
+    pub min_shares_out: u64,
+}
+
+/// What a route executed, set as the instruction's return data so clients read it
+/// from the simulation or confirmed transaction
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RouteResult {
+    /// Tokens the swap leg paid out to the user, net of the router fee; for a
+    /// `batch_swap_stake` leg, its share
+    pub amount_out: u64,
+    /// Vault shares credited to the user's position; 0 for `withdraw_swap_return`
+    pub shares_minted: u128,
+}
+
+/// Which route validation rejected the transaction
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteCheck {
+    InputTokenOwner,
+    OutputTokenOwner,
+    InputMint,
+    OutputMint,
+    RemainingAccountsLength,
+    SwapUserTokenIn,
+    SwapUserTokenOut,
+    PoolAuthority,
+    VaultAuthority,
+    VaultUserTokenAccount,
+    ClaimRewardTokenAccount,
+    RestakeVault,
+    LpTokenOwner,
+    LiquidityUserToken,
+    LiquidityPool,
+    WsolTokenAccount,
+    IntentAuthority,
+    IntentUser,
+    IntentPayer,
+    BatchPayer,
+    PoolMint,
+    PoolVault,
+}
+
+/// Logged just before a validation error so integrators can see the failing check
+/// in the transaction logs. `account_index` indexes `remaining_accounts` (`None` for
+/// fixed accounts/arguments; the provided count for `RemainingAccountsLength`).
+#[event]
+pub struct RouteValidationFailed {
+    pub check: RouteCheck,
+    pub account_index: Option<u8>,
+    pub expected: Pubkey,
+    pub actual: Pubkey,
 }
 
 #[error_code]
@@ -227,4 +2583,40 @@ pub enum RouterError {
     InvalidPoolAuthority,
     #[msg("Invalid vault authority")]
     InvalidVaultAuthority,
+    #[msg("Insufficient accounts provided")]
+    InsufficientAccounts,
+    #[msg("Deposit vault differs from the claim vault")]
+    InvalidVault,
+    #[msg("Vault credited fewer shares than min_shares_out")]
+    SharesBelowMinimum,
+    #[msg("Route deadline has passed")]
+    DeadlineExceeded,
+    #[msg("Liquidity pool differs from the swap pool")]
+    InvalidPool,
+    #[msg("Too many vault programs")]
+    TooManyPrograms,
+    #[msg("Unauthorized")]
+    Unauthorized,
+    #[msg("Router is paused")]
+    RouterPaused,
+    #[msg("Vault program not in allowlist")]
+    VaultProgramNotAllowlisted,
+    #[msg("Missing or invalid ed25519 signature of the intent")]
+    InvalidIntentSignature,
+    #[msg("Input account hasn't approved the intent authority for amount_in")]
+    DelegateNotApproved,
+    #[msg("Allocations must be non-zero and sum to 10000 bps")]
+    InvalidAllocation,
+    #[msg("Fee above the maximum")]
+    InvalidFee,
+    #[msg("Fee account not owned by the router authority or router")]
+    InvalidFeeAccount,
+    #[msg("Fee account required for the router fee or referral")]
+    FeeAccountRequired,
+    #[msg("Route can't refer its own user")]
+    InvalidReferrer,
+    #[msg("Fee exceeds the protocol bound")]
+    ExceedsProtocolBound,
+    #[msg("Math overflow")]
+    MathOverflow,
 }
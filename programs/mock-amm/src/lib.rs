@@ -1,19 +1,94 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{Mint, Token, TokenAccount, Transfer};
+use anchor_lang::solana_program::instruction::Instruction;
+use anchor_lang::solana_program::program::invoke;
+use anchor_spl::associated_token::AssociatedToken;
+use anchor_spl::metadata::mpl_token_metadata::types::DataV2;
+use anchor_spl::metadata::{self, CreateMetadataAccountsV3, Metadata};
+use anchor_spl::token::Token;
+use anchor_spl::token_2022::spl_token_2022;
+use anchor_spl::token_interface::{
+    self, Burn, Mint, MintTo, TokenAccount, TokenInterface, TransferChecked,
+};
+use vault_core::program::VaultCore;
 
 declare_id!("8TN4YaBrKm5WZAcFTxzEBTA1i8AXxwnnYWTFxYF5PsSU");
 
+/// Anchor discriminator of the borrower's
+/// `on_flash_swap(amount_a_out: u64, amount_b_out: u64, initiator: Pubkey, data: Vec<u8>)`
+/// callback: first 8 bytes of sha256("global:on_flash_swap")
+pub const ON_FLASH_SWAP: [u8; 8] = [231, 227, 105, 50, 75, 86, 102, 148];
+
+/// Client-side address derivation. A pair has a single canonical order, `mint_a` <
+/// `mint_b` by bytes, which `initialize_pool` enforces; these helpers take the mints
+/// in either order.
+pub mod pda {
+    use super::*;
+
+    /// `(mint_a, mint_b)` in the canonical pool order
+    pub fn sorted_mints(mint_x: Pubkey, mint_y: Pubkey) -> (Pubkey, Pubkey) {
+        if mint_x <= mint_y {
+            (mint_x, mint_y)
+        } else {
+            (mint_y, mint_x)
+        }
+    }
+
+    pub fn pool(mint_x: &Pubkey, mint_y: &Pubkey, fee_tier: u16) -> (Pubkey, u8) {
+        derive(mint_x, mint_y, fee_tier, &[])
+    }
+
+    pub fn pool_authority(mint_x: &Pubkey, mint_y: &Pubkey, fee_tier: u16) -> (Pubkey, u8) {
+        derive(mint_x, mint_y, fee_tier, b"authority")
+    }
+
+    pub fn lp_mint(mint_x: &Pubkey, mint_y: &Pubkey, fee_tier: u16) -> (Pubkey, u8) {
+        derive(mint_x, mint_y, fee_tier, b"lp_mint")
+    }
+
+    /// Token account holding the pool's permanently locked LP
+    pub fn locked_lp(mint_x: &Pubkey, mint_y: &Pubkey, fee_tier: u16) -> (Pubkey, u8) {
+        derive(mint_x, mint_y, fee_tier, b"locked_lp")
+    }
+
+    fn derive(mint_x: &Pubkey, mint_y: &Pubkey, fee_tier: u16, suffix: &[u8]) -> (Pubkey, u8) {
+        let (mint_a, mint_b) = sorted_mints(*mint_x, *mint_y);
+        let fee_tier = fee_tier.to_le_bytes();
+        let mut seeds: Vec<&[u8]> = vec![b"pool", mint_a.as_ref(), mint_b.as_ref(), &fee_tier];
+        if !suffix.is_empty() {
+            seeds.push(suffix);
+        }
+        Pubkey::find_program_address(&seeds, &crate::ID)
+    }
+}
+
 #[program]
 pub mod mock_amm {
     use super::*;
 
-    pub fn initialize_pool(
-        ctx: Context<InitializePool>,
+    /// Permissionless: anyone may create a pool for a pair and fee tier, paying the
+    /// creation fee set in the AMM config (if any), and becomes its authority.
+    /// Remaining accounts: transfer-hook accounts, see `transfer_hooked_tokens`.
+    pub fn initialize_pool<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, InitializePool<'info>>,
         initial_amount_a: u64,
         initial_amount_b: u64,
+        curve: CurveType,
+        fee_bps: u16,
     ) -> Result<()> {
         require!(initial_amount_a > 0, AmmError::InvalidAmount);
         require!(initial_amount_b > 0, AmmError::InvalidAmount);
+        require!(fee_bps <= Pool::MAX_FEE_BPS, AmmError::InvalidFee);
+        // One pool address per pair and fee tier, see `pda::sorted_mints`
+        require!(
+            ctx.accounts.mint_a.key() < ctx.accounts.mint_b.key(),
+            AmmError::UnsortedMints
+        );
+        if let CurveType::Stable { amp } = curve {
+            require!(
+                (1..=CurveType::MAX_AMP).contains(&amp),
+                AmmError::InvalidAmp
+            );
+        }
 
         // Verify vault token accounts match mints
         require!(
@@ -40,35 +115,876 @@ pub mod mock_amm {
         pool.vault_a = ctx.accounts.vault_a.key();
         pool.vault_b = ctx.accounts.vault_b.key();
         pool.authority = ctx.accounts.authority.key();
+        pool.curve = curve;
+        pool.lp_mint = ctx.accounts.lp_mint.key();
+        pool.fee_bps = fee_bps;
+        pool.fee_tier = fee_bps;
 
         // Transfer initial liquidity from authority
-        // Transfer token A
-        let cpi_accounts_a = Transfer {
-            from: ctx.accounts.authority_token_account_a.to_account_info(),
-            to: ctx.accounts.vault_a.to_account_info(),
-            authority: ctx.accounts.authority.to_account_info(),
+        let (before_a, before_b) = (ctx.accounts.vault_a.amount, ctx.accounts.vault_b.amount);
+        transfer_hooked_tokens(
+            &ctx.accounts.authority_token_account_a.to_account_info(),
+            &ctx.accounts.vault_a.to_account_info(),
+            &ctx.accounts.authority.to_account_info(),
+            &ctx.accounts.mint_a,
+            &ctx.accounts.token_program_a,
+            ctx.remaining_accounts,
+            &[],
+            initial_amount_a,
+        )?;
+        transfer_hooked_tokens(
+            &ctx.accounts.authority_token_account_b.to_account_info(),
+            &ctx.accounts.vault_b.to_account_info(),
+            &ctx.accounts.authority.to_account_info(),
+            &ctx.accounts.mint_b,
+            &ctx.accounts.token_program_b,
+            ctx.remaining_accounts,
+            &[],
+            initial_amount_b,
+        )?;
+        let received_a = balance_increase(&mut ctx.accounts.vault_a, before_a)?;
+        let received_b = balance_increase(&mut ctx.accounts.vault_b, before_b)?;
+        require!(received_a > 0 && received_b > 0, AmmError::InvalidAmount);
+        ctx.accounts.pool.reserve_a = received_a;
+        ctx.accounts.pool.reserve_b = received_b;
+        ctx.accounts.pool.start_price_oracle()?;
+
+        pay_creation_fee(ctx.accounts)?;
+        create_lp_metadata(ctx.accounts, ctx.bumps.pool_authority)?;
+
+        let entry = PoolEntry {
+            pool: ctx.accounts.pool.key(),
+            mint_a: ctx.accounts.mint_a.key(),
+            mint_b: ctx.accounts.mint_b.key(),
+            fee_tier: fee_bps,
+        };
+        let registry = &mut ctx.accounts.pool_registry;
+        registry.pools.push(entry);
+        let new_len = PoolRegistry::space(registry.pools.len());
+        grow_account(
+            &registry.to_account_info(),
+            &ctx.accounts.authority.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            new_len,
+        )?;
+
+        // The seed liquidity is the first LP position: sqrt(a * b) LP tokens, counting
+        // only what reached the vaults after any Token-2022 transfer fee
+        let initial_lp = integer_sqrt(received_a as u128 * received_b as u128) as u64;
+        require!(
+            initial_lp > Pool::MINIMUM_LIQUIDITY,
+            AmmError::InsufficientLiquidity
+        );
+        // As in Uniswap v2, the first MINIMUM_LIQUIDITY LP tokens are locked for good so
+        // the supply can never be inflated from near zero against later depositors
+        mint_lp(
+            &ctx.accounts.pool,
+            &ctx.accounts.lp_mint,
+            &ctx.accounts.locked_lp_token_account,
+            &ctx.accounts.pool_authority,
+            &ctx.accounts.token_program,
+            ctx.bumps.pool_authority,
+            Pool::MINIMUM_LIQUIDITY,
+        )?;
+        mint_lp(
+            &ctx.accounts.pool,
+            &ctx.accounts.lp_mint,
+            &ctx.accounts.authority_lp_token_account,
+            &ctx.accounts.pool_authority,
+            &ctx.accounts.token_program,
+            ctx.bumps.pool_authority,
+            initial_lp - Pool::MINIMUM_LIQUIDITY,
+        )?;
+
+        Ok(())
+    }
+
+    /// Create the AMM-wide config PDA. Only mock-amm's upgrade authority may create it;
+    /// until it exists pool creation is free (see `AmmConfig::load`).
+    pub fn initialize_config(ctx: Context<InitializeConfig>, admin: Pubkey) -> Result<()> {
+        ctx.accounts.config.set_inner(AmmConfig {
+            admin,
+            bump: ctx.bumps.config,
+            ..AmmConfig::default()
+        });
+        Ok(())
+    }
+
+    /// Set what `initialize_pool` charges: `creation_fee` lamports, or base units of
+    /// `creation_fee_mint` when it isn't the default key, paid to `treasury`. Admin only.
+    pub fn set_creation_fee(
+        ctx: Context<SetCreationFee>,
+        creation_fee: u64,
+        creation_fee_mint: Pubkey,
+        treasury: Pubkey,
+    ) -> Result<()> {
+        require!(
+            creation_fee == 0 || treasury != Pubkey::default(),
+            AmmError::InvalidTreasury
+        );
+        let config = &mut ctx.accounts.config;
+        config.creation_fee = creation_fee;
+        config.creation_fee_mint = creation_fee_mint;
+        config.treasury = treasury;
+        Ok(())
+    }
+
+    /// Change the swap fee. Pool authority only.
+    pub fn set_fee(ctx: Context<UpdatePool>, fee_bps: u16) -> Result<()> {
+        require!(fee_bps <= Pool::MAX_FEE_BPS, AmmError::InvalidFee);
+        ctx.accounts.pool.fee_bps = fee_bps;
+        Ok(())
+    }
+
+    /// Set the protocol's share of each swap fee (bps of the fee). Pool authority only.
+    pub fn set_protocol_fee_share(
+        ctx: Context<UpdatePool>,
+        protocol_fee_share_bps: u16,
+    ) -> Result<()> {
+        require!(
+            protocol_fee_share_bps as u32 + ctx.accounts.pool.fee_rebate_share_bps as u32
+                <= Pool::MAX_PROTOCOL_FEE_SHARE_BPS as u32,
+            AmmError::InvalidFee
+        );
+        ctx.accounts.pool.protocol_fee_share_bps = protocol_fee_share_bps;
+        Ok(())
+    }
+
+    /// Route `fee_rebate_share_bps` of the swap fee (bps of the fee) to the stakers of
+    /// `fee_rebate_vault`, a vault-core vault staking this pool's LP token and paying
+    /// rewards in one of the pool's tokens. Only swaps paying in that token accrue a
+    /// rebate; `forward_fees` sends it over. Counts against the same cap as the
+    /// protocol's share. Rebates not yet forwarded to a previous vault fall to the
+    /// protocol. Pool authority only.
+    pub fn set_fee_rebate(ctx: Context<SetFeeRebate>, fee_rebate_share_bps: u16) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        require!(
+            pool.protocol_fee_share_bps as u32 + fee_rebate_share_bps as u32
+                <= Pool::MAX_PROTOCOL_FEE_SHARE_BPS as u32,
+            AmmError::InvalidFee
+        );
+        let vault = ctx.accounts.fee_rebate_vault.load()?;
+        require_keys_eq!(vault.token_mint, pool.lp_mint, AmmError::InvalidRebateVault);
+        require!(
+            vault.reward_mint == pool.mint_a || vault.reward_mint == pool.mint_b,
+            AmmError::InvalidRebateVault
+        );
+
+        let unforwarded = std::mem::take(&mut pool.fee_rebates);
+        if unforwarded > 0 {
+            let protocol_fees = if pool.fee_rebate_mint == pool.mint_a {
+                &mut pool.protocol_fees_a
+            } else {
+                &mut pool.protocol_fees_b
+            };
+            *protocol_fees = protocol_fees
+                .checked_add(unforwarded)
+                .ok_or(AmmError::MathOverflow)?;
+        }
+        pool.fee_rebate_vault = ctx.accounts.fee_rebate_vault.key();
+        pool.fee_rebate_mint = vault.reward_mint;
+        pool.fee_rebate_share_bps = fee_rebate_share_bps;
+        Ok(())
+    }
+
+    /// Permissionless crank: fund the rebate vault's rewards with the fee rebates
+    /// accrued since the last call, through vault-core's `fund_rewards`. Its stakers
+    /// earn them at the vault's reward rate.
+    pub fn forward_fees(ctx: Context<ForwardFees>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        require!(!pool.locked, AmmError::PoolLocked);
+        let amount = pool.fee_rebates;
+        require!(amount > 0, AmmError::InvalidAmount);
+        pool.fee_rebates = 0;
+
+        let accounts = &ctx.accounts;
+        let pool = &accounts.pool;
+        let seeds = &[
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority",
+            &[ctx.bumps.pool_authority],
+        ];
+        let signer = &[&seeds[..]];
+        vault_core::cpi::fund_rewards(
+            CpiContext::new_with_signer(
+                accounts.vault_core_program.to_account_info(),
+                vault_core::cpi::accounts::FundRewards {
+                    vault: accounts.fee_rebate_vault.to_account_info(),
+                    funder: accounts.pool_authority.to_account_info(),
+                    funder_token_account: accounts.pool_vault.to_account_info(),
+                    reward_vault: accounts.reward_vault.to_account_info(),
+                    reward_mint: accounts.reward_mint.to_account_info(),
+                    reward_token_program: accounts.reward_token_program.to_account_info(),
+                    protocol_config: accounts.protocol_config.to_account_info(),
+                },
+                signer,
+            ),
+            amount,
+            0,
+        )?;
+
+        emit!(FeesForwarded {
+            pool: pool.key(),
+            vault: pool.fee_rebate_vault,
+            amount,
+        });
+        Ok(())
+    }
+
+    /// Pay the accrued protocol fees out to the pool authority's token accounts
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let (amount_a, amount_b) = (pool.protocol_fees_a, pool.protocol_fees_b);
+        pool.protocol_fees_a = 0;
+        pool.protocol_fees_b = 0;
+
+        let accounts = &ctx.accounts;
+        let pool = &accounts.pool;
+        let seeds = &[
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority",
+            &[ctx.bumps.pool_authority],
+        ];
+        let signer = &[&seeds[..]];
+        for (from, to, mint, token_program, amount) in [
+            (
+                &accounts.vault_a,
+                &accounts.fee_token_account_a,
+                &accounts.mint_a,
+                &accounts.token_program_a,
+                amount_a,
+            ),
+            (
+                &accounts.vault_b,
+                &accounts.fee_token_account_b,
+                &accounts.mint_b,
+                &accounts.token_program_b,
+                amount_b,
+            ),
+        ] {
+            if amount == 0 {
+                continue;
+            }
+            transfer_tokens(
+                &from.to_account_info(),
+                &to.to_account_info(),
+                &accounts.pool_authority.to_account_info(),
+                mint,
+                token_program,
+                signer,
+                amount,
+            )?;
+        }
+
+        emit!(ProtocolFeesCollected {
+            pool: pool.key(),
+            amount_a,
+            amount_b,
+        });
+        Ok(())
+    }
+
+    /// Deposit both tokens, in any proportion, for LP tokens. The part matching the
+    /// pool ratio mints LP pro rata. The excess on the other side is treated as if
+    /// half of it were swapped to rebalance, as Curve does: the swap fee is charged on
+    /// that half, and the rest mints LP by how much it grows the curve's liquidity.
+    /// Amounts are what reaches the vaults, so Token-2022 transfer fees are excluded.
+    /// Remaining accounts: transfer-hook accounts, see `transfer_hooked_tokens`.
+    pub fn add_liquidity<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, AddLiquidity<'info>>,
+        amount_a: u64,
+        amount_b: u64,
+        min_lp_out: u64,
+    ) -> Result<()> {
+        require!(amount_a > 0 && amount_b > 0, AmmError::InvalidAmount);
+        require!(!ctx.accounts.pool.paused, AmmError::PoolPaused);
+        require!(!ctx.accounts.pool.locked, AmmError::PoolLocked);
+
+        let pool = &ctx.accounts.pool;
+        let (reserve_a, reserve_b) = (pool.reserve_a as u128, pool.reserve_b as u128);
+        let lp_supply = ctx.accounts.lp_mint.supply as u128;
+        require!(
+            reserve_a > 0 && reserve_b > 0 && lp_supply > 0,
+            AmmError::InsufficientLiquidity
+        );
+
+        let (before_a, before_b) = (ctx.accounts.vault_a.amount, ctx.accounts.vault_b.amount);
+        let accounts = &ctx.accounts;
+        for (from, to, mint, token_program, amount) in [
+            (
+                &accounts.user_token_a,
+                &accounts.vault_a,
+                &accounts.mint_a,
+                &accounts.token_program_a,
+                amount_a,
+            ),
+            (
+                &accounts.user_token_b,
+                &accounts.vault_b,
+                &accounts.mint_b,
+                &accounts.token_program_b,
+                amount_b,
+            ),
+        ] {
+            transfer_hooked_tokens(
+                &from.to_account_info(),
+                &to.to_account_info(),
+                &accounts.user.to_account_info(),
+                mint,
+                token_program,
+                ctx.remaining_accounts,
+                &[],
+                amount,
+            )?;
+        }
+        let received_a = balance_increase(&mut ctx.accounts.vault_a, before_a)?;
+        let received_b = balance_increase(&mut ctx.accounts.vault_b, before_b)?;
+
+        // Split off the balanced part: the side with the smaller share of its reserve
+        // binds, the other side's surplus is the excess
+        let (received_a, received_b) = (received_a as u128, received_b as u128);
+        let excess_is_a = received_a * reserve_b > received_b * reserve_a;
+        let (balanced_lp, balanced_a, balanced_b) = if excess_is_a {
+            let lp = received_b * lp_supply / reserve_b;
+            (lp, received_b * reserve_a / reserve_b, received_b)
+        } else {
+            let lp = received_a * lp_supply / reserve_a;
+            (lp, received_a, received_a * reserve_b / reserve_a)
+        };
+        let excess = if excess_is_a {
+            received_a - balanced_a
+        } else {
+            received_b - balanced_b
+        } as u64;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.accumulate_price()?;
+        let mut lp_out = balanced_lp;
+        let mut protocol_fee = 0;
+        if excess > 0 {
+            let fee = swap_fee(&pool.curve, pool.fee_bps, excess / 2);
+            protocol_fee = pool.accrue_protocol_fee(excess_is_a, excess / 2)?;
+            let to_u64 = |v: u128| u64::try_from(v).map_err(|_| AmmError::MathOverflow);
+            let (mid_a, mid_b) = (
+                to_u64(reserve_a + balanced_a)?,
+                to_u64(reserve_b + balanced_b)?,
+            );
+            let size_before = liquidity_size(&pool.curve, mid_a, mid_b)?;
+            let size_after = if excess_is_a {
+                liquidity_size(&pool.curve, mid_a + (excess - fee), mid_b)?
+            } else {
+                liquidity_size(&pool.curve, mid_a, mid_b + (excess - fee))?
+            };
+            lp_out += (lp_supply + balanced_lp)
+                .checked_mul(size_after - size_before)
+                .ok_or(AmmError::MathOverflow)?
+                / size_before;
+        }
+        let lp_out = u64::try_from(lp_out).map_err(|_| AmmError::MathOverflow)?;
+        require!(lp_out > 0, AmmError::InvalidAmount);
+        require!(lp_out >= min_lp_out, AmmError::SlippageExceeded);
+
+        // Everything received joins the reserves except the protocol's fee share
+        let (fee_a, fee_b) = if excess_is_a {
+            (protocol_fee, 0)
+        } else {
+            (0, protocol_fee)
+        };
+        pool.reserve_a = pool
+            .reserve_a
+            .checked_add(received_a as u64 - fee_a)
+            .ok_or(AmmError::MathOverflow)?;
+        pool.reserve_b = pool
+            .reserve_b
+            .checked_add(received_b as u64 - fee_b)
+            .ok_or(AmmError::MathOverflow)?;
+
+        mint_lp(
+            &ctx.accounts.pool,
+            &ctx.accounts.lp_mint,
+            &ctx.accounts.user_lp_token_account,
+            &ctx.accounts.pool_authority,
+            &ctx.accounts.token_program,
+            ctx.bumps.pool_authority,
+            lp_out,
+        )
+    }
+
+    /// Zap: deposit `amount_in` of one pool token (`token_in` is its mint) for LP
+    /// tokens. Part of it is swapped inside the pool, at the usual fees, so that the
+    /// rest matches the pool ratio (see `zap_swap_amount`); whatever rounding leaves
+    /// unmatched stays with the LPs.
+    pub fn add_liquidity_single(
+        ctx: Context<AddLiquiditySingle>,
+        token_in: Pubkey,
+        amount_in: u64,
+        min_lp_out: u64,
+    ) -> Result<()> {
+        require!(amount_in > 0, AmmError::InvalidAmount);
+        let pool = &ctx.accounts.pool;
+        require!(!pool.paused, AmmError::PoolPaused);
+        require!(!pool.locked, AmmError::PoolLocked);
+        let a_to_b = token_in == pool.mint_a;
+        require!(a_to_b || token_in == pool.mint_b, AmmError::InvalidMint);
+        let lp_supply = ctx.accounts.lp_mint.supply as u128;
+        require!(
+            pool.reserve_a > 0 && pool.reserve_b > 0 && lp_supply > 0,
+            AmmError::InsufficientLiquidity
+        );
+
+        let vault_in = if a_to_b {
+            &mut ctx.accounts.vault_a
+        } else {
+            &mut ctx.accounts.vault_b
+        };
+        let vault_in_before = vault_in.amount;
+        transfer_tokens(
+            &ctx.accounts.user_token_in.to_account_info(),
+            &vault_in.to_account_info(),
+            &ctx.accounts.user.to_account_info(),
+            &ctx.accounts.mint_in,
+            &ctx.accounts.token_program_in,
+            &[],
+            amount_in,
+        )?;
+        let received = balance_increase(vault_in, vault_in_before)?;
+
+        // The swap leg is only booked: both of its sides already sit in the vaults
+        let pool = &mut ctx.accounts.pool;
+        let (reserve_in, reserve_out) = if a_to_b {
+            (pool.reserve_a, pool.reserve_b)
+        } else {
+            (pool.reserve_b, pool.reserve_a)
+        };
+        let swap_in = zap_swap_amount(&pool.curve, pool.fee_bps, received, reserve_in);
+        let swap_out =
+            calculate_amount_out(&pool.curve, pool.fee_bps, swap_in, reserve_in, reserve_out)?;
+        let protocol_fee = pool.accrue_protocol_fee(a_to_b, swap_in)?;
+        let swapped_in = reserve_in
+            .checked_add(swap_in - protocol_fee)
+            .ok_or(AmmError::MathOverflow)?;
+        let swapped_out = reserve_out
+            .checked_sub(swap_out)
+            .filter(|reserve| *reserve > 0)
+            .ok_or(AmmError::InsufficientLiquidity)?;
+
+        let lp_out = ((received - swap_in) as u128 * lp_supply / swapped_in as u128)
+            .min(swap_out as u128 * lp_supply / swapped_out as u128);
+        let lp_out = u64::try_from(lp_out).map_err(|_| AmmError::MathOverflow)?;
+        require!(lp_out > 0, AmmError::InvalidAmount);
+        require!(lp_out >= min_lp_out, AmmError::SlippageExceeded);
+
+        // Net of both legs, the deposit less the protocol's cut joins the input reserve
+        pool.accumulate_price()?;
+        let reserve_in = reserve_in
+            .checked_add(received - protocol_fee)
+            .ok_or(AmmError::MathOverflow)?;
+        if a_to_b {
+            pool.reserve_a = reserve_in;
+        } else {
+            pool.reserve_b = reserve_in;
+        }
+
+        mint_lp(
+            &ctx.accounts.pool,
+            &ctx.accounts.lp_mint,
+            &ctx.accounts.user_lp_token_account,
+            &ctx.accounts.pool_authority,
+            &ctx.accounts.token_program,
+            ctx.bumps.pool_authority,
+            lp_out,
+        )
+    }
+
+    /// Burn `lp_amount` LP tokens for their pro-rata share of both reserves (rounded
+    /// down, in the pool's favor). Allowed while the pool is paused.
+    /// Remaining accounts: transfer-hook accounts, see `transfer_hooked_tokens`.
+    pub fn remove_liquidity<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, RemoveLiquidity<'info>>,
+        lp_amount: u64,
+        min_a_out: u64,
+        min_b_out: u64,
+    ) -> Result<()> {
+        process_remove_liquidity(
+            ctx.accounts,
+            ctx.remaining_accounts,
+            ctx.bumps.pool_authority,
+            lp_amount,
+            min_a_out,
+            min_b_out,
+        )
+    }
+
+    /// Block (or re-allow) swaps and new liquidity. Withdrawals stay open so LPs can
+    /// always leave a frozen market. Pool authority only.
+    pub fn set_pool_paused(ctx: Context<UpdatePool>, paused: bool) -> Result<()> {
+        ctx.accounts.pool.paused = paused;
+        Ok(())
+    }
+
+    /// Paused pools only: burn the caller's whole LP balance for its share of both
+    /// reserves, with no slippage bounds to get wrong in a hurry
+    pub fn emergency_withdraw<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, RemoveLiquidity<'info>>,
+    ) -> Result<()> {
+        require!(ctx.accounts.pool.paused, AmmError::PoolNotPaused);
+        let lp_amount = ctx.accounts.user_lp_token_account.amount;
+        process_remove_liquidity(
+            ctx.accounts,
+            ctx.remaining_accounts,
+            ctx.bumps.pool_authority,
+            lp_amount,
+            0,
+            0,
+        )
+    }
+
+    /// Raise the delay between announcing and executing an `admin_withdraw`. Lowering
+    /// it would let a withdrawal skip the notice LPs were given, so it only goes up.
+    pub fn set_withdraw_timelock(ctx: Context<UpdatePool>, delay_secs: i64) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        require!(
+            delay_secs >= pool.withdraw_timelock_secs,
+            AmmError::TimelockLowered
+        );
+        pool.withdraw_timelock_secs = delay_secs;
+        Ok(())
+    }
+
+    /// Announce an `admin_withdraw` of up to `amount_a` / `amount_b` from the reserves,
+    /// executable once `withdraw_timelock_secs` has passed. Pool authority only; one
+    /// announcement per pool at a time.
+    pub fn announce_admin_withdraw(
+        ctx: Context<AnnounceAdminWithdraw>,
+        amount_a: u64,
+        amount_b: u64,
+    ) -> Result<()> {
+        require!(amount_a > 0 || amount_b > 0, AmmError::InvalidAmount);
+        let eta = Clock::get()?
+            .unix_timestamp
+            .checked_add(ctx.accounts.pool.withdraw_timelock_secs)
+            .ok_or(AmmError::MathOverflow)?;
+        ctx.accounts.pending_withdraw.set_inner(PendingWithdraw {
+            pool: ctx.accounts.pool.key(),
+            amount_a,
+            amount_b,
+            eta,
+            bump: ctx.bumps.pending_withdraw,
+        });
+        emit!(AdminWithdrawAnnounced {
+            pool: ctx.accounts.pool.key(),
+            amount_a,
+            amount_b,
+            eta,
+        });
+        Ok(())
+    }
+
+    /// Drop the announced withdrawal and refund its rent
+    pub fn cancel_admin_withdraw(_ctx: Context<CancelAdminWithdraw>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Execute the announced withdrawal after its timelock, taking the amounts (capped
+    /// at the reserves) straight out of the pool to the authority. LP tokens are not
+    /// burned, so every LP's share shrinks accordingly.
+    pub fn admin_withdraw(ctx: Context<AdminWithdraw>) -> Result<()> {
+        let pending = &ctx.accounts.pending_withdraw;
+        require!(
+            Clock::get()?.unix_timestamp >= pending.eta,
+            AmmError::TimelockNotElapsed
+        );
+        require!(!ctx.accounts.pool.locked, AmmError::PoolLocked);
+
+        let pool = &mut ctx.accounts.pool;
+        let amount_a = pending.amount_a.min(pool.reserve_a);
+        let amount_b = pending.amount_b.min(pool.reserve_b);
+        pool.accumulate_price()?;
+        pool.reserve_a -= amount_a;
+        pool.reserve_b -= amount_b;
+
+        let pool = &ctx.accounts.pool;
+        let seeds = &[
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority",
+            &[ctx.bumps.pool_authority],
+        ];
+        let signer = &[&seeds[..]];
+        let accounts = &ctx.accounts;
+        for (from, to, mint, token_program, amount) in [
+            (
+                &accounts.vault_a,
+                &accounts.authority_token_a,
+                &accounts.mint_a,
+                &accounts.token_program_a,
+                amount_a,
+            ),
+            (
+                &accounts.vault_b,
+                &accounts.authority_token_b,
+                &accounts.mint_b,
+                &accounts.token_program_b,
+                amount_b,
+            ),
+        ] {
+            if amount == 0 {
+                continue;
+            }
+            transfer_tokens(
+                &from.to_account_info(),
+                &to.to_account_info(),
+                &accounts.pool_authority.to_account_info(),
+                mint,
+                token_program,
+                signer,
+                amount,
+            )?;
+        }
+
+        emit!(AdminWithdrawn {
+            pool: pool.key(),
+            amount_a,
+            amount_b,
+        });
+        Ok(())
+    }
+
+    /// Uniswap v2-style flash swap: send the outputs to the user first, call
+    /// `callback_program` (`on_flash_swap`, with the remaining accounts), then require
+    /// the curve invariant of the fee-adjusted balances to be no lower than before.
+    /// The callback may repay in either token, or both. The pool is locked against
+    /// re-entry meanwhile.
+    pub fn flash_swap<'info>(
+        ctx: Context<'_, '_, '_, 'info, FlashSwap<'info>>,
+        amount_a_out: u64,
+        amount_b_out: u64,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        require!(amount_a_out > 0 || amount_b_out > 0, AmmError::InvalidAmount);
+        let pool = &ctx.accounts.pool;
+        require!(!pool.paused, AmmError::PoolPaused);
+        require!(!pool.locked, AmmError::PoolLocked);
+
+        let (reserve_a, reserve_b) = (pool.reserve_a, pool.reserve_b);
+        require!(
+            amount_a_out < reserve_a && amount_b_out < reserve_b,
+            AmmError::InsufficientLiquidity
+        );
+        let invariant_before = curve_invariant(&pool.curve, reserve_a, reserve_b)?;
+
+        // Optimistic transfer of the outputs
+        let seeds = &[
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority",
+            &[ctx.bumps.pool_authority],
+        ];
+        let signer = &[&seeds[..]];
+        let accounts = &ctx.accounts;
+        for (from, to, mint, token_program, amount) in [
+            (
+                &accounts.vault_a,
+                &accounts.user_token_a,
+                &accounts.mint_a,
+                &accounts.token_program_a,
+                amount_a_out,
+            ),
+            (
+                &accounts.vault_b,
+                &accounts.user_token_b,
+                &accounts.mint_b,
+                &accounts.token_program_b,
+                amount_b_out,
+            ),
+        ] {
+            if amount == 0 {
+                continue;
+            }
+            transfer_tokens(
+                &from.to_account_info(),
+                &to.to_account_info(),
+                &accounts.pool_authority.to_account_info(),
+                mint,
+                token_program,
+                signer,
+                amount,
+            )?;
+        }
+
+        // Persist the lock before handing over control
+        ctx.accounts.pool.locked = true;
+        ctx.accounts.pool.exit(&crate::ID)?;
+
+        let mut ix_data = ON_FLASH_SWAP.to_vec();
+        (amount_a_out, amount_b_out, ctx.accounts.user.key(), data).serialize(&mut ix_data)?;
+        let ix = Instruction {
+            program_id: ctx.accounts.callback_program.key(),
+            accounts: ctx
+                .remaining_accounts
+                .iter()
+                .map(|acc| AccountMeta {
+                    pubkey: acc.key(),
+                    is_signer: acc.is_signer,
+                    is_writable: acc.is_writable,
+                })
+                .collect(),
+            data: ix_data,
         };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program.clone(), cpi_accounts_a);
-        anchor_spl::token::transfer(cpi_ctx, initial_amount_a)?;
+        let mut infos = ctx.remaining_accounts.to_vec();
+        infos.push(ctx.accounts.callback_program.to_account_info());
+        invoke(&ix, &infos)?;
 
-        // Transfer token B
-        let cpi_accounts_b = Transfer {
-            from: ctx.accounts.authority_token_account_b.to_account_info(),
-            to: ctx.accounts.vault_b.to_account_info(),
-            authority: ctx.accounts.authority.to_account_info(),
+        ctx.accounts.pool.reload()?;
+        ctx.accounts.vault_a.reload()?;
+        ctx.accounts.vault_b.reload()?;
+        let pool = &mut ctx.accounts.pool;
+        pool.locked = false;
+
+        // Whatever came back on top of what was left counts as input and pays the fee
+        let (balance_a, balance_b) =
+            pool.balances(ctx.accounts.vault_a.amount, ctx.accounts.vault_b.amount);
+        let adjusted = |balance: u64, reserve: u64, amount_out: u64| {
+            let amount_in = balance.saturating_sub(reserve - amount_out);
+            balance - swap_fee(&pool.curve, pool.fee_bps, amount_in)
         };
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts_b);
-        anchor_spl::token::transfer(cpi_ctx, initial_amount_b)?;
+        let invariant_after = curve_invariant(
+            &pool.curve,
+            adjusted(balance_a, reserve_a, amount_a_out),
+            adjusted(balance_b, reserve_b, amount_b_out),
+        )?;
+        require!(
+            invariant_after >= invariant_before,
+            AmmError::FlashSwapNotRepaid
+        );
+        pool.accumulate_price()?;
+        pool.reserve_a = balance_a;
+        pool.reserve_b = balance_b;
+
+        Ok(())
+    }
+
+    /// Permissionless: adopt tokens sent straight to the vaults into the reserves, as
+    /// Uniswap v2's `sync`
+    pub fn sync(ctx: Context<SyncReserves>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        require!(!pool.locked, AmmError::PoolLocked);
+        let (balance_a, balance_b) =
+            pool.balances(ctx.accounts.vault_a.amount, ctx.accounts.vault_b.amount);
+        pool.accumulate_price()?;
+        pool.reserve_a = balance_a;
+        pool.reserve_b = balance_b;
+        Ok(())
+    }
+
+    /// Permissionless: send whatever the vaults hold beyond the reserves and protocol
+    /// fees to `to_token_a` / `to_token_b`, as Uniswap v2's `skim`
+    pub fn skim(ctx: Context<Skim>) -> Result<()> {
+        let accounts = &ctx.accounts;
+        let pool = &accounts.pool;
+        require!(!pool.locked, AmmError::PoolLocked);
+        let (balance_a, balance_b) = pool.balances(accounts.vault_a.amount, accounts.vault_b.amount);
 
+        let seeds = &[
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority",
+            &[ctx.bumps.pool_authority],
+        ];
+        let signer = &[&seeds[..]];
+        for (from, to, mint, token_program, amount) in [
+            (
+                &accounts.vault_a,
+                &accounts.to_token_a,
+                &accounts.mint_a,
+                &accounts.token_program_a,
+                balance_a.saturating_sub(pool.reserve_a),
+            ),
+            (
+                &accounts.vault_b,
+                &accounts.to_token_b,
+                &accounts.mint_b,
+                &accounts.token_program_b,
+                balance_b.saturating_sub(pool.reserve_b),
+            ),
+        ] {
+            if amount == 0 {
+                continue;
+            }
+            transfer_tokens(
+                &from.to_account_info(),
+                &to.to_account_info(),
+                &accounts.pool_authority.to_account_info(),
+                mint,
+                token_program,
+                signer,
+                amount,
+            )?;
+        }
         Ok(())
     }
 
-    pub fn swap(ctx: Context<Swap>, amount_in: u64, min_amount_out: u64) -> Result<()> {
+    /// Read-only: what `swap` would pay out for `amount_in` right now, swap fees
+    /// included, returned as Anchor return data (read it by simulation or after a CPI).
+    /// Token-2022 transfer fees on either leg are not taken into account.
+    pub fn quote(ctx: Context<Quote>, amount_in: u64, direction: SwapDirection) -> Result<u64> {
         require!(amount_in > 0, AmmError::InvalidAmount);
+        quote_amount_out(&ctx.accounts.pool, amount_in, direction)
+    }
 
+    /// Oracle view: the spot and time-weighted prices of `mint_a` in `mint_b`, returned
+    /// via return data (see `crates/amm-oracle` for the CPI client)
+    pub fn get_price(ctx: Context<Quote>) -> Result<PoolPrice> {
         let pool = &ctx.accounts.pool;
-        
+        let now = Clock::get()?.unix_timestamp;
+        let spot_price = pool.spot_price(true)?;
+        let twap_window = now - pool.twap_checkpoint_at;
+        // A pool younger than a second has no history yet; its TWAP is the spot price
+        let twap_price = if twap_window > 0 {
+            pool.price_cumulative_at(now)?
+                .wrapping_sub(pool.twap_checkpoint_cumulative)
+                / twap_window as u128
+        } else {
+            spot_price
+        };
+        Ok(PoolPrice {
+            spot_price,
+            twap_price,
+            twap_window,
+        })
+    }
+
+    /// Swap `amount_in` of one pool token for the other. The output is priced off what
+    /// the input vault actually receives and `min_amount_out` bounds what reaches
+    /// `user_token_out`, so Token-2022 transfer fees on either leg count as slippage.
+    /// With a `limit_price` (Q64.64 output per input, see `Pool::spot_price`) the swap
+    /// reverts if it would leave the pool's marginal price below the limit.
+    /// Remaining accounts: for mints with a Token-2022 transfer hook, the hook
+    /// program, the mint's extra-account-meta list and the accounts it resolves to.
+    pub fn swap<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, Swap<'info>>,
+        amount_in: u64,
+        min_amount_out: u64,
+        limit_price: Option<u128>,
+    ) -> Result<()> {
+        require!(amount_in > 0, AmmError::InvalidAmount);
+
+        let pool = &mut ctx.accounts.pool;
+        require!(!pool.paused, AmmError::PoolPaused);
+        require!(!pool.locked, AmmError::PoolLocked);
+
         // Validate token accounts match pool
         require!(
             ctx.accounts.user_token_in.mint == pool.mint_a
@@ -86,153 +1002,1837 @@ pub mod mock_amm {
         );
 
         // Determine which vault is input and which is output
-        let (vault_in, vault_out) = if ctx.accounts.user_token_in.mint == pool.mint_a {
-            (&ctx.accounts.vault_a, &ctx.accounts.vault_b)
-        } else {
-            (&ctx.accounts.vault_b, &ctx.accounts.vault_a)
-        };
+        let a_to_b = ctx.accounts.user_token_in.mint == pool.mint_a;
 
-        // Get current reserves
-        let reserve_in = vault_in.amount;
-        let reserve_out = vault_out.amount;
-
-        require!(reserve_in > 0 && reserve_out > 0, AmmError::InsufficientLiquidity);
-
-        // Calculate output using constant product formula: (x + dx) * (y - dy) = x * y
-        // dy = (y * dx) / (x + dx)
-        // Using u128 to prevent overflow
-        let amount_out = ((amount_in as u128)
-            .checked_mul(reserve_out as u128)
-            .ok_or(AmmError::MathOverflow)?
-            .checked_div(reserve_in.checked_add(amount_in).ok_or(AmmError::MathOverflow)? as u128)
-            .ok_or(AmmError::DivisionByZero)?) as u64;
-
-        require!(amount_out >= min_amount_out, AmmError::SlippageExceeded);
-        require!(amount_out > 0, AmmError::InvalidAmount);
-
-        // Transfer tokens from user to pool (input)
-        let cpi_accounts_in = Transfer {
-            from: ctx.accounts.user_token_in.to_account_info(),
-            to: vault_in.to_account_info(),
-            authority: ctx.accounts.user.to_account_info(),
+        // Transfer tokens from user to pool (input); only what arrives is priced
+        let (vault_in, mint_in, token_program_in) = if a_to_b {
+            (
+                &mut ctx.accounts.vault_a,
+                &ctx.accounts.mint_a,
+                &ctx.accounts.token_program_a,
+            )
+        } else {
+            (
+                &mut ctx.accounts.vault_b,
+                &ctx.accounts.mint_b,
+                &ctx.accounts.token_program_b,
+            )
         };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts_in);
-        anchor_spl::token::transfer(cpi_ctx, amount_in)?;
+        let vault_in_before = vault_in.amount;
+        transfer_hooked_tokens(
+            &ctx.accounts.user_token_in.to_account_info(),
+            &vault_in.to_account_info(),
+            &ctx.accounts.user.to_account_info(),
+            mint_in,
+            token_program_in,
+            ctx.remaining_accounts,
+            &[],
+            amount_in,
+        )?;
+        let received = balance_increase(vault_in, vault_in_before)?;
+        let amount_out = pool.apply_swap(a_to_b, received)?;
+        if let Some(limit_price) = limit_price {
+            require!(
+                pool.spot_price(a_to_b)? >= limit_price,
+                AmmError::PriceLimitExceeded
+            );
+        }
 
         // Transfer tokens from pool to user (output)
         let seeds = &[
             b"pool",
             pool.mint_a.as_ref(),
             pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
             b"authority",
             &[ctx.bumps.pool_authority],
         ];
         let signer = &[&seeds[..]];
-
-        let cpi_accounts_out = Transfer {
-            from: vault_out.to_account_info(),
-            to: ctx.accounts.user_token_out.to_account_info(),
-            authority: ctx.accounts.pool_authority.to_account_info(),
+        let (vault_out, mint_out, token_program_out) = if a_to_b {
+            (
+                &ctx.accounts.vault_b,
+                &ctx.accounts.mint_b,
+                &ctx.accounts.token_program_b,
+            )
+        } else {
+            (
+                &ctx.accounts.vault_a,
+                &ctx.accounts.mint_a,
+                &ctx.accounts.token_program_a,
+            )
         };
+        let user_out_before = ctx.accounts.user_token_out.amount;
+        transfer_hooked_tokens(
+            &vault_out.to_account_info(),
+            &ctx.accounts.user_token_out.to_account_info(),
+            &ctx.accounts.pool_authority.to_account_info(),
+            mint_out,
+            token_program_out,
+            ctx.remaining_accounts,
+            signer,
+            amount_out,
+        )?;
+        let delivered = balance_increase(&mut ctx.accounts.user_token_out, user_out_before)?;
+        require!(delivered >= min_amount_out, AmmError::SlippageExceeded);
+
+        Ok(())
+    }
+
+    /// Multi-hop swap through the pools in the remaining accounts, `ROUTE_HOP_ACCOUNTS`
+    /// per pool: pool (mut), vault_a (mut), vault_b (mut), pool_authority, mint_a,
+    /// mint_b, token_program_a, token_program_b. Each hop's output goes straight into
+    /// the next pool's vault; `min_amount_out` bounds what reaches `user_token_out`.
+    pub fn route_swap<'c: 'info, 'info>(
+        ctx: Context<'_, '_, 'c, 'info, RouteSwap<'info>>,
+        amount_in: u64,
+        min_amount_out: u64,
+    ) -> Result<()> {
+        require!(amount_in > 0, AmmError::InvalidAmount);
+        let remaining = ctx.remaining_accounts;
+        require!(
+            !remaining.is_empty() && remaining.len().is_multiple_of(ROUTE_HOP_ACCOUNTS),
+            AmmError::InvalidRoute
+        );
+
+        let mut hops: Vec<RouteHop> = Vec::with_capacity(remaining.len() / ROUTE_HOP_ACCOUNTS);
+        let mut mint = ctx.accounts.user_token_in.mint;
+        for accounts in remaining.chunks(ROUTE_HOP_ACCOUNTS) {
+            // A pool visited twice would be priced off stale reserves
+            require!(
+                hops.iter().all(|hop| hop.pool.key() != accounts[0].key()),
+                AmmError::InvalidRoute
+            );
+            let hop = RouteHop::load(accounts, mint)?;
+            mint = hop.mints[1 - hop.side_in].key();
+            hops.push(hop);
+        }
+        require_keys_eq!(
+            ctx.accounts.user_token_out.mint,
+            mint,
+            AmmError::InvalidRoute
+        );
+
+        let first = &mut hops[0];
+        let side = first.side_in;
+        let before = first.vaults[side].amount;
+        transfer_tokens(
+            &ctx.accounts.user_token_in.to_account_info(),
+            &first.vaults[side].to_account_info(),
+            &ctx.accounts.user.to_account_info(),
+            &first.mints[side],
+            &first.token_programs[side],
+            &[],
+            amount_in,
+        )?;
+        let mut received = balance_increase(&mut first.vaults[side], before)?;
 
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts_out, signer);
-        anchor_spl::token::transfer(cpi_ctx, amount_out)?;
+        let mut rest = &mut hops[..];
+        while let Some((hop, next)) = rest.split_first_mut() {
+            let amount_out = hop.pool.apply_swap(hop.side_in == 0, received)?;
+            let to = match next.first_mut() {
+                Some(next_hop) => &mut next_hop.vaults[next_hop.side_in],
+                None => &mut ctx.accounts.user_token_out,
+            };
+            let before = to.amount;
+            hop.transfer_out(to, amount_out)?;
+            received = balance_increase(to, before)?;
+            hop.pool.exit(&crate::ID)?;
+            rest = next;
+        }
+        require!(received >= min_amount_out, AmmError::SlippageExceeded);
 
         Ok(())
     }
 }
 
-#[account]
-pub struct Pool {
-    pub mint_a: Pubkey,
-    pub mint_b: Pubkey,
-    pub vault_a: Pubkey,
-    pub vault_b: Pubkey,
-    pub authority: Pubkey,
-}
+/// Remaining accounts per pool of a `route_swap`
+pub const ROUTE_HOP_ACCOUNTS: usize = 8;
 
-impl Pool {
-    pub const LEN: usize = 8 + std::mem::size_of::<Self>();
+// One pool of a `route_swap`, loaded from its remaining accounts. Sides are indexed
+// 0 = a, 1 = b.
+struct RouteHop<'info> {
+    pool: Account<'info, Pool>,
+    vaults: [InterfaceAccount<'info, TokenAccount>; 2],
+    mints: [InterfaceAccount<'info, Mint>; 2],
+    token_programs: [Interface<'info, TokenInterface>; 2],
+    pool_authority: AccountInfo<'info>,
+    pool_authority_bump: u8,
+    // Side of the token sold into this pool
+    side_in: usize,
 }
 
-#[derive(Accounts)]
-pub struct InitializePool<'info> {
-    #[account(
-        init,
-        payer = authority,
-        space = Pool::LEN,
-        seeds = [b"pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
-        bump
-    )]
-    pub pool: Account<'info, Pool>,
-
-    #[account(mut)]
-    pub authority: Signer<'info>,
+impl<'info> RouteHop<'info> {
+    fn load(accounts: &'info [AccountInfo<'info>], mint_in: Pubkey) -> Result<Self> {
+        // Owned by this program, so created by `initialize_pool` at its canonical PDA
+        let pool = Account::<Pool>::try_from(&accounts[0])?;
+        require!(!pool.paused, AmmError::PoolPaused);
+        require!(!pool.locked, AmmError::PoolLocked);
+        require!(accounts[0].is_writable, AmmError::InvalidRoute);
 
-    pub mint_a: Account<'info, Mint>,
-    pub mint_b: Account<'info, Mint>,
+        let (pool_authority, pool_authority_bump) =
+            pda::pool_authority(&pool.mint_a, &pool.mint_b, pool.fee_tier);
+        require!(
+            accounts[1].key() == pool.vault_a
+                && accounts[2].key() == pool.vault_b
+                && accounts[3].key() == pool_authority
+                && accounts[4].key() == pool.mint_a
+                && accounts[5].key() == pool.mint_b,
+            AmmError::InvalidRoute
+        );
+        let side_in = if mint_in == pool.mint_a {
+            0
+        } else {
+            require_keys_eq!(mint_in, pool.mint_b, AmmError::InvalidRoute);
+            1
+        };
 
-    #[account(mut)]
-    pub vault_a: Account<'info, TokenAccount>,
+        Ok(Self {
+            vaults: [
+                InterfaceAccount::try_from(&accounts[1])?,
+                InterfaceAccount::try_from(&accounts[2])?,
+            ],
+            mints: [
+                InterfaceAccount::try_from(&accounts[4])?,
+                InterfaceAccount::try_from(&accounts[5])?,
+            ],
+            token_programs: [
+                Interface::try_from(&accounts[6])?,
+                Interface::try_from(&accounts[7])?,
+            ],
+            pool_authority: accounts[3].clone(),
+            pool_authority_bump,
+            side_in,
+            pool,
+        })
+    }
+
+    // Pay `amount` of the side bought from this pool to `to`
+    fn transfer_out(&self, to: &InterfaceAccount<'info, TokenAccount>, amount: u64) -> Result<()> {
+        let side = 1 - self.side_in;
+        let seeds = &[
+            b"pool",
+            self.pool.mint_a.as_ref(),
+            self.pool.mint_b.as_ref(),
+            &self.pool.fee_tier.to_le_bytes(),
+            b"authority",
+            &[self.pool_authority_bump],
+        ];
+        transfer_tokens(
+            &self.vaults[side].to_account_info(),
+            &to.to_account_info(),
+            &self.pool_authority,
+            &self.mints[side],
+            &self.token_programs[side],
+            &[&seeds[..]],
+            amount,
+        )
+    }
+}
+
+// Metaplex metadata for the LP mint so wallets show it as `MOCKAMM-LP-<A>-<B>`, each
+// mint abbreviated to its first base58 characters. The pool authority, as mint
+// authority, signs and keeps the update authority.
+fn create_lp_metadata(accounts: &InitializePool, pool_authority_bump: u8) -> Result<()> {
+    let pool = &accounts.pool;
+    let abbreviate = |mint: &Pubkey| mint.to_string()[..Pool::LP_NAME_MINT_CHARS].to_string();
+    let data = DataV2 {
+        name: format!(
+            "MOCKAMM-LP-{}-{}",
+            abbreviate(&pool.mint_a),
+            abbreviate(&pool.mint_b)
+        ),
+        symbol: Pool::LP_SYMBOL.to_string(),
+        uri: String::new(),
+        seller_fee_basis_points: 0,
+        creators: None,
+        collection: None,
+        uses: None,
+    };
+    let seeds = &[
+        b"pool",
+        pool.mint_a.as_ref(),
+        pool.mint_b.as_ref(),
+        &pool.fee_tier.to_le_bytes(),
+        b"authority",
+        &[pool_authority_bump],
+    ];
+    let cpi_accounts = CreateMetadataAccountsV3 {
+        metadata: accounts.lp_metadata.to_account_info(),
+        mint: accounts.lp_mint.to_account_info(),
+        mint_authority: accounts.pool_authority.to_account_info(),
+        payer: accounts.authority.to_account_info(),
+        update_authority: accounts.pool_authority.to_account_info(),
+        system_program: accounts.system_program.to_account_info(),
+        rent: accounts.rent.to_account_info(),
+    };
+    metadata::create_metadata_accounts_v3(
+        CpiContext::new_with_signer(
+            accounts.token_metadata_program.to_account_info(),
+            cpi_accounts,
+            &[&seeds[..]],
+        ),
+        data,
+        true,
+        true,
+        None,
+    )
+}
+
+// Resize a program-owned account, topping up rent from `payer`
+fn grow_account<'info>(
+    info: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    new_len: usize,
+) -> Result<()> {
+    if new_len <= info.data_len() {
+        return Ok(());
+    }
+    let required = Rent::get()?.minimum_balance(new_len);
+    let shortfall = required.saturating_sub(info.lamports());
+    if shortfall > 0 {
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                system_program.clone(),
+                anchor_lang::system_program::Transfer {
+                    from: payer.clone(),
+                    to: info.clone(),
+                },
+            ),
+            shortfall,
+        )?;
+    }
+    info.resize(new_len)?;
+    Ok(())
+}
+
+// Charge the pool creator the config's creation fee, in SOL or in its fee token
+fn pay_creation_fee(accounts: &InitializePool) -> Result<()> {
+    let config = AmmConfig::load(&accounts.config)?;
+    if config.creation_fee == 0 {
+        return Ok(());
+    }
+    if config.creation_fee_mint == Pubkey::default() {
+        let treasury = accounts.treasury.as_ref().ok_or(AmmError::InvalidTreasury)?;
+        require_keys_eq!(treasury.key(), config.treasury, AmmError::InvalidTreasury);
+        let cpi_accounts = anchor_lang::system_program::Transfer {
+            from: accounts.authority.to_account_info(),
+            to: treasury.to_account_info(),
+        };
+        return anchor_lang::system_program::transfer(
+            CpiContext::new(accounts.system_program.to_account_info(), cpi_accounts),
+            config.creation_fee,
+        );
+    }
+
+    let (Some(fee_mint), Some(from), Some(to), Some(fee_token_program)) = (
+        &accounts.fee_mint,
+        &accounts.authority_fee_token_account,
+        &accounts.treasury_fee_token_account,
+        &accounts.fee_token_program,
+    ) else {
+        return err!(AmmError::InvalidTreasury);
+    };
+    require_keys_eq!(fee_mint.key(), config.creation_fee_mint, AmmError::InvalidTreasury);
+    require_keys_eq!(to.owner, config.treasury, AmmError::InvalidTreasury);
+    transfer_tokens(
+        &from.to_account_info(),
+        &to.to_account_info(),
+        &accounts.authority.to_account_info(),
+        fee_mint,
+        fee_token_program,
+        &[],
+        config.creation_fee,
+    )
+}
+
+// Shared by `remove_liquidity` and `emergency_withdraw`. The remaining accounts are
+// Token-2022 transfer-hook accounts, see `transfer_hooked_tokens`.
+fn process_remove_liquidity<'info>(
+    accounts: &mut RemoveLiquidity<'info>,
+    hook_accounts: &[AccountInfo<'info>],
+    pool_authority_bump: u8,
+    lp_amount: u64,
+    min_a_out: u64,
+    min_b_out: u64,
+) -> Result<()> {
+    require!(lp_amount > 0, AmmError::InvalidAmount);
+    require!(!accounts.pool.locked, AmmError::PoolLocked);
+
+    let lp_supply = accounts.lp_mint.supply as u128;
+    require!(lp_supply > 0, AmmError::InsufficientLiquidity);
+    let pool = &mut accounts.pool;
+    let amount_a = (lp_amount as u128 * pool.reserve_a as u128 / lp_supply) as u64;
+    let amount_b = (lp_amount as u128 * pool.reserve_b as u128 / lp_supply) as u64;
+    require!(amount_a > 0 || amount_b > 0, AmmError::InvalidAmount);
+    require!(
+        amount_a >= min_a_out && amount_b >= min_b_out,
+        AmmError::SlippageExceeded
+    );
+    // The vaults drop by exactly what is sent (a Token-2022 fee comes out of the user's end)
+    pool.accumulate_price()?;
+    pool.reserve_a -= amount_a;
+    pool.reserve_b -= amount_b;
+
+    let cpi_accounts = Burn {
+        mint: accounts.lp_mint.to_account_info(),
+        from: accounts.user_lp_token_account.to_account_info(),
+        authority: accounts.user.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new(accounts.token_program.to_account_info(), cpi_accounts);
+    token_interface::burn(cpi_ctx, lp_amount)?;
+
+    let pool = &accounts.pool;
+    let seeds = &[
+        b"pool",
+        pool.mint_a.as_ref(),
+        pool.mint_b.as_ref(),
+        &pool.fee_tier.to_le_bytes(),
+        b"authority",
+        &[pool_authority_bump],
+    ];
+    let signer = &[&seeds[..]];
+    for (from, to, mint, token_program, amount) in [
+        (
+            &accounts.vault_a,
+            &accounts.user_token_a,
+            &accounts.mint_a,
+            &accounts.token_program_a,
+            amount_a,
+        ),
+        (
+            &accounts.vault_b,
+            &accounts.user_token_b,
+            &accounts.mint_b,
+            &accounts.token_program_b,
+            amount_b,
+        ),
+    ] {
+        transfer_hooked_tokens(
+            &from.to_account_info(),
+            &to.to_account_info(),
+            &accounts.pool_authority.to_account_info(),
+            mint,
+            token_program,
+            hook_accounts,
+            signer,
+            amount,
+        )?;
+    }
+
+    Ok(())
+}
+
+// Mint LP tokens, signed by the pool authority PDA
+fn mint_lp<'info>(
+    pool: &Pool,
+    lp_mint: &InterfaceAccount<'info, Mint>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    pool_authority: &UncheckedAccount<'info>,
+    token_program: &Program<'info, Token>,
+    pool_authority_bump: u8,
+    amount: u64,
+) -> Result<()> {
+    let seeds = &[
+        b"pool",
+        pool.mint_a.as_ref(),
+        pool.mint_b.as_ref(),
+        &pool.fee_tier.to_le_bytes(),
+        b"authority",
+        &[pool_authority_bump],
+    ];
+    let signer = &[&seeds[..]];
+    let cpi_accounts = MintTo {
+        mint: lp_mint.to_account_info(),
+        to: to.to_account_info(),
+        authority: pool_authority.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer);
+    token_interface::mint_to(cpi_ctx, amount)
+}
+
+// `transfer_checked` through `mint`'s own token program, so either side of a pair may
+// be a Token-2022 mint
+fn transfer_tokens<'info>(
+    from: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    signer: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    let cpi_accounts = TransferChecked {
+        from: from.clone(),
+        mint: mint.to_account_info(),
+        to: to.clone(),
+        authority: authority.clone(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer);
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)
+}
+
+// `transfer_tokens` for a mint that may carry a Token-2022 transfer hook. The caller
+// passes the hook program, the mint's extra-account-meta list and the extra accounts
+// it resolves to in `hook_accounts` (any order, both legs' hooks together); the
+// token program's helper picks out the ones this mint's hook asks for.
+#[allow(clippy::too_many_arguments)]
+fn transfer_hooked_tokens<'info>(
+    from: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    token_program: &Interface<'info, TokenInterface>,
+    hook_accounts: &[AccountInfo<'info>],
+    signer: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    if hook_accounts.is_empty() {
+        return transfer_tokens(from, to, authority, mint, token_program, signer, amount);
+    }
+    spl_token_2022::onchain::invoke_transfer_checked(
+        token_program.key,
+        from.clone(),
+        mint.to_account_info(),
+        to.clone(),
+        authority.clone(),
+        hook_accounts,
+        amount,
+        mint.decimals,
+        signer,
+    )
+    .map_err(Into::into)
+}
+
+// Reload `account` and return how much its balance grew from `before`. A Token-2022
+// transfer fee is withheld from the receiving side, so this is what actually arrived.
+fn balance_increase(account: &mut InterfaceAccount<TokenAccount>, before: u64) -> Result<u64> {
+    account.reload()?;
+    Ok(account
+        .amount
+        .checked_sub(before)
+        .ok_or(AmmError::MathOverflow)?)
+}
+
+// How much of a single-sided deposit `add_liquidity_single` swaps so that the rest
+// matches the post-swap ratio. Exact for constant product (the usual zap formula,
+// s = (sqrt(r^2(2 - f)^2 + 4(1 - f)ar) - r(2 - f)) / 2(1 - f), in bps); half for the
+// other curves, which trade near 1:1, or when the exact form would overflow.
+fn zap_swap_amount(curve: &CurveType, fee_bps: u16, amount_in: u64, reserve_in: u64) -> u64 {
+    let half = amount_in / 2;
+    if *curve != CurveType::ConstantProduct {
+        return half;
+    }
+    let (r, a) = (reserve_in as u128, amount_in as u128);
+    let after_fee = 10_000 - fee_bps as u128;
+    let two_minus_fee = 10_000 + after_fee;
+    let discriminant = r
+        .checked_mul(r)
+        .and_then(|v| v.checked_mul(two_minus_fee * two_minus_fee))
+        .zip(
+            (4 * after_fee * 10_000)
+                .checked_mul(a)
+                .and_then(|v| v.checked_mul(r)),
+        )
+        .and_then(|(x, y)| x.checked_add(y));
+    match discriminant {
+        Some(d) => ((integer_sqrt(d) - r * two_minus_fee) / (2 * after_fee)) as u64,
+        None => half,
+    }
+}
+
+// `numerator / denominator` as Q64.64, shifting the denominator down when the
+// numerator has no headroom for the full 64 fractional bits
+fn q64_div(numerator: u128, denominator: u128) -> Result<u128> {
+    let shift = numerator.leading_zeros().min(64);
+    let denominator = denominator >> (64 - shift);
+    require!(denominator > 0, AmmError::MathOverflow);
+    Ok((numerator << shift) / denominator)
+}
+
+// Floor square root (Newton's method)
+fn integer_sqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = (x + n / x) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+// Part of `amount_in` charged as the swap fee (rounded up); the constant-sum curve
+// stays fee-free
+fn swap_fee(curve: &CurveType, fee_bps: u16, amount_in: u64) -> u64 {
+    match curve {
+        CurveType::ConstantProduct | CurveType::Stable { .. } => {
+            let after_fee = amount_in as u128 * (10_000 - fee_bps as u128) / 10_000;
+            amount_in - after_fee as u64
+        }
+        CurveType::ConstantSum => 0,
+    }
+}
+
+// Liquidity the reserves represent, linear in a pro-rata deposit: sqrt(x·y) for
+// constant product, x + y for constant sum and D for stable
+fn liquidity_size(curve: &CurveType, x: u64, y: u64) -> Result<u128> {
+    match curve {
+        CurveType::ConstantProduct => Ok(integer_sqrt(x as u128 * y as u128)),
+        _ => curve_invariant(curve, x, y),
+    }
+}
+
+// The quantity each curve conserves across a swap
+fn curve_invariant(curve: &CurveType, x: u64, y: u64) -> Result<u128> {
+    match curve {
+        CurveType::ConstantProduct => Ok(x as u128 * y as u128),
+        CurveType::ConstantSum => Ok(x as u128 + y as u128),
+        CurveType::Stable { amp } => {
+            if x == 0 || y == 0 {
+                return Ok(0);
+            }
+            stable_invariant(x as u128, y as u128, *amp as u128 * 4)
+        }
+    }
+}
+
+// Swap output against the pool's current reserves
+fn quote_amount_out(pool: &Pool, amount_in: u64, direction: SwapDirection) -> Result<u64> {
+    let (reserve_in, reserve_out) = match direction {
+        SwapDirection::AToB => (pool.reserve_a, pool.reserve_b),
+        SwapDirection::BToA => (pool.reserve_b, pool.reserve_a),
+    };
+
+    require!(reserve_in > 0 && reserve_out > 0, AmmError::InsufficientLiquidity);
+
+    calculate_amount_out(&pool.curve, pool.fee_bps, amount_in, reserve_in, reserve_out)
+}
+
+// Helper function to calculate swap output for the pool's curve
+fn calculate_amount_out(
+    curve: &CurveType,
+    fee_bps: u16,
+    amount_in: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+) -> Result<u64> {
+    match curve {
+        CurveType::ConstantProduct => {
+            // The fee stays in the pool: only the rest of the input moves the price
+            let amount_in_after_fee = (amount_in - swap_fee(curve, fee_bps, amount_in)) as u128;
+            // Calculate output using constant product formula: (x + dx) * (y - dy) = x * y
+            // dy = (y * dx) / (x + dx)
+            // Using u128 to prevent overflow
+            let amount_out = amount_in_after_fee
+                .checked_mul(reserve_out as u128)
+                .ok_or(AmmError::MathOverflow)?
+                .checked_div(
+                    (reserve_in as u128)
+                        .checked_add(amount_in_after_fee)
+                        .ok_or(AmmError::MathOverflow)?,
+                )
+                .ok_or(AmmError::DivisionByZero)?;
+            Ok(amount_out as u64)
+        }
+        CurveType::ConstantSum => {
+            // x + y = k: swaps are exactly 1:1 until the output reserve runs out
+            require!(amount_in <= reserve_out, AmmError::InsufficientLiquidity);
+            Ok(amount_in)
+        }
+        CurveType::Stable { amp } => {
+            let amount_in_after_fee = (amount_in - swap_fee(curve, fee_bps, amount_in)) as u128;
+            let ann = *amp as u128 * 4;
+            let d = stable_invariant(reserve_in as u128, reserve_out as u128, ann)?;
+            let new_reserve_in = (reserve_in as u128)
+                .checked_add(amount_in_after_fee)
+                .ok_or(AmmError::MathOverflow)?;
+            let new_reserve_out = stable_other_reserve(new_reserve_in, d, ann)?;
+            // One unit kept back so Newton rounding never favors the trader
+            Ok((reserve_out as u128)
+                .saturating_sub(new_reserve_out)
+                .saturating_sub(1) as u64)
+        }
+    }
+}
+
+// StableSwap invariant D for two reserves: A·n^n·(x + y) + D = A·n^n·D + D^(n+1) / (n^n·x·y)
+// with n = 2 and `ann` = A·n^n, solved by Newton's method
+fn stable_invariant(x: u128, y: u128, ann: u128) -> Result<u128> {
+    let sum = x + y;
+    let mut d = sum;
+    for _ in 0..255 {
+        // D^3 / (4xy), built up stepwise to stay within u128
+        let mut d_p = d;
+        for reserve in [x, y] {
+            d_p = d_p
+                .checked_mul(d)
+                .ok_or(AmmError::MathOverflow)?
+                .checked_div(reserve * 2)
+                .ok_or(AmmError::DivisionByZero)?;
+        }
+        let prev = d;
+        let numerator = ann
+            .checked_mul(sum)
+            .and_then(|v| v.checked_add(d_p * 2))
+            .and_then(|v| v.checked_mul(d))
+            .ok_or(AmmError::MathOverflow)?;
+        let denominator = (ann - 1)
+            .checked_mul(d)
+            .and_then(|v| v.checked_add(d_p * 3))
+            .ok_or(AmmError::MathOverflow)?;
+        d = numerator / denominator;
+        if d.abs_diff(prev) <= 1 {
+            return Ok(d);
+        }
+    }
+    err!(AmmError::MathOverflow)
+}
+
+// The other reserve that keeps invariant `d` once one side holds `x`
+fn stable_other_reserve(x: u128, d: u128, ann: u128) -> Result<u128> {
+    // y^2 + (x + D/Ann - D)·y = D^3 / (4·x·Ann)
+    let c = d
+        .checked_mul(d)
+        .ok_or(AmmError::MathOverflow)?
+        / (x * 2)
+        * d
+        / (ann * 2);
+    let b = x + d / ann;
+    let mut y = d;
+    for _ in 0..255 {
+        let prev = y;
+        let numerator = y
+            .checked_mul(y)
+            .and_then(|v| v.checked_add(c))
+            .ok_or(AmmError::MathOverflow)?;
+        let denominator = (2 * y + b)
+            .checked_sub(d)
+            .ok_or(AmmError::MathOverflow)?;
+        y = numerator / denominator;
+        if y.abs_diff(prev) <= 1 {
+            return Ok(y);
+        }
+    }
+    err!(AmmError::MathOverflow)
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CurveType {
+    /// x * y = k
+    ConstantProduct,
+    /// x + y = k (zero price impact, for deterministic test scenarios)
+    ConstantSum,
+    /// StableSwap invariant: flat around the 1:1 peg, higher `amp` = flatter
+    Stable { amp: u64 },
+}
+
+impl CurveType {
+    pub const MAX_AMP: u64 = 10_000;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapDirection {
+    /// Sell `mint_a` for `mint_b`
+    AToB,
+    /// Sell `mint_b` for `mint_a`
+    BToA,
+}
+
+#[account]
+pub struct Pool {
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub vault_a: Pubkey,
+    pub vault_b: Pubkey,
+    pub authority: Pubkey,
+    pub curve: CurveType,
+    // LP token, minted by `pool_authority` (seeds `[b"pool", mint_a, mint_b, fee_tier, b"lp_mint"]`).
+    // `MINIMUM_LIQUIDITY` of it sits locked at `[.., fee_tier, b"locked_lp"]`.
+    pub lp_mint: Pubkey,
+    // Swap fee on the constant-product and stable curves, left in the reserves for LPs
+    pub fee_bps: u16,
+    // Protocol's share of `fee_bps` (bps of the fee), see `collect_protocol_fees`
+    pub protocol_fee_share_bps: u16,
+    // Protocol fees held in the vaults until collected; not part of the reserves
+    pub protocol_fees_a: u64,
+    pub protocol_fees_b: u64,
+    // Initial `fee_bps`, fixed as the pool's seed component (little-endian) so each
+    // pair can have one pool per fee tier
+    pub fee_tier: u16,
+    // Blocks swaps and new liquidity, see `set_pool_paused`
+    pub paused: bool,
+    // Set while a `flash_swap` callback runs, see `flash_swap`
+    pub locked: bool,
+    // Reserves the curve prices against, kept up to date by every pool instruction.
+    // Tokens sent straight to the vaults stay outside them until `sync` (or `skim`).
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    // Running sum of `spot_price(true)` × seconds (wrapping), as of `price_updated_at`
+    pub price_cumulative: u128,
+    pub price_updated_at: i64,
+    // `get_price` averages from this observation. Every `TWAP_WINDOW` the pending one
+    // replaces it, so the averaged span stays between one and two windows.
+    pub twap_checkpoint_cumulative: u128,
+    pub twap_checkpoint_at: i64,
+    pub pending_checkpoint_cumulative: u128,
+    pub pending_checkpoint_at: i64,
+    // Notice `admin_withdraw` must give, see `announce_admin_withdraw`
+    pub withdraw_timelock_secs: i64,
+    // vault-core vault whose stakers get `fee_rebate_share_bps` of the swap fee paid in
+    // `fee_rebate_mint`, see `set_fee_rebate`. Rebates sit in that mint's vault outside
+    // the reserves until `forward_fees`.
+    pub fee_rebate_vault: Pubkey,
+    pub fee_rebate_mint: Pubkey,
+    pub fee_rebate_share_bps: u16,
+    pub fee_rebates: u64,
+}
+
+/// An `admin_withdraw` announced by the pool authority, one per pool at
+/// `[b"pending_withdraw", pool]`
+#[account]
+pub struct PendingWithdraw {
+    pub pool: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    // Earliest time `admin_withdraw` may execute it
+    pub eta: i64,
+    pub bump: u8,
+}
+
+impl PendingWithdraw {
+    pub const LEN: usize = 8 + std::mem::size_of::<Self>();
+}
+
+/// AMM-wide settings, one PDA at `[b"amm_config"]`
+#[account]
+#[derive(Default)]
+pub struct AmmConfig {
+    pub admin: Pubkey,
+    pub bump: u8,
+    // Charged by `initialize_pool`; zero = free
+    pub creation_fee: u64,
+    // Token the fee is paid in; the default key means lamports
+    pub creation_fee_mint: Pubkey,
+    // Receives the fee: lamports directly, tokens into an account it owns
+    pub treasury: Pubkey,
+}
+
+impl AmmConfig {
+    pub const LEN: usize = 8 + std::mem::size_of::<Self>();
+
+    /// The config, or free pool creation while the PDA doesn't exist
+    pub fn load(info: &AccountInfo) -> Result<Self> {
+        if info.data_is_empty() {
+            return Ok(Self::default());
+        }
+        require_keys_eq!(*info.owner, crate::ID, ErrorCode::AccountOwnedByWrongProgram);
+        Self::try_deserialize(&mut &info.try_borrow_data()?[..])
+    }
+}
+
+/// Every pool ever created, in creation order, so routers can discover pools on-chain.
+/// One PDA at `[b"pool_registry"]`, created with the first pool and grown by each one
+/// after it. Entries are fixed-size: the `i`-th starts at byte `8 + 4 + i * PoolEntry::LEN`.
+#[account]
+pub struct PoolRegistry {
+    pub pools: Vec<PoolEntry>,
+}
+
+impl PoolRegistry {
+    pub const fn space(pool_count: usize) -> usize {
+        8 + 4 + pool_count * PoolEntry::LEN
+    }
+
+    /// Pools of the pair `mint_x`/`mint_y` (either order), one per fee tier
+    pub fn pools_for(&self, mint_x: Pubkey, mint_y: Pubkey) -> impl Iterator<Item = &PoolEntry> {
+        let (mint_a, mint_b) = pda::sorted_mints(mint_x, mint_y);
+        self.pools
+            .iter()
+            .filter(move |entry| entry.mint_a == mint_a && entry.mint_b == mint_b)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolEntry {
+    pub pool: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub fee_tier: u16,
+}
+
+impl PoolEntry {
+    pub const LEN: usize = 32 * 3 + 2;
+}
+
+/// `get_price` result; prices are Q64.64 `mint_b` per `mint_a`
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolPrice {
+    pub spot_price: u128,
+    pub twap_price: u128,
+    /// Seconds covered by `twap_price`
+    pub twap_window: i64,
+}
+
+impl Pool {
+    pub const LEN: usize = 8 + std::mem::size_of::<Self>();
+    pub const LP_DECIMALS: u8 = 9;
+    /// Metaplex symbol of every LP token
+    pub const LP_SYMBOL: &'static str = "MAMM-LP";
+    /// Leading base58 characters of each mint in the LP token's name
+    pub const LP_NAME_MINT_CHARS: usize = 4;
+    /// LP locked in `locked_lp_token_account` when the pool is seeded
+    pub const MINIMUM_LIQUIDITY: u64 = 1_000;
+    /// Minimum span of the TWAP reported by `get_price`, once the pool is old enough
+    pub const TWAP_WINDOW: i64 = 30 * 60;
+    pub const MAX_FEE_BPS: u16 = 1_000;
+    pub const MAX_PROTOCOL_FEE_SHARE_BPS: u16 = 5_000;
+
+    /// Book the protocol's share of the swap fee on `amount_in`, and the stakers'
+    /// rebate when the input is the rebate mint. Both stay in the input vault but
+    /// outside the reserves; returns their sum.
+    pub fn accrue_protocol_fee(&mut self, a_to_b: bool, amount_in: u64) -> Result<u64> {
+        let fee = swap_fee(&self.curve, self.fee_bps, amount_in) as u128;
+        let protocol_fee = (fee * self.protocol_fee_share_bps as u128 / 10_000) as u64;
+        let (protocol_fees, mint_in) = if a_to_b {
+            (&mut self.protocol_fees_a, self.mint_a)
+        } else {
+            (&mut self.protocol_fees_b, self.mint_b)
+        };
+        *protocol_fees = protocol_fees
+            .checked_add(protocol_fee)
+            .ok_or(AmmError::MathOverflow)?;
+
+        let mut rebate = 0;
+        if mint_in == self.fee_rebate_mint {
+            rebate = (fee * self.fee_rebate_share_bps as u128 / 10_000) as u64;
+            self.fee_rebates = self
+                .fee_rebates
+                .checked_add(rebate)
+                .ok_or(AmmError::MathOverflow)?;
+        }
+        Ok(protocol_fee + rebate)
+    }
+
+    /// Price `amount_in` (already in the input vault) against the reserves and book the
+    /// trade: the input less the protocol's fee share joins its reserve and the
+    /// returned output leaves the other
+    pub fn apply_swap(&mut self, a_to_b: bool, amount_in: u64) -> Result<u64> {
+        self.accumulate_price()?;
+        let (reserve_in, reserve_out) = if a_to_b {
+            (self.reserve_a, self.reserve_b)
+        } else {
+            (self.reserve_b, self.reserve_a)
+        };
+        require!(reserve_in > 0 && reserve_out > 0, AmmError::InsufficientLiquidity);
+        let amount_out =
+            calculate_amount_out(&self.curve, self.fee_bps, amount_in, reserve_in, reserve_out)?;
+        require!(amount_out > 0, AmmError::InvalidAmount);
+
+        let protocol_fee = self.accrue_protocol_fee(a_to_b, amount_in)?;
+        let reserve_in = reserve_in
+            .checked_add(amount_in - protocol_fee)
+            .ok_or(AmmError::MathOverflow)?;
+        let reserve_out = reserve_out
+            .checked_sub(amount_out)
+            .ok_or(AmmError::InsufficientLiquidity)?;
+        (self.reserve_a, self.reserve_b) = if a_to_b {
+            (reserve_in, reserve_out)
+        } else {
+            (reserve_out, reserve_in)
+        };
+        Ok(amount_out)
+    }
+
+    fn start_price_oracle(&mut self) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        self.price_updated_at = now;
+        self.twap_checkpoint_at = now;
+        self.pending_checkpoint_at = now;
+        Ok(())
+    }
+
+    /// Bring the price accumulator up to now at the current reserves. Called before
+    /// any reserve change so each price is weighted by how long it held.
+    pub fn accumulate_price(&mut self) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        self.price_cumulative = self.price_cumulative_at(now)?;
+        self.price_updated_at = now;
+        if now - self.pending_checkpoint_at >= Self::TWAP_WINDOW {
+            self.twap_checkpoint_cumulative = self.pending_checkpoint_cumulative;
+            self.twap_checkpoint_at = self.pending_checkpoint_at;
+            self.pending_checkpoint_cumulative = self.price_cumulative;
+            self.pending_checkpoint_at = now;
+        }
+        Ok(())
+    }
+
+    /// `price_cumulative` extended to `now` at the current spot price
+    pub fn price_cumulative_at(&self, now: i64) -> Result<u128> {
+        let elapsed = now - self.price_updated_at;
+        if elapsed <= 0 || self.reserve_a == 0 || self.reserve_b == 0 {
+            return Ok(self.price_cumulative);
+        }
+        Ok(self
+            .price_cumulative
+            .wrapping_add(self.spot_price(true)?.wrapping_mul(elapsed as u128)))
+    }
+
+    /// Marginal price of the input token in output tokens at the current reserves, as a
+    /// Q64.64 fixed-point number, before fees. Selling into the pool only lowers it.
+    pub fn spot_price(&self, a_to_b: bool) -> Result<u128> {
+        let (reserve_in, reserve_out) = if a_to_b {
+            (self.reserve_a as u128, self.reserve_b as u128)
+        } else {
+            (self.reserve_b as u128, self.reserve_a as u128)
+        };
+        require!(reserve_in > 0 && reserve_out > 0, AmmError::InsufficientLiquidity);
+        match self.curve {
+            CurveType::ConstantProduct => q64_div(reserve_out, reserve_in),
+            CurveType::ConstantSum => Ok(1 << 64),
+            CurveType::Stable { amp } => {
+                // -dy/dx of the invariant: y·(k·x + D²) / (x·(k·y + D²)) with k = 4·Ann·x·y / D
+                let ann = amp as u128 * 4;
+                let d = stable_invariant(reserve_in, reserve_out, ann)?;
+                let k = (reserve_in * reserve_out)
+                    .checked_mul(ann * 4)
+                    .ok_or(AmmError::MathOverflow)?
+                    / d;
+                let d_squared = d.checked_mul(d).ok_or(AmmError::MathOverflow)?;
+                let mut numerator = k
+                    .checked_mul(reserve_in)
+                    .and_then(|v| v.checked_add(d_squared))
+                    .ok_or(AmmError::MathOverflow)?;
+                let mut denominator = k
+                    .checked_mul(reserve_out)
+                    .and_then(|v| v.checked_add(d_squared))
+                    .ok_or(AmmError::MathOverflow)?;
+                // Only the ratio matters; scale both into u64 so the reserves fit alongside
+                let shift = 64u32.saturating_sub(numerator.max(denominator).leading_zeros());
+                numerator >>= shift;
+                denominator >>= shift;
+                q64_div(reserve_out * numerator, reserve_in * denominator)
+            }
+        }
+    }
+
+    /// Vault balances without uncollected protocol fees and unforwarded rebates: the
+    /// reserves plus anything sent to the vaults directly
+    pub fn balances(&self, vault_a_amount: u64, vault_b_amount: u64) -> (u64, u64) {
+        let (mut held_a, mut held_b) = (self.protocol_fees_a, self.protocol_fees_b);
+        if self.fee_rebate_mint == self.mint_a {
+            held_a = held_a.saturating_add(self.fee_rebates);
+        } else if self.fee_rebate_mint == self.mint_b {
+            held_b = held_b.saturating_add(self.fee_rebates);
+        }
+        (
+            vault_a_amount.saturating_sub(held_a),
+            vault_b_amount.saturating_sub(held_b),
+        )
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(initial_amount_a: u64, initial_amount_b: u64, curve: CurveType, fee_bps: u16)]
+pub struct InitializePool<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = Pool::LEN,
+        seeds = [b"pool", mint_a.key().as_ref(), mint_b.key().as_ref(), &fee_bps.to_le_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub mint_a: InterfaceAccount<'info, Mint>,
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for pool token accounts
+    #[account(
+        seeds = [
+            b"pool",
+            mint_a.key().as_ref(),
+            mint_b.key().as_ref(),
+            &fee_bps.to_le_bytes(),
+            b"authority"
+        ],
+        bump
+    )]
+    pub pool_authority: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        mint::decimals = Pool::LP_DECIMALS,
+        mint::authority = pool_authority,
+        seeds = [
+            b"pool",
+            mint_a.key().as_ref(),
+            mint_b.key().as_ref(),
+            &fee_bps.to_le_bytes(),
+            b"lp_mint"
+        ],
+        bump
+    )]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(mut)]
+    pub authority_token_account_a: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut)]
+    pub authority_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+    /// Receives the LP tokens for the seed liquidity
+    #[account(
+        init,
+        payer = authority,
+        associated_token::mint = lp_mint,
+        associated_token::authority = authority
+    )]
+    pub authority_lp_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: owner of every pool's locked LP; nothing ever signs for it
+    #[account(seeds = [b"lp_burn"], bump)]
+    pub lp_burn_authority: UncheckedAccount<'info>,
+
+    /// Holds the `Pool::MINIMUM_LIQUIDITY` LP locked at init
+    #[account(
+        init,
+        payer = authority,
+        token::mint = lp_mint,
+        token::authority = lp_burn_authority,
+        seeds = [
+            b"pool",
+            mint_a.key().as_ref(),
+            mint_b.key().as_ref(),
+            &fee_bps.to_le_bytes(),
+            b"locked_lp"
+        ],
+        bump
+    )]
+    pub locked_lp_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    /// LP mint's token program
+    pub token_program: Program<'info, Token>,
+    /// Token program of `mint_a` (SPL Token or Token-2022)
+    pub token_program_a: Interface<'info, TokenInterface>,
+    /// Token program of `mint_b`
+    pub token_program_b: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: AMM config PDA, may be uninitialized (see `AmmConfig::load`)
+    #[account(seeds = [b"amm_config"], bump)]
+    pub config: UncheckedAccount<'info>,
+
+    /// Created with the first pool; `initialize_pool` grows it for each new entry
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = PoolRegistry::space(0),
+        seeds = [b"pool_registry"],
+        bump
+    )]
+    pub pool_registry: Account<'info, PoolRegistry>,
+
+    /// CHECK: the LP mint's Metaplex metadata PDA, created by the metadata program
+    #[account(
+        mut,
+        seeds = [b"metadata", token_metadata_program.key().as_ref(), lp_mint.key().as_ref()],
+        seeds::program = token_metadata_program.key(),
+        bump
+    )]
+    pub lp_metadata: UncheckedAccount<'info>,
+    pub token_metadata_program: Program<'info, Metadata>,
+    pub rent: Sysvar<'info, Rent>,
+
+    /// CHECK: `config.treasury`, for a creation fee in lamports (checked in `pay_creation_fee`)
+    #[account(mut)]
+    pub treasury: Option<UncheckedAccount<'info>>,
+
+    /// For a creation fee in tokens: `config.creation_fee_mint`, the creator's account
+    /// paying it and the treasury's account receiving it
+    pub fee_mint: Option<InterfaceAccount<'info, Mint>>,
+    #[account(mut)]
+    pub authority_fee_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut)]
+    pub treasury_fee_token_account: Option<InterfaceAccount<'info, TokenAccount>>,
+    pub fee_token_program: Option<Interface<'info, TokenInterface>>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(
+        init,
+        payer = upgrade_authority,
+        space = AmmConfig::LEN,
+        seeds = [b"amm_config"],
+        bump
+    )]
+    pub config: Account<'info, AmmConfig>,
+
+    #[account(mut)]
+    pub upgrade_authority: Signer<'info>,
+
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()) @ AmmError::Unauthorized)]
+    pub program: Program<'info, crate::program::MockAmm>,
+
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(upgrade_authority.key())
+            @ AmmError::Unauthorized
+    )]
+    pub program_data: Account<'info, ProgramData>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetCreationFee<'info> {
+    #[account(mut, seeds = [b"amm_config"], bump = config.bump, has_one = admin)]
+    pub config: Account<'info, AmmConfig>,
+
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FlashSwap<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut, token::mint = pool.mint_a)]
+    pub user_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = pool.mint_b)]
+    pub user_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_a
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for pool token accounts
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority"
+        ],
+        bump
+    )]
+    pub pool_authority: UncheckedAccount<'info>,
+
+    /// CHECK: receives the `on_flash_swap` callback; repayment is checked afterwards
+    #[account(executable)]
+    pub callback_program: UncheckedAccount<'info>,
+
+    #[account(address = pool.mint_a)]
+    pub mint_a: InterfaceAccount<'info, Mint>,
+
+    #[account(address = pool.mint_b)]
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    /// Token program of `mint_a` (SPL Token or Token-2022)
+    pub token_program_a: Interface<'info, TokenInterface>,
+    /// Token program of `mint_b`
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Quote<'info> {
+    #[account(
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct SyncReserves<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(address = pool.vault_a)]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.vault_b)]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct Skim<'info> {
+    #[account(
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut, token::mint = pool.mint_a)]
+    pub to_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = pool.mint_b)]
+    pub to_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_a
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for pool token accounts
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority"
+        ],
+        bump
+    )]
+    pub pool_authority: UncheckedAccount<'info>,
+
+    #[account(address = pool.mint_a)]
+    pub mint_a: InterfaceAccount<'info, Mint>,
+
+    #[account(address = pool.mint_b)]
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    /// Token program of `mint_a` (SPL Token or Token-2022)
+    pub token_program_a: Interface<'info, TokenInterface>,
+    /// Token program of `mint_b`
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_token_in: InterfaceAccount<'info, TokenAccount>,
 
     #[account(mut)]
-    pub vault_b: Account<'info, TokenAccount>,
+    pub user_token_out: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_a
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
 
     /// CHECK: PDA authority for pool token accounts
     #[account(
-        seeds = [b"pool", mint_a.key().as_ref(), mint_b.key().as_ref(), b"authority"],
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority"
+        ],
         bump
     )]
     pub pool_authority: UncheckedAccount<'info>,
 
+    #[account(address = pool.mint_a)]
+    pub mint_a: InterfaceAccount<'info, Mint>,
+
+    #[account(address = pool.mint_b)]
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    /// Token program of `mint_a` (SPL Token or Token-2022)
+    pub token_program_a: Interface<'info, TokenInterface>,
+    /// Token program of `mint_b`
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RouteSwap<'info> {
+    pub user: Signer<'info>,
+
     #[account(mut)]
-    pub authority_token_account_a: Account<'info, TokenAccount>,
+    pub user_token_in: InterfaceAccount<'info, TokenAccount>,
+
     #[account(mut)]
-    pub authority_token_account_b: Account<'info, TokenAccount>,
+    pub user_token_out: InterfaceAccount<'info, TokenAccount>,
+}
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+#[derive(Accounts)]
+pub struct UpdatePool<'info> {
+    #[account(mut, has_one = authority)]
+    pub pool: Account<'info, Pool>,
+
+    pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct Swap<'info> {
+pub struct CollectProtocolFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump,
+        has_one = authority
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub authority: Signer<'info>,
+
+    #[account(mut, token::mint = pool.mint_a)]
+    pub fee_token_account_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = pool.mint_b)]
+    pub fee_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_a
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for pool token accounts
     #[account(
-        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority"
+        ],
         bump
     )]
+    pub pool_authority: UncheckedAccount<'info>,
+
+    #[account(address = pool.mint_a)]
+    pub mint_a: InterfaceAccount<'info, Mint>,
+
+    #[account(address = pool.mint_b)]
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    /// Token program of `mint_a` (SPL Token or Token-2022)
+    pub token_program_a: Interface<'info, TokenInterface>,
+    /// Token program of `mint_b`
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetFeeRebate<'info> {
+    #[account(mut, has_one = authority)]
+    pub pool: Account<'info, Pool>,
+
+    pub authority: Signer<'info>,
+
+    pub fee_rebate_vault: AccountLoader<'info, vault_core::Vault>,
+}
+
+#[derive(Accounts)]
+pub struct ForwardFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump,
+        has_one = fee_rebate_vault
+    )]
     pub pool: Account<'info, Pool>,
 
+    /// CHECK: the pool's rebate vault, validated by vault-core
+    #[account(mut)]
+    pub fee_rebate_vault: UncheckedAccount<'info>,
+
+    /// CHECK: PDA authority for pool token accounts; funds the rewards
+    #[account(
+        mut,
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority"
+        ],
+        bump
+    )]
+    pub pool_authority: UncheckedAccount<'info>,
+
+    /// The pool vault holding the rebates, in `pool.fee_rebate_mint`
+    #[account(
+        mut,
+        token::mint = pool.fee_rebate_mint,
+        constraint = pool_vault.key() == pool.vault_a || pool_vault.key() == pool.vault_b
+            @ AmmError::InvalidRebateVault
+    )]
+    pub pool_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// The rebate vault's reward token account, checked by vault-core
     #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.fee_rebate_mint)]
+    pub reward_mint: InterfaceAccount<'info, Mint>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+
+    /// CHECK: vault-core's protocol bounds PDA, checked by vault-core
+    pub protocol_config: UncheckedAccount<'info>,
+
+    pub vault_core_program: Program<'info, VaultCore>,
+}
+
+#[derive(Accounts)]
+pub struct AddLiquidity<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump,
+        has_one = lp_mint
+    )]
+    pub pool: Account<'info, Pool>,
+
     pub user: Signer<'info>,
 
+    #[account(mut, token::mint = pool.mint_a)]
+    pub user_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = pool.mint_b)]
+    pub user_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = lp_mint)]
+    pub user_lp_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_a
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
     #[account(mut)]
-    pub user_token_in: Account<'info, TokenAccount>,
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: PDA authority for pool token accounts and the LP mint
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority"
+        ],
+        bump
+    )]
+    pub pool_authority: UncheckedAccount<'info>,
+
+    #[account(address = pool.mint_a)]
+    pub mint_a: InterfaceAccount<'info, Mint>,
+
+    #[account(address = pool.mint_b)]
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    /// LP mint's token program
+    pub token_program: Program<'info, Token>,
+    /// Token program of `mint_a` (SPL Token or Token-2022)
+    pub token_program_a: Interface<'info, TokenInterface>,
+    /// Token program of `mint_b`
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(token_in: Pubkey)]
+pub struct AddLiquiditySingle<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump,
+        has_one = lp_mint
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut, token::mint = token_in)]
+    pub user_token_in: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = lp_mint)]
+    pub user_lp_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_a
+    )]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b
+    )]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
 
     #[account(mut)]
-    pub user_token_out: Account<'info, TokenAccount>,
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    /// CHECK: PDA authority for pool token accounts and the LP mint
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority"
+        ],
+        bump
+    )]
+    pub pool_authority: UncheckedAccount<'info>,
+
+    #[account(address = token_in)]
+    pub mint_in: InterfaceAccount<'info, Mint>,
+
+    /// LP mint's token program
+    pub token_program: Program<'info, Token>,
+    /// Token program of `mint_in`
+    pub token_program_in: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveLiquidity<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump,
+        has_one = lp_mint
+    )]
+    pub pool: Account<'info, Pool>,
+
+    pub user: Signer<'info>,
+
+    #[account(mut, token::mint = pool.mint_a)]
+    pub user_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = pool.mint_b)]
+    pub user_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = lp_mint)]
+    pub user_lp_token_account: InterfaceAccount<'info, TokenAccount>,
 
     #[account(
         mut,
         address = pool.vault_a
     )]
-    pub vault_a: Account<'info, TokenAccount>,
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
 
     #[account(
         mut,
         address = pool.vault_b
     )]
-    pub vault_b: Account<'info, TokenAccount>,
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
 
     /// CHECK: PDA authority for pool token accounts
     #[account(
-        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), b"authority"],
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority"
+        ],
         bump
     )]
     pub pool_authority: UncheckedAccount<'info>,
 
+    #[account(address = pool.mint_a)]
+    pub mint_a: InterfaceAccount<'info, Mint>,
+
+    #[account(address = pool.mint_b)]
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    /// LP mint's token program
     pub token_program: Program<'info, Token>,
+    /// Token program of `mint_a` (SPL Token or Token-2022)
+    pub token_program_a: Interface<'info, TokenInterface>,
+    /// Token program of `mint_b`
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct AnnounceAdminWithdraw<'info> {
+    #[account(has_one = authority)]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = PendingWithdraw::LEN,
+        seeds = [b"pending_withdraw", pool.key().as_ref()],
+        bump
+    )]
+    pub pending_withdraw: Account<'info, PendingWithdraw>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelAdminWithdraw<'info> {
+    #[account(has_one = authority)]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [b"pending_withdraw", pool.key().as_ref()],
+        bump = pending_withdraw.bump
+    )]
+    pub pending_withdraw: Account<'info, PendingWithdraw>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AdminWithdraw<'info> {
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &pool.fee_tier.to_le_bytes()],
+        bump,
+        has_one = authority
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [b"pending_withdraw", pool.key().as_ref()],
+        bump = pending_withdraw.bump
+    )]
+    pub pending_withdraw: Account<'info, PendingWithdraw>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(mut, token::mint = pool.mint_a)]
+    pub authority_token_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, token::mint = pool.mint_b)]
+    pub authority_token_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.vault_a)]
+    pub vault_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut, address = pool.vault_b)]
+    pub vault_b: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for pool token accounts
+    #[account(
+        seeds = [
+            b"pool",
+            pool.mint_a.as_ref(),
+            pool.mint_b.as_ref(),
+            &pool.fee_tier.to_le_bytes(),
+            b"authority"
+        ],
+        bump
+    )]
+    pub pool_authority: UncheckedAccount<'info>,
+
+    #[account(address = pool.mint_a)]
+    pub mint_a: InterfaceAccount<'info, Mint>,
+
+    #[account(address = pool.mint_b)]
+    pub mint_b: InterfaceAccount<'info, Mint>,
+
+    /// Token program of `mint_a` (SPL Token or Token-2022)
+    pub token_program_a: Interface<'info, TokenInterface>,
+    /// Token program of `mint_b`
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[event]
+pub struct AdminWithdrawAnnounced {
+    pub pool: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub eta: i64,
+}
+
+#[event]
+pub struct AdminWithdrawn {
+    pub pool: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct FeesForwarded {
+    pub pool: Pubkey,
+    pub vault: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ProtocolFeesCollected {
+    pub pool: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
 }
 
 #[error_code]
@@ -251,4 +2851,32 @@ pub enum AmmError {
     MathOverflow,
     #[msg("Division by zero")]
     DivisionByZero,
+    #[msg("Fee above the maximum")]
+    InvalidFee,
+    #[msg("Amplification outside 1..=MAX_AMP")]
+    InvalidAmp,
+    #[msg("Pool is paused")]
+    PoolPaused,
+    #[msg("Pool is not paused")]
+    PoolNotPaused,
+    #[msg("Pool is locked by a flash swap in progress")]
+    PoolLocked,
+    #[msg("Flash swap left the pool invariant below its starting value")]
+    FlashSwapNotRepaid,
+    #[msg("Pool mints must be in ascending order (mint_a < mint_b)")]
+    UnsortedMints,
+    #[msg("Route accounts don't form a chain of pools from token in to token out")]
+    InvalidRoute,
+    #[msg("Swap would move the pool price past the limit price")]
+    PriceLimitExceeded,
+    #[msg("Creation fee accounts missing or not the configured treasury")]
+    InvalidTreasury,
+    #[msg("Signer is not allowed to perform this action")]
+    Unauthorized,
+    #[msg("Timelock can only be raised")]
+    TimelockLowered,
+    #[msg("Timelock has not elapsed yet")]
+    TimelockNotElapsed,
+    #[msg("Rebate vault must stake the pool's LP token for rewards in a pool token")]
+    InvalidRebateVault,
 }
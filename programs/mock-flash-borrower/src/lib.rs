@@ -0,0 +1,201 @@
+use anchor_lang::prelude::*;
+use anchor_spl::token::{Token, TokenAccount, Transfer};
+use flash_loan_interface::{
+    borrower_pda, FlashLoanReceiver, OnFlashLoanAccounts, OnFlashLoanArgs, BORROWER_SEED,
+};
+
+declare_id!("AR46yL95JAWpg7eqThjAfgWt5grakoBocPxiZoT8YVhq");
+
+/// Flash-loan borrower for tests: repays the loan plus fee straight away, from either
+/// the signing borrower or its own borrower PDA. Also serves as a mock-amm flash-swap
+/// callback that pays back whatever the caller asked it to, and can try to borrow
+/// from vault-core through CPI.
+#[program]
+pub mod mock_flash_borrower {
+    use super::*;
+
+    pub fn on_flash_loan<'info>(
+        ctx: Context<'_, '_, '_, 'info, OnFlashLoan<'info>>,
+        amount: u64,
+        fee: u64,
+        initiator: Pubkey,
+    ) -> Result<()> {
+        let accounts = OnFlashLoanAccounts {
+            vault_token_account: ctx.accounts.vault_token_account.to_account_info(),
+            borrower_token_account: ctx.accounts.borrower_token_account.to_account_info(),
+            borrower: ctx.accounts.borrower.to_account_info(),
+            token_program: ctx.accounts.token_program.to_account_info(),
+        };
+        let args = OnFlashLoanArgs {
+            amount,
+            fee,
+            initiator,
+        };
+        Repayer::on_flash_loan(accounts, args, ctx.remaining_accounts)
+    }
+
+    /// Take two vault-core flash loans of `amount` from one instruction, through CPI.
+    /// The loans call back into `callback_program`, which repays them.
+    pub fn borrow_twice(ctx: Context<BorrowTwice>, amount: u64) -> Result<()> {
+        let accounts = &ctx.accounts;
+        let ix = vault_interface::instruction::flash_loan(
+            &vault_interface::accounts::FlashLoan {
+                vault: accounts.vault.key(),
+                vault_token_account: accounts.vault_token_account.key(),
+                reward_vault: accounts.reward_vault.key(),
+                vault_authority: accounts.vault_authority.key(),
+                borrower: accounts.borrower.key(),
+                borrower_token_account: accounts.borrower_token_account.key(),
+                callback_program: accounts.callback_program.key(),
+            },
+            amount,
+            vec![],
+        );
+        let account_infos = [
+            accounts.vault.to_account_info(),
+            accounts.vault_token_account.to_account_info(),
+            accounts.reward_vault.to_account_info(),
+            accounts.vault_authority.to_account_info(),
+            accounts.borrower.to_account_info(),
+            accounts.borrower_token_account.to_account_info(),
+            accounts.callback_program.to_account_info(),
+            accounts.token_program.to_account_info(),
+            accounts.system_program.to_account_info(),
+            accounts.instructions_sysvar.to_account_info(),
+            accounts.vault_program.to_account_info(),
+        ];
+        for _ in 0..2 {
+            vault_interface::invoke_vault(&ix, &account_infos, &[])?;
+        }
+        Ok(())
+    }
+
+    /// mock-amm `flash_swap` callback. `data` is the Borsh-encoded `(repay_a, repay_b)`
+    /// to send back to the pool vaults.
+    pub fn on_flash_swap(
+        ctx: Context<OnFlashSwap>,
+        amount_a_out: u64,
+        amount_b_out: u64,
+        _initiator: Pubkey,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let (repay_a, repay_b) = <(u64, u64)>::try_from_slice(&data)?;
+        msg!(
+            "Borrowed {}/{}, repaying {}/{}",
+            amount_a_out,
+            amount_b_out,
+            repay_a,
+            repay_b
+        );
+        let accounts = &ctx.accounts;
+        for (from, to, amount) in [
+            (&accounts.borrower_token_a, &accounts.vault_a, repay_a),
+            (&accounts.borrower_token_b, &accounts.vault_b, repay_b),
+        ] {
+            if amount == 0 {
+                continue;
+            }
+            let cpi_accounts = Transfer {
+                from: from.to_account_info(),
+                to: to.to_account_info(),
+                authority: accounts.borrower.to_account_info(),
+            };
+            anchor_spl::token::transfer(
+                CpiContext::new(accounts.token_program.to_account_info(), cpi_accounts),
+                amount,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Repayer;
+
+impl FlashLoanReceiver for Repayer {
+    fn on_flash_loan<'info>(
+        accounts: OnFlashLoanAccounts<'info>,
+        args: OnFlashLoanArgs,
+        _remaining_accounts: &[AccountInfo<'info>],
+    ) -> Result<()> {
+        msg!("Repaying {} + {} fee", args.amount, args.fee);
+        if accounts.borrower.is_signer {
+            return flash_loan_interface::repay(&accounts, &args);
+        }
+        let (pda, bump) = borrower_pda(&crate::ID);
+        require_keys_eq!(accounts.borrower.key(), pda, ErrorCode::AccountNotSigner);
+        flash_loan_interface::repay_signed(&accounts, &args, &[&[BORROWER_SEED, &[bump]]])
+    }
+}
+
+#[derive(Accounts)]
+pub struct OnFlashLoan<'info> {
+    #[account(mut)]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub borrower_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: a signer, or this program's borrower PDA (checked by `Repayer`)
+    #[account(mut)]
+    pub borrower: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct BorrowTwice<'info> {
+    /// CHECK: passed through to vault-core `flash_loan`, which checks it
+    #[account(mut)]
+    pub vault: UncheckedAccount<'info>,
+
+    /// CHECK: passed through to vault-core `flash_loan`
+    #[account(mut)]
+    pub vault_token_account: UncheckedAccount<'info>,
+
+    /// CHECK: passed through to vault-core `flash_loan`
+    pub reward_vault: UncheckedAccount<'info>,
+
+    /// CHECK: passed through to vault-core `flash_loan`
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub borrower: Signer<'info>,
+
+    /// CHECK: passed through to vault-core `flash_loan`
+    #[account(mut)]
+    pub borrower_token_account: UncheckedAccount<'info>,
+
+    /// CHECK: the loans' callback, passed through to vault-core `flash_loan`
+    pub callback_program: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: instructions sysvar, passed through to vault-core `flash_loan`
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions_sysvar: UncheckedAccount<'info>,
+
+    /// CHECK: vault-core, the program the loans are taken from
+    #[account(address = vault_interface::ID)]
+    pub vault_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct OnFlashSwap<'info> {
+    /// The `flash_swap` signer, still a signer inside the callback
+    pub borrower: Signer<'info>,
+
+    #[account(mut)]
+    pub borrower_token_a: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub borrower_token_b: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub vault_a: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub vault_b: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
@@ -0,0 +1,121 @@
+use anchor_lang::prelude::*;
+use spl_discriminator::SplDiscriminate;
+use spl_tlv_account_resolution::account::ExtraAccountMeta;
+use spl_tlv_account_resolution::seeds::Seed;
+use spl_tlv_account_resolution::state::ExtraAccountMetaList;
+use spl_transfer_hook_interface::instruction::{
+    ExecuteInstruction, InitializeExtraAccountMetaListInstruction,
+};
+
+declare_id!("G7ZXVk51v7TtD2TiFGDTX5v8Gzi5V68D2p4drAuKPXdA");
+
+/// Token-2022 transfer hook for tests: counts every transfer of a mint in a
+/// per-mint PDA, which transfers must pass as an extra account, and rejects
+/// transfers above the mint's `max_amount`.
+#[program]
+pub mod mock_transfer_hook {
+    use super::*;
+
+    /// Create the mint's extra-account-meta list (asking for its counter PDA) and
+    /// the counter itself
+    #[instruction(discriminator = InitializeExtraAccountMetaListInstruction::SPL_DISCRIMINATOR_SLICE)]
+    pub fn initialize_extra_account_meta_list(
+        ctx: Context<InitializeExtraAccountMetaList>,
+        max_amount: u64,
+    ) -> Result<()> {
+        let mut data = ctx.accounts.extra_account_meta_list.try_borrow_mut_data()?;
+        ExtraAccountMetaList::init::<ExecuteInstruction>(&mut data, &extra_account_metas()?)?;
+        ctx.accounts.counter.set_inner(TransferCounter {
+            transfers: 0,
+            max_amount,
+        });
+        Ok(())
+    }
+
+    #[instruction(discriminator = ExecuteInstruction::SPL_DISCRIMINATOR_SLICE)]
+    pub fn transfer_hook(ctx: Context<TransferHook>, amount: u64) -> Result<()> {
+        let counter = &mut ctx.accounts.counter;
+        require!(amount <= counter.max_amount, HookError::AmountTooLarge);
+        counter.transfers += 1;
+        Ok(())
+    }
+}
+
+// Execute accounts: source (0), mint (1), destination (2), owner (3), meta list (4),
+// then the counter at `[b"counter", mint]`
+fn extra_account_metas() -> Result<Vec<ExtraAccountMeta>> {
+    Ok(vec![ExtraAccountMeta::new_with_seeds(
+        &[
+            Seed::Literal {
+                bytes: b"counter".to_vec(),
+            },
+            Seed::AccountKey { index: 1 },
+        ],
+        false,
+        true,
+    )?])
+}
+
+#[account]
+pub struct TransferCounter {
+    pub transfers: u64,
+    pub max_amount: u64,
+}
+
+impl TransferCounter {
+    pub const LEN: usize = 8 + 8 + 8;
+}
+
+#[derive(Accounts)]
+pub struct InitializeExtraAccountMetaList<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: the list's PDA, written by `ExtraAccountMetaList::init`
+    #[account(
+        init,
+        payer = payer,
+        space = ExtraAccountMetaList::size_of(1)?,
+        seeds = [b"extra-account-metas", mint.key().as_ref()],
+        bump
+    )]
+    pub extra_account_meta_list: UncheckedAccount<'info>,
+
+    /// CHECK: the hooked mint; only its key is used
+    pub mint: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = TransferCounter::LEN,
+        seeds = [b"counter", mint.key().as_ref()],
+        bump
+    )]
+    pub counter: Account<'info, TransferCounter>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct TransferHook<'info> {
+    /// CHECK: source token account
+    pub source: UncheckedAccount<'info>,
+    /// CHECK: the hooked mint
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: destination token account
+    pub destination: UncheckedAccount<'info>,
+    /// CHECK: source owner or delegate
+    pub owner: UncheckedAccount<'info>,
+    /// CHECK: checked by its seeds
+    #[account(seeds = [b"extra-account-metas", mint.key().as_ref()], bump)]
+    pub extra_account_meta_list: UncheckedAccount<'info>,
+
+    #[account(mut, seeds = [b"counter", mint.key().as_ref()], bump)]
+    pub counter: Account<'info, TransferCounter>,
+}
+
+#[error_code]
+pub enum HookError {
+    #[msg("Transfer amount above the mint's limit")]
+    AmountTooLarge,
+}
@@ -1,224 +1,526 @@
 use anchor_lang::prelude::*;
-use anchor_lang::solana_program::program::invoke;
-use anchor_spl::{token::{Mint, Token, TokenAccount, Transfer}, associated_token::AssociatedToken};
+use anchor_lang::solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE;
+use anchor_lang::solana_program::instruction::{get_stack_height, TRANSACTION_LEVEL_STACK_HEIGHT};
+use anchor_lang::solana_program::program::invoke_signed;
+use anchor_lang::solana_program::sysvar::instructions::load_instruction_at_checked;
+use anchor_spl::{token::{spl_token::native_mint, CloseAccount, Mint, SyncNative, Token, TokenAccount, Transfer}, associated_token::{get_associated_token_address, AssociatedToken}};
+use anchor_spl::token_interface::{self, TokenInterface, TransferChecked};
+use bytemuck::Zeroable;
+use flash_loan_interface::{OnFlashLoanAccounts, OnFlashLoanArgs};
+use solana_loader_v3_interface::get_program_data_address;
+use solana_program::hash::hashv;
+use solana_sdk_ids::bpf_loader_upgradeable;
 
 declare_id!("A4nGMAE6j5xty4a5PALzz7nYnWQcB59mYcLptZMoYkfN");
 
 // Precision scaling factor for reward calculations (1e12)
 pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
 
+// Scaling factor for `get_exchange_rate` (1e12)
+pub const EXCHANGE_RATE_PRECISION: u128 = 1_000_000_000_000;
+
+// Seconds per (365-day) year, used to pro-rate the annualized management fee
+pub const SECONDS_PER_YEAR: u128 = 365 * 24 * 60 * 60;
+
 #[program]
 pub mod vault_core {
     use super::*;
 
     pub fn initialize_vault(ctx: Context<InitializeVault>) -> Result<()> {
-        let clock = Clock::get()?;
-        let vault = &mut ctx.accounts.vault;
-        vault.authority = ctx.accounts.authority.key();
-        vault.token_mint = ctx.accounts.token_mint.key();
-        vault.total_shares = 0;
-        vault.reward_rate = 0;
-        vault.acc_reward_per_share = 0;
-        vault.last_update_ts = clock.unix_timestamp;
-        vault.reward_mint = ctx.accounts.reward_mint.key();
-        vault.reward_vault = ctx.accounts.reward_vault.key();
-        // Initialize flash loan fields
-        vault.flash_fee_bps = 0;
-        vault.fee_treasury = Pubkey::default();
-        vault.callback_allowlist_enabled = false;
-        vault.callback_allowlist = Vec::new();
-        Ok(())
+        let accounts = ctx.accounts;
+        init_vault(
+            &accounts.vault,
+            &mut accounts.vault_entry,
+            ctx.bumps.vault_entry,
+            accounts.authority.key(),
+            accounts.token_mint.key(),
+            accounts.reward_mint.key(),
+            accounts.reward_vault.key(),
+            &accounts.protocol_config,
+            0,
+        )
     }
 
-    pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
-        require!(amount > 0, VaultError::InvalidAmount);
-
-        let vault = &mut ctx.accounts.vault;
-        let vault_token_account = &ctx.accounts.vault_token_account;
-        let user_position = &mut ctx.accounts.user_position;
+    /// Open another vault for a mint that already has one, e.g. a v2 vault operators
+    /// roll out next to the original. `generation` (1-255) is an extra seed of the
+    /// vault and its authority, so each generation has its own token accounts; the
+    /// original vault is generation 0. Positions move over with
+    /// `migrate_position_to_vault`.
+    pub fn initialize_vault_generation(
+        ctx: Context<InitializeVaultGeneration>,
+        generation: u8,
+    ) -> Result<()> {
+        require!(generation > 0, VaultError::InvalidGeneration);
+        let accounts = ctx.accounts;
+        init_vault(
+            &accounts.vault,
+            &mut accounts.vault_entry,
+            ctx.bumps.vault_entry,
+            accounts.authority.key(),
+            accounts.token_mint.key(),
+            accounts.reward_mint.key(),
+            accounts.reward_vault.key(),
+            &accounts.protocol_config,
+            generation,
+        )
+    }
 
-        // Verify token mint matches
-        require!(
-            vault.token_mint == vault_token_account.mint,
-            VaultError::InvalidTokenMint
+    /// Move the signer's whole position from `src` to `dst`, another vault of the
+    /// same mint: withdraw every share from `src` and deposit the tokens into `dst`
+    /// in one instruction. The source position's pending rewards are settled into
+    /// its `rewards_owed` and stay claimable from `src`. Fails with
+    /// `SlippageExceeded` if the deposit mints fewer than `min_shares_out` shares.
+    pub fn migrate_position_to_vault(
+        ctx: Context<MigratePositionToVault>,
+        min_shares_out: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        require_keys_neq!(
+            accounts.src.vault.key(),
+            accounts.dst.vault.key(),
+            VaultError::InvalidVault
         );
-        require!(
-            vault.token_mint == ctx.accounts.user_token_account.mint,
+        require_keys_eq!(
+            accounts.src.user_token_account.key(),
+            accounts.dst.user_token_account.key(),
             VaultError::InvalidTokenMint
         );
-
-        // Update rewards before processing deposit
-        let clock = Clock::get()?;
-        update_rewards(vault, clock.unix_timestamp)?;
-
-        // Initialize or update user position
-        // Protect against re-initialization: if account exists, verify it matches
-        let is_new_position = user_position.shares == 0;
-        if !is_new_position {
-            // Account already exists - verify it matches
+        {
+            let src = accounts.src.vault.load()?;
+            let dst = accounts.dst.vault.load()?;
+            require_keys_eq!(src.token_mint, dst.token_mint, VaultError::InvalidTokenMint);
             require!(
-                user_position.user == ctx.accounts.user.key(),
-                VaultError::InvalidUserPosition
+                !src.is_dual_stake() && !dst.is_dual_stake(),
+                VaultError::DualStakeRequired
             );
-            require!(user_position.vault == vault.key(), VaultError::InvalidVault);
-        } else {
-            // New account - initialize fields
-            user_position.user = ctx.accounts.user.key();
-            user_position.vault = vault.key();
-            user_position.reward_debt = 0;
         }
 
-        // Get current vault balance
-        let vault_balance = vault_token_account.amount;
-
-        // Calculate shares to mint
-        let shares = calculate_shares_for_deposit(amount, vault_balance, vault.total_shares)?;
-
-        require!(shares > 0, VaultError::InvalidAmount);
-
-        // Transfer tokens from user to vault
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.user_token_account.to_account_info(),
-            to: vault_token_account.to_account_info(),
-            authority: ctx.accounts.user.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        anchor_spl::token::transfer(cpi_ctx, amount)?;
-
-        // Update state
-        vault.total_shares = vault
-            .total_shares
-            .checked_add(shares)
-            .ok_or(VaultError::MathOverflow)?;
-
-        user_position.shares = user_position
-            .shares
-            .checked_add(shares)
-            .ok_or(VaultError::MathOverflow)?;
-
-        // Update reward_debt: user's new debt = new_shares * acc_reward_per_share (stored scaled)
-        let new_shares = user_position.shares;
-        user_position.reward_debt = (new_shares as u128)
-            .checked_mul(vault.acc_reward_per_share)
-            .ok_or(VaultError::MathOverflow)?;
+        let shares = u64::try_from(accounts.src.user_position.shares)
+            .map_err(|_| VaultError::MathOverflow)?;
+        let tokens = process_withdraw(
+            &mut accounts.src,
+            ctx.bumps.src.vault_authority,
+            shares,
+            0,
+        )?;
+        process_deposit(
+            accounts.dst.deposit_accounts(),
+            &[],
+            ctx.remaining_accounts.first(),
+            tokens,
+            min_shares_out,
+            &proof,
+        )
+    }
 
+    /// Set the vault's display name and metadata URI on its registry entry, resizing the
+    /// entry to fit the URI (the authority pays or is refunded the rent difference).
+    pub fn set_metadata(ctx: Context<SetMetadata>, name: [u8; 32], metadata_uri: String) -> Result<()> {
+        let entry = &mut ctx.accounts.vault_entry;
+        entry.name = name;
+        entry.metadata_uri = metadata_uri;
         Ok(())
     }
 
-    pub fn withdraw(ctx: Context<Withdraw>, shares: u64) -> Result<()> {
-        require!(shares > 0, VaultError::InvalidAmount);
+    /// Permissionless: create the registry entry for a vault initialized before
+    /// `initialize_vault` created them. `created_ts` is the registration time.
+    pub fn register_vault(ctx: Context<RegisterVault>) -> Result<()> {
+        let vault = ctx.accounts.vault.load()?;
+        ctx.accounts.vault_entry.set_inner(VaultEntry {
+            vault: ctx.accounts.vault.key(),
+            token_mint: vault.token_mint,
+            reward_mint: vault.reward_mint,
+            created_ts: Clock::get()?.unix_timestamp,
+            bump: ctx.bumps.vault_entry,
+            name: [0; 32],
+            metadata_uri: String::new(),
+        });
+        Ok(())
+    }
 
-        let vault = &mut ctx.accounts.vault;
-        let user_position = &mut ctx.accounts.user_position;
-        let vault_token_account = &ctx.accounts.vault_token_account;
+    /// `proof` is only checked when the vault has a depositor allowlist
+    /// (`allowlist_root` set); pass an empty vec for open vaults.
+    ///
+    /// Vaults with a USD deposit cap also need the oracle account as the first
+    /// remaining account.
+    ///
+    /// Fails with `SlippageExceeded` if `amount` mints fewer than `min_shares_out`
+    /// shares; pass 0 to accept any share price.
+    pub fn deposit(
+        ctx: Context<Deposit>,
+        amount: u64,
+        min_shares_out: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        require!(
+            !ctx.accounts.vault.load()?.is_dual_stake(),
+            VaultError::DualStakeRequired
+        );
+        process_deposit(
+            ctx.accounts.deposit_accounts(),
+            &[],
+            ctx.remaining_accounts.first(),
+            amount,
+            min_shares_out,
+            &proof,
+        )
+    }
 
-        // Verify user position matches
-        require!(user_position.vault == vault.key(), VaultError::InvalidVault);
+    /// `deposit` without the user's signature: the user has approved the vault authority
+    /// as delegate on `user_token_account` (SPL `approve`), and anyone, typically a
+    /// keeper or relayer, pulls `amount` from it into the user's position. The caller
+    /// pays for a new position. Allowlist proof, oracle and `min_shares_out` work as
+    /// in `deposit`.
+    pub fn deposit_with_delegate(
+        ctx: Context<DepositWithDelegate>,
+        amount: u64,
+        min_shares_out: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        let (token_mint, generation) = {
+            let vault = accounts.vault.load()?;
+            require!(!vault.is_dual_stake(), VaultError::DualStakeRequired);
+            (vault.token_mint, vault.generation)
+        };
+        let delegate: Option<Pubkey> = accounts.user_token_account.delegate.into();
         require!(
-            user_position.user == ctx.accounts.user.key(),
-            VaultError::InvalidVault
+            delegate == Some(accounts.vault_authority.key())
+                && accounts.user_token_account.delegated_amount >= amount,
+            VaultError::DelegateNotApproved
         );
 
-        // Verify sufficient shares
+        let seeds = &[
+            b"vault",
+            token_mint.as_ref(),
+            b"authority",
+            generation_seed(&generation),
+            &[ctx.bumps.vault_authority],
+        ];
+        process_deposit(
+            DepositAccounts {
+                vault: &accounts.vault,
+                user_position: &mut accounts.user_position,
+                user: accounts.user.key(),
+                user_token_account: &accounts.user_token_account,
+                vault_token_account: &accounts.vault_token_account,
+                token_program: &accounts.token_program,
+                transfer_authority: accounts.vault_authority.to_account_info(),
+                user_checkpoints: accounts.user_checkpoints.as_mut(),
+            },
+            &[&seeds[..]],
+            ctx.remaining_accounts.first(),
+            amount,
+            min_shares_out,
+            &proof,
+        )
+    }
+
+    /// Fails with `SlippageExceeded` if `shares` redeem for fewer than
+    /// `min_tokens_out` tokens; pass 0 to accept any share price.
+    pub fn withdraw(ctx: Context<Withdraw>, shares: u64, min_tokens_out: u64) -> Result<()> {
         require!(
-            user_position.shares >= shares,
-            VaultError::InsufficientShares
+            !ctx.accounts.vault.load()?.is_dual_stake(),
+            VaultError::DualStakeRequired
         );
+        process_withdraw(
+            ctx.accounts,
+            ctx.bumps.vault_authority,
+            shares,
+            min_tokens_out,
+        )?;
+        Ok(())
+    }
 
-        // Update rewards before processing withdraw
-        let clock = Clock::get()?;
-        update_rewards(vault, clock.unix_timestamp)?;
+    /// Turn an empty vault into a dual-stake vault: every deposit must then bring
+    /// `ratio_b` units of `second_mint` per `ratio_a` units of the vault's token (see
+    /// `deposit_pair`), and withdrawals return both legs pro rata (`withdraw_pair`).
+    /// Shares, rewards, flash loans and strategies are all priced off the first leg.
+    pub fn configure_dual_stake(
+        ctx: Context<ConfigureDualStake>,
+        ratio_a: u64,
+        ratio_b: u64,
+    ) -> Result<()> {
+        require!(ratio_a > 0 && ratio_b > 0, VaultError::InvalidAmount);
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        require!(vault.total_shares == 0, VaultError::VaultNotEmpty);
+        require_keys_neq!(
+            ctx.accounts.second_mint.key(),
+            vault.token_mint,
+            VaultError::InvalidTokenMint
+        );
+        vault.second_mint = ctx.accounts.second_mint.key();
+        vault.pair_ratio_a = ratio_a;
+        vault.pair_ratio_b = ratio_b;
+        Ok(())
+    }
+
+    /// `deposit` for dual-stake vaults: `amount` of the first leg plus
+    /// `Vault::pair_amount(amount)` of the second, for shares priced off the first leg.
+    /// `min_shares_out` floors the shares minted, as in `deposit`.
+    pub fn deposit_pair(
+        ctx: Context<DepositPair>,
+        amount: u64,
+        min_shares_out: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let amount_b = {
+            let vault = ctx.accounts.deposit.vault.load()?;
+            require!(vault.is_dual_stake(), VaultError::NotDualStake);
+            check_second_leg_accounts(
+                &vault,
+                &ctx.accounts.deposit.vault_authority.key(),
+                &ctx.accounts.user_second_token_account,
+                &ctx.accounts.vault_second_token_account,
+            )?;
+            vault.pair_amount(amount)?
+        };
+
+        process_deposit(
+            ctx.accounts.deposit.deposit_accounts(),
+            &[],
+            ctx.remaining_accounts.first(),
+            amount,
+            min_shares_out,
+            &proof,
+        )?;
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.user_second_token_account.to_account_info(),
+            to: ctx.accounts.vault_second_token_account.to_account_info(),
+            authority: ctx.accounts.deposit.user.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.deposit.token_program.to_account_info();
+        anchor_spl::token::transfer(CpiContext::new(cpi_program, cpi_accounts), amount_b)
+    }
 
-        // Get current vault balance
-        let vault_balance = vault_token_account.amount;
+    /// `withdraw` for dual-stake vaults: also returns the shares' pro-rata part of the
+    /// second-leg balance. `min_tokens_out` floors the first leg, as in `withdraw`; the
+    /// second leg is paid at the same share price.
+    pub fn withdraw_pair(
+        ctx: Context<WithdrawPair>,
+        shares: u64,
+        min_tokens_out: u64,
+    ) -> Result<()> {
+        let (amount_b, token_mint, generation) = {
+            let vault = ctx.accounts.withdraw.vault.load()?;
+            require!(vault.is_dual_stake(), VaultError::NotDualStake);
+            check_second_leg_accounts(
+                &vault,
+                &ctx.accounts.withdraw.vault_authority.key(),
+                &ctx.accounts.user_second_token_account,
+                &ctx.accounts.vault_second_token_account,
+            )?;
+            let amount_b = calculate_tokens_for_withdraw(
+                shares as u128,
+                ctx.accounts.vault_second_token_account.amount,
+                vault.total_shares,
+            )?;
+            (amount_b, vault.token_mint, vault.generation)
+        };
 
-        // Calculate tokens to withdraw
-        let tokens = calculate_tokens_for_withdraw(shares, vault_balance, vault.total_shares)?;
+        let bump = ctx.bumps.withdraw.vault_authority;
+        process_withdraw(&mut ctx.accounts.withdraw, bump, shares, min_tokens_out)?;
 
-        // Transfer tokens from vault to user
         let seeds = &[
             b"vault",
-            vault.token_mint.as_ref(),
+            token_mint.as_ref(),
             b"authority",
-            &[ctx.bumps.vault_authority],
+            generation_seed(&generation),
+            &[bump],
         ];
         let signer = &[&seeds[..]];
-
         let cpi_accounts = Transfer {
-            from: vault_token_account.to_account_info(),
-            to: ctx.accounts.user_token_account.to_account_info(),
-            authority: ctx.accounts.vault_authority.to_account_info(),
+            from: ctx.accounts.vault_second_token_account.to_account_info(),
+            to: ctx.accounts.user_second_token_account.to_account_info(),
+            authority: ctx.accounts.withdraw.vault_authority.to_account_info(),
         };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
-        anchor_spl::token::transfer(cpi_ctx, tokens)?;
+        let cpi_program = ctx.accounts.withdraw.token_program.to_account_info();
+        anchor_spl::token::transfer(
+            CpiContext::new_with_signer(cpi_program, cpi_accounts, signer),
+            amount_b,
+        )
+    }
+
+    /// Withdraw the position's whole principal without touching reward math: no accrual,
+    /// no settlement. Pending rewards (including any IOU) are forfeited and the position
+    /// is zeroed, ready for `close_position`. Works while paused, so funds never sit
+    /// behind a broken reward path.
+    pub fn emergency_withdraw(ctx: Context<Withdraw>) -> Result<()> {
+        let accounts = ctx.accounts;
+        let vault = &mut accounts.vault.load_mut()?;
+        require!(!vault.is_dual_stake(), VaultError::DualStakeRequired);
+        let shares = accounts.user_position.shares;
+
+        let tokens = if shares > 0 {
+            calculate_tokens_for_withdraw(
+                shares,
+                vault.total_assets(accounts.vault_token_account.amount)?,
+                vault.total_shares,
+            )?
+        } else {
+            0
+        };
+
+        if tokens > 0 {
+            let seeds = &[
+                b"vault",
+                vault.token_mint.as_ref(),
+                b"authority",
+                vault.generation_seed(),
+                &[ctx.bumps.vault_authority],
+            ];
+            let signer = &[&seeds[..]];
+
+            let cpi_accounts = Transfer {
+                from: accounts.vault_token_account.to_account_info(),
+                to: accounts.user_token_account.to_account_info(),
+                authority: accounts.vault_authority.to_account_info(),
+            };
+            let cpi_program = accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            anchor_spl::token::transfer(cpi_ctx, tokens)?;
+        }
 
-        // Update state
         vault.total_shares = vault
             .total_shares
             .checked_sub(shares)
             .ok_or(VaultError::MathOverflow)?;
+        vault.lifetime_withdrawn = vault.lifetime_withdrawn.saturating_add(tokens);
+        msg!("Emergency withdraw: {} shares for {} tokens, rewards forfeited", shares, tokens);
+
+        let user_position = &mut accounts.user_position;
+        user_position.shares = 0;
+        user_position.reward_debt = 0;
+        set_rewards_owed(vault, user_position, 0);
+
+        record_checkpoint(
+            user_position,
+            accounts.user_checkpoints.as_mut(),
+            accounts.vault.key(),
+        )
+    }
 
-        // Calculate pending rewards BEFORE updating shares
-        // This uses the current (old) shares value
-        let total_owed_scaled_before_withdrawal = (user_position.shares as u128)
-            .checked_mul(vault.acc_reward_per_share)
-            .ok_or(VaultError::MathOverflow)?;
-        
-        let old_reward_debt = user_position.reward_debt;
-        let pending_rewards_scaled = total_owed_scaled_before_withdrawal
-            .saturating_sub(old_reward_debt);
-        
-        // Calculate new shares
-        let new_shares = user_position
-            .shares
-            .checked_sub(shares)
-            .ok_or(VaultError::MathOverflow)?;
-        
-        // Update shares
-        user_position.shares = new_shares;
-
-        // Update reward_debt based on new shares
-        if new_shares > 0 {
-            // Normal case: update reward_debt to match new shares
-            user_position.reward_debt = (new_shares as u128)
-                .checked_mul(vault.acc_reward_per_share)
-                .ok_or(VaultError::MathOverflow)?;
-        } else {
-            // Shares reached 0
-            if pending_rewards_scaled > 0 {
-                // Store the pending rewards amount directly in reward_debt
-                // This is a special case: when shares=0, reward_debt represents pending rewards (scaled)
-                // In claim_rewards, we'll detect shares=0 and use reward_debt directly as pending
-                user_position.reward_debt = pending_rewards_scaled;
-            } else {
-                // No pending rewards, set to 0
-                user_position.reward_debt = 0;
-            }
-        }
-
-        // Close account only if shares reach zero AND no pending rewards
-        // If there are pending rewards, account remains open for claiming
-        // After claiming, claim_rewards will close the account
-        if new_shares == 0 && pending_rewards_scaled == 0 {
-            // No pending rewards, safe to close
-            let user = ctx.accounts.user.to_account_info();
-            let user_position_account = ctx.accounts.user_position.to_account_info();
-            let dest_starting_lamports = user.lamports();
-            **user.lamports.borrow_mut() = dest_starting_lamports
-                .checked_add(user_position_account.lamports())
-                .ok_or(VaultError::MathOverflow)?;
-            **user_position_account.lamports.borrow_mut() = 0;
+    /// Permissionless: publish a position's `PositionIndex` entry so programs can walk
+    /// a vault's positions by index. `index` must be the position's own, or for a
+    /// position migrated without one, the vault's next index (which it then takes).
+    pub fn index_position(ctx: Context<IndexPosition>, index: u64) -> Result<()> {
+        let user_position = &mut ctx.accounts.user_position;
+        if user_position.index == UserPosition::UNINDEXED {
+            let mut vault = ctx.accounts.vault.load_mut()?;
+            require!(
+                index == vault.position_count,
+                VaultError::InvalidPositionIndex
+            );
+            user_position.index = vault.next_position_index()?;
         }
+        require!(
+            user_position.index == index,
+            VaultError::InvalidPositionIndex
+        );
+        ctx.accounts.position_index.set_inner(PositionIndex {
+            vault: ctx.accounts.vault.key(),
+            user: user_position.user,
+            index,
+            bump: ctx.bumps.position_index,
+        });
+        Ok(())
+    }
 
+    /// Close an emptied position (no shares, less than one reward token owed) and
+    /// return its rent to the user. Anchor's `close` zeroes the data and hands the
+    /// account back to the system program, so it can't be revived in the same tx.
+    pub fn close_position(ctx: Context<ClosePosition>) -> Result<()> {
+        let user_position = &ctx.accounts.user_position;
+        require!(
+            user_position.shares == 0 && user_position.rewards_owed < REWARD_PRECISION,
+            VaultError::PositionNotEmpty
+        );
         Ok(())
     }
 
+    /// `deposit` for native-mint vaults straight from lamports: wraps `amount` into the
+    /// user's WSOL ATA (created if missing), syncs it, then deposits as usual, including
+    /// the `min_shares_out` floor.
+    pub fn deposit_sol(
+        ctx: Context<DepositSol>,
+        amount: u64,
+        min_shares_out: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.user_wsol_account.key(),
+            ctx.accounts.deposit.user_token_account.key(),
+            VaultError::InvalidWsolAccount
+        );
+        require!(
+            !ctx.accounts.deposit.vault.load()?.is_dual_stake(),
+            VaultError::DualStakeRequired
+        );
+
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                anchor_lang::system_program::Transfer {
+                    from: ctx.accounts.user.to_account_info(),
+                    to: ctx.accounts.user_wsol_account.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        anchor_spl::token::sync_native(CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            SyncNative {
+                account: ctx.accounts.user_wsol_account.to_account_info(),
+            },
+        ))?;
+
+        process_deposit(
+            ctx.accounts.deposit.deposit_accounts(),
+            &[],
+            ctx.remaining_accounts.first(),
+            amount,
+            min_shares_out,
+            &proof,
+        )
+    }
+
+    /// `withdraw` for native-mint vaults that unwraps the proceeds: the user's WSOL ATA
+    /// is closed afterwards, so its whole balance (and rent) comes back as SOL.
+    /// `min_tokens_out` floors the unwrapped amount as in `withdraw`.
+    pub fn withdraw_sol(ctx: Context<WithdrawSol>, shares: u64, min_tokens_out: u64) -> Result<()> {
+        require_keys_eq!(
+            ctx.accounts.user_wsol_account.key(),
+            ctx.accounts.withdraw.user_token_account.key(),
+            VaultError::InvalidWsolAccount
+        );
+        require!(
+            !ctx.accounts.withdraw.vault.load()?.is_dual_stake(),
+            VaultError::DualStakeRequired
+        );
+
+        process_withdraw(
+            &mut ctx.accounts.withdraw,
+            ctx.bumps.withdraw.vault_authority,
+            shares,
+            min_tokens_out,
+        )?;
+
+        anchor_spl::token::close_account(CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            CloseAccount {
+                account: ctx.accounts.user_wsol_account.to_account_info(),
+                destination: ctx.accounts.user.to_account_info(),
+                authority: ctx.accounts.user.to_account_info(),
+            },
+        ))
+    }
+
     pub fn fund_rewards(ctx: Context<FundRewards>, amount: u64, reward_rate: u64) -> Result<()> {
         require!(amount > 0, VaultError::InvalidAmount);
 
-        let vault = &mut ctx.accounts.vault;
+        let vault = &mut ctx.accounts.vault.load_mut()?;
         let reward_vault = &ctx.accounts.reward_vault;
 
         // Validate reward_vault matches vault's reward_vault
@@ -238,645 +540,5542 @@ pub mod vault_core {
         );
 
         // Transfer tokens from funder to reward vault
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.funder_token_account.to_account_info(),
-            to: reward_vault.to_account_info(),
-            authority: ctx.accounts.funder.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        anchor_spl::token::transfer(cpi_ctx, amount)?;
+        let balance_before = reward_vault.amount;
+        transfer_reward(
+            &ctx.accounts.funder_token_account.to_account_info(),
+            &reward_vault.to_account_info(),
+            &ctx.accounts.funder.to_account_info(),
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.reward_token_program.to_account_info(),
+            &[],
+            amount,
+        )?;
+
+        // A Token-2022 transfer fee is withheld from the reward vault's balance; only
+        // budget what actually arrived
+        ctx.accounts.reward_vault.reload()?;
+        let received = ctx
+            .accounts
+            .reward_vault
+            .amount
+            .checked_sub(balance_before)
+            .ok_or(VaultError::MathOverflow)?;
+        vault.rewards_funded = vault
+            .rewards_funded
+            .checked_add(received as u128)
+            .ok_or(VaultError::MathOverflow)?;
 
-        // Update reward rate if provided
+        // Anyone may top up rewards; changing the emission rate needs the role
         if reward_rate > 0 {
+            require!(
+                vault.has_role(VaultRole::RewardManager, ctx.accounts.funder.key),
+                VaultError::MissingRole
+            );
+            let bounds = ProtocolConfig::load(&ctx.accounts.protocol_config)?;
+            require!(
+                reward_rate <= bounds.max_reward_rate,
+                VaultError::ExceedsProtocolBound
+            );
+            // Close out the old rate first; a new rate restarts the halving clock
+            let now = Clock::get()?.unix_timestamp;
+            accrue_rewards(vault, now)?;
             vault.reward_rate = reward_rate;
+            vault.next_halving_ts = now.saturating_add(vault.halving_interval_secs as i64);
         }
 
         Ok(())
     }
 
     pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
-        let vault = &mut ctx.accounts.vault;
-        let user_position = &mut ctx.accounts.user_position;
-        let reward_vault = &ctx.accounts.reward_vault;
+        let accounts = ctx.accounts;
+        process_claim_rewards(
+            &accounts.vault,
+            &mut accounts.user_position,
+            &accounts.user.to_account_info(),
+            &accounts.user_reward_token_account.to_account_info(),
+            &accounts.reward_vault,
+            &accounts.reward_mint,
+            &accounts.vault_authority.to_account_info(),
+            ctx.bumps.vault_authority,
+            &accounts.reward_token_program.to_account_info(),
+        )
+    }
 
-        // Validate reward_vault matches vault's reward_vault
-        require!(
-            vault.reward_vault == reward_vault.key(),
-            VaultError::RewardVaultMismatch
-        );
+    /// Claim a position's rewards and deposit them into `target`, the vault staking this
+    /// vault's reward mint, in one go. Both vaults accrue as in a separate claim and
+    /// deposit. Only what the claim actually paid is staked, so an IOU stays owed here.
+    /// `min_shares_out`, `proof` and remaining accounts are the target vault's, as in
+    /// `deposit`.
+    pub fn claim_and_stake(
+        ctx: Context<ClaimAndStake>,
+        min_shares_out: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        {
+            let target = accounts.target.vault.load()?;
+            require_keys_eq!(
+                target.token_mint,
+                accounts.reward_mint.key(),
+                VaultError::CompoundMintMismatch
+            );
+            require!(!target.is_dual_stake(), VaultError::DualStakeRequired);
+        }
 
-        // Validate reward mint matches
-        require!(
-            vault.reward_mint == reward_vault.mint,
-            VaultError::InvalidRewardMint
-        );
-        require!(
-            vault.reward_mint == ctx.accounts.user_reward_token_account.mint,
-            VaultError::InvalidRewardMint
-        );
+        let balance_before = accounts.target.user_token_account.amount;
+        process_claim_rewards(
+            &accounts.vault,
+            &mut accounts.user_position,
+            &accounts.target.user.to_account_info(),
+            &accounts.target.user_token_account.to_account_info(),
+            &accounts.reward_vault,
+            &accounts.reward_mint,
+            &accounts.vault_authority.to_account_info(),
+            ctx.bumps.vault_authority,
+            &accounts.reward_token_program.to_account_info(),
+        )?;
+        accounts.target.user_token_account.reload()?;
+        let claimed = accounts
+            .target
+            .user_token_account
+            .amount
+            .checked_sub(balance_before)
+            .ok_or(VaultError::MathOverflow)?;
 
-        // Verify user position matches
-        require!(user_position.vault == vault.key(), VaultError::InvalidVault);
-        require!(
-            user_position.user == ctx.accounts.user.key(),
-            VaultError::InvalidVault
-        );
+        process_deposit(
+            accounts.target.deposit_accounts(),
+            &[],
+            ctx.remaining_accounts.first(),
+            claimed,
+            min_shares_out,
+            &proof,
+        )
+    }
 
-        // Update rewards before calculating pending
-        let clock = Clock::get()?;
-        update_rewards(vault, clock.unix_timestamp)?;
-
-        // Calculate pending rewards
-        // Special case: if shares == 0, reward_debt stores pending_rewards_scaled (from withdraw)
-        // In this case, pending = reward_debt directly (since shares * acc_reward_per_share = 0)
-        let pending_scaled = if user_position.shares == 0 {
-            // When shares are 0, reward_debt stores the pending rewards amount (scaled)
-            // This was set in withdraw when shares became 0 and there were pending rewards
-            user_position.reward_debt
-        } else {
-            // Normal case: pending = (shares * acc_reward_per_share) - reward_debt
-            let total_owed_scaled = (user_position.shares as u128)
-                .checked_mul(vault.acc_reward_per_share)
-                .ok_or(VaultError::MathOverflow)?;
-            total_owed_scaled
-                .saturating_sub(user_position.reward_debt)
-        };
-        
-        let pending = pending_scaled
-            .checked_div(REWARD_PRECISION)
-            .ok_or(VaultError::DivisionByZero)?;
+    /// `claim_rewards` paid to any reward-mint token account the owner chooses
+    /// (treasury, tax wallet, smart account) instead of their own ATA.
+    pub fn claim_rewards_to(ctx: Context<ClaimRewardsTo>) -> Result<()> {
+        let accounts = ctx.accounts;
+        process_claim_rewards(
+            &accounts.vault,
+            &mut accounts.user_position,
+            &accounts.user.to_account_info(),
+            &accounts.recipient_token_account.to_account_info(),
+            &accounts.reward_vault,
+            &accounts.reward_mint,
+            &accounts.vault_authority.to_account_info(),
+            ctx.bumps.vault_authority,
+            &accounts.reward_token_program.to_account_info(),
+        )
+    }
 
-        // Transfer rewards if there are any pending
-        if pending > 0 {
-            let pending_u64 = pending.min(u64::MAX as u128) as u64;
-            
-            // Check sufficient balance in reward vault
-            require!(
-                reward_vault.amount >= pending_u64,
-                VaultError::InsufficientRewardBalance
-            );
+    /// Pay positions `min_bps` of their rewards when freshly staked, ramping linearly to
+    /// the full amount once their share-weighted stake age reaches `ramp_secs`. The
+    /// forfeited part returns to the unallocated budget. `ramp_secs = 0` disables it.
+    pub fn set_loyalty_boost(ctx: Context<SetLoyaltyBoost>, min_bps: u16, ramp_secs: u32) -> Result<()> {
+        require!(min_bps <= 10_000, VaultError::InvalidLoyaltyBoost);
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        vault.loyalty_min_bps = min_bps;
+        vault.loyalty_ramp_secs = ramp_secs;
+        Ok(())
+    }
 
-            // Transfer tokens from reward vault to user
-            let seeds = &[
-                b"vault",
-                vault.token_mint.as_ref(),
-                b"authority",
-                &[ctx.bumps.vault_authority],
-            ];
-            let signer = &[&seeds[..]];
+    /// Halve `reward_rate` every `interval_secs`, starting a full interval from now, so
+    /// emissions decay without the reward manager resetting rates by hand. Accrual
+    /// splits at each halving, so no time is lost between segments. `0` disables it.
+    pub fn set_reward_halving(ctx: Context<SetRewardHalving>, interval_secs: u32) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        accrue_rewards(vault, now)?;
+        vault.halving_interval_secs = interval_secs;
+        vault.next_halving_ts = now.saturating_add(interval_secs as i64);
+        Ok(())
+    }
 
-            let cpi_accounts = Transfer {
-                from: reward_vault.to_account_info(),
-                to: ctx.accounts.user_reward_token_account.to_account_info(),
-                authority: ctx.accounts.vault_authority.to_account_info(),
-            };
-            let cpi_program = ctx.accounts.token_program.to_account_info();
-            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
-            anchor_spl::token::transfer(cpi_ctx, pending_u64)?;
+    /// Return reward tokens that were funded but never accrued to stakers.
+    ///
+    /// Unallocated = funded − accrued; everything already accrued stays in the reward
+    /// vault for positions to claim. Emissions are stopped (`reward_rate = 0`) since
+    /// the vault no longer holds a budget for them.
+    pub fn withdraw_unallocated_rewards(ctx: Context<WithdrawUnallocatedRewards>) -> Result<()> {
+        let clock = Clock::get()?;
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        accrue_rewards(vault, clock.unix_timestamp)?;
 
-            // Update reward_debt: recalculate based on current shares (stored scaled)
-            if user_position.shares > 0 {
-                user_position.reward_debt = (user_position.shares as u128)
-                    .checked_mul(vault.acc_reward_per_share)
-                    .ok_or(VaultError::MathOverflow)?;
-            } else {
-                // Shares are 0, no more rewards can accrue, set reward_debt to 0
-                user_position.reward_debt = 0;
-                
-                // Close the account since rewards are claimed and shares are 0
-                let user = ctx.accounts.user.to_account_info();
-                let user_position_account = ctx.accounts.user_position.to_account_info();
-                let dest_starting_lamports = user.lamports();
-                **user.lamports.borrow_mut() = dest_starting_lamports
-                    .checked_add(user_position_account.lamports())
-                    .ok_or(VaultError::MathOverflow)?;
-                **user_position_account.lamports.borrow_mut() = 0;
-            }
-        }
+        let unallocated = vault.rewards_funded.saturating_sub(vault.rewards_accrued);
+        let amount = unallocated.min(ctx.accounts.reward_vault.amount as u128) as u64;
+        require!(amount > 0, VaultError::NoUnallocatedRewards);
+
+        let seeds = &[
+            b"vault",
+            vault.token_mint.as_ref(),
+            b"authority",
+            vault.generation_seed(),
+            &[ctx.bumps.vault_authority],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_reward(
+            &ctx.accounts.reward_vault.to_account_info(),
+            &ctx.accounts.authority_reward_token_account.to_account_info(),
+            &ctx.accounts.vault_authority.to_account_info(),
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.reward_token_program.to_account_info(),
+            signer,
+            amount,
+        )?;
+
+        vault.rewards_funded = vault
+            .rewards_funded
+            .checked_sub(amount as u128)
+            .ok_or(VaultError::MathOverflow)?;
+        vault.reward_rate = 0;
 
         Ok(())
     }
 
-    pub fn update_flash_loan_config(
-        ctx: Context<UpdateFlashLoanConfig>,
-        flash_fee_bps: Option<u16>,
-        fee_treasury: Option<Pubkey>,
-        callback_allowlist_enabled: Option<bool>,
-        callback_allowlist: Option<Vec<Pubkey>>,
+    /// Set the annualized management fee and the account whose position receives it.
+    ///
+    /// While a fee is active, the elapsed period must be collected first (bundle
+    /// `collect_management_fee` in the same transaction) so it isn't charged at the new rate.
+    /// Once the vault has a config timelock, use `propose_config_change` instead.
+    pub fn set_management_fee(
+        ctx: Context<SetManagementFee>,
+        management_fee_bps: u16,
+        fee_recipient: Pubkey,
     ) -> Result<()> {
-        let vault = &mut ctx.accounts.vault;
-        
-        // Validate authority
-        require!(
-            vault.authority == ctx.accounts.authority.key(),
-            VaultError::InvalidVault
-        );
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        require!(vault.config_timelock_secs == 0, VaultError::ConfigTimelocked);
+        apply_management_fee(
+            vault,
+            &ctx.accounts.protocol_config,
+            management_fee_bps,
+            fee_recipient,
+        )
+    }
 
-        if let Some(fee_bps) = flash_fee_bps {
-            require!(fee_bps <= 10000, VaultError::InvalidAmount); // Max 100% fee
-            vault.flash_fee_bps = fee_bps;
-        }
+    /// Permissionless crank: mint the management fee accrued since `last_fee_accrual_ts`
+    /// as new shares to the fee recipient's position, diluting depositors.
+    ///
+    /// fee_shares = total_shares * fee_bps * elapsed / (10_000 * SECONDS_PER_YEAR)
+    pub fn collect_management_fee(ctx: Context<CollectManagementFee>) -> Result<()> {
+        let clock = Clock::get()?;
+        let vault_key = ctx.accounts.vault.key();
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        let fee_position = &mut ctx.accounts.fee_position;
+
+        let elapsed = clock.unix_timestamp.saturating_sub(vault.last_fee_accrual_ts);
+        let fee_shares = mul_div_floor(
+            vault.total_shares,
+            (vault.management_fee_bps as u128) * (elapsed.max(0) as u128),
+            10_000 * SECONDS_PER_YEAR,
+        )
+        .ok_or(VaultError::MathOverflow)?;
 
-        if let Some(treasury) = fee_treasury {
-            vault.fee_treasury = treasury;
+        // Leave the clock running while the fee rounds to zero so short intervals
+        // accumulate instead of being dropped
+        if fee_shares == 0 {
+            if vault.total_shares == 0 {
+                vault.last_fee_accrual_ts = clock.unix_timestamp;
+            }
+            return Ok(());
         }
-
-        if let Some(enabled) = callback_allowlist_enabled {
-            vault.callback_allowlist_enabled = enabled;
+        // Settle rewards at the pre-dilution share count
+        accrue_rewards(vault, clock.unix_timestamp)?;
+
+        if fee_position.user == Pubkey::default() {
+            fee_position.index = vault.next_position_index()?;
+            fee_position.user = vault.fee_recipient;
+            fee_position.vault = vault_key;
+            fee_position.version = UserPosition::CURRENT_VERSION;
         }
 
-        if let Some(allowlist) = callback_allowlist {
-            require!(
-                allowlist.len() <= Vault::MAX_CALLBACK_PROGRAMS,
-                VaultError::MathOverflow
-            );
-            vault.callback_allowlist = allowlist;
-        }
+        // Keep any rewards the fee position already earned
+        let pending_scaled = pending_rewards_scaled(fee_position, vault.acc_reward_per_share)?;
+        fee_position.shares = fee_position
+            .shares
+            .checked_add(fee_shares)
+            .ok_or(VaultError::MathOverflow)?;
+        settle_rewards(vault, fee_position, pending_scaled)?;
+
+        vault.total_shares = vault
+            .total_shares
+            .checked_add(fee_shares)
+            .ok_or(VaultError::MathOverflow)?;
+        vault.last_fee_accrual_ts = clock.unix_timestamp;
 
         Ok(())
     }
 
-    pub fn flash_loan(
-        ctx: Context<FlashLoan>,
-        amount: u64,
-        callback_ix_data: Vec<u8>,
-    ) -> Result<()> {
-        require!(amount > 0, VaultError::InvalidFlashLoanAmount);
-
-        let vault = &ctx.accounts.vault;
-        let vault_token_account = &mut ctx.accounts.vault_token_account;
+    /// Restrict deposits to depositors in a Merkle tree (see `verify_merkle_proof`).
+    /// An all-zero root makes the vault open again. Existing positions can always withdraw.
+    pub fn set_allowlist_root(ctx: Context<SetAllowlistRoot>, root: [u8; 32]) -> Result<()> {
+        ctx.accounts.vault.load_mut()?.allowlist_root = root;
+        Ok(())
+    }
 
-        // Validate flash loan is configured
+    /// Bar `address` from depositing and taking flash loans. A blocked holder can
+    /// still withdraw and claim; the list holds `Vault::MAX_BLOCKED_ADDRESSES` entries.
+    pub fn block_address(ctx: Context<SetAllowlistRoot>, address: Pubkey) -> Result<()> {
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        require!(!vault.is_blocked(&address), VaultError::AddressBlocked);
+        let count = vault.blocked_count as usize;
         require!(
-            vault.fee_treasury != Pubkey::default(),
-            VaultError::FlashLoanNotConfigured
+            count < Vault::MAX_BLOCKED_ADDRESSES,
+            VaultError::BlocklistFull
         );
+        vault.blocked_addresses[count] = address;
+        vault.blocked_count += 1;
+        Ok(())
+    }
 
-        // Validate borrower token account
+    pub fn unblock_address(ctx: Context<SetAllowlistRoot>, address: Pubkey) -> Result<()> {
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        let count = vault.blocked_count as usize;
+        let index = vault.blocked_addresses[..count]
+            .iter()
+            .position(|a| *a == address)
+            .ok_or(VaultError::AddressNotBlocked)?;
+        vault.blocked_addresses.swap(index, count - 1);
+        vault.blocked_addresses[count - 1] = Pubkey::default();
+        vault.blocked_count -= 1;
+        Ok(())
+    }
+
+    /// Escrow `total_amount` reward tokens for a one-off distribution computed off-chain.
+    ///
+    /// Leaves are `(index: u64 LE, user, amount: u64 LE)`; each index can be claimed
+    /// once via `claim_drop`. Drops are numbered per vault by `drop_count`.
+    pub fn create_reward_drop(
+        ctx: Context<CreateRewardDrop>,
+        root: [u8; 32],
+        total_amount: u64,
+    ) -> Result<()> {
+        require!(total_amount > 0, VaultError::InvalidAmount);
+
+        transfer_reward(
+            &ctx.accounts.authority_token_account.to_account_info(),
+            &ctx.accounts.drop_token_account.to_account_info(),
+            &ctx.accounts.authority.to_account_info(),
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.reward_token_program.to_account_info(),
+            &[],
+            total_amount,
+        )?;
+
+        let vault_key = ctx.accounts.vault.key();
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        ctx.accounts.reward_drop.set_inner(RewardDrop {
+            vault: vault_key,
+            mint: vault.reward_mint,
+            id: vault.drop_count,
+            root,
+            total_amount,
+            claimed_amount: 0,
+            bump: ctx.bumps.reward_drop,
+        });
+        vault.drop_count = vault.drop_count.checked_add(1).ok_or(VaultError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Claim leaf `index` of a reward drop. The claim bit lives in the bitmap chunk
+    /// covering `index`, created on first use.
+    pub fn claim_drop(
+        ctx: Context<ClaimDrop>,
+        index: u64,
+        amount: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        let reward_drop = &mut ctx.accounts.reward_drop;
         require!(
-            ctx.accounts.borrower_token_account.mint == vault.token_mint,
-            VaultError::InvalidTokenMint
+            verify_merkle_proof(
+                &proof,
+                reward_drop.root,
+                &[
+                    &index.to_le_bytes(),
+                    ctx.accounts.user.key().as_ref(),
+                    &amount.to_le_bytes(),
+                ],
+            ),
+            VaultError::InvalidDropProof
         );
+
+        let bitmap = &mut ctx.accounts.claim_bitmap;
+        bitmap.reward_drop = reward_drop.key();
+        let bit = (index % ClaimBitmap::CLAIMS_PER_ACCOUNT) as usize;
+        let mask = 1u8 << (bit % 8);
         require!(
-            ctx.accounts.borrower_token_account.owner == ctx.accounts.borrower.key(),
-            VaultError::InvalidTokenMint
+            bitmap.claimed[bit / 8] & mask == 0,
+            VaultError::DropAlreadyClaimed
         );
+        bitmap.claimed[bit / 8] |= mask;
 
-        // Validate fee treasury token account
+        reward_drop.claimed_amount = reward_drop
+            .claimed_amount
+            .checked_add(amount)
+            .ok_or(VaultError::MathOverflow)?;
+        require!(
+            reward_drop.claimed_amount <= reward_drop.total_amount,
+            VaultError::InsufficientRewardBalance
+        );
+
+        let id_bytes = reward_drop.id.to_le_bytes();
+        let seeds = &[
+            b"drop",
+            reward_drop.vault.as_ref(),
+            id_bytes.as_ref(),
+            &[reward_drop.bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_reward(
+            &ctx.accounts.drop_token_account.to_account_info(),
+            &ctx.accounts.user_reward_token_account.to_account_info(),
+            &reward_drop.to_account_info(),
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.reward_token_program.to_account_info(),
+            signer,
+            amount,
+        )?;
+
+        Ok(())
+    }
+
+    /// Rewrite a vault written by an older program version as the current layout:
+    /// the Borsh layouts (v0-v3) and the zero-copy layouts with u64 shares (v4, v5).
+    ///
+    /// Permissionless. The callback allowlist keeps the capacity bought with
+    /// `expand_callback_allowlist`. If the account grows the payer covers the extra rent;
+    /// if it shrinks the surplus is refunded to the vault authority. Fields that didn't
+    /// exist in the old layout read as zero; per-version defaults are applied below.
+    pub fn migrate_vault(ctx: Context<MigrateVault>) -> Result<()> {
+        let info = ctx.accounts.vault.to_account_info();
+        require_keys_eq!(*info.owner, crate::ID, ErrorCode::AccountOwnedByWrongProgram);
+        let discriminator = info.try_borrow_data()?[..8].to_vec();
         require!(
-            ctx.accounts.fee_treasury_token_account.mint == vault.token_mint,
-            VaultError::InvalidFeeTreasury
+            discriminator != Vault::DISCRIMINATOR,
+            VaultError::AlreadyMigrated
         );
+        let is_v5 = discriminator == Vault::DISCRIMINATOR_V5;
         require!(
-            ctx.accounts.fee_treasury_token_account.owner == vault.fee_treasury,
-            VaultError::InvalidFeeTreasury
+            is_v5 || discriminator == Vault::legacy_discriminator(),
+            ErrorCode::AccountDiscriminatorMismatch
         );
 
-        // Check callback allowlist if enabled
-        if vault.callback_allowlist_enabled {
-            require!(
-                vault.callback_allowlist.contains(&ctx.accounts.callback_program.key()),
-                VaultError::CallbackNotAllowlisted
-            );
+        let data_len = info.data_len();
+        let (vault, callback_allowlist, capacity) = if LegacyVault::is_legacy_len(data_len) {
+            // Bytes past the end of an older layout read as zero
+            let mut data = info.try_borrow_data()?[8..].to_vec();
+            data.resize(data.len().max(LegacyVault::LEN_V3), 0);
+            let legacy = LegacyVault::deserialize(&mut data.as_slice())?;
+
+            // Roles arrived in v3; older vaults keep the admin holding all of them
+            let (guardian, fee_manager, reward_manager) = if legacy.version < 3 {
+                (legacy.authority, legacy.authority, legacy.authority)
+            } else {
+                (legacy.guardian, legacy.fee_manager, legacy.reward_manager)
+            };
+            let vault = Vault {
+                acc_reward_per_share: legacy.acc_reward_per_share,
+                rewards_funded: legacy.rewards_funded,
+                rewards_accrued: legacy.rewards_accrued,
+                total_shares: legacy.total_shares as u128,
+                reward_rate: legacy.reward_rate,
+                last_update_ts: legacy.last_update_ts,
+                last_fee_accrual_ts: legacy.last_fee_accrual_ts,
+                drop_count: legacy.drop_count,
+                config_timelock_secs: legacy.config_timelock_secs,
+                authority: legacy.authority,
+                token_mint: legacy.token_mint,
+                reward_mint: legacy.reward_mint,
+                reward_vault: legacy.reward_vault,
+                fee_treasury: legacy.fee_treasury,
+                keeper: legacy.keeper,
+                fee_recipient: legacy.fee_recipient,
+                guardian,
+                fee_manager,
+                reward_manager,
+                allowlist_root: legacy.allowlist_root,
+                flash_fee_bps: legacy.flash_fee_bps,
+                keeper_tip_bps: legacy.keeper_tip_bps,
+                management_fee_bps: legacy.management_fee_bps,
+                callback_allowlist_enabled: legacy.callback_allowlist_enabled as u8,
+                flash_loans_enabled: legacy.flash_loans_enabled as u8,
+                paused: legacy.paused as u8,
+                ..Vault::zeroed()
+            };
+            let capacity = Vault::MAX_CALLBACK_PROGRAMS
+                + (data_len - LegacyVault::layout_len(legacy.version)) / 32;
+            (vault, legacy.callback_allowlist, capacity)
+        } else {
+            // v4 → v5: fields appended after `token_decimals`, allowlist moves back.
+            // v5 → v6: same size, `total_shares` widened into the reserved bytes.
+            let data = info.try_borrow_data()?;
+            let fixed_len = if is_v5 { Vault::LEN } else { Vault::LEN_V4 };
+            require!(data_len >= fixed_len, ErrorCode::AccountDidNotDeserialize);
+            let mut vault = Vault::zeroed();
+            bytemuck::bytes_of_mut(&mut vault)[..fixed_len - 8]
+                .copy_from_slice(&data[8..fixed_len]);
+            vault.total_shares = vault._legacy_total_shares as u128;
+            vault._legacy_total_shares = 0;
+            let callback_allowlist = data[fixed_len..]
+                .chunks_exact(32)
+                .take(vault.callback_allowlist_len as usize)
+                .map(|entry| Pubkey::new_from_array(entry.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            (vault, callback_allowlist, (data_len - fixed_len) / 32)
+        };
+        require_keys_eq!(
+            ctx.accounts.authority.key(),
+            vault.authority,
+            VaultError::InvalidVault
+        );
+
+        let new_len = Vault::LEN + 32 * capacity;
+        grow_account(
+            &info,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            new_len,
+        )?;
+        info.resize(new_len)?;
+        {
+            let mut data = info.try_borrow_mut_data()?;
+            data[..8].copy_from_slice(Vault::DISCRIMINATOR);
+            data[8..Vault::LEN].copy_from_slice(bytemuck::bytes_of(&Vault {
+                version: Vault::CURRENT_VERSION,
+                callback_allowlist_len: 0,
+                ..vault
+            }));
+            Vault::set_callback_allowlist(&mut data, &callback_allowlist)?;
         }
 
-        // Record initial vault balance
-        let balance_before = vault_token_account.amount;
+        let surplus = info
+            .lamports()
+            .saturating_sub(Rent::get()?.minimum_balance(new_len));
+        **info.try_borrow_mut_lamports()? -= surplus;
+        **ctx.accounts.authority.try_borrow_mut_lamports()? += surplus;
+        Ok(())
+    }
 
-        // Validate sufficient vault balance
+    /// Same as `migrate_vault` for `UserPosition`.
+    pub fn migrate_position(ctx: Context<MigratePosition>) -> Result<()> {
+        let info = ctx.accounts.user_position.to_account_info();
+        let mut position: UserPosition = load_legacy(&info, UserPosition::LEN)?;
         require!(
-            balance_before >= amount,
-            VaultError::InsufficientVaultBalance
+            position.version < UserPosition::CURRENT_VERSION,
+            VaultError::AlreadyMigrated
         );
 
-        // Calculate fee using u128 to prevent overflow
-        let fee = ((amount as u128)
-            .checked_mul(vault.flash_fee_bps as u128)
-            .ok_or(VaultError::MathOverflow)?
-            .checked_div(10000)
-            .ok_or(VaultError::DivisionByZero)?) as u64;
+        if position.version == 0 {
+            // v0 → v1: version byte fits in the existing struct padding
+            position.version = 1;
+        }
+        if position.version == 1 {
+            // v1 → v2: `delegate` appended (zero = none)
+            position.version = 2;
+        }
+        if position.version == 2 {
+            // v2 → v3: `stake_weighted_ts` appended; the loyalty clock starts now
+            position.stake_weighted_ts = Clock::get()?.unix_timestamp;
+            position.version = 3;
+        }
+        if position.version == 3 {
+            // v3 → v4: `rewards_owed` appended. An emptied position used to keep its
+            // pending rewards in `reward_debt`; move them over.
+            if position._legacy_shares == 0 {
+                position.rewards_owed = position.reward_debt;
+                position.reward_debt = 0;
+            }
+            position.version = 4;
+        }
+        if position.version == 4 {
+            // v4 → v5: `shares` widened to u128 and appended
+            position.shares = position._legacy_shares as u128;
+            position._legacy_shares = 0;
+            position.version = 5;
+        }
+        if position.version == 5 {
+            // v5 → v6: `index` appended; `index_position` numbers the position later
+            position.index = UserPosition::UNINDEXED;
+            position.version = 6;
+        }
+
+        grow_account(
+            &info,
+            &ctx.accounts.payer.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            info.data_len().max(UserPosition::LEN),
+        )?;
+        position.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+        Ok(())
+    }
+
+    /// Let `delegate` claim rewards to the owner's ATA and compound them on the owner's
+    /// behalf. The delegate never gets access to principal. `Pubkey::default()` clears it.
+    pub fn set_position_delegate(ctx: Context<SetPositionDelegate>, delegate: Pubkey) -> Result<()> {
+        ctx.accounts.user_position.delegate = delegate;
+        Ok(())
+    }
+
+    /// `claim_rewards` signed by the position's delegate; rewards go to the owner's ATA.
+    pub fn delegate_claim_rewards(ctx: Context<DelegateClaimRewards>) -> Result<()> {
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        let user_position = &mut ctx.accounts.user_position;
+        let reward_vault = &ctx.accounts.reward_vault;
+
+        require!(
+            is_owner_or_delegate(user_position, &ctx.accounts.delegate.key()),
+            VaultError::UnauthorizedDelegate
+        );
+
+        let clock = Clock::get()?;
+        accrue_rewards(vault, clock.unix_timestamp)?;
+
+        let pending_scaled = pending_rewards_scaled(user_position, vault.acc_reward_per_share)?;
+        let pending = pending_scaled
+            .checked_div(REWARD_PRECISION)
+            .ok_or(VaultError::DivisionByZero)?;
+        require!(pending > 0, VaultError::InvalidAmount);
+
+        if reward_vault.amount == 0 {
+            msg!("Reward vault empty, {} owed to position", saturating_u64(pending));
+            return Ok(());
+        }
+        let (paid, owed_scaled) = split_claim(
+            vault,
+            user_position,
+            pending_scaled,
+            reward_vault.amount,
+            clock.unix_timestamp,
+        )?;
 
-        // Transfer loan amount to borrower
         let seeds = &[
             b"vault",
             vault.token_mint.as_ref(),
             b"authority",
+            vault.generation_seed(),
             &[ctx.bumps.vault_authority],
         ];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
-            from: vault_token_account.to_account_info(),
-            to: ctx.accounts.borrower_token_account.to_account_info(),
-            authority: ctx.accounts.vault_authority.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
-        anchor_spl::token::transfer(cpi_ctx, amount)?;
+        transfer_reward(
+            &reward_vault.to_account_info(),
+            &ctx.accounts.user_reward_token_account.to_account_info(),
+            &ctx.accounts.vault_authority.to_account_info(),
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.reward_token_program.to_account_info(),
+            signer,
+            paid,
+        )?;
+        vault.lifetime_rewards_paid = vault.lifetime_rewards_paid.saturating_add(paid);
 
-        // CPI to callback program
-        // Build instruction from provided data
-        let callback_ix = anchor_lang::solana_program::instruction::Instruction {
-            program_id: ctx.accounts.callback_program.key(),
-            accounts: ctx.remaining_accounts.iter().map(|acc| {
-                anchor_lang::solana_program::instruction::AccountMeta {
-                    pubkey: acc.key(),
-                    is_signer: acc.is_signer,
-                    is_writable: acc.is_writable,
-                }
-            }).collect(),
-            data: callback_ix_data,
-        };
+        settle_rewards(vault, user_position, owed_scaled)?;
 
-        // Invoke callback program
-        let callback_account_infos: Vec<_> = ctx.remaining_accounts.iter().map(|acc| (*acc).clone()).collect();
-        invoke(&callback_ix, &callback_account_infos)?;
+        Ok(())
+    }
 
-        // Reload vault token account to get updated balance
-        vault_token_account.reload()?;
+    /// Turn a position's pending rewards into shares. Only for vaults whose reward
+    /// mint is the staked mint. Callable by the owner or their delegate. Like a claim,
+    /// compounds what the reward vault covers and keeps the rest owed.
+    pub fn compound_rewards(ctx: Context<CompoundRewards>) -> Result<()> {
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        let user_position = &mut ctx.accounts.user_position;
+        let vault_token_account = &ctx.accounts.vault_token_account;
+        let reward_vault = &ctx.accounts.reward_vault;
 
-        // Record final vault balance
-        let balance_after = vault_token_account.amount;
+        require!(
+            is_owner_or_delegate(user_position, &ctx.accounts.authority.key()),
+            VaultError::UnauthorizedDelegate
+        );
+        require!(
+            vault.reward_mint == vault.token_mint,
+            VaultError::CompoundMintMismatch
+        );
+        require!(user_position.shares > 0, VaultError::InsufficientShares);
 
-        // Verify repayment invariant by checking balance delta
-        // After loan transfer, vault should have: balance_before - amount
-        // After callback repayment, vault should have: balance_before - amount + (amount + fee) = balance_before + fee
-        // So: balance_after >= balance_before + fee
-        let required_balance = balance_before
-            .checked_add(fee)
-            .ok_or(VaultError::MathOverflow)?;
-        
+        let clock = Clock::get()?;
+        accrue_rewards(vault, clock.unix_timestamp)?;
+
+        let pending_scaled = pending_rewards_scaled(user_position, vault.acc_reward_per_share)?;
+        let pending = pending_scaled
+            .checked_div(REWARD_PRECISION)
+            .ok_or(VaultError::DivisionByZero)?;
+        require!(pending > 0, VaultError::InvalidAmount);
+        if reward_vault.amount == 0 {
+            msg!("Reward vault empty, {} owed to position", saturating_u64(pending));
+            return Ok(());
+        }
+        let (paid, owed_scaled) = split_claim(
+            vault,
+            user_position,
+            pending_scaled,
+            reward_vault.amount,
+            clock.unix_timestamp,
+        )?;
+
+        // With one mint both vault ATAs are the same account, so the rewards are
+        // already in the stake balance; price the shares off the balance without them
+        let same_account = vault_token_account.key() == reward_vault.key();
+        let vault_balance = if same_account {
+            vault_token_account
+                .amount
+                .checked_sub(paid)
+                .ok_or(VaultError::MathOverflow)?
+        } else {
+            vault_token_account.amount
+        };
+        let shares = calculate_shares_for_deposit(
+            paid,
+            vault.total_assets(vault_balance)?,
+            vault.total_shares,
+        )?;
+        require!(shares > 0, VaultError::InvalidAmount);
         require!(
-            balance_after >= required_balance,
-            VaultError::InsufficientRepayment
+            vault.total_shares.saturating_add(shares) <= Vault::MAX_TOTAL_SHARES,
+            VaultError::SharesCapExceeded
         );
 
-        // Transfer fee to treasury (from vault)
-        // The fee is already in the vault (as part of repayment), so we transfer it out
-        if fee > 0 {
-            let fee_seeds = &[
+        if !same_account {
+            let seeds = &[
                 b"vault",
                 vault.token_mint.as_ref(),
                 b"authority",
+                vault.generation_seed(),
                 &[ctx.bumps.vault_authority],
             ];
-            let fee_signer = &[&fee_seeds[..]];
+            let signer = &[&seeds[..]];
 
-            let fee_cpi_accounts = Transfer {
-                from: vault_token_account.to_account_info(),
-                to: ctx.accounts.fee_treasury_token_account.to_account_info(),
+            let cpi_accounts = Transfer {
+                from: reward_vault.to_account_info(),
+                to: vault_token_account.to_account_info(),
                 authority: ctx.accounts.vault_authority.to_account_info(),
             };
-            let fee_cpi_program = ctx.accounts.token_program.to_account_info();
-            let fee_cpi_ctx = CpiContext::new_with_signer(fee_cpi_program, fee_cpi_accounts, fee_signer);
-            anchor_spl::token::transfer(fee_cpi_ctx, fee)?;
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            anchor_spl::token::transfer(cpi_ctx, paid)?;
         }
 
+        vault.lifetime_rewards_paid = vault.lifetime_rewards_paid.saturating_add(paid);
+        vault.total_shares = vault
+            .total_shares
+            .checked_add(shares)
+            .ok_or(VaultError::MathOverflow)?;
+        user_position.add_shares(shares, clock.unix_timestamp)?;
+        settle_rewards(vault, user_position, owed_scaled)?;
+
+        record_checkpoint(
+            user_position,
+            ctx.accounts.user_checkpoints.as_mut(),
+            ctx.accounts.vault.key(),
+        )
+    }
+
+    /// Register the keeper allowed to push rewards for opted-in positions, and its tip.
+    /// Pass `Pubkey::default()` to unregister.
+    pub fn set_keeper(ctx: Context<SetKeeper>, keeper: Pubkey, keeper_tip_bps: u16) -> Result<()> {
+        require!(
+            keeper_tip_bps <= Vault::MAX_KEEPER_TIP_BPS,
+            VaultError::KeeperTipTooHigh
+        );
+
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        vault.keeper = keeper;
+        vault.keeper_tip_bps = keeper_tip_bps;
         Ok(())
     }
-}
 
-// Helper function to calculate shares for deposit
-fn calculate_shares_for_deposit(
-    deposit_amount: u64,
-    vault_balance: u64,
-    total_shares: u64,
-) -> Result<u64> {
-    if vault_balance == 0 {
-        // First deposit: 1:1 ratio
-        Ok(deposit_amount)
-    } else {
-        // shares = (deposit_amount * total_shares) / vault_balance
-        // Use u128 to prevent overflow
-        // Note: Integer division rounds down (truncates), which is correct for vault security
-        // This prevents share inflation and ensures the vault can always honor withdrawals
-        let shares = (deposit_amount as u128)
-            .checked_mul(total_shares as u128)
+    /// Cap the vault's staked balance at `cap_usd` (6 decimals), priced by the Pyth-style
+    /// price account `oracle`. Prices older than `max_price_age_secs` or with a confidence
+    /// interval wider than `max_confidence_bps` of the price are rejected. A zero cap
+    /// removes the limit.
+    pub fn set_deposit_cap(
+        ctx: Context<SetDepositCap>,
+        cap_usd: u64,
+        oracle: Pubkey,
+        max_price_age_secs: u32,
+        max_confidence_bps: u16,
+    ) -> Result<()> {
+        if cap_usd > 0 {
+            require!(oracle != Pubkey::default(), VaultError::InvalidOracle);
+            require!(max_price_age_secs > 0, VaultError::InvalidAmount);
+            require!(
+                max_confidence_bps > 0 && max_confidence_bps <= 10_000,
+                VaultError::InvalidAmount
+            );
+        }
+
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        vault.deposit_cap_usd = cap_usd;
+        vault.oracle = oracle;
+        vault.max_price_age_secs = max_price_age_secs;
+        vault.max_confidence_bps = max_confidence_bps;
+        vault.token_decimals = ctx.accounts.token_mint.decimals;
+        Ok(())
+    }
+
+    /// Permissionless crank: accrue rewards up to now without moving funds, so
+    /// `acc_reward_per_share` is fresh for off-chain readers and CPI callers.
+    pub fn update_rewards(ctx: Context<UpdateRewards>) -> Result<()> {
+        let clock = Clock::get()?;
+        accrue_rewards(&mut *ctx.accounts.vault.load_mut()?, clock.unix_timestamp)
+    }
+
+    /// Canonical share price for integrators: tokens per share scaled by
+    /// `EXCHANGE_RATE_PRECISION`, returned as Anchor return data (read it by simulation
+    /// or after a CPI). Accrues rewards first. An empty vault prices shares 1:1, as the
+    /// first deposit does.
+    pub fn get_exchange_rate(ctx: Context<GetExchangeRate>) -> Result<u128> {
+        let clock = Clock::get()?;
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        accrue_rewards(vault, clock.unix_timestamp)?;
+
+        if vault.total_shares == 0 {
+            return Ok(EXCHANGE_RATE_PRECISION);
+        }
+        let total_assets = vault.total_assets(ctx.accounts.vault_token_account.amount)?;
+        Ok((total_assets as u128)
+            .checked_mul(EXCHANGE_RATE_PRECISION)
             .ok_or(VaultError::MathOverflow)?
-            .checked_div(vault_balance as u128)
-            .ok_or(VaultError::DivisionByZero)?;
+            / vault.total_shares)
+    }
 
-        Ok(shares as u64)
+    /// Create the vault's share-price history buffer. Permissionless; the payer covers rent.
+    pub fn initialize_vault_stats(ctx: Context<InitializeVaultStats>) -> Result<()> {
+        let stats = &mut ctx.accounts.vault_stats.load_init()?;
+        stats.vault = ctx.accounts.vault.key();
+        Ok(())
     }
-}
 
-// Helper function to calculate tokens for withdraw
-fn calculate_tokens_for_withdraw(
-    shares: u64,
-    vault_balance: u64,
-    total_shares: u64,
-) -> Result<u64> {
-    require!(total_shares > 0, VaultError::DivisionByZero);
+    /// Permissionless crank: accrue rewards and record the vault's balance, share supply
+    /// and reward accumulator in the stats ring buffer, at most once per
+    /// `VaultStats::MIN_SNAPSHOT_INTERVAL_SECS`.
+    pub fn snapshot(ctx: Context<Snapshot>) -> Result<()> {
+        let clock = Clock::get()?;
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        let stats = &mut ctx.accounts.vault_stats.load_mut()?;
 
-    // tokens = (shares * vault_balance) / total_shares
-    // Use u128 to prevent overflow
-    let tokens = (shares as u128)
-        .checked_mul(vault_balance as u128)
-        .ok_or(VaultError::MathOverflow)?
-        .checked_div(total_shares as u128)
-        .ok_or(VaultError::DivisionByZero)?;
+        if let Some(last) = stats.latest() {
+            require!(
+                clock.unix_timestamp >= last.timestamp + VaultStats::MIN_SNAPSHOT_INTERVAL_SECS,
+                VaultError::SnapshotTooSoon
+            );
+        }
 
-    let tokens_u64 = tokens as u64;
-    require!(tokens_u64 > 0, VaultError::InvalidAmount);
+        accrue_rewards(vault, clock.unix_timestamp)?;
+        stats.push(VaultSnapshot {
+            acc_reward_per_share: vault.acc_reward_per_share,
+            timestamp: clock.unix_timestamp,
+            tracked_balance: vault.total_assets(ctx.accounts.vault_token_account.amount)?,
+            total_shares: saturating_u64(vault.total_shares),
+            _reserved: [0; 8],
+        });
+        Ok(())
+    }
 
-    Ok(tokens_u64)
-}
+    /// Permissionless: append `user`'s current share balance to their checkpoint history,
+    /// for governance to read historical voting power (see `UserCheckpoints::shares_at`).
+    /// Deposits, withdrawals and compounding record a checkpoint when given the history;
+    /// this starts one for positions that predate it and covers share changes made
+    /// elsewhere (management fees). A live position must be migrated first, and from
+    /// here on every share change must pass the history. A closed or missing position
+    /// checkpoints as zero. The payer funds a new history.
+    pub fn checkpoint(ctx: Context<Checkpoint>) -> Result<()> {
+        let vault = ctx.accounts.vault.key();
+        let user = ctx.accounts.user.key();
+        let now = Clock::get()?.unix_timestamp;
+        let position_info = ctx.accounts.user_position.to_account_info();
+        if position_info.owner != &crate::ID || position_info.data_is_empty() {
+            ctx.accounts.user_checkpoints.record(vault, user, 0, now);
+            return Ok(());
+        }
 
-// Helper function to update rewards based on elapsed time
-// This is idempotent - multiple calls in the same slot produce the same result
-fn update_rewards(vault: &mut Vault, current_ts: i64) -> Result<()> {
-    let delta_ts = current_ts.saturating_sub(vault.last_update_ts);
+        let mut position: UserPosition = load_legacy(&position_info, UserPosition::LEN)?;
+        require!(
+            position.version == UserPosition::CURRENT_VERSION,
+            VaultError::PositionNotMigrated
+        );
+        record_checkpoint(
+            &mut position,
+            Some(&mut ctx.accounts.user_checkpoints),
+            vault,
+        )?;
+        position.try_serialize(&mut &mut position_info.try_borrow_mut_data()?[..])?;
+        Ok(())
+    }
+
+    /// Move `bps` of the vault's total assets into its insurance fund without burning
+    /// shares, so every holder loses the same fraction of their position's value. Only
+    /// idle tokens can be slashed; recall from strategies first if they do not cover it.
+    pub fn slash(ctx: Context<Slash>, bps: u16) -> Result<()> {
+        require!(bps > 0, VaultError::InvalidAmount);
+        let (amount, token_mint, generation) = {
+            let vault = &mut ctx.accounts.vault.load_mut()?;
+            require!(bps <= vault.max_slash_bps, VaultError::SlashExceedsCap);
+            let total_assets = vault.total_assets(ctx.accounts.vault_token_account.amount)?;
+            let amount = (total_assets as u128)
+                .checked_mul(bps as u128)
+                .ok_or(VaultError::MathOverflow)?
+                / 10_000;
+            let amount = amount as u64;
+            require!(amount > 0, VaultError::InvalidAmount);
+            require!(
+                amount <= vault.idle_balance(ctx.accounts.vault_token_account.amount),
+                VaultError::InsufficientVaultBalance
+            );
+            vault.lifetime_slashed = vault
+                .lifetime_slashed
+                .checked_add(amount)
+                .ok_or(VaultError::MathOverflow)?;
+            (amount, vault.token_mint, vault.generation)
+        };
+
+        let seeds = &[
+            b"vault",
+            token_mint.as_ref(),
+            b"authority",
+            generation_seed(&generation),
+            &[ctx.bumps.vault_authority],
+        ];
+        let signer = &[&seeds[..]];
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.vault_token_account.to_account_info(),
+            to: ctx.accounts.insurance_fund.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        anchor_spl::token::transfer(
+            CpiContext::new_with_signer(cpi_program, cpi_accounts, signer),
+            amount,
+        )?;
+
+        let vault = ctx.accounts.vault.load()?;
+        emit!(SlashEvent {
+            vault: ctx.accounts.vault.key(),
+            slasher: ctx.accounts.slasher.key(),
+            bps,
+            amount,
+            total_assets: vault.total_assets(ctx.accounts.vault_token_account.amount - amount)?,
+            total_shares: saturating_u64(vault.total_shares),
+        });
+        Ok(())
+    }
+
+    /// Add tokens to the vault without minting shares, raising the share price for all
+    /// current holders. The sanctioned way for protocols to share fees with stakers.
+    pub fn donate(ctx: Context<Donate>, amount: u64) -> Result<()> {
+        require!(amount > 0, VaultError::InvalidAmount);
+        let (total_shares, total_assets) = {
+            let vault = ctx.accounts.vault.load()?;
+            require!(!vault.is_paused(), VaultError::VaultPaused);
+            // With no shares outstanding the whole donation would go to the next depositor
+            require!(vault.total_shares > 0, VaultError::NoSharesOutstanding);
+            (vault.total_shares, vault.total_assets(ctx.accounts.vault_token_account.amount)?)
+        };
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.donor_token_account.to_account_info(),
+            to: ctx.accounts.vault_token_account.to_account_info(),
+            authority: ctx.accounts.donor.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        anchor_spl::token::transfer(CpiContext::new(cpi_program, cpi_accounts), amount)?;
+
+        emit!(DonationEvent {
+            vault: ctx.accounts.vault.key(),
+            donor: ctx.accounts.donor.key(),
+            amount,
+            total_assets: total_assets
+                .checked_add(amount)
+                .ok_or(VaultError::MathOverflow)?,
+            total_shares: saturating_u64(total_shares),
+        });
+        Ok(())
+    }
+
+    /// Permissionless monitoring check: errors with `InvariantViolated` if the vault's
+    /// accounting is inconsistent. The balance the vault tracks as held in its token
+    /// account besides stake (buffered flash fees, plus unallocated and owed rewards
+    /// when rewards share the account) must be there. Any `UserPosition`s of this vault
+    /// passed as remaining accounts are checked too (their shares must fit in
+    /// `total_shares` and their reward debt can't exceed what their shares have earned).
+    pub fn assert_invariants(ctx: Context<AssertInvariants>) -> Result<()> {
+        let vault = ctx.accounts.vault.load()?;
+        let vault_key = ctx.accounts.vault.key();
+        let total_assets = vault.total_assets(ctx.accounts.vault_token_account.amount)?;
+
+        let mut ok = true;
+        let mut check = |holds: bool, what: &str| {
+            if !holds {
+                msg!("Invariant violated: {}", what);
+                ok = false;
+            }
+        };
+        check(
+            vault.total_shares == 0 || total_assets > 0,
+            "shares outstanding with no assets",
+        );
+        // Emissions may outrun funding (the shortfall becomes IOUs), so accrued rewards
+        // aren't bounded by funded ones
+        let unallocated_rewards = vault.rewards_funded.saturating_sub(vault.rewards_accrued);
+        let mut tracked_balance = vault.pending_protocol_fees as u128;
+        if vault.reward_vault == ctx.accounts.vault_token_account.key() {
+            tracked_balance = tracked_balance
+                .saturating_add(unallocated_rewards)
+                .saturating_add(vault.unpaid_rewards as u128);
+        }
+        check(
+            tracked_balance <= ctx.accounts.vault_token_account.amount as u128,
+            "tracked balance exceeds vault token account",
+        );
+        check(
+            ctx.accounts.reward_vault.amount as u128 >= unallocated_rewards,
+            "reward vault does not cover unallocated rewards",
+        );
+
+        let mut position_shares: u128 = 0;
+        for info in ctx.remaining_accounts {
+            require_keys_eq!(*info.owner, crate::ID, ErrorCode::AccountOwnedByWrongProgram);
+            let position = UserPosition::try_deserialize(&mut &info.try_borrow_data()?[..])?;
+            require_keys_eq!(position.vault, vault_key, VaultError::InvalidVault);
+            position_shares = position_shares.saturating_add(position.shares);
+            if position.shares > 0 {
+                let earned = position
+                    .shares
+                    .checked_mul(vault.acc_reward_per_share)
+                    .ok_or(VaultError::MathOverflow)?;
+                check(position.reward_debt <= earned, "position reward debt exceeds earned rewards");
+            }
+        }
+        check(
+            position_shares <= vault.total_shares,
+            "position shares exceed total shares",
+        );
+
+        require!(ok, VaultError::InvariantViolated);
+        msg!(
+            "Invariants hold: total_assets={} total_shares={} positions_checked={}",
+            total_assets,
+            vault.total_shares,
+            ctx.remaining_accounts.len()
+        );
+        Ok(())
+    }
+
+    /// Opt a position in or out of keeper-pushed reward claims
+    pub fn set_keeper_claims(ctx: Context<SetKeeperClaims>, enabled: bool) -> Result<()> {
+        ctx.accounts.user_position.keeper_claims_enabled = enabled;
+        Ok(())
+    }
+
+    /// Keeper pushes an idle user's pending rewards to the user's reward ATA,
+    /// keeping `keeper_tip_bps` of the paid part as payment. A shortfall stays owed
+    /// to the position as with `claim_rewards`.
+    pub fn keeper_claim_rewards(ctx: Context<KeeperClaimRewards>) -> Result<()> {
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        let user_position = &mut ctx.accounts.user_position;
+        let reward_vault = &ctx.accounts.reward_vault;
+
+        require!(
+            vault.keeper != Pubkey::default() && vault.keeper == ctx.accounts.keeper.key(),
+            VaultError::UnauthorizedKeeper
+        );
+        require!(
+            user_position.keeper_claims_enabled,
+            VaultError::KeeperClaimsDisabled
+        );
+        require!(
+            vault.reward_mint == ctx.accounts.keeper_reward_token_account.mint,
+            VaultError::InvalidRewardMint
+        );
+
+        let clock = Clock::get()?;
+        accrue_rewards(vault, clock.unix_timestamp)?;
+
+        let pending_scaled = pending_rewards_scaled(user_position, vault.acc_reward_per_share)?;
+        let pending = pending_scaled
+            .checked_div(REWARD_PRECISION)
+            .ok_or(VaultError::DivisionByZero)?;
+        require!(pending > 0, VaultError::InvalidAmount);
+
+        if reward_vault.amount == 0 {
+            msg!("Reward vault empty, {} owed to position", saturating_u64(pending));
+            return Ok(());
+        }
+        let (paid, owed_scaled) = split_claim(
+            vault,
+            user_position,
+            pending_scaled,
+            reward_vault.amount,
+            clock.unix_timestamp,
+        )?;
+
+        // Tip rounds down so the user never receives less than (1 - tip) of the claim
+        let tip = ((paid as u128)
+            .checked_mul(vault.keeper_tip_bps as u128)
+            .ok_or(VaultError::MathOverflow)?
+            .checked_div(10000)
+            .ok_or(VaultError::DivisionByZero)?) as u64;
+        let user_amount = paid
+            .checked_sub(tip)
+            .ok_or(VaultError::MathOverflow)?;
+
+        let seeds = &[
+            b"vault",
+            vault.token_mint.as_ref(),
+            b"authority",
+            vault.generation_seed(),
+            &[ctx.bumps.vault_authority],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_reward(
+            &reward_vault.to_account_info(),
+            &ctx.accounts.user_reward_token_account.to_account_info(),
+            &ctx.accounts.vault_authority.to_account_info(),
+            &ctx.accounts.reward_mint,
+            &ctx.accounts.reward_token_program.to_account_info(),
+            signer,
+            user_amount,
+        )?;
+
+        if tip > 0 {
+            transfer_reward(
+                &reward_vault.to_account_info(),
+                &ctx.accounts.keeper_reward_token_account.to_account_info(),
+                &ctx.accounts.vault_authority.to_account_info(),
+                &ctx.accounts.reward_mint,
+                &ctx.accounts.reward_token_program.to_account_info(),
+                signer,
+                tip,
+            )?;
+        }
+        vault.lifetime_rewards_paid = vault.lifetime_rewards_paid.saturating_add(paid);
+
+        // Update reward_debt to the current accumulator, keeping any shortfall owed
+        settle_rewards(vault, user_position, owed_scaled)?;
+
+        Ok(())
+    }
+
+    /// Apply flash-loan settings immediately. Only while the vault has no config
+    /// timelock; afterwards changes go through `propose_config_change`.
+    pub fn update_flash_loan_config(
+        ctx: Context<UpdateFlashLoanConfig>,
+        flash_fee_bps: Option<u16>,
+        fee_treasury: Option<Pubkey>,
+        callback_allowlist_enabled: Option<bool>,
+        callback_allowlist: Option<Vec<Pubkey>>,
+    ) -> Result<()> {
+        require!(
+            ctx.accounts.vault.load()?.config_timelock_secs == 0,
+            VaultError::ConfigTimelocked
+        );
+
+        apply_flash_loan_config(
+            &ctx.accounts.vault,
+            &ctx.accounts.protocol_config,
+            flash_fee_bps,
+            fee_treasury,
+            callback_allowlist_enabled,
+            callback_allowlist,
+        )
+    }
+
+    /// Raise the delay between proposing and executing fee/allowlist changes.
+    /// Lowering it is itself a config change and has to wait out the current delay.
+    pub fn set_config_timelock(ctx: Context<SetConfigTimelock>, delay_secs: i64) -> Result<()> {
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        require!(
+            delay_secs >= vault.config_timelock_secs,
+            VaultError::ConfigTimelocked
+        );
+        vault.config_timelock_secs = delay_secs;
+        Ok(())
+    }
+
+    /// Queue a fee/allowlist change that can be executed once the vault's
+    /// `config_timelock_secs` has passed.
+    pub fn propose_config_change(
+        ctx: Context<ProposeConfigChange>,
+        change: ConfigChange,
+    ) -> Result<()> {
+        if let Some(delay) = change.config_timelock_secs {
+            require!(delay >= 0, VaultError::InvalidAmount);
+            require!(
+                ctx.accounts.authority.key() == ctx.accounts.vault.load()?.authority,
+                VaultError::MissingRole
+            );
+        }
+
+        let clock = Clock::get()?;
+        let vault_key = ctx.accounts.vault.key();
+        let vault = &ctx.accounts.vault.load()?;
+        let pending = &mut ctx.accounts.pending_change;
+        pending.vault = vault_key;
+        pending.eta = clock
+            .unix_timestamp
+            .checked_add(vault.config_timelock_secs)
+            .ok_or(VaultError::MathOverflow)?;
+        pending.change = change;
+        pending.bump = ctx.bumps.pending_change;
+
+        msg!("Config change queued, executable at {}", pending.eta);
+        Ok(())
+    }
+
+    /// Drop a queued config change and refund its rent
+    pub fn cancel_config_change(_ctx: Context<CancelConfigChange>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Apply a queued config change after its delay. Bounds are re-checked against
+    /// the current protocol config, and an active management fee must be collected
+    /// in the same transaction just like with `set_management_fee`.
+    pub fn execute_config_change(ctx: Context<ExecuteConfigChange>) -> Result<()> {
+        let clock = Clock::get()?;
+        let pending = &mut ctx.accounts.pending_change;
+        require!(
+            clock.unix_timestamp >= pending.eta,
+            VaultError::TimelockNotElapsed
+        );
+
+        let change = &mut pending.change;
+        apply_flash_loan_config(
+            &ctx.accounts.vault,
+            &ctx.accounts.protocol_config,
+            change.flash_fee_bps,
+            change.fee_treasury,
+            change.callback_allowlist_enabled,
+            change.callback_allowlist.take(),
+        )?;
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        if change.management_fee_bps.is_some() || change.fee_recipient.is_some() {
+            let bps = change.management_fee_bps.unwrap_or(vault.management_fee_bps);
+            let recipient = change.fee_recipient.unwrap_or(vault.fee_recipient);
+            apply_management_fee(vault, &ctx.accounts.protocol_config, bps, recipient)?;
+        }
+        if let Some(delay) = change.config_timelock_secs {
+            vault.config_timelock_secs = delay;
+        }
+        Ok(())
+    }
+
+    /// Let protocol-owned accounts borrow: `flash_loan` then accepts a non-signing
+    /// borrower if it is the allowlisted callback program's `borrower_pda`.
+    pub fn set_pda_borrowers_enabled(ctx: Context<SetConfigTimelock>, enabled: bool) -> Result<()> {
+        ctx.accounts.vault.load_mut()?.pda_borrowers_enabled = enabled as u8;
+        Ok(())
+    }
+
+    /// Pause or resume flash loans without affecting deposits, withdrawals or claims
+    pub fn set_flash_loans_enabled(ctx: Context<GuardianAction>, enabled: bool) -> Result<()> {
+        ctx.accounts.vault.load_mut()?.set_flash_loans_enabled(enabled);
+        Ok(())
+    }
+
+    /// Pause or resume deposits and flash loans. Withdrawals and claims stay open
+    /// so depositors can always exit.
+    pub fn set_paused(ctx: Context<GuardianAction>, paused: bool) -> Result<()> {
+        ctx.accounts.vault.load_mut()?.set_paused(paused);
+        Ok(())
+    }
+
+    /// Assign `role` to `holder`, or revoke it with `Pubkey::default()`.
+    /// The admin (`vault.authority`) implicitly holds every role.
+    pub fn set_role(ctx: Context<SetRole>, role: VaultRole, holder: Pubkey) -> Result<()> {
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        match role {
+            VaultRole::Guardian => vault.guardian = holder,
+            VaultRole::FeeManager => vault.fee_manager = holder,
+            VaultRole::RewardManager => vault.reward_manager = holder,
+            VaultRole::Slasher => vault.slasher = holder,
+        }
+        Ok(())
+    }
+
+    /// Cap on the share of total assets a single `slash` may take. Zero disables slashing.
+    pub fn set_max_slash(ctx: Context<SetRole>, max_slash_bps: u16) -> Result<()> {
+        require!(max_slash_bps <= 10_000, VaultError::InvalidAmount);
+        ctx.accounts.vault.load_mut()?.max_slash_bps = max_slash_bps;
+        Ok(())
+    }
+
+    /// Hand the admin role to a new key
+    pub fn transfer_admin(ctx: Context<SetRole>, new_admin: Pubkey) -> Result<()> {
+        require!(new_admin != Pubkey::default(), VaultError::InvalidVault);
+        ctx.accounts.vault.load_mut()?.authority = new_admin;
+        Ok(())
+    }
+
+    /// Grow the vault account so the callback allowlist can hold
+    /// `additional_programs` more entries. Rent for the extra space is paid by the authority.
+    pub fn expand_callback_allowlist(
+        ctx: Context<ExpandCallbackAllowlist>,
+        additional_programs: u32,
+    ) -> Result<()> {
+        require!(additional_programs > 0, VaultError::InvalidAmount);
+        // Realloc bounds are enforced by the account constraint; nothing else to update
+        msg!(
+            "Callback allowlist capacity: {}",
+            Vault::callback_allowlist_capacity(ctx.accounts.vault.to_account_info().data_len())
+        );
+        Ok(())
+    }
+
+    pub fn add_callback_program(ctx: Context<UpdateFlashLoanConfig>, program: Pubkey) -> Result<()> {
+        require!(
+            ctx.accounts.vault.load()?.config_timelock_secs == 0,
+            VaultError::ConfigTimelocked
+        );
+        let info = ctx.accounts.vault.to_account_info();
+        let mut data = info.try_borrow_mut_data()?;
+        let mut allowlist = Vault::callback_allowlist(&data);
+
+        require!(
+            !allowlist.contains(&program),
+            VaultError::CallbackAlreadyAllowlisted
+        );
+
+        allowlist.push(program);
+        Vault::set_callback_allowlist(&mut data, &allowlist)
+    }
+
+    pub fn remove_callback_program(ctx: Context<UpdateFlashLoanConfig>, program: Pubkey) -> Result<()> {
+        require!(
+            ctx.accounts.vault.load()?.config_timelock_secs == 0,
+            VaultError::ConfigTimelocked
+        );
+        let info = ctx.accounts.vault.to_account_info();
+        let mut data = info.try_borrow_mut_data()?;
+        let mut allowlist = Vault::callback_allowlist(&data);
+
+        let index = allowlist
+            .iter()
+            .position(|p| *p == program)
+            .ok_or(VaultError::CallbackNotAllowlisted)?;
+        allowlist.swap_remove(index);
+        Vault::set_callback_allowlist(&mut data, &allowlist)
+    }
+
+    /// Lend `amount` to the borrower, then call `callback_program`'s `on_flash_loan`
+    /// (see the `flash-loan-interface` crate), which must repay `amount + fee`.
+    /// Remaining accounts are passed through to the callback.
+    ///
+    /// The borrower signs, unless the vault allows PDA borrowers: then it may be the
+    /// callback program's `flash_loan_interface::borrower_pda`, provided the callback
+    /// is on the allowlist (whether or not the allowlist is enforced for signers).
+    ///
+    /// At most `Vault::MAX_FLASH_LOANS_PER_TX` loans against one vault per transaction,
+    /// counted from the instructions sysvar. Only top-level instructions are visible
+    /// there, so loans can't be taken through CPI: one outer instruction could make
+    /// any number of them.
+    pub fn flash_loan<'info>(ctx: Context<'_, '_, '_, 'info, FlashLoan<'info>>, amount: u64) -> Result<()> {
+        require!(amount > 0, VaultError::InvalidFlashLoanAmount);
+        require!(
+            get_stack_height() == TRANSACTION_LEVEL_STACK_HEIGHT,
+            VaultError::FlashLoanViaCpi
+        );
+        require!(
+            count_flash_loans(&ctx.accounts.instructions_sysvar, &ctx.accounts.vault.key())?
+                <= Vault::MAX_FLASH_LOANS_PER_TX,
+            VaultError::TooManyFlashLoans
+        );
+
+        // Copy out what the loan needs so the vault isn't borrowed across the callback CPI
+        let (
+            token_mint,
+            generation,
+            fee_treasury,
+            flash_fee_bps,
+            callback_allowlist_enabled,
+            pda_borrowers,
+        ) = {
+            let vault = ctx.accounts.vault.load()?;
+            require!(vault.is_flash_loans_enabled(), VaultError::FlashLoansDisabled);
+            require!(!vault.is_paused(), VaultError::VaultPaused);
+            require!(
+                !vault.is_blocked(ctx.accounts.borrower.key),
+                VaultError::AddressBlocked
+            );
+            (
+                vault.token_mint,
+                vault.generation,
+                vault.fee_treasury,
+                vault.flash_fee_bps,
+                vault.is_callback_allowlist_enabled(),
+                vault.is_pda_borrowers_enabled(),
+            )
+        };
+        let vault_token_account = &mut ctx.accounts.vault_token_account;
+
+        // Validate flash loan is configured
+        require!(
+            fee_treasury != Pubkey::default(),
+            VaultError::FlashLoanNotConfigured
+        );
+
+        // Validate borrower token account
+        require!(
+            ctx.accounts.borrower_token_account.mint == token_mint,
+            VaultError::InvalidTokenMint
+        );
+        require!(
+            ctx.accounts.borrower_token_account.owner == ctx.accounts.borrower.key(),
+            VaultError::InvalidTokenMint
+        );
+
+        // Check callback allowlist if enabled; a PDA borrower always needs it
+        let pda_borrower = !ctx.accounts.borrower.is_signer;
+        if callback_allowlist_enabled || pda_borrower {
+            let allowlist =
+                Vault::callback_allowlist(&ctx.accounts.vault.to_account_info().try_borrow_data()?);
+            require!(
+                allowlist.contains(&ctx.accounts.callback_program.key()),
+                VaultError::CallbackNotAllowlisted
+            );
+        }
+        if pda_borrower {
+            require!(pda_borrowers, VaultError::PdaBorrowerNotAllowed);
+            require_keys_eq!(
+                ctx.accounts.borrower.key(),
+                flash_loan_interface::borrower_pda(&ctx.accounts.callback_program.key()).0,
+                VaultError::PdaBorrowerNotAllowed
+            );
+        }
+
+        // Record initial vault balance
+        let balance_before = vault_token_account.amount;
+
+        // Record initial reward vault balance (not part of the loan, must not decrease)
+        let reward_balance_before = ctx.accounts.reward_vault.amount;
+
+        // Validate sufficient vault balance
+        require!(
+            balance_before >= amount,
+            VaultError::InsufficientVaultBalance
+        );
+
+        // Calculate fee using u128 to prevent overflow
+        let fee = ((amount as u128)
+            .checked_mul(flash_fee_bps as u128)
+            .ok_or(VaultError::MathOverflow)?
+            .checked_div(10000)
+            .ok_or(VaultError::DivisionByZero)?) as u64;
+
+        // Transfer loan amount to borrower
+        let seeds = &[
+            b"vault",
+            token_mint.as_ref(),
+            b"authority",
+            generation_seed(&generation),
+            &[ctx.bumps.vault_authority],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = Transfer {
+            from: vault_token_account.to_account_info(),
+            to: ctx.accounts.borrower_token_account.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        anchor_spl::token::transfer(cpi_ctx, amount)?;
+
+        // Hand control to the borrower's callback
+        flash_loan_interface::invoke_on_flash_loan(
+            &ctx.accounts.callback_program.to_account_info(),
+            OnFlashLoanAccounts {
+                vault_token_account: vault_token_account.to_account_info(),
+                borrower_token_account: ctx.accounts.borrower_token_account.to_account_info(),
+                borrower: ctx.accounts.borrower.to_account_info(),
+                token_program: ctx.accounts.token_program.to_account_info(),
+            },
+            &OnFlashLoanArgs {
+                amount,
+                fee,
+                initiator: ctx.accounts.borrower.key(),
+            },
+            ctx.remaining_accounts,
+        )?;
+
+        // Reload vault token account to get updated balance
+        vault_token_account.reload()?;
+
+        // Record final vault balance
+        let balance_after = vault_token_account.amount;
+
+        // Verify repayment invariant by checking balance delta
+        // After loan transfer, vault should have: balance_before - amount
+        // After callback repayment, vault should have: balance_before - amount + (amount + fee) = balance_before + fee
+        // So: balance_after >= balance_before + fee
+        let required_balance = balance_before
+            .checked_add(fee)
+            .ok_or(VaultError::MathOverflow)?;
+        
+        require!(
+            balance_after >= required_balance,
+            VaultError::InsufficientRepayment
+        );
+
+        // Verify the callback did not touch the reward treasury
+        ctx.accounts.reward_vault.reload()?;
+        require!(
+            ctx.accounts.reward_vault.amount >= reward_balance_before,
+            VaultError::RewardVaultDrained
+        );
+
+        // The fee stays in the vault token account until `collect_fees`, so a
+        // misconfigured treasury can't fail the loan
+        if fee > 0 {
+            let vault = &mut ctx.accounts.vault.load_mut()?;
+            vault.pending_protocol_fees = vault
+                .pending_protocol_fees
+                .checked_add(fee)
+                .ok_or(VaultError::MathOverflow)?;
+            vault.lifetime_flash_fees = vault.lifetime_flash_fees.saturating_add(fee);
+        }
+
+        Ok(())
+    }
+
+    /// Pay out the flash fees buffered in the vault token account: the protocol's cut
+    /// to the protocol treasury, the rest to the vault's `fee_treasury`. Permissionless,
+    /// since the destinations are fixed; typically cranked by the treasury.
+    pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
+        let (token_mint, generation, fees) = {
+            let vault = &mut ctx.accounts.vault.load_mut()?;
+            require!(
+                ctx.accounts.fee_treasury_token_account.owner == vault.fee_treasury
+                    && ctx.accounts.fee_treasury_token_account.mint == vault.token_mint,
+                VaultError::InvalidFeeTreasury
+            );
+            let fees = vault.pending_protocol_fees;
+            require!(fees > 0, VaultError::NoPendingFees);
+            vault.pending_protocol_fees = 0;
+            (vault.token_mint, vault.generation, fees)
+        };
+
+        let seeds = &[
+            b"vault",
+            token_mint.as_ref(),
+            b"authority",
+            generation_seed(&generation),
+            &[ctx.bumps.vault_authority],
+        ];
+        let signer = &[&seeds[..]];
+
+        // The protocol's cut comes off the top; the vault's treasury gets the rest
+        let protocol_config = ProtocolConfig::load(&ctx.accounts.protocol_config)?;
+        let protocol_cut = protocol_config.protocol_cut(fees);
+        if protocol_cut > 0 {
+            let protocol_fee_token_account = ctx
+                .accounts
+                .protocol_fee_token_account
+                .as_ref()
+                .ok_or(VaultError::InvalidFeeTreasury)?;
+            require!(
+                protocol_fee_token_account.owner == protocol_config.treasury
+                    && protocol_fee_token_account.mint == token_mint,
+                VaultError::InvalidFeeTreasury
+            );
+
+            let cpi_accounts = Transfer {
+                from: ctx.accounts.vault_token_account.to_account_info(),
+                to: protocol_fee_token_account.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            anchor_spl::token::transfer(cpi_ctx, protocol_cut)?;
+        }
+
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.vault_token_account.to_account_info(),
+            to: ctx.accounts.fee_treasury_token_account.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        anchor_spl::token::transfer(cpi_ctx, fees - protocol_cut)
+    }
+
+    /// Register an external yield program the vault may deploy idle funds into.
+    ///
+    /// `deposit_account` is the token account `invest` pays into; the strategy
+    /// program takes it from there. At most `max_allocation_bps` of the vault's
+    /// total assets may be deployed to this strategy.
+    pub fn add_strategy(
+        ctx: Context<AddStrategy>,
+        deposit_account: Pubkey,
+        max_allocation_bps: u16,
+    ) -> Result<()> {
+        require!(max_allocation_bps <= 10_000, VaultError::InvalidAmount);
+
+        let strategy = &mut ctx.accounts.strategy;
+        strategy.vault = ctx.accounts.vault.key();
+        strategy.strategy_program = ctx.accounts.strategy_program.key();
+        strategy.deposit_account = deposit_account;
+        strategy.max_allocation_bps = max_allocation_bps;
+        strategy.deployed = 0;
+        strategy.total_profit = 0;
+        strategy.total_loss = 0;
+        strategy.last_harvest_ts = Clock::get()?.unix_timestamp;
+        strategy.bump = ctx.bumps.strategy;
+        strategy.retired = false;
+        Ok(())
+    }
+
+    /// Move `amount` idle tokens into a strategy, then CPI into the strategy program
+    /// with `ix_data` (skipped when empty) so it can put them to work. The `Strategy`
+    /// PDA signs the CPI, never the vault authority; pass the strategy's accounts as
+    /// remaining accounts. What actually left the vault token account is booked as
+    /// deployed.
+    pub fn invest(ctx: Context<StrategyAction>, amount: u64, ix_data: Vec<u8>) -> Result<()> {
+        require!(amount > 0, VaultError::InvalidAmount);
+        let strategy = &mut ctx.accounts.strategy;
+        require!(!strategy.retired, VaultError::StrategyRetired);
+        let deposit_account = ctx
+            .accounts
+            .deposit_account
+            .as_ref()
+            .ok_or(VaultError::InvalidStrategyAccount)?;
+        require_keys_eq!(
+            deposit_account.key(),
+            strategy.deposit_account,
+            VaultError::InvalidStrategyAccount
+        );
+
+        let (token_mint, generation) = {
+            let vault = ctx.accounts.vault.load()?;
+            require!(!vault.is_paused(), VaultError::VaultPaused);
+            require!(
+                amount <= vault.idle_balance(ctx.accounts.vault_token_account.amount),
+                VaultError::InsufficientVaultBalance
+            );
+            let total_assets = vault.total_assets(ctx.accounts.vault_token_account.amount)?;
+            let deployed = strategy
+                .deployed
+                .checked_add(amount)
+                .ok_or(VaultError::MathOverflow)?;
+            require!(
+                deployed as u128 * 10_000
+                    <= total_assets as u128 * strategy.max_allocation_bps as u128,
+                VaultError::StrategyAllocationExceeded
+            );
+            (vault.token_mint, vault.generation)
+        };
+
+        let seeds = &[
+            b"vault",
+            token_mint.as_ref(),
+            b"authority",
+            generation_seed(&generation),
+            &[ctx.bumps.vault_authority],
+        ];
+        let balance_before = ctx.accounts.vault_token_account.amount;
+        anchor_spl::token::transfer(
+            CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.vault_token_account.to_account_info(),
+                    to: deposit_account.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+                &[&seeds[..]],
+            ),
+            amount,
+        )?;
+
+        if !ix_data.is_empty() {
+            invoke_strategy(
+                strategy,
+                &ctx.accounts.strategy_program,
+                ctx.remaining_accounts,
+                ix_data,
+            )?;
+        }
+
+        ctx.accounts.vault_token_account.reload()?;
+        let invested = balance_before
+            .checked_sub(ctx.accounts.vault_token_account.amount)
+            .ok_or(VaultError::MathOverflow)?;
+        strategy.deployed = strategy
+            .deployed
+            .checked_add(invested)
+            .ok_or(VaultError::MathOverflow)?;
+        let vault = &mut ctx.accounts.vault.load_mut()?;
+        vault.total_deployed = vault
+            .total_deployed
+            .checked_add(invested)
+            .ok_or(VaultError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// CPI into the strategy program with `ix_data` to pull funds back into the vault
+    /// token account. Whatever arrives reduces the strategy's deployed balance; anything
+    /// beyond it is booked as profit.
+    pub fn divest<'info>(
+        ctx: Context<'_, '_, '_, 'info, StrategyAction<'info>>,
+        ix_data: Vec<u8>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        let received = call_strategy(
+            &accounts.strategy,
+            &accounts.strategy_program,
+            &mut accounts.vault_token_account,
+            ctx.remaining_accounts,
+            ix_data,
+        )?;
+        require!(received > 0, VaultError::InvalidAmount);
+
+        let strategy = &mut accounts.strategy;
+        let vault = &mut accounts.vault.load_mut()?;
+        let principal = received.min(strategy.deployed);
+        let profit = received - principal;
+        strategy.deployed -= principal;
+        vault.total_deployed = vault.total_deployed.saturating_sub(principal);
+        strategy.total_profit = strategy
+            .total_profit
+            .checked_add(profit)
+            .ok_or(VaultError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// CPI into the strategy program with `ix_data` so it pays its realized yield into
+    /// the vault token account. Only that balance increase is booked as profit, never
+    /// a value the strategy reports about itself; it is liquid and flows into share
+    /// pricing as idle balance. Losses are realized by `emergency_divest_all`.
+    pub fn harvest_strategy<'info>(
+        ctx: Context<'_, '_, '_, 'info, StrategyAction<'info>>,
+        ix_data: Vec<u8>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        let profit = call_strategy(
+            &accounts.strategy,
+            &accounts.strategy_program,
+            &mut accounts.vault_token_account,
+            ctx.remaining_accounts,
+            ix_data,
+        )?;
+
+        let strategy = &mut accounts.strategy;
+        strategy.total_profit = strategy
+            .total_profit
+            .checked_add(profit)
+            .ok_or(VaultError::MathOverflow)?;
+        strategy.last_harvest_ts = Clock::get()?.unix_timestamp;
+        Ok(())
+    }
+
+    /// Guardian exit for a compromised integration: CPI into the strategy with `ix_data`
+    /// to pull everything back (pass empty data if the strategy can't be called), write
+    /// off whatever didn't come back as a loss, retire the strategy and pause the vault
+    /// so only withdrawals and claims remain. Bundle one call per strategy.
+    pub fn emergency_divest_all<'info>(
+        ctx: Context<'_, '_, '_, 'info, EmergencyDivest<'info>>,
+        ix_data: Vec<u8>,
+    ) -> Result<()> {
+        let accounts = ctx.accounts;
+        let received = if ix_data.is_empty() {
+            0
+        } else {
+            call_strategy(
+                &accounts.strategy,
+                &accounts.strategy_program,
+                &mut accounts.vault_token_account,
+                ctx.remaining_accounts,
+                ix_data,
+            )?
+        };
+
+        let strategy = &mut accounts.strategy;
+        let vault = &mut accounts.vault.load_mut()?;
+        let principal = received.min(strategy.deployed);
+        strategy.total_profit = strategy
+            .total_profit
+            .checked_add(received - principal)
+            .ok_or(VaultError::MathOverflow)?;
+        strategy.total_loss = strategy
+            .total_loss
+            .checked_add(strategy.deployed - principal)
+            .ok_or(VaultError::MathOverflow)?;
+        vault.total_deployed = vault.total_deployed.saturating_sub(strategy.deployed);
+        strategy.deployed = 0;
+        strategy.retired = true;
+        vault.set_paused(true);
+
+        msg!("Strategy retired, recovered {} tokens", received);
+        Ok(())
+    }
+
+    /// Decommission an empty vault.
+    ///
+    /// Requires `total_shares == 0` and no rewards still owed to positions
+    /// (`unpaid_rewards`; their owners claim them first). Residual stake and reward
+    /// tokens (rounding dust, unspent rewards) are swept to the authority, both PDA-owned
+    /// ATAs are closed and the rent of all three accounts is returned to the authority.
+    pub fn close_vault(ctx: Context<CloseVault>) -> Result<()> {
+        let vault = &ctx.accounts.vault.load()?;
+        require!(vault.total_shares == 0, VaultError::VaultNotEmpty);
+        require!(vault.total_deployed == 0, VaultError::VaultNotEmpty);
+        require!(vault.pending_protocol_fees == 0, VaultError::VaultNotEmpty);
+        require!(vault.unpaid_rewards == 0, VaultError::RewardsStillOwed);
+
+        let seeds = &[
+            b"vault",
+            vault.token_mint.as_ref(),
+            b"authority",
+            vault.generation_seed(),
+            &[ctx.bumps.vault_authority],
+        ];
+        let signer = &[&seeds[..]];
+
+        // Stake and reward mint may be the same, in which case both ATAs are one account
+        let same_account =
+            ctx.accounts.vault_token_account.key() == ctx.accounts.reward_vault.key();
+
+        sweep_and_close_token_account(
+            &ctx.accounts.vault_token_account.to_account_info(),
+            ctx.accounts.vault_token_account.amount,
+            &ctx.accounts.token_mint.to_account_info(),
+            ctx.accounts.token_mint.decimals,
+            &ctx.accounts.authority_token_account.to_account_info(),
+            &ctx.accounts.authority.to_account_info(),
+            &ctx.accounts.vault_authority.to_account_info(),
+            &ctx.accounts.token_program.to_account_info(),
+            signer,
+        )?;
+
+        if !same_account {
+            sweep_and_close_token_account(
+                &ctx.accounts.reward_vault.to_account_info(),
+                ctx.accounts.reward_vault.amount,
+                &ctx.accounts.reward_mint.to_account_info(),
+                ctx.accounts.reward_mint.decimals,
+                &ctx.accounts.authority_reward_token_account.to_account_info(),
+                &ctx.accounts.authority.to_account_info(),
+                &ctx.accounts.vault_authority.to_account_info(),
+                &ctx.accounts.reward_token_program.to_account_info(),
+                signer,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Create the upgrade governance PDA (timelock in front of a multisig admin).
+    ///
+    /// Only the current upgrade authority of vault-core may initialize it, so the
+    /// PDA can't be front-run by someone installing their own admin. Once created,
+    /// each workspace program hands its upgrade authority to the governance PDA
+    /// (loader `SetAuthority`), after which upgrades go through
+    /// `propose_upgrade` → delay → `execute_upgrade`.
+    pub fn initialize_governance(
+        ctx: Context<InitializeGovernance>,
+        admin: Pubkey,
+        upgrade_delay: i64,
+    ) -> Result<()> {
+        require!(upgrade_delay >= 0, VaultError::InvalidTimelockDelay);
+
+        let governance = &mut ctx.accounts.governance;
+        governance.admin = admin;
+        governance.upgrade_delay = upgrade_delay;
+        governance.pending_program = Pubkey::default();
+        governance.pending_buffer = Pubkey::default();
+        governance.upgrade_eta = 0;
+        governance.bump = ctx.bumps.governance;
+        Ok(())
+    }
+
+    /// Queue an upgrade of `program` to the bytecode in `buffer`.
+    /// It becomes executable after `upgrade_delay` seconds.
+    pub fn propose_upgrade(
+        ctx: Context<ProposeUpgrade>,
+        program: Pubkey,
+        buffer: Pubkey,
+    ) -> Result<()> {
+        let clock = Clock::get()?;
+        let governance = &mut ctx.accounts.governance;
+        governance.pending_program = program;
+        governance.pending_buffer = buffer;
+        governance.upgrade_eta = clock
+            .unix_timestamp
+            .checked_add(governance.upgrade_delay)
+            .ok_or(VaultError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Hand the upgrade role (governance admin) to a new key. Upgrade rights are
+    /// deployment-wide, so they live here rather than in the per-vault roles.
+    pub fn set_governance_admin(ctx: Context<ProposeUpgrade>, new_admin: Pubkey) -> Result<()> {
+        require!(new_admin != Pubkey::default(), VaultError::Unauthorized);
+        ctx.accounts.governance.admin = new_admin;
+        Ok(())
+    }
+
+    /// Execute a queued upgrade once its timelock has elapsed (permissionless).
+    /// The governance PDA signs the loader `Upgrade` as upgrade authority.
+    pub fn execute_upgrade(ctx: Context<ExecuteUpgrade>) -> Result<()> {
+        let clock = Clock::get()?;
+        let governance = &ctx.accounts.governance;
+
+        require!(
+            governance.pending_program != Pubkey::default(),
+            VaultError::NoPendingUpgrade
+        );
+        require!(
+            clock.unix_timestamp >= governance.upgrade_eta,
+            VaultError::TimelockNotElapsed
+        );
+
+        let upgrade_ix = solana_loader_v3_interface::instruction::upgrade(
+            &governance.pending_program,
+            &governance.pending_buffer,
+            &governance.key(),
+            &ctx.accounts.spill.key(),
+        );
+        let seeds = &[b"governance".as_ref(), &[governance.bump]];
+        invoke_signed(
+            &upgrade_ix,
+            &[
+                ctx.accounts.program_data.to_account_info(),
+                ctx.accounts.program.to_account_info(),
+                ctx.accounts.buffer.to_account_info(),
+                ctx.accounts.spill.to_account_info(),
+                ctx.accounts.rent.to_account_info(),
+                ctx.accounts.clock.to_account_info(),
+                ctx.accounts.governance.to_account_info(),
+            ],
+            &[&seeds[..]],
+        )?;
+
+        let governance = &mut ctx.accounts.governance;
+        governance.pending_program = Pubkey::default();
+        governance.pending_buffer = Pubkey::default();
+        governance.upgrade_eta = 0;
+        Ok(())
+    }
+
+    /// View: errors unless `program`'s upgrade authority is the governance PDA.
+    /// Works for any upgradeable program, so every workspace program can be graded.
+    pub fn verify_upgrade_authority(ctx: Context<VerifyUpgradeAuthority>) -> Result<()> {
+        require!(
+            ctx.accounts.program_data.key() == get_program_data_address(ctx.accounts.program.key),
+            VaultError::InvalidProgramData
+        );
+        require!(
+            ctx.accounts.program_data.upgrade_authority_address
+                == Some(ctx.accounts.governance.key()),
+            VaultError::UpgradeAuthorityMismatch
+        );
+        Ok(())
+    }
+
+    /// Create the deployment-wide bounds PDA. Same gate as `initialize_governance`:
+    /// only vault-core's current upgrade authority may create it.
+    ///
+    /// Until it exists every bound is at its permissive default.
+    pub fn initialize_protocol_config(
+        ctx: Context<InitializeProtocolConfig>,
+        admin: Pubkey,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.protocol_config;
+        config.set_inner(ProtocolConfig {
+            admin,
+            bump: ctx.bumps.protocol_config,
+            ..ProtocolConfig::permissive()
+        });
+        Ok(())
+    }
+
+    /// Set the flash fee new vaults start with and the protocol's cut of every vault's
+    /// flash fees, paid to token accounts owned by `treasury`. A zero `treasury`
+    /// disables the cut. Also grows a config PDA created before these fields existed.
+    pub fn set_protocol_fees(
+        ctx: Context<SetProtocolFees>,
+        default_flash_fee_bps: u16,
+        protocol_fee_bps: u16,
+        treasury: Pubkey,
+    ) -> Result<()> {
+        let info = ctx.accounts.protocol_config.to_account_info();
+        let mut config: ProtocolConfig = load_legacy(&info, ProtocolConfig::LEN)?;
+        require_keys_eq!(config.admin, ctx.accounts.admin.key(), VaultError::Unauthorized);
+        require!(
+            default_flash_fee_bps <= config.max_flash_fee_bps,
+            VaultError::ExceedsProtocolBound
+        );
+        require!(protocol_fee_bps <= 10_000, VaultError::InvalidAmount);
+
+        config.default_flash_fee_bps = default_flash_fee_bps;
+        config.protocol_fee_bps = protocol_fee_bps;
+        config.treasury = treasury;
+
+        grow_account(
+            &info,
+            &ctx.accounts.admin.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(),
+            ProtocolConfig::LEN,
+        )?;
+        config.try_serialize(&mut &mut info.try_borrow_mut_data()?[..])?;
+        Ok(())
+    }
+
+    /// Set the ceilings per-vault and router settings must stay under: `reward_rate`
+    /// (`fund_rewards`), the flash fee (`update_flash_loan_config`, timelocked changes),
+    /// the management fee and composer-router's `set_fees`.
+    ///
+    /// Bounds are checked when a setting is changed; lowering a bound doesn't
+    /// retroactively rewrite vaults that are already above it.
+    pub fn set_protocol_bounds(
+        ctx: Context<SetProtocolBounds>,
+        max_reward_rate: u64,
+        max_flash_fee_bps: u16,
+        max_router_fee_bps: u16,
+        max_management_fee_bps: u16,
+    ) -> Result<()> {
+        for bps in [max_flash_fee_bps, max_router_fee_bps, max_management_fee_bps] {
+            require!(bps <= 10_000, VaultError::InvalidAmount);
+        }
+
+        let config = &mut ctx.accounts.protocol_config;
+        config.max_reward_rate = max_reward_rate;
+        config.max_flash_fee_bps = max_flash_fee_bps;
+        config.max_router_fee_bps = max_router_fee_bps;
+        config.max_management_fee_bps = max_management_fee_bps;
+        Ok(())
+    }
+}
+
+// Shared by `initialize_vault` and `initialize_vault_generation`
+#[allow(clippy::too_many_arguments)]
+fn init_vault(
+    vault_loader: &AccountLoader<Vault>,
+    vault_entry: &mut Account<VaultEntry>,
+    vault_entry_bump: u8,
+    authority: Pubkey,
+    token_mint: Pubkey,
+    reward_mint: Pubkey,
+    reward_vault: Pubkey,
+    protocol_config: &AccountInfo,
+    generation: u8,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let vault = &mut vault_loader.load_init()?;
+    vault.authority = authority;
+    vault.token_mint = token_mint;
+    vault.generation = generation;
+    vault.total_shares = 0;
+    vault.reward_rate = 0;
+    vault.acc_reward_per_share = 0;
+    vault.last_update_ts = clock.unix_timestamp;
+    vault.reward_mint = reward_mint;
+    vault.reward_vault = reward_vault;
+    // Initialize flash loan fields
+    vault.flash_fee_bps = ProtocolConfig::load(protocol_config)?.default_flash_fee_bps;
+    vault.fee_treasury = Pubkey::default();
+    vault.set_callback_allowlist_enabled(false);
+    vault.callback_allowlist_len = 0;
+    vault.set_flash_loans_enabled(true);
+    vault.keeper = Pubkey::default();
+    vault.keeper_tip_bps = 0;
+    vault.rewards_funded = 0;
+    vault.rewards_accrued = 0;
+    vault.management_fee_bps = 0;
+    vault.fee_recipient = Pubkey::default();
+    vault.last_fee_accrual_ts = clock.unix_timestamp;
+    vault.allowlist_root = [0u8; 32];
+    vault.drop_count = 0;
+    vault.version = Vault::CURRENT_VERSION;
+    vault.config_timelock_secs = 0;
+    // The creator starts out holding every role
+    vault.guardian = vault.authority;
+    vault.fee_manager = vault.authority;
+    vault.reward_manager = vault.authority;
+    vault.set_paused(false);
+
+    vault_entry.set_inner(VaultEntry {
+        vault: vault_loader.key(),
+        token_mint,
+        reward_mint,
+        created_ts: clock.unix_timestamp,
+        bump: vault_entry_bump,
+        name: [0; 32],
+        metadata_uri: String::new(),
+    });
+    Ok(())
+}
+
+// Shared by `claim_rewards` and `claim_rewards_to`; `destination` must hold the reward mint
+#[allow(clippy::too_many_arguments)]
+fn process_claim_rewards<'info>(
+    vault: &AccountLoader<'info, Vault>,
+    user_position: &mut Account<'info, UserPosition>,
+    user: &AccountInfo<'info>,
+    destination: &AccountInfo<'info>,
+    reward_vault: &InterfaceAccount<'info, token_interface::TokenAccount>,
+    reward_mint: &InterfaceAccount<'info, token_interface::Mint>,
+    vault_authority: &AccountInfo<'info>,
+    vault_authority_bump: u8,
+    token_program: &AccountInfo<'info>,
+) -> Result<()> {
+    let vault_key = vault.key();
+    let vault = &mut vault.load_mut()?;
+
+    // Validate reward_vault matches vault's reward_vault
+    require!(
+        vault.reward_vault == reward_vault.key(),
+        VaultError::RewardVaultMismatch
+    );
+
+    // Validate reward mint matches
+    require!(
+        vault.reward_mint == reward_vault.mint,
+        VaultError::InvalidRewardMint
+    );
+
+    // Verify user position matches
+    require!(user_position.vault == vault_key, VaultError::InvalidVault);
+    require!(
+        user_position.user == user.key(),
+        VaultError::InvalidVault
+    );
+
+    // Update rewards before calculating pending
+    let clock = Clock::get()?;
+    accrue_rewards(vault, clock.unix_timestamp)?;
+
+    // Calculate pending rewards
+    let pending_scaled = pending_rewards_scaled(user_position, vault.acc_reward_per_share)?;
+    
+    let pending = pending_scaled
+        .checked_div(REWARD_PRECISION)
+        .ok_or(VaultError::DivisionByZero)?;
+
+    // Transfer rewards if there are any pending
+    if pending > 0 {
+        if reward_vault.amount == 0 {
+            msg!("Reward vault empty, {} owed to position", saturating_u64(pending));
+            return Ok(());
+        }
+        let (paid, owed_scaled) = split_claim(
+            vault,
+            user_position,
+            pending_scaled,
+            reward_vault.amount,
+            clock.unix_timestamp,
+        )?;
+
+        // Transfer tokens from reward vault to user
+        let seeds = &[
+            b"vault",
+            vault.token_mint.as_ref(),
+            b"authority",
+            vault.generation_seed(),
+            &[vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        transfer_reward(
+            &reward_vault.to_account_info(),
+            destination,
+            vault_authority,
+            reward_mint,
+            token_program,
+            signer,
+            paid,
+        )?;
+        vault.lifetime_rewards_paid = vault.lifetime_rewards_paid.saturating_add(paid);
+
+        // Keep the unpaid shortfall and sub-token remainder owed to the user
+        settle_rewards(vault, user_position, owed_scaled)?;
+    }
+
+    Ok(())
+}
+
+// The user's and the vault's second-leg accounts for `deposit_pair`/`withdraw_pair`.
+// The vault side must be the authority's ATA, the balance `withdraw_pair` prices off.
+fn check_second_leg_accounts(
+    vault: &Vault,
+    vault_authority: &Pubkey,
+    user_second_token_account: &TokenAccount,
+    vault_second_token_account: &Account<TokenAccount>,
+) -> Result<()> {
+    require_keys_eq!(
+        vault_second_token_account.key(),
+        get_associated_token_address(vault_authority, &vault.second_mint),
+        VaultError::InvalidTokenMint
+    );
+    require_keys_eq!(
+        user_second_token_account.mint,
+        vault.second_mint,
+        VaultError::InvalidTokenMint
+    );
+    Ok(())
+}
+
+// What `process_deposit` needs from `Deposit` and `DepositWithDelegate`
+struct DepositAccounts<'a, 'info> {
+    vault: &'a AccountLoader<'info, Vault>,
+    user_position: &'a mut Account<'info, UserPosition>,
+    user: Pubkey,
+    user_token_account: &'a Account<'info, TokenAccount>,
+    vault_token_account: &'a Account<'info, TokenAccount>,
+    token_program: &'a Program<'info, Token>,
+    // Signs the pull from `user_token_account`: the user, or the vault authority as
+    // the account's approved delegate
+    transfer_authority: AccountInfo<'info>,
+    user_checkpoints: Option<&'a mut Account<'info, UserCheckpoints>>,
+}
+
+impl<'info> Deposit<'info> {
+    fn deposit_accounts(&mut self) -> DepositAccounts<'_, 'info> {
+        DepositAccounts {
+            vault: &self.vault,
+            user_position: &mut self.user_position,
+            user: self.user.key(),
+            user_token_account: &self.user_token_account,
+            vault_token_account: &self.vault_token_account,
+            token_program: &self.token_program,
+            transfer_authority: self.user.to_account_info(),
+            user_checkpoints: self.user_checkpoints.as_mut(),
+        }
+    }
+}
+
+// Shared by `deposit`, `deposit_sol` and `deposit_with_delegate`. `signer` carries the
+// vault authority seeds when it pulls the tokens as delegate.
+fn process_deposit(
+    accounts: DepositAccounts,
+    signer: &[&[&[u8]]],
+    oracle: Option<&AccountInfo>,
+    amount: u64,
+    min_shares_out: u64,
+    proof: &[[u8; 32]],
+) -> Result<()> {
+    require!(amount > 0, VaultError::InvalidAmount);
+    let vault_key = accounts.vault.key();
+    let vault = &mut accounts.vault.load_mut()?;
+    require!(!vault.is_paused(), VaultError::VaultPaused);
+    require!(
+        !vault.is_blocked(&accounts.user),
+        VaultError::AddressBlocked
+    );
+
+    if vault.allowlist_root != [0u8; 32] {
+        require!(
+            verify_merkle_proof(
+                proof,
+                vault.allowlist_root,
+                &[accounts.user.as_ref()]
+            ),
+            VaultError::NotAllowlisted
+        );
+    }
+
+    let vault_token_account = accounts.vault_token_account;
+    let user_position = accounts.user_position;
+
+    // Verify token mint matches
+    require!(
+        vault.token_mint == vault_token_account.mint,
+        VaultError::InvalidTokenMint
+    );
+    require!(
+        vault.token_mint == accounts.user_token_account.mint,
+        VaultError::InvalidTokenMint
+    );
+
+    // Update rewards before processing deposit
+    let clock = Clock::get()?;
+    accrue_rewards(vault, clock.unix_timestamp)?;
+
+    // Initialize or update user position
+    // Protect against re-initialization: if account exists, verify it matches
+    let is_new_position = user_position.shares == 0;
+    if !is_new_position {
+        // Account already exists - verify it matches
+        require!(
+            user_position.user == accounts.user,
+            VaultError::InvalidUserPosition
+        );
+        require!(user_position.vault == vault_key, VaultError::InvalidVault);
+    } else {
+        // New (or emptied) account - initialize fields; rewards still owed are kept
+        if user_position.user == Pubkey::default() {
+            user_position.index = vault.next_position_index()?;
+        }
+        user_position.user = accounts.user;
+        user_position.vault = vault_key;
+        user_position.version = UserPosition::CURRENT_VERSION;
+    }
+
+    // Get current vault balance
+    let vault_balance = vault_token_account.amount;
+
+    if vault.deposit_cap_usd > 0 {
+        let oracle = oracle.ok_or(VaultError::InvalidOracle)?;
+        require_keys_eq!(oracle.key(), vault.oracle, VaultError::InvalidOracle);
+        let price = OraclePrice::load(oracle)?;
+        let total = vault
+            .total_assets(vault_balance)?
+            .checked_add(amount)
+            .ok_or(VaultError::MathOverflow)?;
+        require!(
+            price.value_usd(vault, total, clock.unix_timestamp)? <= vault.deposit_cap_usd as u128,
+            VaultError::DepositCapExceeded
+        );
+    }
+
+    // Calculate shares to mint
+    let shares = calculate_shares_for_deposit(
+        amount,
+        vault.total_assets(vault_balance)?,
+        vault.total_shares,
+    )?;
+
+    require!(shares > 0, VaultError::InvalidAmount);
+    require!(
+        shares >= min_shares_out as u128,
+        VaultError::SlippageExceeded
+    );
+    require!(
+        vault.total_shares.saturating_add(shares) <= Vault::MAX_TOTAL_SHARES,
+        VaultError::SharesCapExceeded
+    );
+
+    // Transfer tokens from user to vault
+    let cpi_accounts = Transfer {
+        from: accounts.user_token_account.to_account_info(),
+        to: vault_token_account.to_account_info(),
+        authority: accounts.transfer_authority,
+    };
+    let cpi_program = accounts.token_program.to_account_info();
+    let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+    anchor_spl::token::transfer(cpi_ctx, amount)?;
+
+    // Update state
+    vault.lifetime_deposited = vault.lifetime_deposited.saturating_add(amount);
+    vault.total_shares = vault
+        .total_shares
+        .checked_add(shares)
+        .ok_or(VaultError::MathOverflow)?;
+
+    // Settle what the existing shares earned before the new ones join
+    let pending_scaled = pending_rewards_scaled(user_position, vault.acc_reward_per_share)?;
+    user_position.add_shares(shares, clock.unix_timestamp)?;
+    settle_rewards(vault, user_position, pending_scaled)?;
+
+    record_checkpoint(user_position, accounts.user_checkpoints, vault_key)
+}
+
+// Shared by `withdraw`, `withdraw_sol` and `migrate_position_to_vault`; returns the
+// tokens paid out
+fn process_withdraw(
+    accounts: &mut Withdraw,
+    vault_authority_bump: u8,
+    shares: u64,
+    min_tokens_out: u64,
+) -> Result<u64> {
+    require!(shares > 0, VaultError::InvalidAmount);
+    let shares = shares as u128;
+
+    let vault_key = accounts.vault.key();
+    let vault = &mut accounts.vault.load_mut()?;
+    let user_position = &mut accounts.user_position;
+    let vault_token_account = &accounts.vault_token_account;
+
+    // Verify user position matches
+    require!(user_position.vault == vault_key, VaultError::InvalidVault);
+    require!(
+        user_position.user == accounts.user.key(),
+        VaultError::InvalidVault
+    );
+
+    // Verify sufficient shares
+    require!(
+        user_position.shares >= shares,
+        VaultError::InsufficientShares
+    );
+
+    // Update rewards before processing withdraw
+    let clock = Clock::get()?;
+    accrue_rewards(vault, clock.unix_timestamp)?;
+
+    // Get current vault balance
+    let vault_balance = vault_token_account.amount;
+
+    // Calculate tokens to withdraw
+    let tokens = calculate_tokens_for_withdraw(
+        shares,
+        vault.total_assets(vault_balance)?,
+        vault.total_shares,
+    )?;
+    require!(tokens >= min_tokens_out, VaultError::SlippageExceeded);
+    require!(
+        tokens <= vault.idle_balance(vault_balance),
+        VaultError::InsufficientVaultBalance
+    );
+
+    // Transfer tokens from vault to user
+    let seeds = &[
+        b"vault",
+        vault.token_mint.as_ref(),
+        b"authority",
+        vault.generation_seed(),
+        &[vault_authority_bump],
+    ];
+    let signer = &[&seeds[..]];
+
+    let cpi_accounts = Transfer {
+        from: vault_token_account.to_account_info(),
+        to: accounts.user_token_account.to_account_info(),
+        authority: accounts.vault_authority.to_account_info(),
+    };
+    let cpi_program = accounts.token_program.to_account_info();
+    let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+    anchor_spl::token::transfer(cpi_ctx, tokens)?;
+
+    // Update state
+    vault.lifetime_withdrawn = vault.lifetime_withdrawn.saturating_add(tokens);
+    vault.total_shares = vault
+        .total_shares
+        .checked_sub(shares)
+        .ok_or(VaultError::MathOverflow)?;
+
+    // Settle pending rewards at the old share count; they stay claimable after the
+    // withdrawal. An emptied position stays open until `close_position`.
+    let pending_scaled = pending_rewards_scaled(user_position, vault.acc_reward_per_share)?;
+    user_position.shares = user_position
+        .shares
+        .checked_sub(shares)
+        .ok_or(VaultError::MathOverflow)?;
+    settle_rewards(vault, user_position, pending_scaled)?;
+
+    record_checkpoint(user_position, accounts.user_checkpoints.as_mut(), vault_key)?;
+    Ok(tokens)
+}
+
+fn apply_flash_loan_config(
+    vault: &AccountLoader<Vault>,
+    protocol_config: &AccountInfo,
+    flash_fee_bps: Option<u16>,
+    fee_treasury: Option<Pubkey>,
+    callback_allowlist_enabled: Option<bool>,
+    callback_allowlist: Option<Vec<Pubkey>>,
+) -> Result<()> {
+    if let Some(allowlist) = callback_allowlist {
+        let info = vault.to_account_info();
+        Vault::set_callback_allowlist(&mut info.try_borrow_mut_data()?, &allowlist)?;
+    }
+
+    let vault = &mut vault.load_mut()?;
+    if let Some(fee_bps) = flash_fee_bps {
+        require!(fee_bps <= 10000, VaultError::InvalidAmount); // Max 100% fee
+        let bounds = ProtocolConfig::load(protocol_config)?;
+        require!(
+            fee_bps <= bounds.max_flash_fee_bps,
+            VaultError::ExceedsProtocolBound
+        );
+        vault.flash_fee_bps = fee_bps;
+    }
+
+    if let Some(treasury) = fee_treasury {
+        vault.fee_treasury = treasury;
+    }
+
+    if let Some(enabled) = callback_allowlist_enabled {
+        vault.set_callback_allowlist_enabled(enabled);
+    }
+
+    Ok(())
+}
+
+fn apply_management_fee(
+    vault: &mut Vault,
+    protocol_config: &AccountInfo,
+    management_fee_bps: u16,
+    fee_recipient: Pubkey,
+) -> Result<()> {
+    require!(
+        management_fee_bps <= Vault::MAX_MANAGEMENT_FEE_BPS,
+        VaultError::ManagementFeeTooHigh
+    );
+    let bounds = ProtocolConfig::load(protocol_config)?;
+    require!(
+        management_fee_bps <= bounds.max_management_fee_bps,
+        VaultError::ExceedsProtocolBound
+    );
+
+    let clock = Clock::get()?;
+    if vault.management_fee_bps > 0 {
+        require!(
+            vault.last_fee_accrual_ts == clock.unix_timestamp,
+            VaultError::ManagementFeeNotCollected
+        );
+    }
+
+    vault.management_fee_bps = management_fee_bps;
+    vault.fee_recipient = fee_recipient;
+    vault.last_fee_accrual_ts = clock.unix_timestamp;
+    Ok(())
+}
+
+// CPI into a strategy program, signed by the `Strategy` PDA. The strategy's custody
+// answers to that PDA; the vault authority, which owns the vault's token accounts,
+// never signs for a strategy.
+fn invoke_strategy(
+    strategy: &Account<Strategy>,
+    strategy_program: &AccountInfo,
+    remaining_accounts: &[AccountInfo],
+    ix_data: Vec<u8>,
+) -> Result<()> {
+    let strategy_key = strategy.key();
+    let ix = anchor_lang::solana_program::instruction::Instruction {
+        program_id: strategy_program.key(),
+        accounts: remaining_accounts
+            .iter()
+            .map(|acc| anchor_lang::solana_program::instruction::AccountMeta {
+                pubkey: acc.key(),
+                is_signer: acc.is_signer || *acc.key == strategy_key,
+                is_writable: acc.is_writable,
+            })
+            .collect(),
+        data: ix_data,
+    };
+    let seeds = &[
+        b"strategy",
+        strategy.vault.as_ref(),
+        strategy.strategy_program.as_ref(),
+        &[strategy.bump],
+    ];
+    invoke_signed(&ix, remaining_accounts, &[&seeds[..]])?;
+    Ok(())
+}
+
+// Shared by `divest`, `harvest_strategy` and `emergency_divest_all`: run the strategy
+// CPI and return how many tokens it paid into the vault token account. The vault's
+// liquid balance may not drop.
+fn call_strategy<'info>(
+    strategy: &Account<'info, Strategy>,
+    strategy_program: &AccountInfo<'info>,
+    vault_token_account: &mut Account<'info, TokenAccount>,
+    remaining_accounts: &[AccountInfo<'info>],
+    ix_data: Vec<u8>,
+) -> Result<u64> {
+    let balance_before = vault_token_account.amount;
+    invoke_strategy(strategy, strategy_program, remaining_accounts, ix_data)?;
+    vault_token_account.reload()?;
+    let balance_after = vault_token_account.amount;
+    require!(
+        balance_after >= balance_before,
+        VaultError::StrategyDrainedVault
+    );
+    Ok(balance_after - balance_before)
+}
+
+/// Aggregate price read from a Pyth-style (v2) price account
+pub struct OraclePrice {
+    pub price: i64,
+    pub conf: u64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+impl OraclePrice {
+    const MAGIC: u32 = 0xa1b2_c3d4;
+    const ACCOUNT_TYPE_PRICE: u32 = 3;
+    const STATUS_TRADING: u32 = 1;
+
+    pub fn load(info: &AccountInfo) -> Result<Self> {
+        let data = info.try_borrow_data()?;
+        require!(data.len() >= 240, VaultError::InvalidOracle);
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        require!(
+            u32_at(0) == Self::MAGIC && u32_at(8) == Self::ACCOUNT_TYPE_PRICE,
+            VaultError::InvalidOracle
+        );
+        require!(u32_at(224) == Self::STATUS_TRADING, VaultError::InvalidOracle);
+        Ok(Self {
+            expo: u32_at(20) as i32,
+            publish_time: u64_at(96) as i64,
+            price: u64_at(208) as i64,
+            conf: u64_at(216),
+        })
+    }
+
+    /// USD value (6 decimals) of `amount` base units of the vault's token, after
+    /// checking the price against the vault's staleness and confidence limits
+    pub fn value_usd(&self, vault: &Vault, amount: u64, now: i64) -> Result<u128> {
+        require!(self.price > 0, VaultError::InvalidOracle);
+        require!(
+            now.saturating_sub(self.publish_time) <= vault.max_price_age_secs as i64,
+            VaultError::StaleOraclePrice
+        );
+        require!(
+            (self.conf as u128) * 10_000
+                <= (self.price as u128) * vault.max_confidence_bps as u128,
+            VaultError::OracleConfidenceTooWide
+        );
+
+        let value = (amount as u128)
+            .checked_mul(self.price as u128)
+            .ok_or(VaultError::MathOverflow)?;
+        let exponent = self.expo + 6 - vault.token_decimals as i32;
+        let scale = 10u128
+            .checked_pow(exponent.unsigned_abs())
+            .ok_or(VaultError::MathOverflow)?;
+        if exponent >= 0 {
+            Ok(value.checked_mul(scale).ok_or(VaultError::MathOverflow)?)
+        } else {
+            Ok(value / scale)
+        }
+    }
+}
+
+// Helper function to transfer a PDA-owned token account's full balance out and close it
+#[allow(clippy::too_many_arguments)]
+fn sweep_and_close_token_account<'info>(
+    account: &AccountInfo<'info>,
+    amount: u64,
+    mint: &AccountInfo<'info>,
+    decimals: u8,
+    destination: &AccountInfo<'info>,
+    rent_destination: &AccountInfo<'info>,
+    vault_authority: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    signer: &[&[&[u8]]],
+) -> Result<()> {
+    if amount > 0 {
+        let cpi_accounts = TransferChecked {
+            from: account.clone(),
+            mint: mint.clone(),
+            to: destination.clone(),
+            authority: vault_authority.clone(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(token_program.clone(), cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, amount, decimals)?;
+    }
+
+    let cpi_accounts = token_interface::CloseAccount {
+        account: account.clone(),
+        destination: rent_destination.clone(),
+        authority: vault_authority.clone(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.clone(), cpi_accounts, signer);
+    token_interface::close_account(cpi_ctx)
+}
+
+// Reward-mint transfers go through `transfer_checked` so the reward mint may be owned
+// by Token-2022 as well as classic SPL Token
+fn transfer_reward<'info>(
+    from: &AccountInfo<'info>,
+    to: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    reward_mint: &InterfaceAccount<'info, token_interface::Mint>,
+    token_program: &AccountInfo<'info>,
+    signer: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    let cpi_accounts = TransferChecked {
+        from: from.clone(),
+        mint: reward_mint.to_account_info(),
+        to: to.clone(),
+        authority: authority.clone(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.clone(), cpi_accounts, signer);
+    token_interface::transfer_checked(cpi_ctx, amount, reward_mint.decimals)
+}
+
+// Helper function to calculate shares for deposit
+fn calculate_shares_for_deposit(
+    deposit_amount: u64,
+    vault_balance: u64,
+    total_shares: u128,
+) -> Result<u128> {
+    if vault_balance == 0 {
+        // First deposit: 1:1 ratio
+        Ok(deposit_amount as u128)
+    } else {
+        // shares = (deposit_amount * total_shares) / vault_balance
+        // The product can pass u128 once the supply does, see `mul_div_floor`
+        // Note: Integer division rounds down (truncates), which is correct for vault security
+        // This prevents share inflation and ensures the vault can always honor withdrawals
+        let shares = mul_div_floor(deposit_amount as u128, total_shares, vault_balance as u128)
+            .ok_or(VaultError::MathOverflow)?;
+
+        Ok(shares)
+    }
+}
+
+// Helper function to calculate tokens for withdraw
+fn calculate_tokens_for_withdraw(
+    shares: u128,
+    vault_balance: u64,
+    total_shares: u128,
+) -> Result<u64> {
+    require!(total_shares > 0, VaultError::DivisionByZero);
+
+    // tokens = (shares * vault_balance) / total_shares
+    let tokens = mul_div_floor(shares, vault_balance as u128, total_shares)
+        .ok_or(VaultError::MathOverflow)?;
+
+    let tokens_u64 = u64::try_from(tokens).map_err(|_| VaultError::MathOverflow)?;
+    require!(tokens_u64 > 0, VaultError::InvalidAmount);
+
+    Ok(tokens_u64)
+}
+
+// floor(a * b / d) through a 256-bit intermediate; `None` if `d` is zero or the
+// quotient doesn't fit in u128
+fn mul_div_floor(a: u128, b: u128, d: u128) -> Option<u128> {
+    if d == 0 {
+        return None;
+    }
+    if let Some(product) = a.checked_mul(b) {
+        return Some(product / d);
+    }
+    const LO: u128 = u64::MAX as u128;
+    let (a1, a0) = (a >> 64, a & LO);
+    let (b1, b0) = (b >> 64, b & LO);
+    let (p00, p01, p10, p11) = (a0 * b0, a0 * b1, a1 * b0, a1 * b1);
+    let mid = (p00 >> 64) + (p01 & LO) + (p10 & LO);
+    let lo = (p00 & LO) | (mid << 64);
+    let hi = p11 + (p01 >> 64) + (p10 >> 64) + (mid >> 64);
+    if hi >= d {
+        return None;
+    }
+    // Shift-subtract long division; `rem` stays below `d`, the carry covers the bit
+    // shifted out of it
+    let mut rem = hi;
+    let mut quotient = 0u128;
+    for bit in (0..128).rev() {
+        let carry = rem >> 127;
+        rem = (rem << 1) | ((lo >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || rem >= d {
+            rem = rem.wrapping_sub(d);
+            quotient |= 1;
+        }
+    }
+    Some(quotient)
+}
+
+// Shares don't fit in u64 event and view fields once the supply passes it
+fn saturating_u64(value: u128) -> u64 {
+    u64::try_from(value).unwrap_or(u64::MAX)
+}
+
+// Flash loans against `vault` in the current transaction: the top-level `flash_loan`
+// instructions naming it (`flash_loan` refuses CPI, so that's all of them)
+fn count_flash_loans(instructions_sysvar: &AccountInfo, vault: &Pubkey) -> Result<usize> {
+    let mut count = 0;
+    let mut index = 0;
+    while let Ok(ix) = load_instruction_at_checked(index, instructions_sysvar) {
+        if ix.program_id == crate::ID
+            && ix.data.starts_with(instruction::FlashLoan::DISCRIMINATOR)
+            && ix.accounts.first().map(|meta| meta.pubkey) == Some(*vault)
+        {
+            count += 1;
+        }
+        index += 1;
+    }
+    Ok(count)
+}
+
+// Merkle proof check shared by the depositor allowlist and reward drops. Leaves are
+// hash(0x00 || payload), inner nodes are hash(0x01 || min(a, b) || max(a, b)), so
+// proofs don't need left/right flags and a leaf can't be passed off as an inner node.
+fn verify_merkle_proof(proof: &[[u8; 32]], root: [u8; 32], leaf_payload: &[&[u8]]) -> bool {
+    let mut leaf = vec![&[0u8][..]];
+    leaf.extend_from_slice(leaf_payload);
+    let mut node = hashv(&leaf).to_bytes();
+    for sibling in proof {
+        node = if node <= *sibling {
+            hashv(&[&[1], node.as_ref(), sibling.as_ref()]).to_bytes()
+        } else {
+            hashv(&[&[1], sibling.as_ref(), node.as_ref()]).to_bytes()
+        };
+    }
+    node == root
+}
+
+fn is_owner_or_delegate(position: &UserPosition, signer: &Pubkey) -> bool {
+    *signer == position.user
+        || (position.delegate != Pubkey::default() && *signer == position.delegate)
+}
+
+// Deserialize an account written by an older (possibly shorter) layout: bytes past the
+// end of the account read as zero, which is the default for every appended field
+fn load_legacy<T: AccountDeserialize>(info: &AccountInfo, len: usize) -> Result<T> {
+    require_keys_eq!(*info.owner, crate::ID, ErrorCode::AccountOwnedByWrongProgram);
+    let mut data = info.try_borrow_data()?.to_vec();
+    if data.len() < len {
+        data.resize(len, 0);
+    }
+    T::try_deserialize(&mut data.as_slice())
+}
+
+// Resize a program-owned account, topping up rent from `payer`
+fn grow_account<'info>(
+    info: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    new_len: usize,
+) -> Result<()> {
+    if new_len <= info.data_len() {
+        return Ok(());
+    }
+    let required = Rent::get()?.minimum_balance(new_len);
+    let shortfall = required.saturating_sub(info.lamports());
+    if shortfall > 0 {
+        anchor_lang::system_program::transfer(
+            CpiContext::new(
+                system_program.clone(),
+                anchor_lang::system_program::Transfer {
+                    from: payer.clone(),
+                    to: info.clone(),
+                },
+            ),
+            shortfall,
+        )?;
+    }
+    info.resize(new_len)?;
+    Ok(())
+}
+
+// Write a position's new share balance to its owner's checkpoint history. Called by
+// every instruction that changes a position's shares, so the history can't go stale:
+// callers may leave the history out only while the position has none.
+fn record_checkpoint(
+    user_position: &mut UserPosition,
+    checkpoints: Option<&mut Account<UserCheckpoints>>,
+    vault: Pubkey,
+) -> Result<()> {
+    let Some(checkpoints) = checkpoints else {
+        return if user_position.checkpointed {
+            err!(VaultError::CheckpointsRequired)
+        } else {
+            Ok(())
+        };
+    };
+    checkpoints.record(
+        vault,
+        user_position.user,
+        user_position.shares,
+        Clock::get()?.unix_timestamp,
+    );
+    user_position.checkpointed = true;
+    Ok(())
+}
+
+// Helper function to calculate a position's pending rewards (scaled by REWARD_PRECISION):
+// (shares * acc_reward_per_share) - reward_debt, plus anything settled but unpaid
+fn pending_rewards_scaled(user_position: &UserPosition, acc_reward_per_share: u128) -> Result<u128> {
+    let total_owed_scaled = user_position
+        .shares
+        .checked_mul(acc_reward_per_share)
+        .ok_or(VaultError::MathOverflow)?;
+    total_owed_scaled
+        .saturating_sub(user_position.reward_debt)
+        .checked_add(user_position.rewards_owed)
+        .ok_or(VaultError::MathOverflow.into())
+}
+
+// Scale a claim by the position's loyalty multiplier. The forfeited part is taken back
+// out of `rewards_accrued`, so it becomes unallocated again rather than stranded.
+fn apply_loyalty(vault: &mut Vault, user_position: &UserPosition, pending: u64, now: i64) -> u64 {
+    let bps = vault.loyalty_multiplier_bps(user_position.stake_weighted_ts, now);
+    let eligible = ((pending as u128) * (bps as u128) / 10_000) as u64;
+    vault.rewards_accrued = vault
+        .rewards_accrued
+        .saturating_sub((pending - eligible) as u128);
+    eligible
+}
+
+// Shared by every claim path: split `pending_scaled` into what the reward vault's
+// `available` balance pays now and what stays owed to the position as an IOU, paid
+// out by a later claim once the vault is topped up. The loyalty haircut is settled
+// either way.
+fn split_claim(
+    vault: &mut Vault,
+    user_position: &UserPosition,
+    pending_scaled: u128,
+    available: u64,
+    now: i64,
+) -> Result<(u64, u128)> {
+    let pending = saturating_u64(pending_scaled / REWARD_PRECISION);
+    let eligible = apply_loyalty(vault, user_position, pending, now);
+    let paid = eligible.min(available);
+    let settled = pending - eligible + paid;
+    let owed_scaled = pending_scaled
+        .checked_sub(
+            (settled as u128)
+                .checked_mul(REWARD_PRECISION)
+                .ok_or(VaultError::MathOverflow)?,
+        )
+        .ok_or(VaultError::MathOverflow)?;
+    if paid < eligible {
+        msg!("Partial claim: paid {}, {} owed to position", paid, eligible - paid);
+    }
+    Ok((paid, owed_scaled))
+}
+
+// Checkpoint the position at the current accumulator (for its current share count),
+// carrying `unpaid_scaled` in `rewards_owed`: the sub-token remainder of a payout so
+// dust isn't dropped on every claim, an IOU, or rewards settled by a deposit/withdraw.
+fn settle_rewards(
+    vault: &mut Vault,
+    user_position: &mut UserPosition,
+    unpaid_scaled: u128,
+) -> Result<()> {
+    user_position.reward_debt = user_position
+        .shares
+        .checked_mul(vault.acc_reward_per_share)
+        .ok_or(VaultError::MathOverflow)?;
+    set_rewards_owed(vault, user_position, unpaid_scaled);
+    Ok(())
+}
+
+// Set a position's `rewards_owed`, keeping the vault's `unpaid_rewards` total of whole
+// owed tokens in step
+fn set_rewards_owed(vault: &mut Vault, user_position: &mut UserPosition, owed_scaled: u128) {
+    let before = saturating_u64(user_position.rewards_owed / REWARD_PRECISION);
+    let after = saturating_u64(owed_scaled / REWARD_PRECISION);
+    vault.unpaid_rewards = vault
+        .unpaid_rewards
+        .saturating_sub(before)
+        .saturating_add(after);
+    user_position.rewards_owed = owed_scaled;
+}
+
+/// Extra PDA seed of a vault and its authority: none for generation 0, so the first
+/// vault of a mint keeps the addresses it had before generations existed, otherwise
+/// the generation byte (see `initialize_vault_generation`)
+pub fn generation_seed(generation: &u8) -> &[u8] {
+    if *generation == 0 {
+        &[]
+    } else {
+        std::slice::from_ref(generation)
+    }
+}
+
+// Helper function to update rewards based on elapsed time
+// This is idempotent - multiple calls in the same slot produce the same result
+fn accrue_rewards(vault: &mut Vault, current_ts: i64) -> Result<()> {
+    // Emissions halve at every boundary passed since the last update; accrue each
+    // segment at the rate in force during it. Ends once the rate reaches zero.
+    while vault.halving_interval_secs > 0
+        && vault.reward_rate > 0
+        && vault.next_halving_ts <= current_ts
+    {
+        let boundary = vault.next_halving_ts;
+        accrue_segment(vault, boundary)?;
+        vault.reward_rate /= 2;
+        vault.next_halving_ts = boundary.saturating_add(vault.halving_interval_secs as i64);
+    }
+    accrue_segment(vault, current_ts)
+}
+
+// Accrue from `last_update_ts` to `current_ts` at the current `reward_rate`
+fn accrue_segment(vault: &mut Vault, current_ts: i64) -> Result<()> {
+    let delta_ts = current_ts.saturating_sub(vault.last_update_ts);
+
+    // If same slot (delta_ts == 0) or no shares, skip update → idempotent!
+    if delta_ts == 0 || vault.total_shares == 0 {
+        vault.last_update_ts = current_ts;
+        return Ok(());
+    }
+
+    // Calculate rewards to distribute: reward_rate * delta_time
+    let rewards = (vault.reward_rate as u128)
+        .checked_mul(delta_ts as u128)
+        .ok_or(VaultError::MathOverflow)?;
+
+    // Update accumulated rewards per share
+    // acc_reward_per_share += (rewards * REWARD_PRECISION) / total_shares
+    if rewards > 0 && vault.total_shares > 0 {
+        let acc_increment = (rewards * REWARD_PRECISION)
+            .checked_div(vault.total_shares)
+            .ok_or(VaultError::DivisionByZero)?;
+
+        vault.acc_reward_per_share = vault
+            .acc_reward_per_share
+            .checked_add(acc_increment)
+            .ok_or(VaultError::MathOverflow)?;
+        vault.rewards_accrued = vault
+            .rewards_accrued
+            .checked_add(rewards)
+            .ok_or(VaultError::MathOverflow)?;
+    }
+
+    vault.last_update_ts = current_ts;
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct InitializeVault<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = Vault::INIT_LEN,
+        seeds = [b"vault", token_mint.key().as_ref()],
+        bump
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        associated_token::mint = token_mint,
+        associated_token::authority = vault_authority,
+        associated_token::token_program = token_program
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [b"vault", token_mint.key().as_ref(), b"authority"],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    /// Classic SPL or Token-2022; the staked mint is always classic SPL
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        associated_token::mint = reward_mint,
+        associated_token::authority = vault_authority,
+        associated_token::token_program = reward_token_program
+    )]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    /// Owner of `reward_mint`
+    pub reward_token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: Clock sysvar for timestamp
+    pub clock: Sysvar<'info, Clock>,
+
+    /// CHECK: protocol bounds PDA, may be uninitialized (see `ProtocolConfig::load`)
+    #[account(seeds = [b"protocol_config"], bump)]
+    pub protocol_config: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = VaultEntry::LEN,
+        seeds = [b"vault_entry", vault.key().as_ref()],
+        bump
+    )]
+    pub vault_entry: Account<'info, VaultEntry>,
+}
+
+#[derive(Accounts)]
+#[instruction(generation: u8)]
+pub struct InitializeVaultGeneration<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = Vault::INIT_LEN,
+        seeds = [b"vault", token_mint.key().as_ref(), &[generation]],
+        bump
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        associated_token::mint = token_mint,
+        associated_token::authority = vault_authority,
+        associated_token::token_program = token_program
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [b"vault", token_mint.key().as_ref(), b"authority", &[generation]],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    /// Classic SPL or Token-2022; the staked mint is always classic SPL
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        associated_token::mint = reward_mint,
+        associated_token::authority = vault_authority,
+        associated_token::token_program = reward_token_program
+    )]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    /// Owner of `reward_mint`
+    pub reward_token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: protocol bounds PDA, may be uninitialized (see `ProtocolConfig::load`)
+    #[account(seeds = [b"protocol_config"], bump)]
+    pub protocol_config: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = VaultEntry::LEN,
+        seeds = [b"vault_entry", vault.key().as_ref()],
+        bump
+    )]
+    pub vault_entry: Account<'info, VaultEntry>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePositionToVault<'info> {
+    /// The vault and position the shares leave
+    pub src: Withdraw<'info>,
+    /// The vault of the same mint they move to; `user_token_account` must be the
+    /// same account as `src`'s
+    pub dst: Deposit<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(name: [u8; 32], metadata_uri: String)]
+pub struct SetMetadata<'info> {
+    #[account(has_one = authority @ VaultError::InvalidVault)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"vault_entry", vault.key().as_ref()],
+        bump = vault_entry.bump,
+        constraint = metadata_uri.len() <= VaultEntry::MAX_METADATA_URI_LEN
+            @ VaultError::MetadataUriTooLong,
+        realloc = VaultEntry::space(metadata_uri.len()),
+        realloc::payer = authority,
+        realloc::zero = false
+    )]
+    pub vault_entry: Account<'info, VaultEntry>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterVault<'info> {
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = VaultEntry::LEN,
+        seeds = [b"vault_entry", vault.key().as_ref()],
+        bump
+    )]
+    pub vault_entry: Account<'info, VaultEntry>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Deposit<'info> {
+    #[account(mut)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserPosition::LEN,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    /// Share history for governance, written on every share change (see `checkpoint`).
+    /// Optional so pre-checkpoint callers keep working, until the position has a history.
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserCheckpoints::LEN,
+        seeds = [b"checkpoints", vault.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub user_checkpoints: Option<Account<'info, UserCheckpoints>>,
+}
+
+#[derive(Accounts)]
+pub struct DepositWithDelegate<'info> {
+    #[account(mut)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = UserPosition::LEN,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    /// CHECK: position owner; doesn't sign, authorizes through the token approval
+    pub user: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = user_token_account.owner == user.key() @ VaultError::DelegateNotApproved
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account, and the approved delegate
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    /// Share history for governance, written on every share change (see `checkpoint`).
+    /// Optional so pre-checkpoint callers keep working, until the position has a history.
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = UserCheckpoints::LEN,
+        seeds = [b"checkpoints", vault.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub user_checkpoints: Option<Account<'info, UserCheckpoints>>,
+}
+
+#[derive(Accounts)]
+pub struct Withdraw<'info> {
+    #[account(mut)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = user @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: Clock sysvar for timestamp
+    pub clock: Sysvar<'info, Clock>,
+
+    /// Share history for governance, written on every share change (see `checkpoint`).
+    /// Optional so pre-checkpoint callers keep working, until the position has a history.
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = UserCheckpoints::LEN,
+        seeds = [b"checkpoints", vault.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub user_checkpoints: Option<Account<'info, UserCheckpoints>>,
+}
+
+#[derive(Accounts)]
+pub struct DepositSol<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(address = native_mint::ID @ VaultError::InvalidTokenMint)]
+    pub native_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = native_mint,
+        associated_token::authority = user
+    )]
+    pub user_wsol_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+
+    /// `user_token_account` must be `user_wsol_account`
+    pub deposit: Deposit<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ConfigureDualStake<'info> {
+    #[account(mut, has_one = authority @ VaultError::InvalidVault)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub second_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        associated_token::mint = second_mint,
+        associated_token::authority = vault_authority,
+        associated_token::token_program = token_program
+    )]
+    pub vault_second_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token accounts
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DepositPair<'info> {
+    #[account(mut)]
+    pub user_second_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub vault_second_token_account: Account<'info, TokenAccount>,
+
+    pub deposit: Deposit<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimAndStake<'info> {
+    #[account(
+        mut,
+        has_one = reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"position", vault.key().as_ref(), target.user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        constraint = user_position.user == target.user.key() @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+
+    /// The deposit into the target vault; its user signs for both sides
+    pub target: Deposit<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawPair<'info> {
+    #[account(mut)]
+    pub user_second_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub vault_second_token_account: Account<'info, TokenAccount>,
+
+    pub withdraw: Withdraw<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawSol<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(address = native_mint::ID @ VaultError::InvalidTokenMint)]
+    pub native_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = native_mint,
+        associated_token::authority = user
+    )]
+    pub user_wsol_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+
+    /// `user_token_account` must be `user_wsol_account`
+    pub withdraw: Withdraw<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FundRewards<'info> {
+    #[account(
+        mut,
+        has_one = reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(mut)]
+    pub funder: Signer<'info>,
+
+    #[account(mut)]
+    pub funder_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+
+    /// CHECK: protocol bounds PDA, may be uninitialized (see `ProtocolConfig::load`)
+    #[account(seeds = [b"protocol_config"], bump)]
+    pub protocol_config: UncheckedAccount<'info>,
+}
+
+/// Fixed-size vault state, read in place through `AccountLoader`.
+///
+/// Fields are ordered by alignment so the `repr(C)` layout has no padding. Booleans are
+/// stored as `u8`; use the accessor methods. The callback allowlist lives in the bytes
+/// after the struct (see `Vault::callback_allowlist`) so `expand_callback_allowlist`
+/// can keep growing it. New fields should be carved out of `_reserved`; a layout that
+/// grows the struct needs a new discriminator so unmigrated accounts fail to load.
+#[account(zero_copy, discriminator = b"vault_v6")]
+pub struct Vault {
+    pub acc_reward_per_share: u128,
+    // Reward accounting (cumulative, in reward token units)
+    pub rewards_funded: u128,
+    pub rewards_accrued: u128,
+    // u64 share supply of v4/v5; `migrate_vault` moves it to `total_shares` and zeroes it
+    pub _legacy_total_shares: u64,
+    pub reward_rate: u64,
+    pub last_update_ts: i64,
+    pub last_fee_accrual_ts: i64,
+    // Number of reward drops created (next drop id)
+    pub drop_count: u64,
+    // Delay before a proposed fee/allowlist change can be executed
+    pub config_timelock_secs: i64,
+    pub authority: Pubkey,
+    pub token_mint: Pubkey,
+    pub reward_mint: Pubkey,
+    pub reward_vault: Pubkey,
+    pub fee_treasury: Pubkey,
+    pub keeper: Pubkey,
+    pub fee_recipient: Pubkey,
+    // Role holders. `authority` is the admin and implicitly holds every role.
+    pub guardian: Pubkey,
+    pub fee_manager: Pubkey,
+    pub reward_manager: Pubkey,
+    // Depositor allowlist (all zeroes = open vault)
+    pub allowlist_root: [u8; 32],
+    pub callback_allowlist_len: u32,
+    pub flash_fee_bps: u16,
+    pub keeper_tip_bps: u16,
+    pub management_fee_bps: u16,
+    pub callback_allowlist_enabled: u8,
+    pub flash_loans_enabled: u8,
+    // Blocks deposits and flash loans
+    pub paused: u8,
+    // Layout version, see `migrate_vault`
+    pub version: u8,
+    // USD deposit cap, see `set_deposit_cap`. Carved out of `_reserved`, so zero
+    // (no cap) on existing accounts.
+    pub max_confidence_bps: u16,
+    pub deposit_cap_usd: u64,
+    pub oracle: Pubkey,
+    pub max_price_age_secs: u32,
+    pub token_decimals: u8,
+    pub _padding: [u8; 3],
+    // Tokens currently deployed to strategies (v5), counted in share pricing
+    pub total_deployed: u64,
+    // Cumulative totals for analytics; never decrease. Carved out of `_reserved`, so
+    // they start counting from the upgrade on existing accounts.
+    pub lifetime_deposited: u64,
+    pub lifetime_withdrawn: u64,
+    pub lifetime_rewards_paid: u64,
+    pub lifetime_flash_fees: u64,
+    // Addresses barred from depositing and borrowing, see `block_address`.
+    // The first `blocked_count` entries are live.
+    pub blocked_addresses: [Pubkey; 8],
+    pub blocked_count: u8,
+    pub _padding2: u8,
+    // Loyalty boost, see `set_loyalty_boost`. Zero ramp = disabled.
+    pub loyalty_min_bps: u16,
+    pub loyalty_ramp_secs: u32,
+    // Dual-stake second leg, see `configure_dual_stake`. Zero mint = single-asset vault.
+    pub second_mint: Pubkey,
+    pub pair_ratio_a: u64,
+    pub pair_ratio_b: u64,
+    // Slashing, see `slash`. A zero cap disables slashing entirely.
+    pub slasher: Pubkey,
+    pub lifetime_slashed: u64,
+    pub max_slash_bps: u16,
+    pub _padding3: [u8; 6],
+    // Widened from u64 in v6, capped at `MAX_TOTAL_SHARES`
+    pub total_shares: u128,
+    // Emission decay, see `set_reward_halving`. Zero interval = constant rate.
+    pub next_halving_ts: i64,
+    pub halving_interval_secs: u32,
+    // Lend to allowlisted callbacks' non-signing borrower PDAs, see `flash_loan`
+    pub pda_borrowers_enabled: u8,
+    pub _padding4: [u8; 3],
+    // Flash fees held in the vault token account until `collect_fees`; not staked assets
+    pub pending_protocol_fees: u64,
+    // Positions numbered so far (next `UserPosition::index`). Carved out of `_reserved`,
+    // so existing vaults number their older positions via `index_position`.
+    pub position_count: u64,
+    // Whole reward tokens settled into positions' `rewards_owed` and not yet paid (IOUs
+    // and rewards left on withdrawn positions). Carved out of `_reserved`, so it only
+    // counts what positions were owed after the upgrade.
+    pub unpaid_rewards: u64,
+    // Distinguishes vaults of the same mint, see `generation_seed`
+    pub generation: u8,
+    pub _padding5: [u8; 7],
+    pub _reserved: [u8; 48],
+}
+
+impl Vault {
+    /// Allowlist slots reserved at `initialize_vault`; grow with `expand_callback_allowlist`
+    pub const MAX_CALLBACK_PROGRAMS: usize = 10;
+    pub const MAX_KEEPER_TIP_BPS: u16 = 500;
+    pub const MAX_MANAGEMENT_FEE_BPS: u16 = 1_000;
+    /// Discriminator plus the fixed fields; allowlist entries follow
+    pub const LEN: usize = 8 + std::mem::size_of::<Vault>();
+    /// Size of the fixed v4 layout, which `migrate_vault` extends in place
+    pub const LEN_V4: usize = 520;
+    /// Account size allocated by `initialize_vault`
+    pub const INIT_LEN: usize = Self::LEN + 32 * Self::MAX_CALLBACK_PROGRAMS;
+
+    /// Versions 0-3 are `LegacyVault`, 4 is the first zero-copy layout
+    pub const CURRENT_VERSION: u8 = 6;
+    /// Discriminator of v5, which stored `total_shares` as u64 (same size as v6)
+    pub const DISCRIMINATOR_V5: &'static [u8] = b"vault_v5";
+    /// Share supply ceiling. Leaves 32 bits of headroom so products of shares with
+    /// reward and fee accumulators stay within u128.
+    pub const MAX_TOTAL_SHARES: u128 = 1 << 96;
+
+    /// Discriminator shared by the Borsh layouts and v4, derived from the type name
+    pub fn legacy_discriminator() -> [u8; 8] {
+        hashv(&[b"account:Vault"]).to_bytes()[..8].try_into().unwrap()
+    }
+
+    /// Staked tokens under management: the liquid `vault_balance` plus what is
+    /// deployed to strategies
+    pub fn total_assets(&self, vault_balance: u64) -> Result<u64> {
+        Ok(self
+            .idle_balance(vault_balance)
+            .checked_add(self.total_deployed)
+            .ok_or(VaultError::MathOverflow)?)
+    }
+
+    /// Part of the vault token account's `vault_balance` that belongs to stakers, i.e.
+    /// without the flash fees awaiting `collect_fees`
+    pub fn idle_balance(&self, vault_balance: u64) -> u64 {
+        vault_balance.saturating_sub(self.pending_protocol_fees)
+    }
+
+    /// Extra PDA seed of the vault and its authority, see `generation_seed`
+    pub fn generation_seed(&self) -> &[u8] {
+        generation_seed(&self.generation)
+    }
+
+    /// Hand out the next `UserPosition::index`
+    pub fn next_position_index(&mut self) -> Result<u64> {
+        let index = self.position_count;
+        self.position_count = index.checked_add(1).ok_or(VaultError::MathOverflow)?;
+        Ok(index)
+    }
+
+    /// Share of pending rewards (bps) a position staked since `stake_weighted_ts` is paid:
+    /// `loyalty_min_bps` for a fresh stake, rising linearly to 100% after `loyalty_ramp_secs`
+    pub fn loyalty_multiplier_bps(&self, stake_weighted_ts: i64, now: i64) -> u64 {
+        if self.loyalty_ramp_secs == 0 {
+            return 10_000;
+        }
+        let ramp = self.loyalty_ramp_secs as u64;
+        let age = (now.saturating_sub(stake_weighted_ts).max(0) as u64).min(ramp);
+        let min = self.loyalty_min_bps as u64;
+        min + (10_000 - min) * age / ramp
+    }
+
+    /// Capacity of the on-chain blocklist
+    pub const MAX_BLOCKED_ADDRESSES: usize = 8;
+    /// Flash loans against one vault allowed in a single transaction
+    pub const MAX_FLASH_LOANS_PER_TX: usize = 1;
+
+    pub fn is_blocked(&self, key: &Pubkey) -> bool {
+        self.blocked_addresses[..self.blocked_count as usize].contains(key)
+    }
+
+    /// Whether `key` may act as `role`: the role holder or the admin
+    pub fn has_role(&self, role: VaultRole, key: &Pubkey) -> bool {
+        let holder = match role {
+            VaultRole::Guardian => self.guardian,
+            VaultRole::FeeManager => self.fee_manager,
+            VaultRole::RewardManager => self.reward_manager,
+            VaultRole::Slasher => self.slasher,
+        };
+        *key == self.authority || (holder != Pubkey::default() && *key == holder)
+    }
+
+    pub fn is_dual_stake(&self) -> bool {
+        self.second_mint != Pubkey::default()
+    }
+
+    /// Second-leg tokens required alongside `amount` of the first leg (rounded up)
+    pub fn pair_amount(&self, amount: u64) -> Result<u64> {
+        let numerator = (amount as u128)
+            .checked_mul(self.pair_ratio_b as u128)
+            .ok_or(VaultError::MathOverflow)?;
+        let ratio_a = self.pair_ratio_a as u128;
+        u64::try_from(numerator.div_ceil(ratio_a)).map_err(|_| VaultError::MathOverflow.into())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused != 0
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused as u8;
+    }
+
+    pub fn is_flash_loans_enabled(&self) -> bool {
+        self.flash_loans_enabled != 0
+    }
+
+    pub fn set_flash_loans_enabled(&mut self, enabled: bool) {
+        self.flash_loans_enabled = enabled as u8;
+    }
+
+    pub fn is_callback_allowlist_enabled(&self) -> bool {
+        self.callback_allowlist_enabled != 0
+    }
+
+    pub fn set_callback_allowlist_enabled(&mut self, enabled: bool) {
+        self.callback_allowlist_enabled = enabled as u8;
+    }
+
+    pub fn is_pda_borrowers_enabled(&self) -> bool {
+        self.pda_borrowers_enabled != 0
+    }
+
+    /// Number of allowlist entries an account of `data_len` bytes can hold
+    pub fn callback_allowlist_capacity(data_len: usize) -> usize {
+        data_len.saturating_sub(Self::LEN) / 32
+    }
+
+    /// Callback allowlist entries stored after the fixed fields of a vault account's `data`
+    pub fn callback_allowlist(data: &[u8]) -> Vec<Pubkey> {
+        let vault: &Vault = bytemuck::from_bytes(&data[8..Self::LEN]);
+        data[Self::LEN..]
+            .chunks_exact(32)
+            .take(vault.callback_allowlist_len as usize)
+            .map(|entry| Pubkey::new_from_array(entry.try_into().unwrap()))
+            .collect()
+    }
+
+    /// Overwrite the callback allowlist stored in a vault account's `data`
+    pub fn set_callback_allowlist(data: &mut [u8], programs: &[Pubkey]) -> Result<()> {
+        require!(
+            programs.len() <= Self::callback_allowlist_capacity(data.len()),
+            VaultError::CallbackAllowlistFull
+        );
+        let (head, tail) = data.split_at_mut(Self::LEN);
+        tail.fill(0);
+        for (slot, program) in tail.chunks_exact_mut(32).zip(programs) {
+            slot.copy_from_slice(program.as_ref());
+        }
+        let vault: &mut Vault = bytemuck::from_bytes_mut(&mut head[8..]);
+        vault.callback_allowlist_len = programs.len() as u32;
+        Ok(())
+    }
+}
+
+/// Borsh layout used by vault versions 0-3. Only read by `migrate_vault`.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct LegacyVault {
+    pub authority: Pubkey,
+    pub token_mint: Pubkey,
+    pub total_shares: u64,
+    pub reward_rate: u64,
+    pub acc_reward_per_share: u128,
+    pub last_update_ts: i64,
+    pub reward_mint: Pubkey,
+    pub reward_vault: Pubkey,
+    pub flash_fee_bps: u16,
+    pub fee_treasury: Pubkey,
+    pub callback_allowlist_enabled: bool,
+    pub callback_allowlist: Vec<Pubkey>,
+    pub flash_loans_enabled: bool,
+    pub keeper: Pubkey,
+    pub keeper_tip_bps: u16,
+    pub rewards_funded: u128,
+    pub rewards_accrued: u128,
+    pub management_fee_bps: u16,
+    pub fee_recipient: Pubkey,
+    pub last_fee_accrual_ts: i64,
+    pub allowlist_root: [u8; 32],
+    pub drop_count: u64,
+    pub version: u8,
+    pub config_timelock_secs: i64,
+    pub guardian: Pubkey,
+    pub fee_manager: Pubkey,
+    pub reward_manager: Pubkey,
+    pub paused: bool,
+}
+
+impl LegacyVault {
+    /// Size of the v3 layout with the initial allowlist slots
+    pub const LEN_V3: usize = 8 + 32 + 32 + 8 + 8 + 16 + 8 + 32 + 32 + 2 + 32 + 1
+        + 4 + (32 * Vault::MAX_CALLBACK_PROGRAMS)
+        + 1 + 32 + 2 + 16 + 16 + 2 + 32 + 8 + 32 + 8 + 1 + 8 + 32 + 32 + 32 + 1;
+    /// Size of the v2 layout (before roles)
+    pub const LEN_V2: usize = Self::LEN_V3 - 97;
+    /// Size of the v1 layout (before `config_timelock_secs`)
+    pub const LEN_V1: usize = Self::LEN_V2 - 8;
+    /// Size of the unversioned (v0) layout
+    pub const LEN_V0: usize = Self::LEN_V1 - 1;
+
+    /// Legacy accounts are the base layout plus whole 32-byte allowlist slots, and no
+    /// base layout shares the zero-copy base size modulo 32, so the size alone tells
+    /// a legacy account apart from a current one
+    pub fn is_legacy_len(data_len: usize) -> bool {
+        [Self::LEN_V0, Self::LEN_V1, Self::LEN_V2, Self::LEN_V3]
+            .iter()
+            .any(|len| data_len >= *len && (data_len - len).is_multiple_of(32))
+    }
+
+    /// Base account size written by layout `version`
+    pub fn layout_len(version: u8) -> usize {
+        match version {
+            0 => Self::LEN_V0,
+            1 => Self::LEN_V1,
+            2 => Self::LEN_V2,
+            _ => Self::LEN_V3,
+        }
+    }
+}
+
+const _: () = {
+    assert!(Vault::LEN % 32 != LegacyVault::LEN_V0 % 32);
+    assert!(Vault::LEN % 32 != LegacyVault::LEN_V1 % 32);
+    assert!(Vault::LEN % 32 != LegacyVault::LEN_V2 % 32);
+    assert!(Vault::LEN % 32 != LegacyVault::LEN_V3 % 32);
+};
+
+#[account]
+pub struct UserPosition {
+    pub user: Pubkey,
+    pub vault: Pubkey,
+    // u64 shares of v0-v4; `migrate_position` moves them to `shares` and zeroes this
+    pub _legacy_shares: u64,
+    pub reward_debt: u128,
+    pub keeper_claims_enabled: bool,
+    // Layout version, see `migrate_position`. New fields go after this one.
+    pub version: u8,
+    // May claim to the owner and compound, never withdraw (v2)
+    pub delegate: Pubkey,
+    // Share-weighted average time the current shares were staked (v3), see
+    // `Vault::loyalty_multiplier_bps`. Withdrawals leave it unchanged.
+    pub stake_weighted_ts: i64,
+    // Rewards settled but not yet paid, scaled by REWARD_PRECISION (v4): claim dust,
+    // IOUs, and what the shares earned before the last deposit/withdraw
+    pub rewards_owed: u128,
+    // Widened to u128 alongside `Vault::total_shares` (v5)
+    pub shares: u128,
+    // Order the position was opened in within its vault (v6), see `PositionIndex`.
+    // Borsh offset 170, for `getProgramAccounts` filters alongside `vault` at 40.
+    pub index: u64,
+    // Has a `UserCheckpoints` history, which every later share change must then be
+    // given (see `record_checkpoint`). Fits the v6 struct padding, zero until set.
+    pub checkpointed: bool,
+}
+
+impl UserPosition {
+    pub const LEN: usize = 8 + std::mem::size_of::<Self>();
+    pub const CURRENT_VERSION: u8 = 6;
+    /// `index` of positions migrated from v5 until `index_position` numbers them
+    pub const UNINDEXED: u64 = u64::MAX;
+
+    /// Add freshly staked shares, pulling the weighted stake time towards `now`
+    pub fn add_shares(&mut self, shares: u128, now: i64) -> Result<()> {
+        let total = self
+            .shares
+            .checked_add(shares)
+            .ok_or(VaultError::MathOverflow)?;
+        // Weighted in timestamp-sized steps so the product fits even near the share cap
+        let elapsed = now.saturating_sub(self.stake_weighted_ts).max(0) as u128;
+        let shift =
+            mul_div_floor(elapsed, shares, total.max(1)).ok_or(VaultError::MathOverflow)?;
+        self.stake_weighted_ts = self.stake_weighted_ts.saturating_add(shift as i64);
+        self.shares = total;
+        Ok(())
+    }
+}
+
+/// An external yield program the vault deploys idle funds into. The account (a PDA)
+/// signs the vault's CPIs into `strategy_program`, so the strategy's custody should
+/// answer to it.
+#[account]
+pub struct Strategy {
+    pub vault: Pubkey,
+    pub strategy_program: Pubkey,
+    // Token account `invest` pays into
+    pub deposit_account: Pubkey,
+    pub max_allocation_bps: u16,
+    // Principal plus harvested profit currently held by the strategy
+    pub deployed: u64,
+    pub total_profit: u64,
+    pub total_loss: u64,
+    pub last_harvest_ts: i64,
+    pub bump: u8,
+    // Set by `emergency_divest_all`; no further investments
+    pub retired: bool,
+}
+
+impl Strategy {
+    pub const LEN: usize = 8 + 32 + 32 + 32 + 2 + 8 + 8 + 8 + 8 + 1 + 1;
+}
+
+#[zero_copy]
+pub struct VaultSnapshot {
+    pub acc_reward_per_share: u128,
+    pub timestamp: i64,
+    // Staked tokens held by the vault token account
+    pub tracked_balance: u64,
+    pub total_shares: u64,
+    pub _reserved: [u8; 8],
+}
+
+/// Share-price history for charts and APY. `snapshots` is a ring buffer: `head` is the
+/// next slot to write and `len` the number of filled slots.
+#[account(zero_copy)]
+pub struct VaultStats {
+    pub snapshots: [VaultSnapshot; VaultStats::CAPACITY],
+    pub vault: Pubkey,
+    pub head: u32,
+    pub len: u32,
+    pub _reserved: [u8; 8],
+}
+
+impl VaultStats {
+    pub const CAPACITY: usize = 64;
+    pub const MIN_SNAPSHOT_INTERVAL_SECS: i64 = 3600;
+    pub const LEN: usize = 8 + std::mem::size_of::<VaultStats>();
+
+    /// Most recent snapshot, if any
+    pub fn latest(&self) -> Option<&VaultSnapshot> {
+        if self.len == 0 {
+            return None;
+        }
+        let index = (self.head as usize + Self::CAPACITY - 1) % Self::CAPACITY;
+        Some(&self.snapshots[index])
+    }
+
+    /// Record `snapshot`, overwriting the oldest one once the buffer is full
+    pub fn push(&mut self, snapshot: VaultSnapshot) {
+        self.snapshots[self.head as usize] = snapshot;
+        self.head = ((self.head as usize + 1) % Self::CAPACITY) as u32;
+        self.len = (self.len + 1).min(Self::CAPACITY as u32);
+    }
+}
+
+/// Registry record created alongside each vault, so clients can enumerate vaults with
+/// `getProgramAccounts`. Fixed layout for memcmp filters: `vault` at offset 8,
+/// `token_mint` at 40, `reward_mint` at 72, `created_ts` at 104. The display metadata
+/// after them is set by `set_metadata`.
+#[account]
+pub struct VaultEntry {
+    pub vault: Pubkey,
+    pub token_mint: Pubkey,
+    pub reward_mint: Pubkey,
+    pub created_ts: i64,
+    pub bump: u8,
+    // UTF-8, zero-padded
+    pub name: [u8; 32],
+    pub metadata_uri: String,
+}
+
+impl VaultEntry {
+    pub const LEN: usize = Self::space(0);
+    pub const MAX_METADATA_URI_LEN: usize = 200;
+
+    /// Account size with a `uri_len`-byte metadata URI
+    pub const fn space(uri_len: usize) -> usize {
+        8 + 32 * 3 + 8 + 1 + 32 + 4 + uri_len
+    }
+}
+
+/// Enumeration entry for the `index`-th position opened in `vault`, created by
+/// `index_position`. Walk `0..Vault::position_count` to list a vault's positions;
+/// entries whose position was closed (or reopened under a new index) are stale, so
+/// check the position's `index` before trusting one.
+#[account]
+pub struct PositionIndex {
+    pub vault: Pubkey,
+    pub user: Pubkey,
+    pub index: u64,
+    pub bump: u8,
+}
+
+impl PositionIndex {
+    pub const LEN: usize = 8 + 32 * 2 + 8 + 1;
+}
+
+/// History of one user's share balance in one vault: the latest
+/// `UserCheckpoints::MAX_CHECKPOINTS` changes, allocated up front so recording never
+/// has to grow the account
+#[account]
+pub struct UserCheckpoints {
+    pub vault: Pubkey,
+    pub user: Pubkey,
+    // Ordered by timestamp; the oldest entry is dropped once full
+    pub checkpoints: Vec<ShareCheckpoint>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShareCheckpoint {
+    pub shares: u64,
+    pub timestamp: i64,
+}
+
+impl UserCheckpoints {
+    pub const MAX_CHECKPOINTS: usize = 32;
+    pub const LEN: usize = Self::space(Self::MAX_CHECKPOINTS);
+
+    /// Account size holding `count` checkpoints
+    pub const fn space(count: usize) -> usize {
+        8 + 32 + 32 + 4 + count * 16
+    }
+
+    /// Record `shares` at `timestamp`. One entry per timestamp: a later checkpoint in
+    /// the same second replaces it. Once full, the oldest entry makes room.
+    pub fn record(&mut self, vault: Pubkey, user: Pubkey, shares: u128, timestamp: i64) {
+        let shares = saturating_u64(shares);
+        self.vault = vault;
+        self.user = user;
+        match self.checkpoints.last_mut() {
+            Some(last) if last.timestamp == timestamp => last.shares = shares,
+            _ => {
+                if self.checkpoints.len() >= Self::MAX_CHECKPOINTS {
+                    self.checkpoints.remove(0);
+                }
+                self.checkpoints.push(ShareCheckpoint { shares, timestamp });
+            }
+        }
+    }
+
+    /// Shares recorded by the latest checkpoint at or before `timestamp`: zero before
+    /// the first, `None` before the oldest entry of a full history (it may have been
+    /// dropped)
+    pub fn shares_at(&self, timestamp: i64) -> Option<u64> {
+        let index = self
+            .checkpoints
+            .partition_point(|c| c.timestamp <= timestamp);
+        match index {
+            0 if self.checkpoints.len() >= Self::MAX_CHECKPOINTS => None,
+            0 => Some(0),
+            _ => Some(self.checkpoints[index - 1].shares),
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewards<'info> {
+    #[account(
+        mut,
+        has_one = reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = user @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    /// Created on the first claim so new users don't need a separate ATA transaction
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = reward_mint,
+        associated_token::authority = user,
+        associated_token::token_program = reward_token_program
+    )]
+    pub user_reward_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+
+    /// CHECK: Clock sysvar for timestamp
+    pub clock: Sysvar<'info, Clock>,
+
+    pub associated_token_program: Program<'info, AssociatedToken>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimRewardsTo<'info> {
+    #[account(
+        mut,
+        has_one = reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = user @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    /// Any reward-mint account, regardless of owner
+    #[account(
+        mut,
+        constraint = recipient_token_account.mint == vault.load()?.reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub recipient_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SetLoyaltyBoost<'info> {
+    #[account(
+        mut,
+        constraint = vault.load()?.has_role(VaultRole::RewardManager, authority.key) @ VaultError::MissingRole
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetRewardHalving<'info> {
+    #[account(
+        mut,
+        constraint = vault.load()?.has_role(VaultRole::RewardManager, authority.key) @ VaultError::MissingRole
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawUnallocatedRewards<'info> {
+    #[account(
+        mut,
+        constraint = vault.load()?.has_role(VaultRole::RewardManager, authority.key) @ VaultError::MissingRole,
+        has_one = reward_vault @ VaultError::RewardVaultMismatch,
+        has_one = reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub authority: Signer<'info>,
+
+    #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    /// CHECK: PDA authority for the reward vault
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(mut, token::mint = reward_mint)]
+    pub authority_reward_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+}
+
+/// One-off Merkle reward distribution, escrowed in its own token account
+#[account]
+pub struct RewardDrop {
+    pub vault: Pubkey,
+    pub mint: Pubkey,
+    pub id: u64,
+    pub root: [u8; 32],
+    pub total_amount: u64,
+    pub claimed_amount: u64,
+    pub bump: u8,
+}
+
+impl RewardDrop {
+    pub const LEN: usize = 8 + std::mem::size_of::<Self>();
+}
+
+/// Operational roles the admin can delegate per vault
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum VaultRole {
+    /// Pause deposits and flash loans
+    Guardian,
+    /// Flash-loan fee and allowlist, management fee
+    FeeManager,
+    /// Reward emission rate and reclaiming unallocated rewards
+    RewardManager,
+    /// Slash the vault into its insurance fund, up to `max_slash_bps` per call. May be a
+    /// PDA so that a slashing program can sign for it.
+    Slasher,
+}
+
+/// Settings a timelocked config change can touch. `None` leaves a setting unchanged;
+/// a lone `fee_recipient` keeps the current management fee rate.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+pub struct ConfigChange {
+    pub flash_fee_bps: Option<u16>,
+    pub fee_treasury: Option<Pubkey>,
+    pub callback_allowlist_enabled: Option<bool>,
+    pub callback_allowlist: Option<Vec<Pubkey>>,
+    pub management_fee_bps: Option<u16>,
+    pub fee_recipient: Option<Pubkey>,
+    pub config_timelock_secs: Option<i64>,
+}
+
+/// Config change waiting out the vault's config timelock
+#[account]
+pub struct PendingConfigChange {
+    pub vault: Pubkey,
+    pub eta: i64,
+    pub change: ConfigChange,
+    pub bump: u8,
+}
+
+impl PendingConfigChange {
+    pub fn space(change: &ConfigChange) -> usize {
+        8 + // discriminator
+            32 + // vault
+            8 + // eta
+            1 + 2 + // flash_fee_bps
+            1 + 32 + // fee_treasury
+            1 + 1 + // callback_allowlist_enabled
+            1 + 4 + 32 * change.callback_allowlist.as_ref().map_or(0, |l| l.len()) + // callback_allowlist
+            1 + 2 + // management_fee_bps
+            1 + 32 + // fee_recipient
+            1 + 8 + // config_timelock_secs
+            1 // bump
+    }
+}
+
+/// Claimed flags for leaves [chunk * CLAIMS_PER_ACCOUNT, (chunk + 1) * CLAIMS_PER_ACCOUNT)
+#[account]
+pub struct ClaimBitmap {
+    pub reward_drop: Pubkey,
+    pub claimed: [u8; 256],
+}
+
+impl ClaimBitmap {
+    pub const CLAIMS_PER_ACCOUNT: u64 = 256 * 8;
+    pub const LEN: usize = 8 + 32 + 256;
+}
+
+#[derive(Accounts)]
+pub struct CreateRewardDrop<'info> {
+    #[account(
+        mut,
+        has_one = authority @ VaultError::InvalidVault,
+        has_one = reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = RewardDrop::LEN,
+        seeds = [b"drop", vault.key().as_ref(), vault.load()?.drop_count.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub reward_drop: Account<'info, RewardDrop>,
+
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        associated_token::mint = reward_mint,
+        associated_token::authority = reward_drop,
+        associated_token::token_program = reward_token_program
+    )]
+    pub drop_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut, token::mint = reward_mint)]
+    pub authority_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u64)]
+pub struct ClaimDrop<'info> {
+    #[account(mut)]
+    pub reward_drop: Account<'info, RewardDrop>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = ClaimBitmap::LEN,
+        seeds = [
+            b"drop_claims",
+            reward_drop.key().as_ref(),
+            (index / ClaimBitmap::CLAIMS_PER_ACCOUNT).to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub claim_bitmap: Account<'info, ClaimBitmap>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(address = reward_drop.mint @ VaultError::InvalidRewardMint)]
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = reward_mint,
+        associated_token::authority = reward_drop,
+        associated_token::token_program = reward_token_program
+    )]
+    pub drop_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut, token::mint = reward_mint)]
+    pub user_reward_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateVault<'info> {
+    /// CHECK: still in an older layout; owner and discriminator are checked in
+    /// `migrate_vault`
+    #[account(mut)]
+    pub vault: UncheckedAccount<'info>,
+
+    /// CHECK: must match the vault's stored authority; receives any surplus rent
+    #[account(mut)]
+    pub authority: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigratePosition<'info> {
+    /// CHECK: may be too short to deserialize as the current `UserPosition`; owner and
+    /// discriminator are checked in `load_legacy`
+    #[account(mut)]
+    pub user_position: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetAllowlistRoot<'info> {
+    #[account(
+        mut,
+        has_one = authority @ VaultError::InvalidVault
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetManagementFee<'info> {
+    #[account(
+        mut,
+        constraint = vault.load()?.has_role(VaultRole::FeeManager, authority.key) @ VaultError::MissingRole
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub authority: Signer<'info>,
+
+    /// CHECK: protocol bounds PDA, may be uninitialized (see `ProtocolConfig::load`)
+    #[account(seeds = [b"protocol_config"], bump)]
+    pub protocol_config: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CollectManagementFee<'info> {
+    #[account(
+        mut,
+        constraint = vault.load()?.management_fee_bps > 0 @ VaultError::ManagementFeeDisabled
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = UserPosition::LEN,
+        seeds = [b"position", vault.key().as_ref(), vault.load()?.fee_recipient.as_ref()],
+        bump
+    )]
+    pub fee_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetKeeper<'info> {
+    #[account(
+        mut,
+        has_one = authority @ VaultError::InvalidVault
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetKeeperClaims<'info> {
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = user @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetDepositCap<'info> {
+    #[account(
+        mut,
+        has_one = authority @ VaultError::InvalidVault,
+        has_one = token_mint @ VaultError::InvalidTokenMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub token_mint: Account<'info, Mint>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateRewards<'info> {
+    #[account(mut)]
+    pub vault: AccountLoader<'info, Vault>,
+}
+
+#[derive(Accounts)]
+pub struct GetExchangeRate<'info> {
+    #[account(mut)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeVaultStats<'info> {
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = VaultStats::LEN,
+        seeds = [b"stats", vault.key().as_ref()],
+        bump
+    )]
+    pub vault_stats: AccountLoader<'info, VaultStats>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Snapshot<'info> {
+    #[account(mut)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(mut, seeds = [b"stats", vault.key().as_ref()], bump)]
+    pub vault_stats: AccountLoader<'info, VaultStats>,
+
+    #[account(
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Checkpoint<'info> {
+    pub vault: AccountLoader<'info, Vault>,
+
+    /// CHECK: any address; only used to derive the position and checkpoint PDAs
+    pub user: UncheckedAccount<'info>,
+
+    /// CHECK: may be closed or never created (zero shares); owner checked in `checkpoint`
+    #[account(mut, seeds = [b"position", vault.key().as_ref(), user.key().as_ref()], bump)]
+    pub user_position: UncheckedAccount<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = UserCheckpoints::LEN,
+        seeds = [b"checkpoints", vault.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub user_checkpoints: Account<'info, UserCheckpoints>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u64)]
+pub struct IndexPosition<'info> {
+    #[account(mut)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(mut, has_one = vault @ VaultError::InvalidVault)]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = PositionIndex::LEN,
+        seeds = [b"position_index", vault.key().as_ref(), &index.to_le_bytes()],
+        bump
+    )]
+    pub position_index: Account<'info, PositionIndex>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePosition<'info> {
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        close = user,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = user @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Donate<'info> {
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub donor: Signer<'info>,
+
+    #[account(mut, token::mint = vault.load()?.token_mint)]
+    pub donor_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Slash<'info> {
+    #[account(
+        mut,
+        constraint = vault.load()?.has_role(VaultRole::Slasher, slasher.key) @ VaultError::MissingRole
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(mut)]
+    pub slasher: Signer<'info>,
+
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    /// Slashed tokens accumulate here, held by the vault authority
+    #[account(
+        init_if_needed,
+        payer = slasher,
+        seeds = [b"insurance", vault.key().as_ref()],
+        bump,
+        token::mint = token_mint,
+        token::authority = vault_authority
+    )]
+    pub insurance_fund: Account<'info, TokenAccount>,
+
+    #[account(address = vault.load()?.token_mint @ VaultError::InvalidTokenMint)]
+    pub token_mint: Account<'info, Mint>,
+
+    /// CHECK: PDA authority for the vault token accounts
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AssertInvariants<'info> {
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(address = vault.load()?.reward_vault @ VaultError::RewardVaultMismatch)]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPositionDelegate<'info> {
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = user @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DelegateClaimRewards<'info> {
+    #[account(
+        mut,
+        has_one = reward_vault @ VaultError::RewardVaultMismatch,
+        has_one = reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = user @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    /// CHECK: Position owner; only used to derive the position and their reward ATA
+    pub user: UncheckedAccount<'info>,
+
+    /// Rewards always land in the owner's canonical ATA
+    #[account(
+        mut,
+        associated_token::mint = reward_mint,
+        associated_token::authority = user,
+        associated_token::token_program = reward_token_program
+    )]
+    pub user_reward_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub delegate: Signer<'info>,
+
+    #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CompoundRewards<'info> {
+    #[account(
+        mut,
+        has_one = reward_vault @ VaultError::RewardVaultMismatch
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = user @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    /// CHECK: Position owner; only used to derive the position
+    pub user: UncheckedAccount<'info>,
+
+    /// Owner or delegate; funds the checkpoint history's growth
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub reward_vault: Account<'info, TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token accounts
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    /// Share history for governance, written on every share change (see `checkpoint`).
+    /// Optional so pre-checkpoint callers keep working, until the position has a history.
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = UserCheckpoints::LEN,
+        seeds = [b"checkpoints", vault.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub user_checkpoints: Option<Account<'info, UserCheckpoints>>,
+}
+
+#[derive(Accounts)]
+pub struct KeeperClaimRewards<'info> {
+    #[account(
+        mut,
+        has_one = reward_vault @ VaultError::RewardVaultMismatch,
+        has_one = reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
+        bump,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = user @ VaultError::InvalidVault
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    /// CHECK: Position owner; only used to derive the position and their reward ATA
+    pub user: UncheckedAccount<'info>,
+
+    /// Rewards always land in the owner's canonical ATA
+    #[account(
+        mut,
+        associated_token::mint = reward_mint,
+        associated_token::authority = user,
+        associated_token::token_program = reward_token_program
+    )]
+    pub user_reward_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub keeper: Signer<'info>,
+
+    #[account(mut)]
+    pub keeper_reward_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    #[account(mut)]
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    pub reward_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateFlashLoanConfig<'info> {
+    #[account(
+        mut,
+        constraint = vault.load()?.has_role(VaultRole::FeeManager, authority.key) @ VaultError::MissingRole
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub authority: Signer<'info>,
+
+    /// CHECK: protocol bounds PDA, may be uninitialized (see `ProtocolConfig::load`)
+    #[account(seeds = [b"protocol_config"], bump)]
+    pub protocol_config: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct GuardianAction<'info> {
+    #[account(
+        mut,
+        constraint = vault.load()?.has_role(VaultRole::Guardian, authority.key) @ VaultError::MissingRole
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    /// Guardian or admin
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetRole<'info> {
+    #[account(
+        mut,
+        has_one = authority @ VaultError::InvalidVault
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetConfigTimelock<'info> {
+    #[account(
+        mut,
+        has_one = authority @ VaultError::InvalidVault
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(change: ConfigChange)]
+pub struct ProposeConfigChange<'info> {
+    #[account(constraint = vault.load()?.has_role(VaultRole::FeeManager, authority.key) @ VaultError::MissingRole)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    /// One queued change per vault; cancel it to propose a different one
+    #[account(
+        init,
+        payer = authority,
+        space = PendingConfigChange::space(&change),
+        seeds = [b"pending_config", vault.key().as_ref()],
+        bump
+    )]
+    pub pending_change: Account<'info, PendingConfigChange>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CancelConfigChange<'info> {
+    #[account(constraint = vault.load()?.has_role(VaultRole::FeeManager, authority.key) @ VaultError::MissingRole)]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [b"pending_config", vault.key().as_ref()],
+        bump = pending_change.bump
+    )]
+    pub pending_change: Account<'info, PendingConfigChange>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteConfigChange<'info> {
+    #[account(
+        mut,
+        constraint = vault.load()?.has_role(VaultRole::FeeManager, authority.key) @ VaultError::MissingRole
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        close = authority,
+        seeds = [b"pending_config", vault.key().as_ref()],
+        bump = pending_change.bump
+    )]
+    pub pending_change: Account<'info, PendingConfigChange>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    /// CHECK: protocol bounds PDA, may be uninitialized (see `ProtocolConfig::load`)
+    #[account(seeds = [b"protocol_config"], bump)]
+    pub protocol_config: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(additional_programs: u32)]
+pub struct ExpandCallbackAllowlist<'info> {
+    #[account(
+        mut,
+        has_one = authority @ VaultError::InvalidVault,
+        constraint = (additional_programs as usize) * 32 <= MAX_PERMITTED_DATA_INCREASE
+            @ VaultError::CallbackAllowlistFull,
+        realloc = vault.to_account_info().data_len() + (additional_programs as usize) * 32,
+        realloc::payer = authority,
+        realloc::zero = true
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct FlashLoan<'info> {
+    #[account(
+        mut,
+        has_one = reward_vault @ VaultError::RewardVaultMismatch
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
+
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    /// CHECK: signs, or is the callback program's borrower PDA (checked in `flash_loan`)
+    #[account(mut)]
+    pub borrower: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub borrower_token_account: Account<'info, TokenAccount>,
+
+    /// CHECK: Callback program to invoke
+    pub callback_program: UncheckedAccount<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+
+    /// CHECK: instructions sysvar, address-checked; read by `count_flash_loans`
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions_sysvar: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CollectFees<'info> {
+    #[account(mut)]
+    pub vault: AccountLoader<'info, Vault>,
 
-    // If same slot (delta_ts == 0) or no shares, skip update → idempotent!
-    if delta_ts == 0 || vault.total_shares == 0 {
-        vault.last_update_ts = current_ts;
-        return Ok(());
-    }
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
+    pub vault_token_account: Account<'info, TokenAccount>,
 
-    // Calculate rewards to distribute: reward_rate * delta_time
-    let rewards = (vault.reward_rate as u128)
-        .checked_mul(delta_ts as u128)
-        .ok_or(VaultError::MathOverflow)?;
+    /// CHECK: PDA authority for the vault token account
+    #[account(
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
+        bump
+    )]
+    pub vault_authority: UncheckedAccount<'info>,
 
-    // Update accumulated rewards per share
-    // acc_reward_per_share += (rewards * REWARD_PRECISION) / total_shares
-    if rewards > 0 && vault.total_shares > 0 {
-        let acc_increment = (rewards * REWARD_PRECISION)
-            .checked_div(vault.total_shares as u128)
-            .ok_or(VaultError::DivisionByZero)?;
+    #[account(mut)]
+    pub fee_treasury_token_account: Account<'info, TokenAccount>,
 
-        vault.acc_reward_per_share = vault
-            .acc_reward_per_share
-            .checked_add(acc_increment)
-            .ok_or(VaultError::MathOverflow)?;
-    }
+    /// CHECK: protocol bounds PDA, may be uninitialized (see `ProtocolConfig::load`)
+    #[account(seeds = [b"protocol_config"], bump)]
+    pub protocol_config: UncheckedAccount<'info>,
 
-    vault.last_update_ts = current_ts;
-    Ok(())
+    /// Receives the protocol's cut of the fee; required once a protocol treasury is set
+    #[account(mut)]
+    pub protocol_fee_token_account: Option<Account<'info, TokenAccount>>,
+
+    pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-pub struct InitializeVault<'info> {
+pub struct AddStrategy<'info> {
+    #[account(has_one = authority @ VaultError::InvalidVault)]
+    pub vault: AccountLoader<'info, Vault>,
+
     #[account(
         init,
         payer = authority,
-        space = Vault::LEN,
-        seeds = [b"vault", token_mint.key().as_ref()],
+        space = Strategy::LEN,
+        seeds = [b"strategy", vault.key().as_ref(), strategy_program.key().as_ref()],
         bump
     )]
-    pub vault: Account<'info, Vault>,
+    pub strategy: Account<'info, Strategy>,
+
+    /// CHECK: only recorded; invoked by `invest`, `divest` and `harvest_strategy`
+    #[account(executable)]
+    pub strategy_program: UncheckedAccount<'info>,
 
     #[account(mut)]
     pub authority: Signer<'info>,
 
-    pub token_mint: Account<'info, Mint>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct StrategyAction<'info> {
+    #[account(mut, has_one = authority @ VaultError::InvalidVault)]
+    pub vault: AccountLoader<'info, Vault>,
 
     #[account(
-        init_if_needed,
-        payer = authority,
-        associated_token::mint = token_mint,
-        associated_token::authority = vault_authority,
-        associated_token::token_program = token_program
+        mut,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = strategy_program @ VaultError::InvalidStrategyAccount
+    )]
+    pub strategy: Account<'info, Strategy>,
+
+    /// CHECK: checked against `strategy.strategy_program`
+    pub strategy_program: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
     )]
     pub vault_token_account: Account<'info, TokenAccount>,
 
-    /// CHECK: PDA authority for the vault token account
+    /// CHECK: PDA authority for the vault token account, signs `invest`'s transfer
     #[account(
-        seeds = [b"vault", token_mint.key().as_ref(), b"authority"],
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
         bump
     )]
     pub vault_authority: UncheckedAccount<'info>,
 
-    pub reward_mint: Account<'info, Mint>,
+    /// Only used by `invest`
+    #[account(mut)]
+    pub deposit_account: Option<Account<'info, TokenAccount>>,
 
-    #[account(
-        init_if_needed,
-        payer = authority,
-        associated_token::mint = reward_mint,
-        associated_token::authority = vault_authority,
-        associated_token::token_program = token_program
-    )]
-    pub reward_vault: Account<'info, TokenAccount>,
+    pub authority: Signer<'info>,
 
     pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
-
-    /// CHECK: Clock sysvar for timestamp
-    pub clock: Sysvar<'info, Clock>,
 }
 
 #[derive(Accounts)]
-pub struct Deposit<'info> {
-    #[account(mut)]
-    pub vault: Account<'info, Vault>,
-
+pub struct EmergencyDivest<'info> {
     #[account(
-        init_if_needed,
-        payer = user,
-        space = UserPosition::LEN,
-        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
-        bump
+        mut,
+        constraint = vault.load()?.has_role(VaultRole::Guardian, authority.key) @ VaultError::MissingRole
     )]
-    pub user_position: Account<'info, UserPosition>,
+    pub vault: AccountLoader<'info, Vault>,
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+    #[account(
+        mut,
+        has_one = vault @ VaultError::InvalidVault,
+        has_one = strategy_program @ VaultError::InvalidStrategyAccount
+    )]
+    pub strategy: Account<'info, Strategy>,
 
-    #[account(mut)]
-    pub user_token_account: Account<'info, TokenAccount>,
+    /// CHECK: checked against `strategy.strategy_program`
+    pub strategy_program: UncheckedAccount<'info>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
+    )]
     pub vault_token_account: Account<'info, TokenAccount>,
 
     /// CHECK: PDA authority for the vault token account
     #[account(
-        seeds = [b"vault", vault.token_mint.as_ref(), b"authority"],
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
         bump
     )]
     pub vault_authority: UncheckedAccount<'info>,
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+    pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct Withdraw<'info> {
+pub struct CloseVault<'info> {
+    #[account(
+        mut,
+        close = authority,
+        has_one = authority @ VaultError::InvalidVault,
+        has_one = reward_vault @ VaultError::RewardVaultMismatch,
+        has_one = token_mint @ VaultError::InvalidTokenMint,
+        has_one = reward_mint @ VaultError::InvalidRewardMint
+    )]
+    pub vault: AccountLoader<'info, Vault>,
+
     #[account(mut)]
-    pub vault: Account<'info, Vault>,
+    pub authority: Signer<'info>,
 
     #[account(
         mut,
-        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
-        bump,
-        has_one = vault @ VaultError::InvalidVault,
-        has_one = user @ VaultError::InvalidVault
+        associated_token::mint = vault.load()?.token_mint,
+        associated_token::authority = vault_authority
     )]
-    pub user_position: Account<'info, UserPosition>,
+    pub vault_token_account: Account<'info, TokenAccount>,
 
     #[account(mut)]
-    pub user: Signer<'info>,
+    pub reward_vault: InterfaceAccount<'info, token_interface::TokenAccount>,
 
-    #[account(mut)]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub token_mint: Account<'info, Mint>,
 
-    #[account(mut)]
-    pub vault_token_account: Account<'info, TokenAccount>,
+    pub reward_mint: InterfaceAccount<'info, token_interface::Mint>,
 
-    /// CHECK: PDA authority for the vault token account
+    /// CHECK: PDA authority for the vault token accounts
     #[account(
-        seeds = [b"vault", vault.token_mint.as_ref(), b"authority"],
+        seeds = [
+            b"vault",
+            vault.load()?.token_mint.as_ref(),
+            b"authority",
+            vault.load()?.generation_seed()
+        ],
         bump
     )]
     pub vault_authority: UncheckedAccount<'info>,
 
+    #[account(mut, token::mint = vault.load()?.token_mint)]
+    pub authority_token_account: Account<'info, TokenAccount>,
+
+    #[account(mut, token::mint = reward_mint)]
+    pub authority_reward_token_account: InterfaceAccount<'info, token_interface::TokenAccount>,
+
+    // Vaults created before the registry need `register_vault` first
+    #[account(
+        mut,
+        close = authority,
+        seeds = [b"vault_entry", vault.key().as_ref()],
+        bump = vault_entry.bump
+    )]
+    pub vault_entry: Account<'info, VaultEntry>,
+
     pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+    pub reward_token_program: Interface<'info, TokenInterface>,
+}
 
-    /// CHECK: Clock sysvar for timestamp
-    pub clock: Sysvar<'info, Clock>,
+#[account]
+pub struct Governance {
+    pub admin: Pubkey,
+    pub upgrade_delay: i64,
+    pub pending_program: Pubkey,
+    pub pending_buffer: Pubkey,
+    pub upgrade_eta: i64,
+    pub bump: u8,
+}
+
+impl Governance {
+    pub const LEN: usize = 8 + std::mem::size_of::<Self>();
 }
 
 #[derive(Accounts)]
-pub struct FundRewards<'info> {
-    #[account(mut)]
-    pub vault: Account<'info, Vault>,
+pub struct InitializeGovernance<'info> {
+    #[account(
+        init,
+        payer = upgrade_authority,
+        space = Governance::LEN,
+        seeds = [b"governance"],
+        bump
+    )]
+    pub governance: Account<'info, Governance>,
 
     #[account(mut)]
-    pub funder: Signer<'info>,
+    pub upgrade_authority: Signer<'info>,
 
-    #[account(mut)]
-    pub funder_token_account: Account<'info, TokenAccount>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()) @ VaultError::InvalidProgramData)]
+    pub program: Program<'info, crate::program::VaultCore>,
 
-    #[account(mut)]
-    pub reward_vault: Account<'info, TokenAccount>,
+    #[account(
+        constraint = program_data.upgrade_authority_address == Some(upgrade_authority.key())
+            @ VaultError::UpgradeAuthorityMismatch
+    )]
+    pub program_data: Account<'info, ProgramData>,
 
-    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
 }
 
+/// Deployment-wide ceilings protecting depositors from rogue per-vault authorities
 #[account]
-pub struct Vault {
-    pub authority: Pubkey,
-    pub token_mint: Pubkey,
-    pub total_shares: u64,
-    pub reward_rate: u64,
-    pub acc_reward_per_share: u128,
-    pub last_update_ts: i64,
-    pub reward_mint: Pubkey,
-    pub reward_vault: Pubkey,
-    // Flash loan fields
-    pub flash_fee_bps: u16,
-    pub fee_treasury: Pubkey,
-    pub callback_allowlist_enabled: bool,
-    pub callback_allowlist: Vec<Pubkey>,
+pub struct ProtocolConfig {
+    pub admin: Pubkey,
+    pub max_reward_rate: u64,
+    pub max_flash_fee_bps: u16,
+    // Withdrawal and performance fee bounds of the first layout; vaults charge neither,
+    // so they were never enforced. Kept so the fields after them don't move.
+    pub _unused_fee_bounds: [u16; 2],
+    // Ceiling for composer-router's `RouterState::fee_bps`
+    pub max_router_fee_bps: u16,
+    pub bump: u8,
+    pub max_management_fee_bps: u16,
+    // Flash fee `initialize_vault` starts new vaults with
+    pub default_flash_fee_bps: u16,
+    // Share of every flash fee paid to `treasury` (zero treasury = no cut)
+    pub protocol_fee_bps: u16,
+    pub treasury: Pubkey,
 }
 
-impl Vault {
-    pub const MAX_CALLBACK_PROGRAMS: usize = 10;
-    pub const LEN: usize = 8 + // discriminator
-        32 + // authority
-        32 + // token_mint
-        8 + // total_shares
-        8 + // reward_rate
-        16 + // acc_reward_per_share
-        8 + // last_update_ts
-        32 + // reward_mint
-        32 + // reward_vault
-        2 + // flash_fee_bps
-        32 + // fee_treasury
-        1 + // callback_allowlist_enabled
-        4 + (32 * Self::MAX_CALLBACK_PROGRAMS); // callback_allowlist (Vec<Pubkey> max size)
-}
+impl ProtocolConfig {
+    pub const LEN: usize = 8 + std::mem::size_of::<Self>();
 
-#[account]
-pub struct UserPosition {
-    pub user: Pubkey,
-    pub vault: Pubkey,
-    pub shares: u64,
-    pub reward_debt: u128,
-}
+    /// Bounds in force before the config PDA is created
+    pub fn permissive() -> Self {
+        Self {
+            admin: Pubkey::default(),
+            max_reward_rate: u64::MAX,
+            max_flash_fee_bps: 10_000,
+            _unused_fee_bounds: [0; 2],
+            max_router_fee_bps: 10_000,
+            bump: 0,
+            max_management_fee_bps: 10_000,
+            default_flash_fee_bps: 0,
+            protocol_fee_bps: 0,
+            treasury: Pubkey::default(),
+        }
+    }
 
-impl UserPosition {
-    pub const LEN: usize = 8 + std::mem::size_of::<Self>();
+    /// Read the bounds from the (seed-checked) config PDA, falling back to
+    /// `permissive()` while it hasn't been initialized. Fields appended after the
+    /// PDA was created read as zero until `set_protocol_fees` grows it.
+    pub fn load(info: &AccountInfo) -> Result<Self> {
+        if info.data_is_empty() {
+            return Ok(Self::permissive());
+        }
+        load_legacy(info, Self::LEN)
+    }
+
+    /// Protocol's cut of a flash fee, or zero while no treasury is set
+    pub fn protocol_cut(&self, fee: u64) -> u64 {
+        if self.treasury == Pubkey::default() {
+            return 0;
+        }
+        ((fee as u128) * (self.protocol_fee_bps as u128) / 10_000) as u64
+    }
 }
 
 #[derive(Accounts)]
-pub struct ClaimRewards<'info> {
+pub struct InitializeProtocolConfig<'info> {
+    #[account(
+        init,
+        payer = upgrade_authority,
+        space = ProtocolConfig::LEN,
+        seeds = [b"protocol_config"],
+        bump
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
     #[account(mut)]
-    pub vault: Account<'info, Vault>,
+    pub upgrade_authority: Signer<'info>,
+
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()) @ VaultError::InvalidProgramData)]
+    pub program: Program<'info, crate::program::VaultCore>,
 
     #[account(
-        mut,
-        seeds = [b"position", vault.key().as_ref(), user.key().as_ref()],
-        bump,
-        has_one = vault @ VaultError::InvalidVault,
-        has_one = user @ VaultError::InvalidVault
+        constraint = program_data.upgrade_authority_address == Some(upgrade_authority.key())
+            @ VaultError::UpgradeAuthorityMismatch
     )]
-    pub user_position: Account<'info, UserPosition>,
+    pub program_data: Account<'info, ProgramData>,
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
 
-    #[account(mut)]
-    pub user_reward_token_account: Account<'info, TokenAccount>,
+#[derive(Accounts)]
+pub struct SetProtocolFees<'info> {
+    /// CHECK: may predate the fee fields; owner and admin checked in `set_protocol_fees`
+    #[account(mut, seeds = [b"protocol_config"], bump)]
+    pub protocol_config: UncheckedAccount<'info>,
 
     #[account(mut)]
-    pub reward_vault: Account<'info, TokenAccount>,
+    pub admin: Signer<'info>,
 
-    /// CHECK: PDA authority for the vault token account
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetProtocolBounds<'info> {
     #[account(
-        seeds = [b"vault", vault.token_mint.as_ref(), b"authority"],
-        bump
+        mut,
+        seeds = [b"protocol_config"],
+        bump = protocol_config.bump,
+        has_one = admin @ VaultError::Unauthorized
     )]
-    pub vault_authority: UncheckedAccount<'info>,
-
-    pub token_program: Program<'info, Token>,
+    pub protocol_config: Account<'info, ProtocolConfig>,
 
-    /// CHECK: Clock sysvar for timestamp
-    pub clock: Sysvar<'info, Clock>,
+    pub admin: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct UpdateFlashLoanConfig<'info> {
+pub struct ProposeUpgrade<'info> {
     #[account(
         mut,
-        has_one = authority @ VaultError::InvalidVault
+        seeds = [b"governance"],
+        bump = governance.bump,
+        has_one = admin @ VaultError::Unauthorized
     )]
-    pub vault: Account<'info, Vault>,
+    pub governance: Account<'info, Governance>,
 
-    pub authority: Signer<'info>,
+    pub admin: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct FlashLoan<'info> {
-    #[account(mut)]
-    pub vault: Account<'info, Vault>,
+pub struct ExecuteUpgrade<'info> {
+    #[account(
+        mut,
+        seeds = [b"governance"],
+        bump = governance.bump
+    )]
+    pub governance: Account<'info, Governance>,
 
-    #[account(mut)]
-    pub vault_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Program being upgraded, must be the queued one
+    #[account(mut, address = governance.pending_program @ VaultError::NoPendingUpgrade)]
+    pub program: UncheckedAccount<'info>,
 
-    /// CHECK: PDA authority for the vault token account
+    /// CHECK: ProgramData of the queued program, validated by the loader
+    #[account(mut, address = get_program_data_address(&governance.pending_program) @ VaultError::InvalidProgramData)]
+    pub program_data: UncheckedAccount<'info>,
+
+    /// CHECK: Buffer holding the new bytecode, must be the queued one
+    #[account(mut, address = governance.pending_buffer @ VaultError::NoPendingUpgrade)]
+    pub buffer: UncheckedAccount<'info>,
+
+    /// CHECK: Receives the buffer's lamports; fixed to the governance admin
+    #[account(mut, address = governance.admin @ VaultError::Unauthorized)]
+    pub spill: UncheckedAccount<'info>,
+
+    pub rent: Sysvar<'info, Rent>,
+    pub clock: Sysvar<'info, Clock>,
+
+    /// CHECK: BPF upgradeable loader
+    #[account(address = bpf_loader_upgradeable::ID)]
+    pub bpf_loader_upgradeable: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyUpgradeAuthority<'info> {
     #[account(
-        seeds = [b"vault", vault.token_mint.as_ref(), b"authority"],
-        bump
+        seeds = [b"governance"],
+        bump = governance.bump
     )]
-    pub vault_authority: UncheckedAccount<'info>,
+    pub governance: Account<'info, Governance>,
 
-    #[account(mut)]
-    pub borrower: Signer<'info>,
-
-    #[account(mut)]
-    pub borrower_token_account: Account<'info, TokenAccount>,
+    /// CHECK: Any program deployed with the upgradeable loader
+    #[account(executable, owner = bpf_loader_upgradeable::ID @ VaultError::InvalidProgramData)]
+    pub program: UncheckedAccount<'info>,
 
-    #[account(mut)]
-    pub fee_treasury_token_account: Account<'info, TokenAccount>,
+    pub program_data: Account<'info, ProgramData>,
+}
 
-    /// CHECK: Callback program to invoke
-    pub callback_program: UncheckedAccount<'info>,
+/// Emitted by `donate`. `total_assets` and `total_shares` are after the donation.
+#[event]
+pub struct DonationEvent {
+    pub vault: Pubkey,
+    pub donor: Pubkey,
+    pub amount: u64,
+    pub total_assets: u64,
+    pub total_shares: u64,
+}
 
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
+/// Emitted by `slash`. `total_assets` and `total_shares` are after the slash.
+#[event]
+pub struct SlashEvent {
+    pub vault: Pubkey,
+    pub slasher: Pubkey,
+    pub bps: u16,
+    pub amount: u64,
+    pub total_assets: u64,
+    pub total_shares: u64,
 }
 
 #[error_code]
@@ -915,4 +6114,126 @@ pub enum VaultError {
     InsufficientRepayment,
     #[msg("Invalid fee treasury")]
     InvalidFeeTreasury,
+    #[msg("Reward vault balance decreased during flash loan")]
+    RewardVaultDrained,
+    #[msg("Unauthorized")]
+    Unauthorized,
+    #[msg("Invalid timelock delay")]
+    InvalidTimelockDelay,
+    #[msg("Timelock has not elapsed")]
+    TimelockNotElapsed,
+    #[msg("No pending upgrade")]
+    NoPendingUpgrade,
+    #[msg("Invalid program data account")]
+    InvalidProgramData,
+    #[msg("Upgrade authority is not the governance PDA")]
+    UpgradeAuthorityMismatch,
+    #[msg("Callback allowlist is full")]
+    CallbackAllowlistFull,
+    #[msg("Callback program already allowlisted")]
+    CallbackAlreadyAllowlisted,
+    #[msg("Keeper tip too high")]
+    KeeperTipTooHigh,
+    #[msg("Signer is not the registered keeper")]
+    UnauthorizedKeeper,
+    #[msg("Position has not opted in to keeper claims")]
+    KeeperClaimsDisabled,
+    #[msg("Flash loans are disabled")]
+    FlashLoansDisabled,
+    #[msg("Vault still has outstanding shares")]
+    VaultNotEmpty,
+    #[msg("No unallocated rewards to withdraw")]
+    NoUnallocatedRewards,
+    #[msg("Setting exceeds the protocol-wide bound")]
+    ExceedsProtocolBound,
+    #[msg("Management fee too high")]
+    ManagementFeeTooHigh,
+    #[msg("Collect the accrued management fee before changing it")]
+    ManagementFeeNotCollected,
+    #[msg("Management fee is not enabled")]
+    ManagementFeeDisabled,
+    #[msg("Depositor is not on the vault allowlist")]
+    NotAllowlisted,
+    #[msg("Invalid reward drop proof")]
+    InvalidDropProof,
+    #[msg("Reward drop already claimed")]
+    DropAlreadyClaimed,
+    #[msg("Account is already at the current version")]
+    AlreadyMigrated,
+    #[msg("Token account is not the user's wrapped SOL account")]
+    InvalidWsolAccount,
+    #[msg("Signer is neither the position owner nor its delegate")]
+    UnauthorizedDelegate,
+    #[msg("Compounding requires the reward mint to be the staked mint")]
+    CompoundMintMismatch,
+    #[msg("Vault config is timelocked; use propose_config_change")]
+    ConfigTimelocked,
+    #[msg("Signer does not hold the required vault role")]
+    MissingRole,
+    #[msg("Vault is paused")]
+    VaultPaused,
+    #[msg("Too soon since the last stats snapshot")]
+    SnapshotTooSoon,
+    #[msg("Missing or invalid price oracle account")]
+    InvalidOracle,
+    #[msg("Oracle price is stale")]
+    StaleOraclePrice,
+    #[msg("Oracle confidence interval is too wide")]
+    OracleConfidenceTooWide,
+    #[msg("Deposit would exceed the vault's USD cap")]
+    DepositCapExceeded,
+    #[msg("Account does not belong to the strategy")]
+    InvalidStrategyAccount,
+    #[msg("Investment exceeds the strategy's allocation")]
+    StrategyAllocationExceeded,
+    #[msg("Strategy call reduced the vault's balance")]
+    StrategyDrainedVault,
+    #[msg("Strategy has been retired")]
+    StrategyRetired,
+    #[msg("Vault has no shares outstanding")]
+    NoSharesOutstanding,
+    #[msg("Vault invariant violated")]
+    InvariantViolated,
+    #[msg("Address is blocked")]
+    AddressBlocked,
+    #[msg("Blocklist is full")]
+    BlocklistFull,
+    #[msg("Address is not blocked")]
+    AddressNotBlocked,
+    #[msg("Loyalty boost minimum exceeds 100%")]
+    InvalidLoyaltyBoost,
+    #[msg("Metadata URI is too long")]
+    MetadataUriTooLong,
+    #[msg("Position still holds shares or owed rewards")]
+    PositionNotEmpty,
+    #[msg("Dual-stake vault: use deposit_pair/withdraw_pair")]
+    DualStakeRequired,
+    #[msg("Vault is not a dual-stake vault")]
+    NotDualStake,
+    #[msg("Slash exceeds the vault's per-slash cap")]
+    SlashExceedsCap,
+    #[msg("Deposit would push total shares past the cap")]
+    SharesCapExceeded,
+    #[msg("Too many flash loans against this vault in one transaction")]
+    TooManyFlashLoans,
+    #[msg("Vault authority is not an approved delegate for this amount")]
+    DelegateNotApproved,
+    #[msg("Borrower must sign, or be the allowlisted callback's borrower PDA")]
+    PdaBorrowerNotAllowed,
+    #[msg("No flash fees waiting to be collected")]
+    NoPendingFees,
+    #[msg("Slippage exceeded")]
+    SlippageExceeded,
+    #[msg("Index is neither the position's nor the vault's next")]
+    InvalidPositionIndex,
+    #[msg("Positions are still owed rewards")]
+    RewardsStillOwed,
+    #[msg("Vault generation must be between 1 and 255")]
+    InvalidGeneration,
+    #[msg("Flash loans must be top-level instructions, not CPIs")]
+    FlashLoanViaCpi,
+    #[msg("Position has a checkpoint history; pass user_checkpoints")]
+    CheckpointsRequired,
+    #[msg("Migrate the position before checkpointing it")]
+    PositionNotMigrated,
 }
@@ -1,9 +1,12 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { ComposerRouter } from "../target/types/composer_router";
+import { ComposerRouterDynamic } from "../target/types/composer_router_dynamic";
 import { MockAmm } from "../target/types/mock_amm";
 import { VaultCore } from "../target/types/vault_core";
 import {
+  ASSOCIATED_TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
   TOKEN_PROGRAM_ID,
   getAssociatedTokenAddress,
   getOrCreateAssociatedTokenAccount,
@@ -26,8 +29,18 @@ describe("composer-router", () => {
     .composerRouter as Program<ComposerRouter>;
   const ammProgram = anchor.workspace.mockAmm as Program<MockAmm>;
   const vaultProgram = anchor.workspace.vaultCore as Program<VaultCore>;
+  const dynamicRouterProgram = anchor.workspace
+    .composerRouterDynamic as Program<ComposerRouterDynamic>;
   const connection = provider.connection;
 
+  // Pools are created at the zero-fee tier
+  const FEE_TIER = 0;
+  const feeTierSeed = Buffer.alloc(2);
+  feeTierSeed.writeUInt16LE(FEE_TIER);
+
+  // Routes must land within a minute of being built
+  const deadline = () => new anchor.BN(Math.floor(Date.now() / 1000) + 60);
+
   let authority: Keypair;
   let user: Keypair;
   let tokenMintA: PublicKey;
@@ -76,6 +89,13 @@ describe("composer-router", () => {
     );
   }
 
+  function getCheckpointsPDA(vault: PublicKey, owner: PublicKey): PublicKey {
+    return PublicKey.findProgramAddressSync(
+      [Buffer.from("checkpoints"), vault.toBuffer(), owner.toBuffer()],
+      vaultProgram.programId
+    )[0];
+  }
+
   async function getPoolPDA(
     mintA: PublicKey,
     mintB: PublicKey
@@ -86,7 +106,7 @@ describe("composer-router", () => {
         ? [mintA, mintB]
         : [mintB, mintA];
     return PublicKey.findProgramAddressSync(
-      [Buffer.from("pool"), mint1.toBuffer(), mint2.toBuffer()],
+      [Buffer.from("pool"), mint1.toBuffer(), mint2.toBuffer(), feeTierSeed],
       ammProgram.programId
     );
   }
@@ -104,6 +124,7 @@ describe("composer-router", () => {
         Buffer.from("pool"),
         mint1.toBuffer(),
         mint2.toBuffer(),
+        feeTierSeed,
         Buffer.from("authority"),
       ],
       ammProgram.programId
@@ -217,7 +238,9 @@ describe("composer-router", () => {
         await ammProgram.methods
           .initializePool(
             new anchor.BN(100000 * 10 ** 9),
-            new anchor.BN(100000 * 10 ** 9)
+            new anchor.BN(100000 * 10 ** 9),
+            { constantProduct: {} },
+            FEE_TIER
           )
           .accounts({
             authority: authority.publicKey,
@@ -227,6 +250,8 @@ describe("composer-router", () => {
             vaultB: poolVaultB,
             authorityTokenAccountA: authorityTokenAccountA.address,
             authorityTokenAccountB: authorityTokenAccountB.address,
+            tokenProgramA: TOKEN_PROGRAM_ID,
+            tokenProgramB: TOKEN_PROGRAM_ID,
           })
           .signers([authority])
           .rpc();
@@ -267,6 +292,7 @@ describe("composer-router", () => {
             authority: authority.publicKey,
             tokenMint: tokenMintB,
             rewardMint: tokenMintB,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
           })
           .signers([authority])
           .rpc();
@@ -326,20 +352,24 @@ describe("composer-router", () => {
         .amount;
 
       // Build remaining accounts for swap CPI
-      // mock-amm swap accounts: pool, user, user_token_in, user_token_out, vault_a, vault_b, pool_authority, token_program
+      // mock-amm swap accounts: pool, user, user_token_in, user_token_out, vault_a, vault_b, pool_authority,
+      // mint_a, mint_b, token_program_a, token_program_b
       const swapAccounts = [
-        { pubkey: pool, isSigner: false, isWritable: false },
+        { pubkey: pool, isSigner: false, isWritable: true },
         { pubkey: user.publicKey, isSigner: true, isWritable: false },
         { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
         { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
         { pubkey: poolVaultA, isSigner: false, isWritable: true },
         { pubkey: poolVaultB, isSigner: false, isWritable: true },
         { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       ];
 
       // Build remaining accounts for vault deposit CPI
-      // vault deposit accounts: vault, user_position, user, user_token_account, vault_token_account, vault_authority, token_program, system_program
+      // vault deposit accounts: vault, user_position, user, user_token_account, vault_token_account, vault_authority, token_program, system_program, user_checkpoints
       const vaultAccounts = [
         { pubkey: vault, isSigner: false, isWritable: true },
         { pubkey: userPosition, isSigner: false, isWritable: true },
@@ -349,6 +379,11 @@ describe("composer-router", () => {
         { pubkey: vaultAuthority, isSigner: false, isWritable: false },
         { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
       ];
 
       const remainingAccounts = [...swapAccounts, ...vaultAccounts];
@@ -365,7 +400,9 @@ describe("composer-router", () => {
             minAmountOut,
             vaultDepositAmount,
             tokenMintA,
-            tokenMintB
+            tokenMintB,
+            FEE_TIER,
+            deadline()
           )
           .accounts({
             user: user.publicKey,
@@ -403,6 +440,318 @@ describe("composer-router", () => {
       expect(position.shares.toNumber()).to.be.greaterThan(0);
     });
 
+    it("Returns the swap output and shares minted as return data", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+
+      const { raw } = await routerProgram.methods
+        .depositSwapStake(
+          new anchor.BN(1000 * 10 ** 9),
+          new anchor.BN(900 * 10 ** 9),
+          new anchor.BN(0),
+          tokenMintA,
+          tokenMintB,
+          FEE_TIER,
+          deadline()
+        )
+        .accounts({
+          user: user.publicKey,
+          inputTokenAccount: userTokenAccountA,
+          outputTokenAccount: userTokenAccountB,
+          vaultProgram: vaultProgram.programId,
+        })
+        .remainingAccounts([...swapAccounts, ...vaultAccounts])
+        .signers([user])
+        .simulate();
+
+      // RouteResult: amount_out (u64) then shares_minted (u128), little-endian
+      const prefix = `Program return: ${routerProgram.programId} `;
+      const returned = Buffer.from(
+        raw.find((line) => line.startsWith(prefix))!.slice(prefix.length),
+        "base64"
+      );
+      const amountOut = new anchor.BN(returned.subarray(0, 8), "le");
+      const sharesMinted = new anchor.BN(returned.subarray(8, 24), "le");
+      expect(amountOut.gte(new anchor.BN(900 * 10 ** 9))).to.be.true;
+      expect(sharesMinted.gtn(0)).to.be.true;
+    });
+
+    it("Executes a serialized swap + deposit route plan", async () => {
+      // Both step programs must be allow-listed in the dynamic router's config
+      const programs = [ammProgram.programId, vaultProgram.programId];
+      try {
+        await dynamicRouterProgram.methods
+          .initializeRouterConfig(programs)
+          .accounts({ authority: provider.wallet.publicKey })
+          .rpc();
+      } catch (e: any) {
+        if (!e.toString().includes("already in use")) {
+          throw e;
+        }
+        await dynamicRouterProgram.methods
+          .updateSwapPrograms(programs)
+          .accounts({ authority: provider.wallet.publicKey })
+          .rpc();
+      }
+
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+      const programAccounts = programs.map((pubkey) => ({
+        pubkey,
+        isSigner: false,
+        isWritable: false,
+      }));
+
+      // The plan fixes the deposit amount up front: the swap's minimum output
+      const plan = {
+        steps: [
+          {
+            invoke: {
+              programIndex: 19,
+              accountCount: swapAccounts.length,
+              data: ammProgram.coder.instruction.encode("swap", {
+                amountIn: new anchor.BN(1000 * 10 ** 9),
+                minAmountOut: new anchor.BN(900 * 10 ** 9),
+                limitPrice: null,
+              }),
+            },
+          },
+          {
+            invoke: {
+              programIndex: 20,
+              accountCount: vaultAccounts.length,
+              data: vaultProgram.coder.instruction.encode("deposit", {
+                amount: new anchor.BN(900 * 10 ** 9),
+                minSharesOut: new anchor.BN(0),
+                proof: [],
+              }),
+            },
+          },
+        ],
+      };
+      await dynamicRouterProgram.methods
+        .executeRoute(plan, deadline())
+        .remainingAccounts([...swapAccounts, ...vaultAccounts, ...programAccounts])
+        .signers([user])
+        .rpc();
+
+      const position = await vaultProgram.account.userPosition.fetch(
+        userPosition
+      );
+      expect(position.shares.gtn(0)).to.be.true;
+
+      // Steps can't reach programs off the allowlist
+      try {
+        await dynamicRouterProgram.methods
+          .executeRoute(
+            {
+              // Index 6 is the vault step's token program
+              steps: [{ invoke: { ...plan.steps[1].invoke, programIndex: 6 } }],
+            },
+            deadline()
+          )
+          .remainingAccounts([...vaultAccounts, ...programAccounts])
+          .signers([user])
+          .rpc();
+        expect.fail("Should have rejected a program off the allowlist");
+      } catch (e) {
+        expect(e.toString()).to.include("SwapProgramNotAllowlisted");
+      }
+
+      // Nor run once the plan's deadline has passed
+      try {
+        await dynamicRouterProgram.methods
+          .executeRoute(plan, new anchor.BN(Math.floor(Date.now() / 1000) - 60))
+          .remainingAccounts([...swapAccounts, ...vaultAccounts, ...programAccounts])
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed past the deadline");
+      } catch (e) {
+        expect(e.toString()).to.include("DeadlineExceeded");
+      }
+    });
+
+    it("Validates deposit_swap_stake against the registered adapter", async () => {
+      const programs = [ammProgram.programId, vaultProgram.programId];
+      try {
+        await dynamicRouterProgram.methods
+          .initializeRouterConfig(programs)
+          .accounts({ authority: provider.wallet.publicKey })
+          .rpc();
+      } catch (e: any) {
+        if (!e.toString().includes("already in use")) {
+          throw e;
+        }
+        await dynamicRouterProgram.methods
+          .updateSwapPrograms(programs)
+          .accounts({ authority: provider.wallet.publicKey })
+          .rpc();
+      }
+
+      // mock-amm: 11 swap accounts, user at 1, token in/out at 2/3, pool authority at 6
+      const discriminator = Array.from(
+        ammProgram.coder.instruction
+          .encode("swap", {
+            amountIn: new anchor.BN(0),
+            minAmountOut: new anchor.BN(0),
+            limitPrice: null,
+          })
+          .subarray(0, 8)
+      );
+      const accountRoles = {
+        accountCount: 11,
+        signer: 1,
+        tokenIn: 2,
+        tokenOut: 3,
+        poolAuthority: 6,
+      };
+      await dynamicRouterProgram.methods
+        .registerAdapter(ammProgram.programId, discriminator, accountRoles)
+        .accounts({ authority: provider.wallet.publicKey })
+        .rpc();
+
+      // Only allow-listed programs can be registered
+      try {
+        await dynamicRouterProgram.methods
+          .registerAdapter(TOKEN_PROGRAM_ID, discriminator, accountRoles)
+          .accounts({ authority: provider.wallet.publicKey })
+          .rpc();
+        expect.fail("Should have rejected a program off the allowlist");
+      } catch (e) {
+        expect(e.toString()).to.include("SwapProgramNotAllowlisted");
+      }
+
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+      const depositSwapStake = (remainingAccounts: anchor.web3.AccountMeta[]) =>
+        dynamicRouterProgram.methods
+          .depositSwapStake(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+            swapProgram: ammProgram.programId,
+            vaultProgram: vaultProgram.programId,
+          })
+          .remainingAccounts(remainingAccounts)
+          .signers([user])
+          .rpc();
+
+      // Token in and out swapped against the adapter's roles
+      const swapped = [...swapAccounts];
+      [swapped[2], swapped[3]] = [swapped[3], swapped[2]];
+      try {
+        await depositSwapStake([...swapped, ...vaultAccounts]);
+        expect.fail("Should have rejected the misplaced token accounts");
+      } catch (e) {
+        expect(e.toString()).to.include("InvalidTokenAccount");
+      }
+
+      await depositSwapStake([...swapAccounts, ...vaultAccounts]);
+      const position = await vaultProgram.account.userPosition.fetch(
+        userPosition
+      );
+      expect(position.shares.gtn(0)).to.be.true;
+    });
+
     it("Fails with wrong mint", async () => {
       const swapAmountIn = new anchor.BN(1000 * 10 ** 9);
       const minAmountOut = new anchor.BN(900 * 10 ** 9);
@@ -424,7 +773,9 @@ describe("composer-router", () => {
             minAmountOut,
             vaultDepositAmount,
             wrongMint, // Wrong mint
-            tokenMintB
+            tokenMintB,
+            FEE_TIER,
+            deadline()
           )
           .accounts({
             user: user.publicKey,
@@ -440,6 +791,32 @@ describe("composer-router", () => {
       }
     });
 
+    it("Fails once the route deadline has passed", async () => {
+      try {
+        await routerProgram.methods
+          .depositSwapStake(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            new anchor.BN(950 * 10 ** 9),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            new anchor.BN(Math.floor(Date.now() / 1000) - 60)
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+          })
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed past the deadline");
+      } catch (e) {
+        expect(e.toString()).to.include("DeadlineExceeded");
+      }
+    });
+
     it("Fails with insufficient balance", async () => {
       const swapAmountIn = new anchor.BN(100000 * 10 ** 9); // More than user has
       const minAmountOut = new anchor.BN(900 * 10 ** 9);
@@ -452,7 +829,9 @@ describe("composer-router", () => {
             minAmountOut,
             vaultDepositAmount,
             tokenMintA,
-            tokenMintB
+            tokenMintB,
+            FEE_TIER,
+            deadline()
           )
           .accounts({
             user: user.publicKey,
@@ -468,6 +847,76 @@ describe("composer-router", () => {
       }
     });
 
+    it("Rejects pool and vault authorities that aren't the route's PDAs", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      // Valid route; each case below swaps one authority for a look-alike
+      const swapAccounts = (authority: PublicKey) => [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: authority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = (authority: PublicKey) => [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: authority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+
+      const route = (poolAuth: PublicKey, vaultAuth: PublicKey) =>
+        routerProgram.methods
+          .depositSwapStake(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            new anchor.BN(950 * 10 ** 9),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+          })
+          .remainingAccounts([...swapAccounts(poolAuth), ...vaultAccounts(vaultAuth)])
+          .signers([user])
+          .rpc();
+
+      try {
+        await route(poolVaultA, vaultAuthority);
+        expect.fail("Should have failed with invalid pool authority");
+      } catch (e) {
+        expect(e.toString()).to.include("InvalidPoolAuthority");
+      }
+      try {
+        await route(poolAuthority, vaultTokenAccount);
+        expect.fail("Should have failed with invalid vault authority");
+      } catch (e) {
+        expect(e.toString()).to.include("InvalidVaultAuthority");
+      }
+    });
+
     it("Fails with insufficient accounts", async () => {
       const swapAmountIn = new anchor.BN(1000 * 10 ** 9);
       const minAmountOut = new anchor.BN(900 * 10 ** 9);
@@ -475,7 +924,7 @@ describe("composer-router", () => {
 
       // Provide fewer accounts than required
       const swapAccounts = [
-        { pubkey: pool, isSigner: false, isWritable: false },
+        { pubkey: pool, isSigner: false, isWritable: true },
         // Missing other accounts
       ];
 
@@ -486,7 +935,9 @@ describe("composer-router", () => {
             minAmountOut,
             vaultDepositAmount,
             tokenMintA,
-            tokenMintB
+            tokenMintB,
+            FEE_TIER,
+            deadline()
           )
           .accounts({
             user: user.publicKey,
@@ -506,4 +957,131 @@ describe("composer-router", () => {
       }
     });
   });
+
+  describe("create_ata leg", () => {
+    const createAtaAccounts = (
+      payer: PublicKey,
+      ata: PublicKey,
+      owner: PublicKey,
+      mint: PublicKey,
+      tokenProgram: PublicKey = TOKEN_PROGRAM_ID
+    ) =>
+      [
+        { pubkey: payer, isSigner: true, isWritable: true },
+        { pubkey: ata, isSigner: false, isWritable: true },
+        { pubkey: owner, isSigner: false, isWritable: false },
+        { pubkey: mint, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        { pubkey: tokenProgram, isSigner: false, isWritable: false },
+        {
+          pubkey: ASSOCIATED_TOKEN_PROGRAM_ID,
+          isSigner: false,
+          isWritable: false,
+        },
+      ];
+
+    it("Sponsor creates a missing ATA for a first-time user", async () => {
+      const newUser = Keypair.generate();
+      const ata = await getAssociatedTokenAddress(tokenMintB, newUser.publicKey);
+
+      // authority acts as sponsor and pays rent for the new user's output ATA
+      await dynamicRouterProgram.methods
+        .executeRoute({ steps: [{ createAta: {} }] }, deadline())
+        .remainingAccounts(
+          createAtaAccounts(authority.publicKey, ata, newUser.publicKey, tokenMintB)
+        )
+        .signers([authority])
+        .rpc();
+
+      const account = await getAccount(connection, ata);
+      expect(account.owner.toString()).to.equal(newUser.publicKey.toString());
+      expect(account.mint.toString()).to.equal(tokenMintB.toString());
+
+      // Running the leg again is a no-op
+      await dynamicRouterProgram.methods
+        .executeRoute({ steps: [{ createAta: {} }] }, deadline())
+        .remainingAccounts(
+          createAtaAccounts(user.publicKey, ata, newUser.publicKey, tokenMintB)
+        )
+        .signers([user])
+        .rpc();
+    });
+
+    it("Creates a Token-2022 ATA under the passed token program", async () => {
+      const newUser = Keypair.generate();
+      const mint2022 = await createMint(
+        connection,
+        authority,
+        authority.publicKey,
+        null,
+        9,
+        undefined,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      );
+      const ata = await getAssociatedTokenAddress(
+        mint2022,
+        newUser.publicKey,
+        false,
+        TOKEN_2022_PROGRAM_ID
+      );
+
+      await dynamicRouterProgram.methods
+        .executeRoute({ steps: [{ createAta: {} }] }, deadline())
+        .remainingAccounts(
+          createAtaAccounts(
+            user.publicKey,
+            ata,
+            newUser.publicKey,
+            mint2022,
+            TOKEN_2022_PROGRAM_ID
+          )
+        )
+        .signers([user])
+        .rpc();
+
+      const account = await getAccount(
+        connection,
+        ata,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      );
+      expect(account.owner.toString()).to.equal(newUser.publicKey.toString());
+
+      // The token program has to be the mint's owner
+      try {
+        await dynamicRouterProgram.methods
+          .executeRoute({ steps: [{ createAta: {} }] }, deadline())
+          .remainingAccounts(
+            createAtaAccounts(user.publicKey, ata, newUser.publicKey, mint2022)
+          )
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed with a mismatched token program");
+      } catch (e) {
+        expect(e.toString()).to.include("InvalidMint");
+      }
+    });
+
+    it("Fails when the account is not the owner's ATA", async () => {
+      const newUser = Keypair.generate();
+      const wrongAta = await getAssociatedTokenAddress(
+        tokenMintA,
+        newUser.publicKey
+      );
+
+      try {
+        await dynamicRouterProgram.methods
+          .executeRoute({ steps: [{ createAta: {} }] }, deadline())
+          .remainingAccounts(
+            createAtaAccounts(user.publicKey, wrongAta, newUser.publicKey, tokenMintB)
+          )
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed with invalid ATA");
+      } catch (e) {
+        expect(e.toString()).to.include("InvalidAssociatedTokenAccount");
+      }
+    });
+  });
 });
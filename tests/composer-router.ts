@@ -11,13 +11,19 @@ import {
   createMint,
   mintTo,
   getAccount,
+  createWrappedNativeAccount,
+  NATIVE_MINT,
+  approve,
 } from "@solana/spl-token";
 import { expect } from "chai";
 import {
   PublicKey,
   Keypair,
   SystemProgram,
+  SYSVAR_CLOCK_PUBKEY,
   SendTransactionError,
+  Ed25519Program,
+  Transaction,
 } from "@solana/web3.js";
 
 describe("composer-router", () => {
@@ -30,11 +36,33 @@ describe("composer-router", () => {
   const vaultProgram = anchor.workspace.vaultCore as Program<VaultCore>;
   const connection = provider.connection;
 
+  // Pools are created at the zero-fee tier
+  const FEE_TIER = 0;
+  const feeTierSeed = Buffer.alloc(2);
+  feeTierSeed.writeUInt16LE(FEE_TIER);
+
+  // Routes must land within a minute of being built
+  const deadline = () => new anchor.BN(Math.floor(Date.now() / 1000) + 60);
+
   let authority: Keypair;
   let user: Keypair;
   let tokenMintA: PublicKey;
   let tokenMintB: PublicKey;
 
+  // One RouterState per deployment, administered by the provider wallet
+  before(async () => {
+    try {
+      await routerProgram.methods
+        .initializeRouterState([vaultProgram.programId])
+        .accounts({ authority: provider.wallet.publicKey })
+        .rpc();
+    } catch (e: any) {
+      if (!e.toString().includes("already in use")) {
+        throw e;
+      }
+    }
+  });
+
   beforeEach(async () => {
     authority = Keypair.generate();
     user = Keypair.generate();
@@ -71,6 +99,32 @@ describe("composer-router", () => {
     );
   });
 
+  // Runs `route` while the router is paused, expecting it to be refused
+  async function expectPaused(route: () => Promise<unknown>) {
+    await routerProgram.methods
+      .setPaused(true)
+      .accounts({ authority: provider.wallet.publicKey })
+      .rpc();
+    try {
+      await route();
+      expect.fail("Should have failed while paused");
+    } catch (e) {
+      expect(e.toString()).to.include("RouterPaused");
+    } finally {
+      await routerProgram.methods
+        .setPaused(false)
+        .accounts({ authority: provider.wallet.publicKey })
+        .rpc();
+    }
+  }
+
+  function getCheckpointsPDA(vault: PublicKey, owner: PublicKey): PublicKey {
+    return PublicKey.findProgramAddressSync(
+      [Buffer.from("checkpoints"), vault.toBuffer(), owner.toBuffer()],
+      vaultProgram.programId
+    )[0];
+  }
+
   async function getPoolPDA(
     mintA: PublicKey,
     mintB: PublicKey
@@ -81,7 +135,7 @@ describe("composer-router", () => {
         ? [mintA, mintB]
         : [mintB, mintA];
     return PublicKey.findProgramAddressSync(
-      [Buffer.from("pool"), mint1.toBuffer(), mint2.toBuffer()],
+      [Buffer.from("pool"), mint1.toBuffer(), mint2.toBuffer(), feeTierSeed],
       ammProgram.programId
     );
   }
@@ -99,6 +153,7 @@ describe("composer-router", () => {
         Buffer.from("pool"),
         mint1.toBuffer(),
         mint2.toBuffer(),
+        feeTierSeed,
         Buffer.from("authority"),
       ],
       ammProgram.programId
@@ -207,7 +262,9 @@ describe("composer-router", () => {
         await ammProgram.methods
           .initializePool(
             new anchor.BN(100000 * 10 ** 9),
-            new anchor.BN(100000 * 10 ** 9)
+            new anchor.BN(100000 * 10 ** 9),
+            { constantProduct: {} },
+            FEE_TIER
           )
           .accounts({
             authority: authority.publicKey,
@@ -217,6 +274,8 @@ describe("composer-router", () => {
             vaultB: poolVaultB,
             authorityTokenAccountA: authorityTokenAccountA.address,
             authorityTokenAccountB: authorityTokenAccountB.address,
+            tokenProgramA: TOKEN_PROGRAM_ID,
+            tokenProgramB: TOKEN_PROGRAM_ID,
           })
           .signers([authority])
           .rpc();
@@ -251,7 +310,9 @@ describe("composer-router", () => {
           .accounts({
             authority: authority.publicKey,
             tokenMint: tokenMintB,
-            rewardMint: tokenMintB,
+            // Rewards in A so they can be swapped back into the vault's token
+            rewardMint: tokenMintA,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
           })
           .signers([authority])
           .rpc();
@@ -310,20 +371,24 @@ describe("composer-router", () => {
         .amount;
 
       // Build remaining accounts for swap CPI
-      // mock-amm swap accounts: pool, user, user_token_in, user_token_out, vault_a, vault_b, pool_authority, token_program
+      // mock-amm swap accounts: pool, user, user_token_in, user_token_out, vault_a, vault_b, pool_authority,
+      // mint_a, mint_b, token_program_a, token_program_b
       const swapAccounts = [
-        { pubkey: pool, isSigner: false, isWritable: false },
+        { pubkey: pool, isSigner: false, isWritable: true },
         { pubkey: user.publicKey, isSigner: true, isWritable: false },
         { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
         { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
         { pubkey: poolVaultA, isSigner: false, isWritable: true },
         { pubkey: poolVaultB, isSigner: false, isWritable: true },
         { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       ];
 
       // Build remaining accounts for vault deposit CPI
-      // vault deposit accounts: vault, user_position, user, user_token_account, vault_token_account, vault_authority, token_program, system_program
+      // vault deposit accounts: vault, user_position, user, user_token_account, vault_token_account, vault_authority, token_program, system_program, user_checkpoints
       const vaultAccounts = [
         { pubkey: vault, isSigner: false, isWritable: true },
         { pubkey: userPosition, isSigner: false, isWritable: true },
@@ -333,6 +398,11 @@ describe("composer-router", () => {
         { pubkey: vaultAuthority, isSigner: false, isWritable: false },
         { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
       ];
 
       const remainingAccounts = [...swapAccounts, ...vaultAccounts];
@@ -342,13 +412,17 @@ describe("composer-router", () => {
           swapAmountIn,
           minAmountOut,
           vaultDepositAmount,
+          new anchor.BN(0),
           tokenMintA,
-          tokenMintB
+          tokenMintB,
+          FEE_TIER,
+          deadline()
         )
         .accounts({
           user: user.publicKey,
           inputTokenAccount: userTokenAccountA,
           outputTokenAccount: userTokenAccountB,
+          vaultProgram: vaultProgram.programId,
         })
         .remainingAccounts(remainingAccounts)
         .signers([user])
@@ -376,6 +450,147 @@ describe("composer-router", () => {
       expect(position.shares.toNumber()).to.be.greaterThan(0);
     });
 
+    it("Deposits exactly the swap output when vault_deposit_amount is 0", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+      const initialBalanceB = (await getAccount(connection, userTokenAccountB))
+        .amount;
+      const initialVaultBalance = (
+        await getAccount(connection, vaultTokenAccount)
+      ).amount;
+      const poolBalanceB = (await getAccount(connection, poolVaultB)).amount;
+
+      await routerProgram.methods
+        .depositSwapStake(
+          new anchor.BN(1000 * 10 ** 9),
+          new anchor.BN(900 * 10 ** 9),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          tokenMintA,
+          tokenMintB,
+          FEE_TIER,
+          deadline()
+        )
+        .accounts({
+          user: user.publicKey,
+          inputTokenAccount: userTokenAccountA,
+          outputTokenAccount: userTokenAccountB,
+          vaultProgram: vaultProgram.programId,
+        })
+        .remainingAccounts([...swapAccounts, ...vaultAccounts])
+        .signers([user])
+        .rpc();
+
+      // Everything the pool paid out went into the vault; no dust left behind
+      const swapOut =
+        poolBalanceB - (await getAccount(connection, poolVaultB)).amount;
+      const finalVaultBalance = (await getAccount(connection, vaultTokenAccount))
+        .amount;
+      expect((finalVaultBalance - initialVaultBalance).toString()).to.equal(
+        swapOut.toString()
+      );
+      expect(
+        (await getAccount(connection, userTokenAccountB)).amount.toString()
+      ).to.equal(initialBalanceB.toString());
+    });
+
+    it("Returns the swap output and shares minted as return data", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+
+      const { raw } = await routerProgram.methods
+        .depositSwapStake(
+          new anchor.BN(1000 * 10 ** 9),
+          new anchor.BN(900 * 10 ** 9),
+          new anchor.BN(0),
+          new anchor.BN(0),
+          tokenMintA,
+          tokenMintB,
+          FEE_TIER,
+          deadline()
+        )
+        .accounts({
+          user: user.publicKey,
+          inputTokenAccount: userTokenAccountA,
+          outputTokenAccount: userTokenAccountB,
+          vaultProgram: vaultProgram.programId,
+        })
+        .remainingAccounts([...swapAccounts, ...vaultAccounts])
+        .signers([user])
+        .simulate();
+
+      // RouteResult: amount_out (u64) then shares_minted (u128), little-endian
+      const prefix = `Program return: ${routerProgram.programId} `;
+      const returned = Buffer.from(
+        raw.find((line) => line.startsWith(prefix))!.slice(prefix.length),
+        "base64"
+      );
+      const amountOut = new anchor.BN(returned.subarray(0, 8), "le");
+      const sharesMinted = new anchor.BN(returned.subarray(8, 24), "le");
+      expect(amountOut.gte(new anchor.BN(900 * 10 ** 9))).to.be.true;
+      expect(sharesMinted.gtn(0)).to.be.true;
+    });
+
     it("Fails with invalid swap program", async () => {
       const swapAmountIn = new anchor.BN(1000 * 10 ** 9);
       const minAmountOut = new anchor.BN(900 * 10 ** 9);
@@ -388,13 +603,16 @@ describe("composer-router", () => {
 
       // Build remaining accounts for swap CPI
       const swapAccounts = [
-        { pubkey: pool, isSigner: false, isWritable: false },
+        { pubkey: pool, isSigner: false, isWritable: true },
         { pubkey: user.publicKey, isSigner: true, isWritable: false },
         { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
         { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
         { pubkey: poolVaultA, isSigner: false, isWritable: true },
         { pubkey: poolVaultB, isSigner: false, isWritable: true },
         { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       ];
 
@@ -408,6 +626,11 @@ describe("composer-router", () => {
         { pubkey: vaultAuthority, isSigner: false, isWritable: false },
         { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
       ];
 
       const remainingAccounts = [...swapAccounts, ...vaultAccounts];
@@ -421,13 +644,17 @@ describe("composer-router", () => {
             swapAmountIn,
             minAmountOut,
             vaultDepositAmount,
+            new anchor.BN(0),
             tokenMintA,
-            tokenMintB
+            tokenMintB,
+            FEE_TIER,
+            deadline()
           )
           .accounts({
             user: user.publicKey,
             inputTokenAccount: userTokenAccountA,
             outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
           })
           .accountsPartial({
             ammProgram: vaultProgram.programId, // Invalid: using vault program instead of AMM program
@@ -449,29 +676,136 @@ describe("composer-router", () => {
       }
     });
 
-    it("Fails with insufficient balance", async () => {
-      const swapAmountIn = new anchor.BN(100000 * 10 ** 9); // More than user has
-      const minAmountOut = new anchor.BN(900 * 10 ** 9);
-      const vaultDepositAmount = new anchor.BN(950 * 10 ** 9);
+    it("Gates deposits on the router pause flag and vault allowlist", async () => {
+      const deposit = () =>
+        routerProgram.methods
+          .depositSwapStake(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            new anchor.BN(0),
+            new anchor.BN(0),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+          })
+          .signers([user])
+          .rpc();
+
+      // Only the state's authority may change it
+      try {
+        await routerProgram.methods
+          .setPaused(true)
+          .accounts({ authority: user.publicKey })
+          .signers([user])
+          .rpc();
+        expect.fail("Should have rejected a non-authority");
+      } catch (e) {
+        expect(e.toString()).to.include("Unauthorized");
+      }
+
+      await routerProgram.methods
+        .setPaused(true)
+        .accounts({ authority: provider.wallet.publicKey })
+        .rpc();
+      try {
+        await deposit();
+        expect.fail("Should have failed while paused");
+      } catch (e) {
+        expect(e.toString()).to.include("RouterPaused");
+      } finally {
+        await routerProgram.methods
+          .setPaused(false)
+          .accounts({ authority: provider.wallet.publicKey })
+          .rpc();
+      }
+
+      await routerProgram.methods
+        .updateVaultPrograms([])
+        .accounts({ authority: provider.wallet.publicKey })
+        .rpc();
+      try {
+        await deposit();
+        expect.fail("Should have failed for a vault program off the allowlist");
+      } catch (e) {
+        expect(e.toString()).to.include("VaultProgramNotAllowlisted");
+      } finally {
+        await routerProgram.methods
+          .updateVaultPrograms([vaultProgram.programId])
+          .accounts({ authority: provider.wallet.publicKey })
+          .rpc();
+      }
+    });
+
+    it("Credits a referrer's share of the router fee", async () => {
+      const referrer = authority;
+      const [routerState] = PublicKey.findProgramAddressSync(
+        [Buffer.from("router_state")],
+        routerProgram.programId
+      );
+      const [referral] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("referral"),
+          referrer.publicKey.toBuffer(),
+          tokenMintB.toBuffer(),
+        ],
+        routerProgram.programId
+      );
+      await routerProgram.methods
+        .registerReferral()
+        .accounts({ referrer: referrer.publicKey, mint: tokenMintB })
+        .signers([referrer])
+        .rpc();
+
+      const feeTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user,
+          tokenMintB,
+          provider.wallet.publicKey
+        )
+      ).address;
+      const referralVault = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user,
+          tokenMintB,
+          routerState,
+          true
+        )
+      ).address;
+      const referrerTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user,
+          tokenMintB,
+          referrer.publicKey
+        )
+      ).address;
 
       const [userPosition] = PublicKey.findProgramAddressSync(
         [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
         vaultProgram.programId
       );
-
-      // Build remaining accounts for swap CPI
       const swapAccounts = [
-        { pubkey: pool, isSigner: false, isWritable: false },
+        { pubkey: pool, isSigner: false, isWritable: true },
         { pubkey: user.publicKey, isSigner: true, isWritable: false },
         { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
         { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
         { pubkey: poolVaultA, isSigner: false, isWritable: true },
         { pubkey: poolVaultB, isSigner: false, isWritable: true },
         { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       ];
-
-      // Build remaining accounts for vault deposit CPI
       const vaultAccounts = [
         { pubkey: vault, isSigner: false, isWritable: true },
         { pubkey: userPosition, isSigner: false, isWritable: true },
@@ -481,34 +815,1185 @@ describe("composer-router", () => {
         { pubkey: vaultAuthority, isSigner: false, isWritable: false },
         { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
         { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
       ];
 
-      const remainingAccounts = [...swapAccounts, ...vaultAccounts];
-
+      // 1% router fee, half of it to the referrer
+      await routerProgram.methods
+        .setFees(100, 5000)
+        .accounts({ authority: provider.wallet.publicKey })
+        .rpc();
       try {
         await routerProgram.methods
           .depositSwapStake(
-            swapAmountIn,
-            minAmountOut,
-            vaultDepositAmount,
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            new anchor.BN(0),
+            new anchor.BN(0),
             tokenMintA,
-            tokenMintB
+            tokenMintB,
+            FEE_TIER,
+            deadline()
           )
           .accounts({
             user: user.publicKey,
             inputTokenAccount: userTokenAccountA,
             outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+            feeTokenAccount,
+            referral,
+            referralVault,
           })
-          .remainingAccounts(remainingAccounts)
+          .remainingAccounts([...swapAccounts, ...vaultAccounts])
           .signers([user])
           .rpc();
-        expect.fail("Should have failed with insufficient balance");
+      } finally {
+        await routerProgram.methods
+          .setFees(0, 0)
+          .accounts({ authority: provider.wallet.publicKey })
+          .rpc();
+      }
+
+      const credited = await routerProgram.account.referral.fetch(referral);
+      expect(credited.routedVolume.gtn(0)).to.be.true;
+      expect(credited.claimable.gtn(0)).to.be.true;
+      // Fee = 1% of the volume, split evenly (up to rounding)
+      expect(
+        credited.routedVolume
+          .divn(200)
+          .sub(credited.claimable)
+          .abs()
+          .lten(1)
+      ).to.be.true;
+
+      await routerProgram.methods
+        .claimReferralFees()
+        .accounts({
+          referrer: referrer.publicKey,
+          referral,
+          referralVault,
+          referrerTokenAccount,
+        })
+        .signers([referrer])
+        .rpc();
+      const claimed = await getAccount(connection, referrerTokenAccount);
+      expect(claimed.amount.toString()).to.equal(credited.claimable.toString());
+      const cleared = await routerProgram.account.referral.fetch(referral);
+      expect(cleared.claimable.toNumber()).to.equal(0);
+    });
+
+    it("Lets a keeper execute a signed intent through token approvals", async () => {
+      const keeper = authority;
+      const intent = {
+        user: user.publicKey,
+        inputMint: tokenMintA,
+        outputMint: tokenMintB,
+        vaultProgram: vaultProgram.programId,
+        amountIn: new anchor.BN(1000 * 10 ** 9),
+        minAmountOut: new anchor.BN(900 * 10 ** 9),
+        minSharesOut: new anchor.BN(1),
+        feeTier: FEE_TIER,
+        expiry: deadline(),
+        nonce: new anchor.BN(1),
+      };
+      const message = Buffer.concat([
+        Buffer.from("composer-router intent"),
+        routerProgram.programId.toBuffer(),
+        routerProgram.coder.types.encode("routeIntent", intent),
+      ]);
+
+      // The user's only on-chain actions: approve the router and the vault
+      const [intentAuthority] = PublicKey.findProgramAddressSync(
+        [Buffer.from("intent_authority")],
+        routerProgram.programId
+      );
+      await approve(
+        connection,
+        user,
+        userTokenAccountA,
+        intentAuthority,
+        user,
+        BigInt(intent.amountIn.toString())
+      );
+      await approve(
+        connection,
+        user,
+        userTokenAccountB,
+        vaultAuthority,
+        user,
+        BigInt(2000 * 10 ** 9)
+      );
+
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: intentAuthority, isSigner: false, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: false, isWritable: false },
+        { pubkey: keeper.publicKey, isSigner: true, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+
+      // Only the keeper signs the transaction; the ed25519 instruction carries the
+      // user's signature of the intent
+      const execute = async () => {
+        const tx = new Transaction().add(
+          Ed25519Program.createInstructionWithPrivateKey({
+            privateKey: user.secretKey,
+            message,
+          }),
+          await routerProgram.methods
+            .executeIntent(intent)
+            .accounts({
+              keeper: keeper.publicKey,
+              inputTokenAccount: userTokenAccountA,
+              outputTokenAccount: userTokenAccountB,
+              vaultProgram: vaultProgram.programId,
+            })
+            .remainingAccounts([...swapAccounts, ...vaultAccounts])
+            .instruction()
+        );
+        return provider.sendAndConfirm(tx, [keeper]);
+      };
+      await expectPaused(execute);
+      await execute();
+
+      const position = await vaultProgram.account.userPosition.fetch(
+        userPosition
+      );
+      expect(position.user.toString()).to.equal(user.publicKey.toString());
+      expect(position.shares.gtn(0)).to.be.true;
+      const inputAccount = await getAccount(connection, userTokenAccountA);
+      expect(inputAccount.delegatedAmount.toString()).to.equal("0");
+
+      // Each nonce executes once
+      try {
+        await execute();
+        expect.fail("Should have rejected a replayed intent");
       } catch (e) {
-        expect(
-          e.toString().includes("InsufficientBalance") ||
-            e.toString().includes("insufficientBalance")
-        ).to.be.true;
+        expect(e.toString()).to.include("already in use");
       }
     });
+
+    it("Splits one swap across several beneficiaries' positions", async () => {
+      // A second beneficiary that approved the vault authority up front
+      const beneficiary = Keypair.generate();
+      const beneficiaryTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user,
+          tokenMintB,
+          beneficiary.publicKey
+        )
+      ).address;
+      await approve(
+        connection,
+        user,
+        beneficiaryTokenAccount,
+        vaultAuthority,
+        beneficiary,
+        BigInt(2000 * 10 ** 9)
+      );
+
+      const legAccounts = (owner: PublicKey, tokenAccount: PublicKey) => {
+        const [position] = PublicKey.findProgramAddressSync(
+          [Buffer.from("position"), vault.toBuffer(), owner.toBuffer()],
+          vaultProgram.programId
+        );
+        return [
+          {
+            pubkey: vaultProgram.programId,
+            isSigner: false,
+            isWritable: false,
+          },
+          { pubkey: vault, isSigner: false, isWritable: true },
+          { pubkey: position, isSigner: false, isWritable: true },
+          { pubkey: owner, isSigner: false, isWritable: false },
+          { pubkey: user.publicKey, isSigner: true, isWritable: true },
+          { pubkey: tokenAccount, isSigner: false, isWritable: true },
+          { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+          { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+          { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+          {
+            pubkey: SystemProgram.programId,
+            isSigner: false,
+            isWritable: false,
+          },
+          {
+            pubkey: getCheckpointsPDA(vault, owner),
+            isSigner: false,
+            isWritable: true,
+          },
+        ];
+      };
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+
+      const batchSwapStake = () =>
+        routerProgram.methods
+          .batchSwapStake(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            [
+              { bps: 6000, minSharesOut: new anchor.BN(1) },
+              { bps: 4000, minSharesOut: new anchor.BN(1) },
+            ],
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+          })
+          .remainingAccounts([
+            ...swapAccounts,
+            ...legAccounts(user.publicKey, userTokenAccountB),
+            ...legAccounts(beneficiary.publicKey, beneficiaryTokenAccount),
+          ])
+          .signers([user])
+          .rpc();
+      await expectPaused(batchSwapStake);
+      await batchSwapStake();
+
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const [beneficiaryPosition] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("position"),
+          vault.toBuffer(),
+          beneficiary.publicKey.toBuffer(),
+        ],
+        vaultProgram.programId
+      );
+      const userShares = (
+        await vaultProgram.account.userPosition.fetch(userPosition)
+      ).shares;
+      const beneficiaryShares = (
+        await vaultProgram.account.userPosition.fetch(beneficiaryPosition)
+      ).shares;
+      expect(beneficiaryShares.gtn(0)).to.be.true;
+      // 60/40 split, up to rounding
+      expect(
+        userShares.muln(2).sub(beneficiaryShares.muln(3)).abs().lten(3)
+      ).to.be.true;
+    });
+
+    it("Fails with insufficient balance", async () => {
+      const swapAmountIn = new anchor.BN(100000 * 10 ** 9); // More than user has
+      const minAmountOut = new anchor.BN(900 * 10 ** 9);
+      const vaultDepositAmount = new anchor.BN(950 * 10 ** 9);
+
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+
+      // Build remaining accounts for swap CPI
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+
+      // Build remaining accounts for vault deposit CPI
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+
+      const remainingAccounts = [...swapAccounts, ...vaultAccounts];
+
+      try {
+        await routerProgram.methods
+          .depositSwapStake(
+            swapAmountIn,
+            minAmountOut,
+            vaultDepositAmount,
+            new anchor.BN(0),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+          })
+          .remainingAccounts(remainingAccounts)
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed with insufficient balance");
+      } catch (e) {
+        expect(
+          e.toString().includes("InsufficientBalance") ||
+            e.toString().includes("insufficientBalance")
+        ).to.be.true;
+      }
+    });
+
+    it("Rejects a vault authority that isn't the output mint's PDA", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      // Valid route; each case below swaps one authority for a look-alike
+      const swapAccounts = (authority: PublicKey) => [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: authority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = (authority: PublicKey) => [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: authority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+
+      // A PDA of the right program but another mint's vault
+      const [otherVaultAuthority] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), tokenMintA.toBuffer(), Buffer.from("authority")],
+        vaultProgram.programId
+      );
+      try {
+        await routerProgram.methods
+          .depositSwapStake(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            new anchor.BN(950 * 10 ** 9),
+            new anchor.BN(0),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+          })
+          .remainingAccounts([
+            ...swapAccounts(poolAuthority),
+            ...vaultAccounts(otherVaultAuthority),
+          ])
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed with invalid vault authority");
+      } catch (e) {
+        expect(e.toString()).to.include("InvalidVaultAuthority");
+        const parser = new anchor.EventParser(
+          routerProgram.programId,
+          routerProgram.coder
+        );
+        const [event] = [...parser.parseLogs(e.logs)];
+        expect(event.data.check).to.have.property("vaultAuthority");
+        expect(event.data.accountIndex).to.equal(16);
+      }
+    });
+
+    it("Fails once the route deadline has passed", async () => {
+      try {
+        await routerProgram.methods
+          .depositSwapStake(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            new anchor.BN(950 * 10 ** 9),
+            new anchor.BN(0),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            new anchor.BN(Math.floor(Date.now() / 1000) - 60)
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+          })
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed past the deadline");
+      } catch (e) {
+        expect(e.toString()).to.include("DeadlineExceeded");
+      }
+    });
+
+    it("Reports the failing check and account index on a bad route", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+
+      // Pool vault passed where the pool authority belongs (index 6)
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+
+      try {
+        await routerProgram.methods
+          .depositSwapStake(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            new anchor.BN(950 * 10 ** 9),
+            new anchor.BN(0),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+          })
+          .remainingAccounts([...swapAccounts, ...vaultAccounts])
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed with invalid pool authority");
+      } catch (e) {
+        expect(e.toString()).to.include("InvalidPoolAuthority");
+
+        const parser = new anchor.EventParser(
+          routerProgram.programId,
+          routerProgram.coder
+        );
+        const [event] = [...parser.parseLogs(e.logs)];
+        expect(event.data.check).to.have.property("poolAuthority");
+        expect(event.data.accountIndex).to.equal(6);
+        expect(event.data.expected.toBase58()).to.equal(poolAuthority.toBase58());
+        expect(event.data.actual.toBase58()).to.equal(poolVaultA.toBase58());
+      }
+    });
+
+    it("Fails the route if the deposit credits fewer than min_shares_out", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+      const initialBalanceA = (await getAccount(connection, userTokenAccountA))
+        .amount;
+
+      try {
+        await routerProgram.methods
+          .depositSwapStake(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(900 * 10 ** 9),
+            new anchor.BN(950 * 10 ** 9),
+            new anchor.BN("18446744073709551615"),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+          })
+          .remainingAccounts([...swapAccounts, ...vaultAccounts])
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed with too few shares");
+      } catch (e) {
+        expect(e.toString()).to.include("SharesBelowMinimum");
+      }
+      // The swap was rolled back with the deposit
+      const finalBalanceA = (await getAccount(connection, userTokenAccountA))
+        .amount;
+      expect(finalBalanceA.toString()).to.equal(initialBalanceA.toString());
+    });
+
+    it("Unstake → Swap → Return gives back the input mint", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const swapAccounts = (tokenIn: PublicKey, tokenOut: PublicKey) => [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: tokenIn, isSigner: false, isWritable: true },
+        { pubkey: tokenOut, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+
+      await routerProgram.methods
+        .depositSwapStake(
+          new anchor.BN(1000 * 10 ** 9),
+          new anchor.BN(900 * 10 ** 9),
+          new anchor.BN(900 * 10 ** 9),
+          new anchor.BN(0),
+          tokenMintA,
+          tokenMintB,
+          FEE_TIER,
+          deadline()
+        )
+        .accounts({
+          user: user.publicKey,
+          inputTokenAccount: userTokenAccountA,
+          outputTokenAccount: userTokenAccountB,
+          vaultProgram: vaultProgram.programId,
+        })
+        .remainingAccounts([
+          ...swapAccounts(userTokenAccountA, userTokenAccountB),
+          ...vaultAccounts,
+        ])
+        .signers([user])
+        .rpc();
+      const position = await vaultProgram.account.userPosition.fetch(
+        userPosition
+      );
+      const balanceA = (await getAccount(connection, userTokenAccountA)).amount;
+      const balanceB = (await getAccount(connection, userTokenAccountB)).amount;
+
+      // Only the swap leg is bounded: the ~900 B staked can't buy back 1000 A
+      const remainingAccounts = [
+        ...vaultAccounts.slice(0, 8),
+        { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+        vaultAccounts[8],
+        ...swapAccounts(userTokenAccountB, userTokenAccountA),
+      ];
+      const withdrawSwapReturn = (minAmountOut: anchor.BN) =>
+        routerProgram.methods
+          .withdrawSwapReturn(
+            position.shares,
+            minAmountOut,
+            tokenMintB,
+            tokenMintA,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            stakedTokenAccount: userTokenAccountB,
+            returnTokenAccount: userTokenAccountA,
+            vaultProgram: vaultProgram.programId,
+          })
+          .remainingAccounts(remainingAccounts)
+          .signers([user])
+          .rpc();
+      await expectPaused(() => withdrawSwapReturn(new anchor.BN(800 * 10 ** 9)));
+      try {
+        await withdrawSwapReturn(new anchor.BN(1000 * 10 ** 9));
+        expect.fail("Should have failed - swap output below min_amount_out");
+      } catch (e) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+
+      await withdrawSwapReturn(new anchor.BN(800 * 10 ** 9));
+      const finalA = (await getAccount(connection, userTokenAccountA)).amount;
+      const finalB = (await getAccount(connection, userTokenAccountB)).amount;
+      expect(Number(finalA - balanceA)).to.be.greaterThan(800 * 10 ** 9);
+      // Everything withdrawn went through the swap
+      expect(finalB.toString()).to.equal(balanceB.toString());
+      const after = await vaultProgram.account.userPosition.fetch(userPosition);
+      expect(after.shares.toNumber()).to.equal(0);
+    });
+
+    it("Claim → Swap → Compound restakes the rewards", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const rewardVault = await getAssociatedTokenAddress(
+        tokenMintA,
+        vaultAuthority,
+        true
+      );
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+
+      await routerProgram.methods
+        .depositSwapStake(
+          new anchor.BN(1000 * 10 ** 9),
+          new anchor.BN(900 * 10 ** 9),
+          new anchor.BN(900 * 10 ** 9),
+          new anchor.BN(0),
+          tokenMintA,
+          tokenMintB,
+          FEE_TIER,
+          deadline()
+        )
+        .accounts({
+          user: user.publicKey,
+          inputTokenAccount: userTokenAccountA,
+          outputTokenAccount: userTokenAccountB,
+          vaultProgram: vaultProgram.programId,
+        })
+        .remainingAccounts([...swapAccounts, ...vaultAccounts])
+        .signers([user])
+        .rpc();
+
+      // One A per second to the only staker
+      const funder = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintA,
+        authority.publicKey
+      );
+      await mintTo(
+        connection,
+        authority,
+        tokenMintA,
+        funder.address,
+        authority,
+        100 * 10 ** 9
+      );
+      await vaultProgram.methods
+        .fundRewards(new anchor.BN(100 * 10 ** 9), new anchor.BN(10 ** 9))
+        .accounts({
+          vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funder.address,
+          rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+
+      const before = await vaultProgram.account.userPosition.fetch(userPosition);
+      const balanceA = (await getAccount(connection, userTokenAccountA)).amount;
+      const balanceB = (await getAccount(connection, userTokenAccountB)).amount;
+
+      const claimAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: true },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: rewardVault, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+        { pubkey: ASSOCIATED_TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+      ];
+      const claimSwapRestake = () =>
+        routerProgram.methods
+          .claimSwapRestake(
+            new anchor.BN(10 ** 9),
+            new anchor.BN(1),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            rewardTokenAccount: userTokenAccountA,
+            stakeTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+          })
+          .remainingAccounts([...claimAccounts, ...swapAccounts, ...vaultAccounts])
+          .signers([user])
+          .rpc();
+      await expectPaused(claimSwapRestake);
+      await claimSwapRestake();
+
+      // The claim left through the swap and the swap output went into the vault
+      expect(
+        (await getAccount(connection, userTokenAccountA)).amount.toString()
+      ).to.equal(balanceA.toString());
+      expect(
+        (await getAccount(connection, userTokenAccountB)).amount.toString()
+      ).to.equal(balanceB.toString());
+      const after = await vaultProgram.account.userPosition.fetch(userPosition);
+      expect(after.shares.gt(before.shares)).to.be.true;
+    });
+
+    it("SOL → Swap → Stake wraps, stakes and closes the WSOL account", async () => {
+      // A SOL/B pool at 1 SOL : 1000 B
+      const [solMintA, solMintB] =
+        NATIVE_MINT.toBuffer().toString("hex") <
+        tokenMintB.toBuffer().toString("hex")
+          ? [NATIVE_MINT, tokenMintB]
+          : [tokenMintB, NATIVE_MINT];
+      const solIsA = solMintA.equals(NATIVE_MINT);
+      const [solPool] = await getPoolPDA(solMintA, solMintB);
+      const [solPoolAuthority] = await getPoolAuthorityPDA(solMintA, solMintB);
+      const solPoolVaultA = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          authority,
+          solMintA,
+          solPoolAuthority,
+          true
+        )
+      ).address;
+      const solPoolVaultB = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          authority,
+          solMintB,
+          solPoolAuthority,
+          true
+        )
+      ).address;
+      const authorityWsol = await createWrappedNativeAccount(
+        connection,
+        authority,
+        authority.publicKey,
+        2 * anchor.web3.LAMPORTS_PER_SOL
+      );
+      const authorityB = await getAssociatedTokenAddress(
+        tokenMintB,
+        authority.publicKey
+      );
+      const solAmount = new anchor.BN(2 * anchor.web3.LAMPORTS_PER_SOL);
+      const bAmount = new anchor.BN(2000 * 10 ** 9);
+      await ammProgram.methods
+        .initializePool(
+          solIsA ? solAmount : bAmount,
+          solIsA ? bAmount : solAmount,
+          { constantProduct: {} },
+          FEE_TIER
+        )
+        .accounts({
+          authority: authority.publicKey,
+          mintA: solMintA,
+          mintB: solMintB,
+          vaultA: solPoolVaultA,
+          vaultB: solPoolVaultB,
+          authorityTokenAccountA: solIsA ? authorityWsol : authorityB,
+          authorityTokenAccountB: solIsA ? authorityB : authorityWsol,
+          tokenProgramA: TOKEN_PROGRAM_ID,
+          tokenProgramB: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const userWsol = await getAssociatedTokenAddress(
+        NATIVE_MINT,
+        user.publicKey
+      );
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const swapAccounts = [
+        { pubkey: solPool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userWsol, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: solPoolVaultA, isSigner: false, isWritable: true },
+        { pubkey: solPoolVaultB, isSigner: false, isWritable: true },
+        { pubkey: solPoolAuthority, isSigner: false, isWritable: false },
+        { pubkey: solMintA, isSigner: false, isWritable: false },
+        { pubkey: solMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: vault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(vault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+      const depositSolSwapStake = (feeAccounts = {}) =>
+        routerProgram.methods
+          .depositSolSwapStake(
+            new anchor.BN(anchor.web3.LAMPORTS_PER_SOL / 10),
+            new anchor.BN(50 * 10 ** 9),
+            new anchor.BN(1),
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            wsolTokenAccount: userWsol,
+            outputTokenAccount: userTokenAccountB,
+            vaultProgram: vaultProgram.programId,
+            ...feeAccounts,
+          })
+          .remainingAccounts([...swapAccounts, ...vaultAccounts])
+          .signers([user])
+          .rpc();
+      await expectPaused(depositSolSwapStake);
+
+      const balanceB = (await getAccount(connection, userTokenAccountB)).amount;
+      const lamportsBefore = await connection.getBalance(user.publicKey);
+      await depositSolSwapStake();
+
+      // The SOL left through the swap, the WSOL account is gone and its rent is
+      // back: beyond the swap, only fees and the new position's rent were spent
+      expect(await connection.getAccountInfo(userWsol)).to.be.null;
+      const spent = lamportsBefore - (await connection.getBalance(user.publicKey));
+      expect(spent).to.be.greaterThanOrEqual(anchor.web3.LAMPORTS_PER_SOL / 10);
+      expect(spent).to.be.lessThan(
+        anchor.web3.LAMPORTS_PER_SOL / 10 + anchor.web3.LAMPORTS_PER_SOL / 100
+      );
+      expect(
+        (await getAccount(connection, userTokenAccountB)).amount.toString()
+      ).to.equal(balanceB.toString());
+      const position = await vaultProgram.account.userPosition.fetch(
+        userPosition
+      );
+      expect(position.shares.toNumber()).to.be.greaterThan(0);
+
+      // Like deposit_swap_stake, the route takes the router fee from the swap output
+      const feeTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user,
+          tokenMintB,
+          provider.wallet.publicKey
+        )
+      ).address;
+      const feesBefore = (await getAccount(connection, feeTokenAccount)).amount;
+      await routerProgram.methods
+        .setFees(100, 0)
+        .accounts({ authority: provider.wallet.publicKey })
+        .rpc();
+      try {
+        try {
+          await depositSolSwapStake();
+          expect.fail("Should have required the fee account");
+        } catch (e) {
+          expect(e.toString()).to.include("FeeAccountRequired");
+        }
+        await depositSolSwapStake({ feeTokenAccount });
+      } finally {
+        await routerProgram.methods
+          .setFees(0, 0)
+          .accounts({ authority: provider.wallet.publicKey })
+          .rpc();
+      }
+      const feesAfter = (await getAccount(connection, feeTokenAccount)).amount;
+      expect(feesAfter > feesBefore).to.be.true;
+    });
+
+    it("Zap → LP → Stake stakes the minted LP tokens", async () => {
+      const lpMint = (await ammProgram.account.pool.fetch(pool)).lpMint;
+      try {
+        await vaultProgram.methods
+          .initializeVault()
+          .accounts({
+            authority: authority.publicKey,
+            tokenMint: lpMint,
+            rewardMint: tokenMintA,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([authority])
+          .rpc();
+      } catch (e: any) {
+        if (!e.toString().includes("already in use")) {
+          throw e;
+        }
+      }
+      const [lpVault] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), lpMint.toBuffer()],
+        vaultProgram.programId
+      );
+      const [lpVaultAuthority] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), lpMint.toBuffer(), Buffer.from("authority")],
+        vaultProgram.programId
+      );
+      const lpVaultTokenAccount = await getAssociatedTokenAddress(
+        lpMint,
+        lpVaultAuthority,
+        true
+      );
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), lpVault.toBuffer(), user.publicKey.toBuffer()],
+        vaultProgram.programId
+      );
+      const userLpAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user,
+          lpMint,
+          user.publicKey
+        )
+      ).address;
+
+      const swapAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const liquidityAccounts = [
+        { pubkey: pool, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userTokenAccountA, isSigner: false, isWritable: true },
+        { pubkey: userTokenAccountB, isSigner: false, isWritable: true },
+        { pubkey: userLpAccount, isSigner: false, isWritable: true },
+        { pubkey: poolVaultA, isSigner: false, isWritable: true },
+        { pubkey: poolVaultB, isSigner: false, isWritable: true },
+        { pubkey: lpMint, isSigner: false, isWritable: true },
+        { pubkey: poolAuthority, isSigner: false, isWritable: false },
+        { pubkey: tokenMintA, isSigner: false, isWritable: false },
+        { pubkey: tokenMintB, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ];
+      const vaultAccounts = [
+        { pubkey: lpVault, isSigner: false, isWritable: true },
+        { pubkey: userPosition, isSigner: false, isWritable: true },
+        { pubkey: user.publicKey, isSigner: true, isWritable: false },
+        { pubkey: userLpAccount, isSigner: false, isWritable: true },
+        { pubkey: lpVaultTokenAccount, isSigner: false, isWritable: true },
+        { pubkey: lpVaultAuthority, isSigner: false, isWritable: false },
+        { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+        {
+          pubkey: getCheckpointsPDA(lpVault, user.publicKey),
+          isSigner: false,
+          isWritable: true,
+        },
+      ];
+      const balanceA = (await getAccount(connection, userTokenAccountA)).amount;
+      const balanceB = (await getAccount(connection, userTokenAccountB)).amount;
+
+      const amountIn = BigInt(1000 * 10 ** 9);
+      const zapLpAndStake = () =>
+        routerProgram.methods
+          .zapLpAndStake(
+            new anchor.BN(amountIn.toString()),
+            new anchor.BN(450 * 10 ** 9),
+            new anchor.BN(1),
+            new anchor.BN(1),
+            tokenMintA,
+            tokenMintB,
+            FEE_TIER,
+            deadline()
+          )
+          .accounts({
+            user: user.publicKey,
+            inputTokenAccount: userTokenAccountA,
+            otherTokenAccount: userTokenAccountB,
+            lpTokenAccount: userLpAccount,
+            vaultProgram: vaultProgram.programId,
+          })
+          .remainingAccounts([
+            ...swapAccounts,
+            ...liquidityAccounts,
+            ...vaultAccounts,
+          ])
+          .signers([user])
+          .rpc();
+      await expectPaused(zapLpAndStake);
+      await zapLpAndStake();
+
+      // The whole input went into the pool, and every LP token into the vault
+      expect(
+        (balanceA - (await getAccount(connection, userTokenAccountA)).amount)
+          .toString()
+      ).to.equal(amountIn.toString());
+      expect(
+        (await getAccount(connection, userTokenAccountB)).amount.toString()
+      ).to.equal(balanceB.toString());
+      expect(
+        (await getAccount(connection, userLpAccount)).amount.toString()
+      ).to.equal("0");
+      const staked = (await getAccount(connection, lpVaultTokenAccount)).amount;
+      expect(Number(staked)).to.be.greaterThan(0);
+      const position = await vaultProgram.account.userPosition.fetch(
+        userPosition
+      );
+      expect(position.shares.toNumber()).to.be.greaterThan(0);
+    });
   });
 });
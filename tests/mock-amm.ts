@@ -0,0 +1,1618 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { MockAmm } from "../target/types/mock_amm";
+import { MockFlashBorrower } from "../target/types/mock_flash_borrower";
+import { MockTransferHook } from "../target/types/mock_transfer_hook";
+import { VaultCore } from "../target/types/vault_core";
+import {
+  TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
+  ExtensionType,
+  getMintLen,
+  createInitializeMintInstruction,
+  createInitializeTransferFeeConfigInstruction,
+  createInitializeTransferHookInstruction,
+  getOrCreateAssociatedTokenAccount,
+  getAssociatedTokenAddressSync,
+  createMint,
+  mintTo,
+  getAccount,
+} from "@solana/spl-token";
+import { expect } from "chai";
+import {
+  AccountMeta,
+  PublicKey,
+  Keypair,
+  SystemProgram,
+  Transaction,
+  sendAndConfirmTransaction,
+} from "@solana/web3.js";
+
+describe("mock-amm", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.mockAmm as Program<MockAmm>;
+  const mockFlashBorrower = anchor.workspace
+    .mockFlashBorrower as Program<MockFlashBorrower>;
+  const mockTransferHook = anchor.workspace
+    .mockTransferHook as Program<MockTransferHook>;
+  const vaultProgram = anchor.workspace.vaultCore as Program<VaultCore>;
+  const connection = provider.connection;
+
+  let authority: Keypair;
+  let user: Keypair;
+  let tokenMintA: PublicKey;
+  let tokenMintB: PublicKey;
+
+  beforeEach(async () => {
+    authority = Keypair.generate();
+    user = Keypair.generate();
+
+    const airdropAmount = 10 * anchor.web3.LAMPORTS_PER_SOL;
+    const airdropTxs = await Promise.all([
+      connection.requestAirdrop(authority.publicKey, airdropAmount),
+      connection.requestAirdrop(user.publicKey, airdropAmount),
+    ]);
+
+    const blockhash = await connection.getLatestBlockhash();
+    await Promise.all(
+      airdropTxs.map((signature) => {
+        return connection.confirmTransaction(
+          { signature, ...blockhash },
+          "confirmed"
+        );
+      })
+    );
+
+    const mint1 = await createMint(
+      connection,
+      authority,
+      authority.publicKey,
+      null,
+      9
+    );
+    const mint2 = await createMint(
+      connection,
+      authority,
+      authority.publicKey,
+      null,
+      9
+    );
+    // Ensure deterministic ordering (smaller mint first)
+    [tokenMintA, tokenMintB] =
+      mint1.toBuffer().toString("hex") < mint2.toBuffer().toString("hex")
+        ? [mint1, mint2]
+        : [mint2, mint1];
+  });
+
+  // Pool seeds end with the fee tier: the pool's initial fee_bps as a u16 LE
+  function feeTierSeed(feeTier: number): Buffer {
+    const seed = Buffer.alloc(2);
+    seed.writeUInt16LE(feeTier);
+    return seed;
+  }
+
+  function getPoolPDA(feeTier = 0): [PublicKey, number] {
+    return PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("pool"),
+        tokenMintA.toBuffer(),
+        tokenMintB.toBuffer(),
+        feeTierSeed(feeTier),
+      ],
+      program.programId
+    );
+  }
+
+  function getPoolAuthorityPDA(feeTier = 0): [PublicKey, number] {
+    return PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("pool"),
+        tokenMintA.toBuffer(),
+        tokenMintB.toBuffer(),
+        feeTierSeed(feeTier),
+        Buffer.from("authority"),
+      ],
+      program.programId
+    );
+  }
+
+  function getLpMintPDA(feeTier = 0): [PublicKey, number] {
+    return PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("pool"),
+        tokenMintA.toBuffer(),
+        tokenMintB.toBuffer(),
+        feeTierSeed(feeTier),
+        Buffer.from("lp_mint"),
+      ],
+      program.programId
+    );
+  }
+
+  // Token-2022 mint withholding `feeBps` of every transfer
+  async function createTransferFeeMint(
+    feeBps: number,
+    mint = Keypair.generate()
+  ): Promise<PublicKey> {
+    const space = getMintLen([ExtensionType.TransferFeeConfig]);
+    const tx = new Transaction().add(
+      SystemProgram.createAccount({
+        fromPubkey: authority.publicKey,
+        newAccountPubkey: mint.publicKey,
+        space,
+        lamports: await connection.getMinimumBalanceForRentExemption(space),
+        programId: TOKEN_2022_PROGRAM_ID,
+      }),
+      createInitializeTransferFeeConfigInstruction(
+        mint.publicKey,
+        authority.publicKey,
+        authority.publicKey,
+        feeBps,
+        BigInt("18446744073709551615"),
+        TOKEN_2022_PROGRAM_ID
+      ),
+      createInitializeMintInstruction(
+        mint.publicKey,
+        9,
+        authority.publicKey,
+        null,
+        TOKEN_2022_PROGRAM_ID
+      )
+    );
+    await sendAndConfirmTransaction(connection, tx, [authority, mint]);
+    return mint.publicKey;
+  }
+
+  // Token-2022 mint whose transfers go through mock-transfer-hook
+  async function createTransferHookMint(
+    mint = Keypair.generate()
+  ): Promise<PublicKey> {
+    const space = getMintLen([ExtensionType.TransferHook]);
+    const tx = new Transaction().add(
+      SystemProgram.createAccount({
+        fromPubkey: authority.publicKey,
+        newAccountPubkey: mint.publicKey,
+        space,
+        lamports: await connection.getMinimumBalanceForRentExemption(space),
+        programId: TOKEN_2022_PROGRAM_ID,
+      }),
+      createInitializeTransferHookInstruction(
+        mint.publicKey,
+        authority.publicKey,
+        mockTransferHook.programId,
+        TOKEN_2022_PROGRAM_ID
+      ),
+      createInitializeMintInstruction(
+        mint.publicKey,
+        9,
+        authority.publicKey,
+        null,
+        TOKEN_2022_PROGRAM_ID
+      )
+    );
+    await sendAndConfirmTransaction(connection, tx, [authority, mint]);
+    return mint.publicKey;
+  }
+
+  interface PoolFixture {
+    pool: PublicKey;
+    poolAuthority: PublicKey;
+    poolVaultA: PublicKey;
+    poolVaultB: PublicKey;
+    userTokenAccountA: PublicKey;
+    userTokenAccountB: PublicKey;
+    tokenProgramA: PublicKey;
+    tokenProgramB: PublicKey;
+    hookAccounts?: AccountMeta[];
+  }
+
+  // Mints and their token programs, passed to every instruction that moves pool tokens
+  function poolTokens(f: PoolFixture) {
+    return {
+      mintA: tokenMintA,
+      mintB: tokenMintB,
+      tokenProgramA: f.tokenProgramA,
+      tokenProgramB: f.tokenProgramB,
+    };
+  }
+
+  async function createPool(
+    curve: any,
+    amountA: number,
+    amountB: number,
+    feeBps = 0,
+    tokenProgramA = TOKEN_PROGRAM_ID,
+    tokenProgramB = TOKEN_PROGRAM_ID,
+    creationFeeAccounts: any = {},
+    hookAccounts: AccountMeta[] = []
+  ): Promise<PoolFixture> {
+    const [pool] = getPoolPDA(feeBps);
+    const [poolAuthority] = getPoolAuthorityPDA(feeBps);
+
+    const poolVaultA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintA,
+        poolAuthority,
+        true,
+        undefined,
+        undefined,
+        tokenProgramA
+      )
+    ).address;
+    const poolVaultB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintB,
+        poolAuthority,
+        true,
+        undefined,
+        undefined,
+        tokenProgramB
+      )
+    ).address;
+
+    const authorityTokenAccountA = await getOrCreateAssociatedTokenAccount(
+      connection,
+      authority,
+      tokenMintA,
+      authority.publicKey,
+      false,
+      undefined,
+      undefined,
+      tokenProgramA
+    );
+    const authorityTokenAccountB = await getOrCreateAssociatedTokenAccount(
+      connection,
+      authority,
+      tokenMintB,
+      authority.publicKey,
+      false,
+      undefined,
+      undefined,
+      tokenProgramB
+    );
+    await mintTo(
+      connection,
+      authority,
+      tokenMintA,
+      authorityTokenAccountA.address,
+      authority,
+      amountA,
+      [],
+      undefined,
+      tokenProgramA
+    );
+    await mintTo(
+      connection,
+      authority,
+      tokenMintB,
+      authorityTokenAccountB.address,
+      authority,
+      amountB,
+      [],
+      undefined,
+      tokenProgramB
+    );
+
+    await program.methods
+      .initializePool(
+        new anchor.BN(amountA),
+        new anchor.BN(amountB),
+        curve,
+        feeBps
+      )
+      .accounts({
+        authority: authority.publicKey,
+        mintA: tokenMintA,
+        mintB: tokenMintB,
+        vaultA: poolVaultA,
+        vaultB: poolVaultB,
+        authorityTokenAccountA: authorityTokenAccountA.address,
+        authorityTokenAccountB: authorityTokenAccountB.address,
+        tokenProgramA,
+        tokenProgramB,
+        ...creationFeeAccounts,
+      })
+      .remainingAccounts(hookAccounts)
+      .signers([authority])
+      .rpc();
+
+    const userTokenAccountA = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        user,
+        tokenMintA,
+        user.publicKey,
+        false,
+        undefined,
+        undefined,
+        tokenProgramA
+      )
+    ).address;
+    const userTokenAccountB = (
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        user,
+        tokenMintB,
+        user.publicKey,
+        false,
+        undefined,
+        undefined,
+        tokenProgramB
+      )
+    ).address;
+    await mintTo(
+      connection,
+      authority,
+      tokenMintA,
+      userTokenAccountA,
+      authority,
+      1000000 * 10 ** 9,
+      [],
+      undefined,
+      tokenProgramA
+    );
+
+    return {
+      pool,
+      poolAuthority,
+      poolVaultA,
+      poolVaultB,
+      userTokenAccountA,
+      userTokenAccountB,
+      tokenProgramA,
+      tokenProgramB,
+      hookAccounts,
+    };
+  }
+
+  async function swapAToB(
+    f: PoolFixture,
+    amountIn: anchor.BN,
+    minAmountOut: anchor.BN,
+    limitPrice: anchor.BN | null = null
+  ) {
+    return program.methods
+      .swap(amountIn, minAmountOut, limitPrice)
+      .accountsPartial({
+        pool: f.pool,
+        user: user.publicKey,
+        userTokenIn: f.userTokenAccountA,
+        userTokenOut: f.userTokenAccountB,
+        vaultA: f.poolVaultA,
+        vaultB: f.poolVaultB,
+        ...poolTokens(f),
+        poolAuthority: f.poolAuthority,
+      })
+      .remainingAccounts(f.hookAccounts ?? [])
+      .signers([user])
+      .rpc();
+  }
+
+  describe("constant_sum curve", () => {
+    it("Swaps exactly 1:1 with zero price impact", async () => {
+      const f = await createPool(
+        { constantSum: {} },
+        100000 * 10 ** 9,
+        100000 * 10 ** 9
+      );
+
+      const poolAccount = await program.account.pool.fetch(f.pool);
+      expect(poolAccount.curve).to.deep.equal({ constantSum: {} });
+
+      // Two identical swaps produce identical output (no price movement)
+      const amountIn = new anchor.BN(1000 * 10 ** 9);
+      await swapAToB(f, amountIn, amountIn);
+      await swapAToB(f, amountIn, amountIn);
+
+      const balanceB = (await getAccount(connection, f.userTokenAccountB))
+        .amount;
+      expect(balanceB.toString()).to.equal(amountIn.muln(2).toString());
+    });
+
+    it("Fails once the output reserve runs out", async () => {
+      const f = await createPool({ constantSum: {} }, 1000, 500);
+
+      try {
+        await swapAToB(f, new anchor.BN(501), new anchor.BN(1));
+        expect.fail("Should have failed - reserve exhausted");
+      } catch (e: any) {
+        expect(e.toString()).to.include("InsufficientLiquidity");
+      }
+
+      // Draining the reserve exactly is allowed
+      await swapAToB(f, new anchor.BN(500), new anchor.BN(500));
+      const vaultB = await getAccount(connection, f.poolVaultB);
+      expect(vaultB.amount.toString()).to.equal("0");
+    });
+  });
+
+  describe("constant_product curve", () => {
+    it("Applies price impact on swaps", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        100000 * 10 ** 9,
+        100000 * 10 ** 9
+      );
+
+      const amountIn = new anchor.BN(1000 * 10 ** 9);
+      await swapAToB(f, amountIn, new anchor.BN(1));
+
+      const balanceB = (await getAccount(connection, f.userTokenAccountB))
+        .amount;
+      expect(Number(balanceB)).to.be.lessThan(amountIn.toNumber());
+    });
+
+    it("Charges the pool fee on the input", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        100000 * 10 ** 9,
+        100000 * 10 ** 9,
+        30
+      );
+
+      // 0.3% of 1000 stays in the pool: 997 * 100000 / (100000 + 997)
+      await swapAToB(f, new anchor.BN(1000 * 10 ** 9), new anchor.BN(1));
+      const balanceB = (await getAccount(connection, f.userTokenAccountB))
+        .amount;
+      const afterFee = BigInt(997 * 10 ** 9);
+      const reserve = BigInt(100000 * 10 ** 9);
+      expect(balanceB.toString()).to.equal(
+        ((afterFee * reserve) / (reserve + afterFee)).toString()
+      );
+
+      await program.methods
+        .setFee(0)
+        .accountsPartial({ pool: f.pool, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      try {
+        await program.methods
+          .setFee(1001)
+          .accountsPartial({ pool: f.pool, authority: authority.publicKey })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - fee above maximum");
+      } catch (e: any) {
+        expect(e.toString()).to.include("InvalidFee");
+      }
+    });
+
+    it("Accrues the protocol's fee share outside the reserves", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        100000 * 10 ** 9,
+        100000 * 10 ** 9,
+        100
+      );
+      await program.methods
+        .setProtocolFeeShare(5000)
+        .accountsPartial({ pool: f.pool, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      // 1% of 1000 is the fee; half of it goes to the protocol
+      await swapAToB(f, new anchor.BN(1000 * 10 ** 9), new anchor.BN(1));
+      let poolAccount = await program.account.pool.fetch(f.pool);
+      expect(poolAccount.protocolFeesA.toString()).to.equal(
+        (5 * 10 ** 9).toString()
+      );
+      expect(poolAccount.protocolFeesB.toNumber()).to.equal(0);
+
+      const feeA = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintA,
+        authority.publicKey
+      );
+      const feeB = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintB,
+        authority.publicKey
+      );
+      const before = (await getAccount(connection, feeA.address)).amount;
+      await program.methods
+        .collectProtocolFees()
+        .accountsPartial({
+          pool: f.pool,
+          authority: authority.publicKey,
+          feeTokenAccountA: feeA.address,
+          feeTokenAccountB: feeB.address,
+          vaultA: f.poolVaultA,
+          vaultB: f.poolVaultB,
+          ...poolTokens(f),
+        })
+        .signers([authority])
+        .rpc();
+
+      const after = (await getAccount(connection, feeA.address)).amount;
+      expect((after - before).toString()).to.equal((5 * 10 ** 9).toString());
+      poolAccount = await program.account.pool.fetch(f.pool);
+      expect(poolAccount.protocolFeesA.toNumber()).to.equal(0);
+    });
+
+    it("Forwards the fee rebate to a vault staking the LP token", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        100000 * 10 ** 9,
+        100000 * 10 ** 9,
+        100
+      );
+      const [lpMint] = getLpMintPDA(100);
+      const [vault] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), lpMint.toBuffer()],
+        vaultProgram.programId
+      );
+      const [vaultAuthority] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), lpMint.toBuffer(), Buffer.from("authority")],
+        vaultProgram.programId
+      );
+      const [protocolConfig] = PublicKey.findProgramAddressSync(
+        [Buffer.from("protocol_config")],
+        vaultProgram.programId
+      );
+      await vaultProgram.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: lpMint,
+          rewardMint: tokenMintA,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+      await program.methods
+        .setFeeRebate(2000)
+        .accountsPartial({
+          pool: f.pool,
+          authority: authority.publicKey,
+          feeRebateVault: vault,
+        })
+        .signers([authority])
+        .rpc();
+
+      // 1% of 1000 is the fee; a fifth of it is rebated. Swaps paying in B don't rebate.
+      await swapAToB(f, new anchor.BN(1000 * 10 ** 9), new anchor.BN(1));
+      let poolAccount = await program.account.pool.fetch(f.pool);
+      expect(poolAccount.feeRebates.toString()).to.equal((2 * 10 ** 9).toString());
+
+      const rewardVault = getAssociatedTokenAddressSync(
+        tokenMintA,
+        vaultAuthority,
+        true
+      );
+      await program.methods
+        .forwardFees()
+        .accountsPartial({
+          pool: f.pool,
+          feeRebateVault: vault,
+          poolVault: f.poolVaultA,
+          rewardVault,
+          rewardMint: tokenMintA,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+          protocolConfig,
+        })
+        .rpc();
+
+      expect(
+        (await getAccount(connection, rewardVault)).amount.toString()
+      ).to.equal((2 * 10 ** 9).toString());
+      const vaultAccount = await vaultProgram.account.vault.fetch(vault);
+      expect(vaultAccount.rewardsFunded.toString()).to.equal(
+        (2 * 10 ** 9).toString()
+      );
+      poolAccount = await program.account.pool.fetch(f.pool);
+      expect(poolAccount.feeRebates.toNumber()).to.equal(0);
+    });
+
+    it("Reverts swaps that push the price past the limit", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        100000 * 10 ** 9,
+        100000 * 10 ** 9
+      );
+      // Marginal price after the trade: reserve_b / reserve_a as Q64.64
+      const amountIn = new anchor.BN(1000 * 10 ** 9);
+      const reserveA = new anchor.BN(101000 * 10 ** 9);
+      const reserveB = new anchor.BN(100000 * 10 ** 9).sub(
+        amountIn.mul(new anchor.BN(100000 * 10 ** 9)).div(reserveA)
+      );
+      const priceAfter = reserveB.shln(64).div(reserveA);
+
+      try {
+        await swapAToB(f, amountIn, new anchor.BN(1), priceAfter.addn(1));
+        expect.fail("Should have failed - price below limit");
+      } catch (e: any) {
+        expect(e.toString()).to.include("PriceLimitExceeded");
+      }
+
+      await swapAToB(f, amountIn, new anchor.BN(1), priceAfter);
+      const poolAccount = await program.account.pool.fetch(f.pool);
+      expect(poolAccount.reserveB.toString()).to.equal(reserveB.toString());
+    });
+
+    it("Quotes exactly what the swap pays out", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        100000 * 10 ** 9,
+        50000 * 10 ** 9,
+        30
+      );
+      const amountIn = new anchor.BN(1000 * 10 ** 9);
+      const quoted: anchor.BN = await program.methods
+        .quote(amountIn, { aToB: {} })
+        .accountsPartial({ pool: f.pool })
+        .view();
+
+      await swapAToB(f, amountIn, quoted);
+      const balanceB = (await getAccount(connection, f.userTokenAccountB))
+        .amount;
+      expect(balanceB.toString()).to.equal(quoted.toString());
+    });
+  });
+
+  describe("creation fee", () => {
+    const [config] = PublicKey.findProgramAddressSync(
+      [Buffer.from("amm_config")],
+      program.programId
+    );
+    const setCreationFee = (fee: number, treasury: PublicKey) =>
+      program.methods
+        .setCreationFee(new anchor.BN(fee), PublicKey.default, treasury)
+        .accountsPartial({ config, admin: provider.wallet.publicKey })
+        .rpc();
+
+    before(async () => {
+      if ((await program.account.ammConfig.fetchNullable(config)) === null) {
+        const [programData] = PublicKey.findProgramAddressSync(
+          [program.programId.toBuffer()],
+          new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+        );
+        await program.methods
+          .initializeConfig(provider.wallet.publicKey)
+          .accountsPartial({
+            upgradeAuthority: provider.wallet.publicKey,
+            programData,
+          })
+          .rpc();
+      }
+    });
+
+    // Other suites create pools for free
+    after(async () => {
+      await setCreationFee(0, PublicKey.default);
+    });
+
+    it("Charges anyone creating a pool the configured SOL fee", async () => {
+      const treasury = Keypair.generate().publicKey;
+      const fee = anchor.web3.LAMPORTS_PER_SOL / 10;
+      await setCreationFee(fee, treasury);
+
+      try {
+        await createPool({ constantProduct: {} }, 10 ** 9, 10 ** 9);
+        expect.fail("Should have failed - no treasury passed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("InvalidTreasury");
+      }
+
+      await createPool(
+        { constantProduct: {} },
+        10 ** 9,
+        10 ** 9,
+        0,
+        TOKEN_PROGRAM_ID,
+        TOKEN_PROGRAM_ID,
+        { treasury }
+      );
+      expect(await connection.getBalance(treasury)).to.equal(fee);
+
+      try {
+        await program.methods
+          .setCreationFee(new anchor.BN(0), PublicKey.default, treasury)
+          .accountsPartial({ config, admin: user.publicKey })
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed - not the config admin");
+      } catch (e: any) {
+        expect(e.toString()).to.include("ConstraintHasOne");
+      }
+    });
+  });
+
+  describe("oracle", () => {
+    it("Reports spot and time-weighted prices of A in B", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        2000 * 10 ** 9,
+        1000 * 10 ** 9
+      );
+      const half = new anchor.BN(1).shln(63);
+      let price = await program.methods
+        .getPrice()
+        .accountsPartial({ pool: f.pool })
+        .view();
+      expect(price.spotPrice.toString()).to.equal(half.toString());
+      // Nothing has traded yet: the average is the only price the pool has had
+      expect(price.twapPrice.toString()).to.equal(half.toString());
+
+      // A swap moves spot at once; the TWAP only as time passes
+      await swapAToB(f, new anchor.BN(2000 * 10 ** 9), new anchor.BN(1));
+      price = await program.methods
+        .getPrice()
+        .accountsPartial({ pool: f.pool })
+        .view();
+      expect(price.spotPrice.toString()).to.equal(half.shrn(2).toString());
+      expect(price.twapPrice.gte(price.spotPrice)).to.equal(true);
+      expect(price.twapPrice.lte(half)).to.equal(true);
+    });
+  });
+
+  describe("sync and skim", () => {
+    it("Keeps direct transfers out of pricing until synced", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9
+      );
+      const donate = () =>
+        mintTo(
+          connection,
+          authority,
+          tokenMintA,
+          f.poolVaultA,
+          authority,
+          500 * 10 ** 9
+        );
+      const quote = (): Promise<anchor.BN> =>
+        program.methods
+          .quote(new anchor.BN(10 * 10 ** 9), { aToB: {} })
+          .accountsPartial({ pool: f.pool })
+          .view();
+
+      const quoteBefore = await quote();
+      await donate();
+      expect((await quote()).toString()).to.equal(quoteBefore.toString());
+
+      const recipientA = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintA,
+        authority.publicKey
+      );
+      const recipientB = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintB,
+        authority.publicKey
+      );
+      await program.methods
+        .skim()
+        .accountsPartial({
+          pool: f.pool,
+          toTokenA: recipientA.address,
+          toTokenB: recipientB.address,
+          vaultA: f.poolVaultA,
+          vaultB: f.poolVaultB,
+          ...poolTokens(f),
+        })
+        .rpc();
+      expect(
+        (await getAccount(connection, recipientA.address)).amount.toString()
+      ).to.equal((500 * 10 ** 9).toString());
+
+      await donate();
+      await program.methods
+        .sync()
+        .accountsPartial({
+          pool: f.pool,
+          vaultA: f.poolVaultA,
+          vaultB: f.poolVaultB,
+        })
+        .rpc();
+      const pool = await program.account.pool.fetch(f.pool);
+      expect(pool.reserveA.toString()).to.equal((1500 * 10 ** 9).toString());
+      expect((await quote()).lt(quoteBefore)).to.equal(true);
+    });
+  });
+
+  describe("fee tiers", () => {
+    it("Keeps one pool per pair and fee tier", async () => {
+      const low = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9,
+        5
+      );
+      const high = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9,
+        100
+      );
+
+      expect(low.pool.toString()).to.not.equal(high.pool.toString());
+      expect(low.poolVaultA.toString()).to.not.equal(
+        high.poolVaultA.toString()
+      );
+      const highPool = await program.account.pool.fetch(high.pool);
+      expect(highPool.feeTier).to.equal(100);
+      expect(highPool.feeBps).to.equal(100);
+    });
+
+    it("Lists every pool in the registry", async () => {
+      const low = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9,
+        5
+      );
+      const high = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9,
+        100
+      );
+
+      const [registryAddress] = PublicKey.findProgramAddressSync(
+        [Buffer.from("pool_registry")],
+        program.programId
+      );
+      const registry = await program.account.poolRegistry.fetch(
+        registryAddress
+      );
+      const pairPools = registry.pools.filter(
+        (entry) => entry.mintA.equals(tokenMintA) && entry.mintB.equals(tokenMintB)
+      );
+      expect(pairPools.map((entry) => entry.pool.toBase58())).to.deep.equal([
+        low.pool.toBase58(),
+        high.pool.toBase58(),
+      ]);
+      expect(pairPools.map((entry) => entry.feeTier)).to.deep.equal([5, 100]);
+    });
+
+    it("Rejects mints out of canonical order", async () => {
+      const [accountA, accountB] = await Promise.all(
+        [tokenMintA, tokenMintB].map((mint) =>
+          getOrCreateAssociatedTokenAccount(
+            connection,
+            authority,
+            mint,
+            authority.publicKey
+          )
+        )
+      );
+      try {
+        await program.methods
+          .initializePool(
+            new anchor.BN(1000),
+            new anchor.BN(1000),
+            { constantProduct: {} },
+            0
+          )
+          .accounts({
+            authority: authority.publicKey,
+            mintA: tokenMintB,
+            mintB: tokenMintA,
+            vaultA: accountB.address,
+            vaultB: accountA.address,
+            authorityTokenAccountA: accountB.address,
+            authorityTokenAccountB: accountA.address,
+            tokenProgramA: TOKEN_PROGRAM_ID,
+            tokenProgramB: TOKEN_PROGRAM_ID,
+          })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - mints reversed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("UnsortedMints");
+      }
+    });
+  });
+
+  describe("pause", () => {
+    it("Blocks swaps but lets LPs withdraw everything", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9
+      );
+      const setPaused = (paused: boolean) =>
+        program.methods
+          .setPoolPaused(paused)
+          .accountsPartial({ pool: f.pool, authority: authority.publicKey })
+          .signers([authority])
+          .rpc();
+      await setPaused(true);
+
+      try {
+        await swapAToB(f, new anchor.BN(10 ** 9), new anchor.BN(1));
+        expect.fail("Should have failed - pool paused");
+      } catch (e: any) {
+        expect(e.toString()).to.include("PoolPaused");
+      }
+
+      const [lpMint] = getLpMintPDA();
+      const authorityLp = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        lpMint,
+        authority.publicKey
+      );
+      const authorityA = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintA,
+        authority.publicKey
+      );
+      const authorityB = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintB,
+        authority.publicKey
+      );
+      await program.methods
+        .emergencyWithdraw()
+        .accountsPartial({
+          pool: f.pool,
+          user: authority.publicKey,
+          userTokenA: authorityA.address,
+          userTokenB: authorityB.address,
+          userLpTokenAccount: authorityLp.address,
+          vaultA: f.poolVaultA,
+          vaultB: f.poolVaultB,
+          ...poolTokens(f),
+          lpMint,
+        })
+        .signers([authority])
+        .rpc();
+
+      // The seed LP was the only position; just the locked minimum's share stays
+      expect(
+        (await getAccount(connection, f.poolVaultA)).amount.toString()
+      ).to.equal("1000");
+      expect(
+        (await getAccount(connection, authorityLp.address)).amount.toString()
+      ).to.equal("0");
+    });
+  });
+
+  describe("admin withdraw", () => {
+    it("Pays out announced amounts only after the timelock", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9
+      );
+      const [pendingWithdraw] = PublicKey.findProgramAddressSync(
+        [Buffer.from("pending_withdraw"), f.pool.toBuffer()],
+        program.programId
+      );
+      const [authorityTokenA, authorityTokenB] = await Promise.all(
+        [tokenMintA, tokenMintB].map(
+          async (mint) =>
+            (
+              await getOrCreateAssociatedTokenAccount(
+                connection,
+                authority,
+                mint,
+                authority.publicKey
+              )
+            ).address
+        )
+      );
+      const adminWithdraw = () =>
+        program.methods
+          .adminWithdraw()
+          .accountsPartial({
+            pool: f.pool,
+            pendingWithdraw,
+            authority: authority.publicKey,
+            authorityTokenA,
+            authorityTokenB,
+            vaultA: f.poolVaultA,
+            vaultB: f.poolVaultB,
+            ...poolTokens(f),
+          })
+          .signers([authority])
+          .rpc();
+
+      await program.methods
+        .setWithdrawTimelock(new anchor.BN(2))
+        .accountsPartial({ pool: f.pool, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      try {
+        await program.methods
+          .setWithdrawTimelock(new anchor.BN(1))
+          .accountsPartial({ pool: f.pool, authority: authority.publicKey })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - timelock lowered");
+      } catch (e: any) {
+        expect(e.toString()).to.include("TimelockLowered");
+      }
+
+      await program.methods
+        .announceAdminWithdraw(new anchor.BN(400 * 10 ** 9), new anchor.BN(0))
+        .accountsPartial({
+          pool: f.pool,
+          pendingWithdraw,
+          authority: authority.publicKey,
+        })
+        .signers([authority])
+        .rpc();
+      try {
+        await adminWithdraw();
+        expect.fail("Should have failed - timelock not elapsed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("TimelockNotElapsed");
+      }
+
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+      await adminWithdraw();
+
+      const poolAccount = await program.account.pool.fetch(f.pool);
+      expect(poolAccount.reserveA.toString()).to.equal(
+        (600 * 10 ** 9).toString()
+      );
+      expect(poolAccount.reserveB.toString()).to.equal(
+        (1000 * 10 ** 9).toString()
+      );
+      expect(await connection.getAccountInfo(pendingWithdraw)).to.be.null;
+    });
+  });
+
+  describe("token-2022", () => {
+    it("Prices swaps off what reaches the vault after transfer fees", async () => {
+      // 1% transfer fee on token A; token B stays on SPL Token. A must sort first.
+      let mintKeypair = Keypair.generate();
+      while (mintKeypair.publicKey.toBuffer().compare(tokenMintB.toBuffer()) >= 0) {
+        mintKeypair = Keypair.generate();
+      }
+      tokenMintA = await createTransferFeeMint(100, mintKeypair);
+      const f = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9,
+        0,
+        TOKEN_2022_PROGRAM_ID,
+        TOKEN_PROGRAM_ID
+      );
+      const reserveA = new anchor.BN(
+        (
+          await getAccount(connection, f.poolVaultA, undefined, TOKEN_2022_PROGRAM_ID)
+        ).amount.toString()
+      );
+      expect(reserveA.toString()).to.equal((990 * 10 ** 9).toString());
+
+      const amountIn = new anchor.BN(10 * 10 ** 9);
+      const received = amountIn.muln(99).divn(100);
+      const expectedOut = received
+        .mul(new anchor.BN(1000 * 10 ** 9))
+        .div(reserveA.add(received));
+
+      // The pre-fee amount can't be met once the fee is withheld
+      try {
+        await swapAToB(
+          f,
+          amountIn,
+          amountIn.mul(new anchor.BN(1000 * 10 ** 9)).div(reserveA.add(amountIn))
+        );
+        expect.fail("Should have failed - output priced on the full input");
+      } catch (e: any) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+
+      await swapAToB(f, amountIn, expectedOut);
+      const balanceB = (await getAccount(connection, f.userTokenAccountB))
+        .amount;
+      expect(balanceB.toString()).to.equal(expectedOut.toString());
+    });
+
+    it("Runs the transfer hook on pool transfers of a hooked mint", async () => {
+      // Token A is hooked and must sort first; the hook caps transfers at 50 tokens
+      let mintKeypair = Keypair.generate();
+      while (mintKeypair.publicKey.toBuffer().compare(tokenMintB.toBuffer()) >= 0) {
+        mintKeypair = Keypair.generate();
+      }
+      tokenMintA = await createTransferHookMint(mintKeypair);
+      const [extraAccountMetaList] = PublicKey.findProgramAddressSync(
+        [Buffer.from("extra-account-metas"), tokenMintA.toBuffer()],
+        mockTransferHook.programId
+      );
+      const [counter] = PublicKey.findProgramAddressSync(
+        [Buffer.from("counter"), tokenMintA.toBuffer()],
+        mockTransferHook.programId
+      );
+      await mockTransferHook.methods
+        .initializeExtraAccountMetaList(new anchor.BN(50 * 10 ** 9))
+        .accounts({ payer: authority.publicKey, mint: tokenMintA })
+        .signers([authority])
+        .rpc();
+
+      const f = await createPool(
+        { constantProduct: {} },
+        50 * 10 ** 9,
+        50 * 10 ** 9,
+        0,
+        TOKEN_2022_PROGRAM_ID,
+        TOKEN_PROGRAM_ID,
+        {},
+        [
+          { pubkey: mockTransferHook.programId, isSigner: false, isWritable: false },
+          { pubkey: extraAccountMetaList, isSigner: false, isWritable: false },
+          { pubkey: counter, isSigner: false, isWritable: true },
+        ]
+      );
+
+      await swapAToB(f, new anchor.BN(10 * 10 ** 9), new anchor.BN(0));
+      let counterAccount = await mockTransferHook.account.transferCounter.fetch(
+        counter
+      );
+      expect(counterAccount.transfers.toNumber()).to.equal(2);
+
+      try {
+        await swapAToB(f, new anchor.BN(60 * 10 ** 9), new anchor.BN(0));
+        expect.fail("Should have failed - hook rejects the transfer");
+      } catch (e: any) {
+        expect(e.toString()).to.include("AmountTooLarge");
+      }
+      counterAccount = await mockTransferHook.account.transferCounter.fetch(
+        counter
+      );
+      expect(counterAccount.transfers.toNumber()).to.equal(2);
+    });
+  });
+
+  describe("flash swap", () => {
+    it("Requires the fee-adjusted invariant to be restored", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9,
+        30
+      );
+      await mintTo(
+        connection,
+        authority,
+        tokenMintB,
+        f.userTokenAccountB,
+        authority,
+        100 * 10 ** 9
+      );
+
+      // The mock borrower sends back (repay_a, repay_b) from `data`
+      const flashSwap = (repayB: anchor.BN) => {
+        const data = Buffer.concat([
+          new anchor.BN(0).toArrayLike(Buffer, "le", 8),
+          repayB.toArrayLike(Buffer, "le", 8),
+        ]);
+        return program.methods
+          .flashSwap(new anchor.BN(0), new anchor.BN(10 * 10 ** 9), data)
+          .accountsPartial({
+            pool: f.pool,
+            user: user.publicKey,
+            userTokenA: f.userTokenAccountA,
+            userTokenB: f.userTokenAccountB,
+            vaultA: f.poolVaultA,
+            vaultB: f.poolVaultB,
+            ...poolTokens(f),
+            poolAuthority: f.poolAuthority,
+            callbackProgram: mockFlashBorrower.programId,
+          })
+          .remainingAccounts([
+            { pubkey: user.publicKey, isSigner: true, isWritable: false },
+            { pubkey: f.userTokenAccountA, isSigner: false, isWritable: true },
+            { pubkey: f.userTokenAccountB, isSigner: false, isWritable: true },
+            { pubkey: f.poolVaultA, isSigner: false, isWritable: true },
+            { pubkey: f.poolVaultB, isSigner: false, isWritable: true },
+            { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+          ])
+          .signers([user])
+          .rpc();
+      };
+
+      // Returning the principal alone skips the 0.3% fee
+      try {
+        await flashSwap(new anchor.BN(10 * 10 ** 9));
+        expect.fail("Should have failed - fee not paid");
+      } catch (e: any) {
+        expect(e.toString()).to.include("FlashSwapNotRepaid");
+      }
+
+      await flashSwap(new anchor.BN(10_031 * 10 ** 6));
+      const vaultB = await getAccount(connection, f.poolVaultB);
+      expect(vaultB.amount.toString()).to.equal(
+        (1000 * 10 ** 9 + 31 * 10 ** 6).toString()
+      );
+      const poolAccount = await program.account.pool.fetch(f.pool);
+      expect(poolAccount.locked).to.equal(false);
+    });
+  });
+
+  describe("route swap", () => {
+    it("Swaps through two pools without touching the middle token", async () => {
+      const first = await createPool(
+        { constantSum: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9
+      );
+      const [mintIn, mintMid] = [tokenMintA, tokenMintB];
+      const mintOut = await createMint(
+        connection,
+        authority,
+        authority.publicKey,
+        null,
+        9
+      );
+      [tokenMintA, tokenMintB] =
+        mintMid.toBuffer().compare(mintOut.toBuffer()) < 0
+          ? [mintMid, mintOut]
+          : [mintOut, mintMid];
+      const second = await createPool(
+        { constantSum: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9
+      );
+      const userTokenOut = tokenMintA.equals(mintOut)
+        ? second.userTokenAccountA
+        : second.userTokenAccountB;
+
+      const hop = (f: PoolFixture, mintA: PublicKey, mintB: PublicKey) =>
+        [
+          f.pool,
+          f.poolVaultA,
+          f.poolVaultB,
+          f.poolAuthority,
+          mintA,
+          mintB,
+          TOKEN_PROGRAM_ID,
+          TOKEN_PROGRAM_ID,
+        ].map((pubkey, i) => ({
+          pubkey,
+          isSigner: false,
+          isWritable: i < 3,
+        }));
+      const route = (minAmountOut: number) =>
+        program.methods
+          .routeSwap(new anchor.BN(10 * 10 ** 9), new anchor.BN(minAmountOut))
+          .accountsPartial({
+            user: user.publicKey,
+            userTokenIn: first.userTokenAccountA,
+            userTokenOut,
+          })
+          .remainingAccounts([
+            ...hop(first, mintIn, mintMid),
+            ...hop(second, tokenMintA, tokenMintB),
+          ])
+          .signers([user])
+          .rpc();
+
+      try {
+        await route(10 * 10 ** 9 + 1);
+        expect.fail("Should have failed - output below minimum");
+      } catch (e: any) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+
+      await route(10 * 10 ** 9);
+      expect(
+        (await getAccount(connection, userTokenOut)).amount.toString()
+      ).to.equal((10 * 10 ** 9).toString());
+      expect(
+        (await getAccount(connection, first.userTokenAccountB)).amount.toString()
+      ).to.equal("0");
+      const secondPool = await program.account.pool.fetch(second.pool);
+      const midReserve = tokenMintA.equals(mintMid)
+        ? secondPool.reserveA
+        : secondPool.reserveB;
+      expect(midReserve.toString()).to.equal((1010 * 10 ** 9).toString());
+    });
+  });
+
+  describe("stable curve", () => {
+    it("Swaps near 1:1 with far less impact than constant product", async () => {
+      const f = await createPool(
+        { stable: { amp: new anchor.BN(100) } },
+        100000 * 10 ** 9,
+        100000 * 10 ** 9
+      );
+
+      // Constant product would pay 1000 * 100000 / 101000 ≈ 990.1
+      const amountIn = new anchor.BN(1000 * 10 ** 9);
+      await swapAToB(f, amountIn, new anchor.BN(999 * 10 ** 9));
+
+      const balanceB = (await getAccount(connection, f.userTokenAccountB))
+        .amount;
+      expect(Number(balanceB)).to.be.lessThan(amountIn.toNumber());
+    });
+  });
+
+  describe("liquidity", () => {
+    it("Locks MINIMUM_LIQUIDITY of the seed LP for good", async () => {
+      await createPool({ constantProduct: {} }, 1000 * 10 ** 9, 4000 * 10 ** 9);
+      const [lockedLp] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("pool"),
+          tokenMintA.toBuffer(),
+          tokenMintB.toBuffer(),
+          feeTierSeed(0),
+          Buffer.from("locked_lp"),
+        ],
+        program.programId
+      );
+      const [lpBurnAuthority] = PublicKey.findProgramAddressSync(
+        [Buffer.from("lp_burn")],
+        program.programId
+      );
+      const locked = await getAccount(connection, lockedLp);
+      expect(locked.amount.toString()).to.equal("1000");
+      expect(locked.owner.toBase58()).to.equal(lpBurnAuthority.toBase58());
+
+      // sqrt(1000e9 * 4000e9) = 2000e9, less the locked part
+      const [lpMint] = getLpMintPDA();
+      const authorityLp = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        lpMint,
+        authority.publicKey
+      );
+      expect(authorityLp.amount.toString()).to.equal(
+        (2000 * 10 ** 9 - 1000).toString()
+      );
+    });
+
+    it("Registers Metaplex metadata naming the LP token after its pair", async () => {
+      await createPool({ constantProduct: {} }, 10 ** 9, 10 ** 9);
+      const metadataProgram = new PublicKey(
+        "metaqbxxUerdq28cj1RbAWkYQm3ybzjb6a8bt518x1s"
+      );
+      const [metadata] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("metadata"),
+          metadataProgram.toBuffer(),
+          getLpMintPDA()[0].toBuffer(),
+        ],
+        metadataProgram
+      );
+      // key (1) + update authority (32) + mint (32), then Borsh strings padded with NULs
+      const data = (await connection.getAccountInfo(metadata))!.data;
+      const readString = (offset: number) => {
+        const len = data.readUInt32LE(offset);
+        const value = data
+          .subarray(offset + 4, offset + 4 + len)
+          .toString()
+          .replace(/\0+$/, "");
+        return [value, offset + 4 + len] as const;
+      };
+      const [name, symbolOffset] = readString(65);
+      const [symbol] = readString(symbolOffset);
+      expect(name).to.equal(
+        `MOCKAMM-LP-${tokenMintA.toBase58().slice(0, 4)}-${tokenMintB
+          .toBase58()
+          .slice(0, 4)}`
+      );
+      expect(symbol).to.equal("MAMM-LP");
+      expect(new PublicKey(data.subarray(1, 33)).toBase58()).to.equal(
+        getPoolAuthorityPDA()[0].toBase58()
+      );
+    });
+
+    it("Mints LP tokens pro rata and for the imbalanced excess", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9
+      );
+      const [lpMint] = getLpMintPDA();
+
+      // Seed liquidity is the first LP position: sqrt(1000e9 * 1000e9), less the
+      // locked minimum
+      const authorityLp = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        lpMint,
+        authority.publicKey
+      );
+      expect(authorityLp.amount.toString()).to.equal(
+        (1000 * 10 ** 9 - 1000).toString()
+      );
+
+      await mintTo(
+        connection,
+        authority,
+        tokenMintB,
+        f.userTokenAccountB,
+        authority,
+        1000 * 10 ** 9
+      );
+      const userLp = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user,
+          lpMint,
+          user.publicKey
+        )
+      ).address;
+
+      // 100/100 is the pro-rata part; the extra 100 B mints by how much it grows
+      // sqrt(a * b): 1100e9 * (sqrt(1100e9 * 1200e9) - 1100e9) / 1100e9
+      await program.methods
+        .addLiquidity(
+          new anchor.BN(100 * 10 ** 9),
+          new anchor.BN(200 * 10 ** 9),
+          new anchor.BN(100 * 10 ** 9)
+        )
+        .accountsPartial({
+          pool: f.pool,
+          user: user.publicKey,
+          userTokenA: f.userTokenAccountA,
+          userTokenB: f.userTokenAccountB,
+          userLpTokenAccount: userLp,
+          vaultA: f.poolVaultA,
+          vaultB: f.poolVaultB,
+          ...poolTokens(f),
+          lpMint,
+        })
+        .signers([user])
+        .rpc();
+
+      expect((await getAccount(connection, userLp)).amount.toString()).to.equal(
+        "148912529307"
+      );
+      expect(
+        (await getAccount(connection, f.poolVaultB)).amount.toString()
+      ).to.equal((1200 * 10 ** 9).toString());
+
+      try {
+        await program.methods
+          .addLiquidity(
+            new anchor.BN(100 * 10 ** 9),
+            new anchor.BN(100 * 10 ** 9),
+            new anchor.BN(101 * 10 ** 9)
+          )
+          .accountsPartial({
+            pool: f.pool,
+            user: user.publicKey,
+            userTokenA: f.userTokenAccountA,
+            userTokenB: f.userTokenAccountB,
+            userLpTokenAccount: userLp,
+            vaultA: f.poolVaultA,
+            vaultB: f.poolVaultB,
+            ...poolTokens(f),
+            lpMint,
+          })
+          .signers([user])
+          .rpc();
+        expect.fail("Should have failed - min_lp_out not met");
+      } catch (e: any) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+    });
+
+    it("Zaps a single-sided deposit into LP tokens", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        1000 * 10 ** 9
+      );
+      const [lpMint] = getLpMintPDA();
+      const userLp = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user,
+          lpMint,
+          user.publicKey
+        )
+      ).address;
+      const zap = (minLpOut: number) =>
+        program.methods
+          .addLiquiditySingle(
+            tokenMintA,
+            new anchor.BN(100 * 10 ** 9),
+            new anchor.BN(minLpOut)
+          )
+          .accountsPartial({
+            pool: f.pool,
+            user: user.publicKey,
+            userTokenIn: f.userTokenAccountA,
+            userLpTokenAccount: userLp,
+            vaultA: f.poolVaultA,
+            vaultB: f.poolVaultB,
+            lpMint,
+            mintIn: tokenMintA,
+            tokenProgramIn: TOKEN_PROGRAM_ID,
+          })
+          .signers([user])
+          .rpc();
+
+      // Worth as much as the deposit: 1e12 * (sqrt(1.1) - 1) LP, less rounding
+      try {
+        await zap(48_808_848_170);
+        expect.fail("Should have failed - min_lp_out not met");
+      } catch (e: any) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+      await zap(48_808_848_169);
+
+      expect((await getAccount(connection, userLp)).amount.toString()).to.equal(
+        "48808848169"
+      );
+      const pool = await program.account.pool.fetch(f.pool);
+      expect(pool.reserveA.toString()).to.equal((1100 * 10 ** 9).toString());
+      expect(pool.reserveB.toString()).to.equal((1000 * 10 ** 9).toString());
+    });
+
+    it("Burns LP tokens for both reserves pro rata", async () => {
+      const f = await createPool(
+        { constantProduct: {} },
+        1000 * 10 ** 9,
+        4000 * 10 ** 9
+      );
+      const [lpMint] = getLpMintPDA();
+      const authorityLp = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        lpMint,
+        authority.publicKey
+      );
+      const authorityA = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintA,
+        authority.publicKey
+      );
+      const authorityB = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMintB,
+        authority.publicKey
+      );
+      // sqrt(1000e9 * 4000e9) = 2000e9 LP supply; burn half of it
+      const remove = (minA: number, minB: number) =>
+        program.methods
+          .removeLiquidity(
+            new anchor.BN(1000 * 10 ** 9),
+            new anchor.BN(minA),
+            new anchor.BN(minB)
+          )
+          .accountsPartial({
+            pool: f.pool,
+            user: authority.publicKey,
+            userTokenA: authorityA.address,
+            userTokenB: authorityB.address,
+            userLpTokenAccount: authorityLp.address,
+            vaultA: f.poolVaultA,
+            vaultB: f.poolVaultB,
+            ...poolTokens(f),
+            lpMint,
+          })
+          .signers([authority])
+          .rpc();
+
+      try {
+        await remove(500 * 10 ** 9, 2000 * 10 ** 9 + 1);
+        expect.fail("Should have failed - min_b_out not met");
+      } catch (e: any) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+      await remove(500 * 10 ** 9, 2000 * 10 ** 9);
+
+      expect(
+        (await getAccount(connection, authorityA.address)).amount.toString()
+      ).to.equal((500 * 10 ** 9).toString());
+      expect(
+        (await getAccount(connection, authorityB.address)).amount.toString()
+      ).to.equal((2000 * 10 ** 9).toString());
+      expect(
+        (await getAccount(connection, authorityLp.address)).amount.toString()
+      ).to.equal((1000 * 10 ** 9 - 1000).toString());
+    });
+  });
+});
@@ -0,0 +1,249 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { ComposerRouter } from "../target/types/composer_router";
+import { VaultCore } from "../target/types/vault_core";
+import {
+  TOKEN_PROGRAM_ID,
+  createMint,
+  getOrCreateAssociatedTokenAccount,
+  mintTo,
+} from "@solana/spl-token";
+import { expect } from "chai";
+import { PublicKey, Keypair } from "@solana/web3.js";
+
+const BPF_LOADER_UPGRADEABLE_ID = new PublicKey(
+  "BPFLoaderUpgradeab1e11111111111111111111111"
+);
+
+// Must run before upgrade-governance.ts hands the upgrade authority away
+describe("protocol-config", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.vaultCore as Program<VaultCore>;
+  const routerProgram = anchor.workspace
+    .composerRouter as Program<ComposerRouter>;
+  const connection = provider.connection;
+  const payer = (provider.wallet as anchor.Wallet).payer;
+
+  const U64_MAX = new anchor.BN("18446744073709551615");
+
+  let vault: PublicKey;
+  let rewardMint: PublicKey;
+
+  function getProgramDataAddress(programId: PublicKey): PublicKey {
+    return PublicKey.findProgramAddressSync(
+      [programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_ID
+    )[0];
+  }
+
+  async function setBounds(
+    maxRewardRate: anchor.BN,
+    maxFlashFeeBps: number,
+    maxRouterFeeBps = 10000,
+    maxManagementFeeBps = 10000
+  ) {
+    await program.methods
+      .setProtocolBounds(
+        maxRewardRate,
+        maxFlashFeeBps,
+        maxRouterFeeBps,
+        maxManagementFeeBps
+      )
+      .accounts({ admin: provider.wallet.publicKey })
+      .rpc();
+  }
+
+  before(async () => {
+    await program.methods
+      .initializeProtocolConfig(provider.wallet.publicKey)
+      .accountsPartial({
+        upgradeAuthority: provider.wallet.publicKey,
+        programData: getProgramDataAddress(program.programId),
+      })
+      .rpc();
+
+    const tokenMint = await createMint(connection, payer, payer.publicKey, null, 9);
+    rewardMint = await createMint(connection, payer, payer.publicKey, null, 9);
+
+    await program.methods
+      .initializeVault()
+      .accounts({
+        authority: provider.wallet.publicKey,
+        tokenMint,
+        rewardMint,
+        rewardTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    [vault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), tokenMint.toBuffer()],
+      program.programId
+    );
+  });
+
+  after(async () => {
+    // Leave the deployment permissive for the remaining suites
+    await setBounds(U64_MAX, 10000);
+    await program.methods
+      .setProtocolFees(0, 0, PublicKey.default)
+      .accounts({ admin: provider.wallet.publicKey })
+      .rpc();
+  });
+
+  it("Caps reward_rate and flash_fee_bps at the protocol bounds", async () => {
+    await setBounds(new anchor.BN(1000), 50);
+
+    const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+      connection,
+      payer,
+      rewardMint,
+      payer.publicKey
+    );
+    await mintTo(
+      connection,
+      payer,
+      rewardMint,
+      funderRewardAccount.address,
+      payer,
+      1_000_000
+    );
+    const vaultAccount = await program.account.vault.fetch(vault);
+
+    try {
+      await program.methods
+        .fundRewards(new anchor.BN(1000), new anchor.BN(1001))
+        .accounts({
+          vault,
+          funder: provider.wallet.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: vaultAccount.rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .rpc();
+      expect.fail("Should have failed - reward rate above bound");
+    } catch (e: any) {
+      expect(e.toString()).to.include("ExceedsProtocolBound");
+    }
+
+    try {
+      await program.methods
+        .updateFlashLoanConfig(51, null, null, null)
+        .accounts({ vault, authority: provider.wallet.publicKey })
+        .rpc();
+      expect.fail("Should have failed - flash fee above bound");
+    } catch (e: any) {
+      expect(e.toString()).to.include("ExceedsProtocolBound");
+    }
+
+    // At the bound is fine
+    await program.methods
+      .fundRewards(new anchor.BN(1000), new anchor.BN(1000))
+      .accounts({
+        vault,
+        funder: provider.wallet.publicKey,
+        funderTokenAccount: funderRewardAccount.address,
+        rewardVault: vaultAccount.rewardVault,
+        rewardTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    await program.methods
+      .updateFlashLoanConfig(50, null, null, null)
+      .accounts({ vault, authority: provider.wallet.publicKey })
+      .rpc();
+  });
+
+  it("Caps the management and router fees at the protocol bounds", async () => {
+    await setBounds(U64_MAX, 10000, 30, 200);
+
+    try {
+      await program.methods
+        .setManagementFee(201, provider.wallet.publicKey)
+        .accounts({ vault, authority: provider.wallet.publicKey })
+        .rpc();
+      expect.fail("Should have failed - management fee above bound");
+    } catch (e: any) {
+      expect(e.toString()).to.include("ExceedsProtocolBound");
+    }
+    await program.methods
+      .setManagementFee(200, provider.wallet.publicKey)
+      .accounts({ vault, authority: provider.wallet.publicKey })
+      .rpc();
+
+    // composer-router's fee answers to the same config
+    try {
+      await routerProgram.methods
+        .initializeRouterState([program.programId])
+        .accounts({ authority: provider.wallet.publicKey })
+        .rpc();
+    } catch (e: any) {
+      if (!e.toString().includes("already in use")) {
+        throw e;
+      }
+    }
+    try {
+      await routerProgram.methods
+        .setFees(31, 0)
+        .accounts({ authority: provider.wallet.publicKey })
+        .rpc();
+      expect.fail("Should have failed - router fee above bound");
+    } catch (e: any) {
+      expect(e.toString()).to.include("ExceedsProtocolBound");
+    }
+    await routerProgram.methods
+      .setFees(30, 0)
+      .accounts({ authority: provider.wallet.publicKey })
+      .rpc();
+    await routerProgram.methods
+      .setFees(0, 0)
+      .accounts({ authority: provider.wallet.publicKey })
+      .rpc();
+  });
+
+  it("Starts new vaults at the protocol's default flash fee", async () => {
+    const treasury = Keypair.generate().publicKey;
+    await program.methods
+      .setProtocolFees(25, 2000, treasury)
+      .accounts({ admin: provider.wallet.publicKey })
+      .rpc();
+
+    const tokenMint = await createMint(connection, payer, payer.publicKey, null, 9);
+    await program.methods
+      .initializeVault()
+      .accounts({
+        authority: provider.wallet.publicKey,
+        tokenMint,
+        rewardMint,
+        rewardTokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+    const [newVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("vault"), tokenMint.toBuffer()],
+      program.programId
+    );
+    expect((await program.account.vault.fetch(newVault)).flashFeeBps).to.equal(25);
+
+    const [configAddress] = PublicKey.findProgramAddressSync(
+      [Buffer.from("protocol_config")],
+      program.programId
+    );
+    const config = await program.account.protocolConfig.fetch(configAddress);
+    expect(config.protocolFeeBps).to.equal(2000);
+    expect(config.treasury.toBase58()).to.equal(treasury.toBase58());
+  });
+
+  it("Only the config admin can change bounds", async () => {
+    const outsider = Keypair.generate();
+    try {
+      await program.methods
+        .setProtocolBounds(U64_MAX, 10000, 10000, 10000)
+        .accounts({ admin: outsider.publicKey })
+        .signers([outsider])
+        .rpc();
+      expect.fail("Should have failed - not the admin");
+    } catch (e: any) {
+      expect(e.toString()).to.include("Unauthorized");
+    }
+  });
+});
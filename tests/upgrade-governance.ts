@@ -0,0 +1,160 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { VaultCore } from "../target/types/vault_core";
+import { expect } from "chai";
+import {
+  PublicKey,
+  Keypair,
+  Transaction,
+  TransactionInstruction,
+} from "@solana/web3.js";
+
+const BPF_LOADER_UPGRADEABLE_ID = new PublicKey(
+  "BPFLoaderUpgradeab1e11111111111111111111111"
+);
+
+describe("upgrade-governance", () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.vaultCore as Program<VaultCore>;
+  const connection = provider.connection;
+
+  // Every program in the workspace must end up governed by the timelock
+  const workspacePrograms = [
+    anchor.workspace.vaultCore.programId,
+    anchor.workspace.mockAmm.programId,
+    anchor.workspace.composerRouter.programId,
+    anchor.workspace.composerRouterDynamic.programId,
+  ] as PublicKey[];
+
+  const multisig = Keypair.generate();
+  const upgradeDelay = new anchor.BN(24 * 60 * 60);
+
+  function getGovernancePDA(): [PublicKey, number] {
+    return PublicKey.findProgramAddressSync(
+      [Buffer.from("governance")],
+      program.programId
+    );
+  }
+
+  function getProgramDataAddress(programId: PublicKey): PublicKey {
+    return PublicKey.findProgramAddressSync(
+      [programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_ID
+    )[0];
+  }
+
+  // Loader `SetAuthority` (instruction index 4)
+  function setUpgradeAuthorityIx(
+    programId: PublicKey,
+    currentAuthority: PublicKey,
+    newAuthority: PublicKey
+  ): TransactionInstruction {
+    const data = Buffer.alloc(4);
+    data.writeUInt32LE(4, 0);
+    return new TransactionInstruction({
+      programId: BPF_LOADER_UPGRADEABLE_ID,
+      keys: [
+        {
+          pubkey: getProgramDataAddress(programId),
+          isSigner: false,
+          isWritable: true,
+        },
+        { pubkey: currentAuthority, isSigner: true, isWritable: false },
+        { pubkey: newAuthority, isSigner: false, isWritable: false },
+      ],
+      data,
+    });
+  }
+
+  it("Initializes governance from the current upgrade authority", async () => {
+    const [governance] = getGovernancePDA();
+
+    await program.methods
+      .initializeGovernance(multisig.publicKey, upgradeDelay)
+      .accountsPartial({
+        upgradeAuthority: provider.wallet.publicKey,
+        programData: getProgramDataAddress(program.programId),
+      })
+      .rpc();
+
+    const governanceAccount = await program.account.governance.fetch(
+      governance
+    );
+    expect(governanceAccount.admin.toString()).to.equal(
+      multisig.publicKey.toString()
+    );
+    expect(governanceAccount.upgradeDelay.toString()).to.equal(
+      upgradeDelay.toString()
+    );
+  });
+
+  it("Fails verification while the deployer still holds upgrade authority", async () => {
+    try {
+      await program.methods
+        .verifyUpgradeAuthority()
+        .accounts({
+          program: workspacePrograms[1],
+          programData: getProgramDataAddress(workspacePrograms[1]),
+        })
+        .rpc();
+      expect.fail("Should have failed - authority not handed over");
+    } catch (e: any) {
+      expect(e.toString()).to.include("UpgradeAuthorityMismatch");
+    }
+  });
+
+  it("Hands every program's upgrade authority to governance", async () => {
+    const [governance] = getGovernancePDA();
+
+    for (const programId of workspacePrograms) {
+      await provider.sendAndConfirm(
+        new Transaction().add(
+          setUpgradeAuthorityIx(
+            programId,
+            provider.wallet.publicKey,
+            governance
+          )
+        )
+      );
+
+      await program.methods
+        .verifyUpgradeAuthority()
+        .accounts({
+          program: programId,
+          programData: getProgramDataAddress(programId),
+        })
+        .rpc();
+    }
+  });
+
+  it("Rejects a mismatched program data account", async () => {
+    try {
+      await program.methods
+        .verifyUpgradeAuthority()
+        .accounts({
+          program: workspacePrograms[1],
+          programData: getProgramDataAddress(workspacePrograms[0]),
+        })
+        .rpc();
+      expect.fail("Should have failed - wrong program data");
+    } catch (e: any) {
+      expect(e.toString()).to.include("InvalidProgramData");
+    }
+  });
+
+  it("Only the governance admin can queue upgrades", async () => {
+    try {
+      await program.methods
+        .proposeUpgrade(program.programId, Keypair.generate().publicKey)
+        .accounts({
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+      expect.fail("Should have failed - not the admin");
+    } catch (e: any) {
+      expect(e.toString()).to.include("Unauthorized");
+    }
+  });
+});
@@ -1,18 +1,24 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { VaultCore } from "../target/types/vault_core";
+import { MockFlashBorrower } from "../target/types/mock_flash_borrower";
 import {
   TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
   ASSOCIATED_TOKEN_PROGRAM_ID,
   getAssociatedTokenAddress,
+  getAssociatedTokenAddressSync,
   getOrCreateAssociatedTokenAccount,
   createMint,
   createAccount,
   mintTo,
   getAccount,
+  approve,
+  NATIVE_MINT,
 } from "@solana/spl-token";
 import { expect } from "chai";
 import { PublicKey, Keypair, SystemProgram } from "@solana/web3.js";
+import { createHash } from "crypto";
 
 describe("vault-core", () => {
   // Configure the client to use the local cluster.
@@ -20,6 +26,8 @@ describe("vault-core", () => {
   anchor.setProvider(provider);
 
   const program = anchor.workspace.vaultCore as Program<VaultCore>;
+  const mockFlashBorrower = anchor.workspace
+    .mockFlashBorrower as Program<MockFlashBorrower>;
   const connection = provider.connection;
 
   let authority: Keypair;
@@ -89,6 +97,15 @@ describe("vault-core", () => {
     );
   }
 
+  // Mirrors verify_merkle_proof: leaf = H(0x00 || payload), node = H(0x01 || sorted pair)
+  const sha256 = (...parts: Buffer[]) =>
+    createHash("sha256").update(Buffer.concat(parts)).digest();
+  const leaf = (...payload: Buffer[]) => sha256(Buffer.from([0]), ...payload);
+  const node = (a: Buffer, b: Buffer) =>
+    Buffer.compare(a, b) <= 0
+      ? sha256(Buffer.from([1]), a, b)
+      : sha256(Buffer.from([1]), b, a);
+
   async function getUserPositionPDA(
     vault: PublicKey,
     user: PublicKey
@@ -99,6 +116,15 @@ describe("vault-core", () => {
     );
   }
 
+  // Callback allowlist entries live after the fixed zero-copy fields (8 + 1024 bytes)
+  async function getCallbackAllowlist(vault: PublicKey): Promise<PublicKey[]> {
+    const { data } = await connection.getAccountInfo(vault);
+    const { callbackAllowlistLen } = await program.account.vault.fetch(vault);
+    return Array.from({ length: callbackAllowlistLen }, (_, i) =>
+      new PublicKey(data.subarray(1032 + 32 * i, 1064 + 32 * i))
+    );
+  }
+
   describe("initialize_vault", () => {
     it("Initializes a vault successfully", async () => {
       const [vault] = await getVaultPDA(tokenMint1);
@@ -119,6 +145,7 @@ describe("vault-core", () => {
           tokenMint: tokenMint1,
           rewardMint: rewardMint,
           //rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
         .signers([authority])
         .rpc();
@@ -133,6 +160,43 @@ describe("vault-core", () => {
       expect(vaultAccount.accRewardPerShare.toString()).to.equal("0");
     });
 
+    it("Registers new vaults for discovery by mint", async () => {
+      const [vault] = await getVaultPDA(tokenMint1);
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const entries = await program.account.vaultEntry.all([
+        { memcmp: { offset: 40, bytes: tokenMint1.toBase58() } },
+      ]);
+      expect(entries).to.have.length(1);
+      expect(entries[0].account.vault.toBase58()).to.equal(vault.toBase58());
+      expect(entries[0].account.rewardMint.toBase58()).to.equal(
+        tokenMint1.toBase58()
+      );
+      expect(entries[0].account.createdTs.toNumber()).to.be.greaterThan(0);
+
+      const name = Buffer.alloc(32);
+      name.write("Staked USDC");
+      await program.methods
+        .setMetadata([...name], "https://example.com/vault.json")
+        .accounts({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      const entry = await program.account.vaultEntry.fetch(entries[0].publicKey);
+      expect(Buffer.from(entry.name).toString().replace(/\0+$/, "")).to.equal(
+        "Staked USDC"
+      );
+      expect(entry.metadataUri).to.equal("https://example.com/vault.json");
+    });
+
     it("Allows multiple vaults for different token mints", async () => {
       // Initialize vault for tokenMint1
       const [vault1] = await getVaultPDA(tokenMint1);
@@ -150,6 +214,7 @@ describe("vault-core", () => {
           tokenMint: tokenMint1,
           rewardMint: tokenMint1,
           //rewardVault: rewardVault1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
         .signers([authority])
         .rpc();
@@ -170,6 +235,7 @@ describe("vault-core", () => {
           tokenMint: tokenMint2,
           rewardMint: tokenMint2,
           //rewardVault: rewardVault2,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
         .signers([authority])
         .rpc();
@@ -185,6 +251,34 @@ describe("vault-core", () => {
       );
       expect(vault1.toString()).to.not.equal(vault2.toString());
     });
+
+    it("Creates vaults at the current layout version", async () => {
+      const [vault] = await getVaultPDA(tokenMint1);
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.version).to.equal(6);
+
+      try {
+        await program.methods
+          .migrateVault()
+          .accounts({ vault, authority: authority.publicKey, payer: authority.publicKey })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - already current");
+      } catch (e: any) {
+        expect(e.toString()).to.include("AlreadyMigrated");
+      }
+    });
   });
 
   describe("deposit", () => {
@@ -219,6 +313,7 @@ describe("vault-core", () => {
           tokenMint: tokenMint1,
           rewardMint: tokenMint1,
           //rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
         .signers([authority])
         .rpc();
@@ -248,7 +343,7 @@ describe("vault-core", () => {
       const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
 
       await program.methods
-        .deposit(depositAmount)
+        .deposit(depositAmount, new anchor.BN(0), [])
         .accounts({
           vault: vault,
           user: user1.publicKey,
@@ -275,6 +370,9 @@ describe("vault-core", () => {
         user1.publicKey.toString()
       );
       expect(positionAccount.vault.toString()).to.equal(vault.toString());
+      // Current layout: u128 shares, nothing left in the legacy u64 slot
+      expect(positionAccount.version).to.equal(6);
+      expect(positionAccount.legacyShares.toNumber()).to.equal(0);
 
       // Verify token balances
       const vaultBalance = await getAccount(connection, vaultTokenAccount);
@@ -289,7 +387,7 @@ describe("vault-core", () => {
 
       // First deposit
       await program.methods
-        .deposit(firstDeposit)
+        .deposit(firstDeposit, new anchor.BN(0), [])
         .accounts({
           vault: vault,
           //userPosition: userPosition,
@@ -305,7 +403,7 @@ describe("vault-core", () => {
 
       // Second deposit - should get proportional shares
       await program.methods
-        .deposit(secondDeposit)
+        .deposit(secondDeposit, new anchor.BN(0), [])
         .accounts({
           vault: vault,
           //userPosition: userPosition,
@@ -329,12 +427,131 @@ describe("vault-core", () => {
       expect(positionAccount.shares.toNumber()).to.equal(1500 * 10 ** 9);
     });
 
+    it("Lets a keeper deposit through the user's token approval", async () => {
+      const amount = new anchor.BN(1000 * 10 ** 9);
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+
+      // Without an approval the keeper can't pull anything
+      try {
+        await program.methods
+          .depositWithDelegate(amount, new anchor.BN(0), [])
+          .accounts({
+            vault,
+            user: user1.publicKey,
+            payer: user2.publicKey,
+            userTokenAccount,
+            vaultTokenAccount,
+          })
+          .signers([user2])
+          .rpc();
+        expect.fail("Should have failed without an approval");
+      } catch (e: any) {
+        expect(e.toString()).to.include("DelegateNotApproved");
+      }
+
+      await approve(
+        connection,
+        user1,
+        userTokenAccount,
+        vaultAuthority,
+        user1,
+        BigInt(amount.toString())
+      );
+      // Only the keeper signs
+      await program.methods
+        .depositWithDelegate(amount, new anchor.BN(0), [])
+        .accounts({
+          vault,
+          user: user1.publicKey,
+          payer: user2.publicKey,
+          userTokenAccount,
+          vaultTokenAccount,
+        })
+        .signers([user2])
+        .rpc();
+
+      const position = await program.account.userPosition.fetch(userPosition);
+      expect(position.user.toString()).to.equal(user1.publicKey.toString());
+      expect(position.shares.toString()).to.equal(amount.toString());
+      const tokenAccount = await getAccount(connection, userTokenAccount);
+      expect(tokenAccount.delegatedAmount.toString()).to.equal("0");
+    });
+
+    it("Bounds a keeper deposit by min_shares_out", async () => {
+      const amount = new anchor.BN(1000 * 10 ** 9);
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      await approve(
+        connection,
+        user1,
+        userTokenAccount,
+        vaultAuthority,
+        user1,
+        BigInt(amount.toString())
+      );
+
+      try {
+        await program.methods
+          .depositWithDelegate(amount, amount.addn(1), [])
+          .accounts({
+            vault,
+            user: user1.publicKey,
+            payer: user2.publicKey,
+            userTokenAccount,
+            vaultTokenAccount,
+          })
+          .signers([user2])
+          .rpc();
+        expect.fail("Should have failed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+    });
+
+    it("Only pulls into the vault's own token account", async () => {
+      const amount = new anchor.BN(1000 * 10 ** 9);
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      await approve(
+        connection,
+        user1,
+        userTokenAccount,
+        vaultAuthority,
+        user1,
+        BigInt(amount.toString())
+      );
+      const keeperTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user2,
+          tokenMint1,
+          user2.publicKey
+        )
+      ).address;
+
+      try {
+        await program.methods
+          .depositWithDelegate(amount, new anchor.BN(0), [])
+          .accounts({
+            vault,
+            user: user1.publicKey,
+            payer: user2.publicKey,
+            userTokenAccount,
+            vaultTokenAccount: keeperTokenAccount,
+          })
+          .signers([user2])
+          .rpc();
+        expect.fail("Should have failed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("ConstraintTokenOwner");
+      }
+    });
+
     it("Handles tiny deposit (1 token) when vault is empty", async () => {
       const tinyDeposit = new anchor.BN(1);
       const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
 
       await program.methods
-        .deposit(tinyDeposit)
+        .deposit(tinyDeposit, new anchor.BN(0), [])
         .accounts({
           vault: vault,
           //userPosition: userPosition,
@@ -360,7 +577,7 @@ describe("vault-core", () => {
 
       // Large deposit first
       await program.methods
-        .deposit(largeDeposit)
+        .deposit(largeDeposit, new anchor.BN(0), [])
         .accounts({
           vault: vault,
           //userPosition: userPosition,
@@ -379,7 +596,7 @@ describe("vault-core", () => {
       // So it should succeed, not fail
       try {
         await program.methods
-          .deposit(tinyDeposit)
+          .deposit(tinyDeposit, new anchor.BN(0), [])
           .accounts({
             vault: vault,
             //userPosition: userPosition,
@@ -443,7 +660,7 @@ describe("vault-core", () => {
 
       // User1 deposits
       await program.methods
-        .deposit(user1Deposit)
+        .deposit(user1Deposit, new anchor.BN(0), [])
         .accounts({
           vault: vault,
           //userPosition: user1Position,
@@ -459,7 +676,7 @@ describe("vault-core", () => {
 
       // User2 deposits
       await program.methods
-        .deposit(user2Deposit)
+        .deposit(user2Deposit, new anchor.BN(0), [])
         .accounts({
           vault: vault,
           //userPosition: user2Position,
@@ -489,111 +706,425 @@ describe("vault-core", () => {
       expect(user2PositionAccount.shares.toNumber()).to.equal(
         user2Deposit.toNumber()
       );
+
+      // Positions are numbered in opening order and can be listed by index
+      const index1 = user1PositionAccount.index;
+      expect(user2PositionAccount.index.toNumber()).to.equal(index1.toNumber() + 1);
+      expect(vaultAccount.positionCount.toNumber()).to.equal(
+        user2PositionAccount.index.toNumber() + 1
+      );
+      for (const [position, index] of [
+        [user1Position, index1],
+        [user2Position, user2PositionAccount.index],
+      ] as [PublicKey, anchor.BN][]) {
+        const [positionIndex] = PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("position_index"),
+            vault.toBuffer(),
+            index.toArrayLike(Buffer, "le", 8),
+          ],
+          program.programId
+        );
+        await program.methods
+          .indexPosition(index)
+          .accountsPartial({
+            vault,
+            userPosition: position,
+            positionIndex,
+            payer: authority.publicKey,
+          })
+          .signers([authority])
+          .rpc();
+      }
+      const [secondIndex] = PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("position_index"),
+          vault.toBuffer(),
+          user2PositionAccount.index.toArrayLike(Buffer, "le", 8),
+        ],
+        program.programId
+      );
+      const entry = await program.account.positionIndex.fetch(secondIndex);
+      expect(entry.user.toString()).to.equal(user2.publicKey.toString());
     });
 
-    it("Fails with zero amount", async () => {
+    it("Donations raise the share price without minting shares", async () => {
+      const donate = (amount: number) =>
+        program.methods
+          .donate(new anchor.BN(amount))
+          .accounts({
+            vault,
+            donor: user1.publicKey,
+            donorTokenAccount: userTokenAccount,
+            vaultTokenAccount,
+          })
+          .signers([user1])
+          .rpc();
+
+      // Nothing to boost before the first deposit
       try {
+        await donate(500 * 10 ** 9);
+        expect.fail("Should have failed with no shares outstanding");
+      } catch (e) {
+        expect(e.toString()).to.include("NoSharesOutstanding");
+      }
+
+      for (const amount of [1000, 150]) {
         await program.methods
-          .deposit(new anchor.BN(0))
+          .deposit(new anchor.BN(amount * 10 ** 9), new anchor.BN(0), [])
           .accounts({
             vault: vault,
-            //userPosition: userPosition,
             user: user1.publicKey,
             userTokenAccount: userTokenAccount,
             vaultTokenAccount: vaultTokenAccount,
-            //vaultAuthority: vaultAuthority,
-            //tokenProgram: TOKEN_PROGRAM_ID,
-            //systemProgram: SystemProgram.programId,
           })
           .signers([user1])
           .rpc();
-        expect.fail("Should have failed with zero amount");
-      } catch (e) {
-        expect(e.toString()).to.include("InvalidAmount");
+        if (amount === 1000) {
+          await donate(500 * 10 ** 9);
+        }
       }
-    });
-  });
-
-  describe("withdraw", () => {
-    let vault: PublicKey;
-    let vaultAuthority: PublicKey;
-    let vaultTokenAccount: PublicKey;
-    let userTokenAccount: PublicKey;
-    let userPosition: PublicKey;
 
-    beforeEach(async () => {
-      // Initialize vault
-      const [vaultPDA] = await getVaultPDA(tokenMint1);
-      vault = vaultPDA;
-      const [vaultAuthorityPDA] = await getVaultAuthorityPDA(tokenMint1);
-      vaultAuthority = vaultAuthorityPDA;
-      const vaultTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
-        connection,
-        authority,
-        tokenMint1,
-        vaultAuthority,
-        true
+      // 1000 shares back 1500 tokens after the donation, so 150 tokens buy 100 shares
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.totalShares.toNumber()).to.equal(1100 * 10 ** 9);
+
+      // The canonical rate reflects the donation: 1.5 tokens per share
+      const { raw } = await program.methods
+        .getExchangeRate()
+        .accounts({ vault, vaultTokenAccount })
+        .simulate();
+      const prefix = `Program return: ${program.programId} `;
+      const returned = raw.find((line) => line.startsWith(prefix))!;
+      const rate = new anchor.BN(
+        Buffer.from(returned.slice(prefix.length), "base64"),
+        "le"
       );
-      vaultTokenAccount = vaultTokenAccountInfo.address;
+      expect(rate.toString()).to.equal("1500000000000");
+    });
 
-      const rewardVault = await getAssociatedTokenAddress(
-        tokenMint1,
-        vaultAuthority,
-        true
+    it("Slashes into the insurance fund up to the cap", async () => {
+      const slasher = Keypair.generate();
+      await connection.confirmTransaction(
+        await connection.requestAirdrop(slasher.publicKey, anchor.web3.LAMPORTS_PER_SOL)
+      );
+      const [insuranceFund] = PublicKey.findProgramAddressSync(
+        [Buffer.from("insurance"), vault.toBuffer()],
+        program.programId
       );
+      const slash = (bps: number) =>
+        program.methods
+          .slash(bps)
+          .accountsPartial({
+            vault,
+            slasher: slasher.publicKey,
+            vaultTokenAccount,
+            insuranceFund,
+            tokenMint: tokenMint1,
+          })
+          .signers([slasher])
+          .rpc();
 
       await program.methods
-        .initializeVault()
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
         .accounts({
-          authority: authority.publicKey,
-          tokenMint: tokenMint1,
-          rewardMint: tokenMint1,
-          //rewardVault: rewardVault,
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: userTokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
         })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .setRole({ slasher: {} }, slasher.publicKey)
+        .accountsPartial({ vault, authority: authority.publicKey })
         .signers([authority])
         .rpc();
 
-      // Create user token account and mint tokens
-      const userTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
-        connection,
-        user1,
-        tokenMint1,
-        user1.publicKey,
-        false
+      // Slashing is off until the admin sets a cap
+      try {
+        await slash(1000);
+        expect.fail("Should have failed - no slash cap");
+      } catch (e) {
+        expect(e.toString()).to.include("SlashExceedsCap");
+      }
+
+      await program.methods
+        .setMaxSlash(2000)
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      await slash(1000);
+
+      const fund = await getAccount(connection, insuranceFund);
+      expect(fund.amount.toString()).to.equal((100 * 10 ** 9).toString());
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.totalShares.toNumber()).to.equal(1000 * 10 ** 9);
+      expect(vaultAccount.lifetimeSlashed.toNumber()).to.equal(100 * 10 ** 9);
+    });
+
+    it("Rejects deposits from blocked addresses", async () => {
+      const deposit = () =>
+        program.methods
+          .deposit(new anchor.BN(10 * 10 ** 9), new anchor.BN(0), [])
+          .accounts({
+            vault: vault,
+            user: user1.publicKey,
+            userTokenAccount: userTokenAccount,
+            vaultTokenAccount: vaultTokenAccount,
+          })
+          .signers([user1])
+          .rpc();
+
+      await program.methods
+        .blockAddress(user1.publicKey)
+        .accounts({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      try {
+        await deposit();
+        expect.fail("Should have failed for a blocked address");
+      } catch (e) {
+        expect(e.toString()).to.include("AddressBlocked");
+      }
+
+      await program.methods
+        .unblockAddress(user1.publicKey)
+        .accounts({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      await deposit();
+    });
+
+    it("Checkpoints share balances for governance", async () => {
+      const [userPosition] = PublicKey.findProgramAddressSync(
+        [Buffer.from("position"), vault.toBuffer(), user1.publicKey.toBuffer()],
+        program.programId
       );
-      userTokenAccount = userTokenAccountInfo.address;
-      await mintTo(
-        connection,
-        authority,
-        tokenMint1,
-        userTokenAccount,
-        authority,
-        1000000 * 10 ** 9
+      const [userCheckpoints] = PublicKey.findProgramAddressSync(
+        [Buffer.from("checkpoints"), vault.toBuffer(), user1.publicKey.toBuffer()],
+        program.programId
       );
+      const latest = async () => {
+        const history = await program.account.userCheckpoints.fetch(userCheckpoints);
+        return history.checkpoints[history.checkpoints.length - 1];
+      };
 
-      // Make initial deposit
-      const [userPositionPDA] = await getUserPositionPDA(
-        vault,
-        user1.publicKey
-      );
-      userPosition = userPositionPDA;
       await program.methods
-        .deposit(new anchor.BN(10000 * 10 ** 9))
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
         .accounts({
           vault: vault,
-          //userPosition: userPosition,
           user: user1.publicKey,
           userTokenAccount: userTokenAccount,
           vaultTokenAccount: vaultTokenAccount,
-          //vaultAuthority: vaultAuthority,
-          //tokenProgram: TOKEN_PROGRAM_ID,
-          //systemProgram: SystemProgram.programId,
         })
         .signers([user1])
         .rpc();
-    });
+      const deposited = await latest();
+      const shares = (await program.account.userPosition.fetch(userPosition)).shares;
+      expect(deposited.shares.toString()).to.equal(shares.toString());
 
-    it("Withdraws tokens and burns shares (partial withdraw)", async () => {
+      // No manual checkpoint: the withdrawal itself records the lower balance
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+      await program.methods
+        .withdraw(shares.divn(2), new anchor.BN(0))
+        .accountsPartial({
+          vault,
+          user: user1.publicKey,
+          userTokenAccount,
+          vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      const withdrawn = await latest();
+      expect(withdrawn.timestamp.gt(deposited.timestamp)).to.be.true;
+      expect(withdrawn.shares.toString()).to.equal(
+        shares.sub(shares.divn(2)).toString()
+      );
+
+      // The explicit checkpoint still works and agrees with the recorded balance
+      await program.methods
+        .checkpoint()
+        .accounts({ vault, user: user1.publicKey, payer: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      expect((await latest()).shares.toString()).to.equal(
+        withdrawn.shares.toString()
+      );
+
+      // Once the position has a history, share changes can't leave it out
+      try {
+        await program.methods
+          .withdraw(new anchor.BN(1), new anchor.BN(0))
+          .accountsPartial({
+            vault,
+            user: user1.publicKey,
+            userTokenAccount,
+            vaultTokenAccount,
+            userCheckpoints: null,
+          })
+          .signers([user1])
+          .rpc();
+        expect.fail("Should have required the checkpoint history");
+      } catch (e) {
+        expect(e.toString()).to.include("CheckpointsRequired");
+      }
+    });
+
+    it("Fails with zero amount", async () => {
+      try {
+        await program.methods
+          .deposit(new anchor.BN(0), new anchor.BN(0), [])
+          .accounts({
+            vault: vault,
+            //userPosition: userPosition,
+            user: user1.publicKey,
+            userTokenAccount: userTokenAccount,
+            vaultTokenAccount: vaultTokenAccount,
+            //vaultAuthority: vaultAuthority,
+            //tokenProgram: TOKEN_PROGRAM_ID,
+            //systemProgram: SystemProgram.programId,
+          })
+          .signers([user1])
+          .rpc();
+        expect.fail("Should have failed with zero amount");
+      } catch (e) {
+        expect(e.toString()).to.include("InvalidAmount");
+      }
+    });
+
+    it("Rejects deposits minting fewer than min_shares_out", async () => {
+      try {
+        await program.methods
+          .deposit(new anchor.BN(1000), new anchor.BN(1_000_000_000), [])
+          .accounts({
+            vault: vault,
+            user: user1.publicKey,
+            userTokenAccount: userTokenAccount,
+            vaultTokenAccount: vaultTokenAccount,
+          })
+          .signers([user1])
+          .rpc();
+        expect.fail("Should have failed with slippage exceeded");
+      } catch (e) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+    });
+    it("Requires the configured oracle once a USD cap is set", async () => {
+      await program.methods
+        .setDepositCap(new anchor.BN(1_000_000 * 10 ** 6), tokenMint1, 60, 100)
+        .accounts({ vault, tokenMint: tokenMint1, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      for (const remaining of [[], [{ pubkey: tokenMint1, isSigner: false, isWritable: false }]]) {
+        try {
+          await program.methods
+            .deposit(new anchor.BN(100 * 10 ** 9), new anchor.BN(0), [])
+            .accounts({
+              vault: vault,
+              user: user1.publicKey,
+              userTokenAccount: userTokenAccount,
+              vaultTokenAccount: vaultTokenAccount,
+            })
+            .remainingAccounts(remaining)
+            .signers([user1])
+            .rpc();
+          expect.fail("Should have failed - no price feed");
+        } catch (e: any) {
+          expect(e.toString()).to.include("InvalidOracle");
+        }
+      }
+
+      await program.methods
+        .setDepositCap(new anchor.BN(0), PublicKey.default, 0, 0)
+        .accounts({ vault, tokenMint: tokenMint1, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+    });
+  });
+
+  describe("withdraw", () => {
+    let vault: PublicKey;
+    let vaultAuthority: PublicKey;
+    let vaultTokenAccount: PublicKey;
+    let userTokenAccount: PublicKey;
+    let userPosition: PublicKey;
+
+    beforeEach(async () => {
+      // Initialize vault
+      const [vaultPDA] = await getVaultPDA(tokenMint1);
+      vault = vaultPDA;
+      const [vaultAuthorityPDA] = await getVaultAuthorityPDA(tokenMint1);
+      vaultAuthority = vaultAuthorityPDA;
+      const vaultTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMint1,
+        vaultAuthority,
+        true
+      );
+      vaultTokenAccount = vaultTokenAccountInfo.address;
+
+      const rewardVault = await getAssociatedTokenAddress(
+        tokenMint1,
+        vaultAuthority,
+        true
+      );
+
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint: tokenMint1,
+          //rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      // Create user token account and mint tokens
+      const userTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        user1,
+        tokenMint1,
+        user1.publicKey,
+        false
+      );
+      userTokenAccount = userTokenAccountInfo.address;
+      await mintTo(
+        connection,
+        authority,
+        tokenMint1,
+        userTokenAccount,
+        authority,
+        1000000 * 10 ** 9
+      );
+
+      // Make initial deposit
+      const [userPositionPDA] = await getUserPositionPDA(
+        vault,
+        user1.publicKey
+      );
+      userPosition = userPositionPDA;
+      await program.methods
+        .deposit(new anchor.BN(10000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          //userPosition: userPosition,
+          user: user1.publicKey,
+          userTokenAccount: userTokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+          //vaultAuthority: vaultAuthority,
+          //tokenProgram: TOKEN_PROGRAM_ID,
+          //systemProgram: SystemProgram.programId,
+        })
+        .signers([user1])
+        .rpc();
+    });
+
+    it("Withdraws tokens and burns shares (partial withdraw)", async () => {
       const withdrawShares = new anchor.BN(5000 * 10 ** 9);
 
       const initialVaultBalance = (
@@ -604,7 +1135,7 @@ describe("vault-core", () => {
       ).amount;
 
       await program.methods
-        .withdraw(withdrawShares)
+        .withdraw(withdrawShares, new anchor.BN(0))
         .accountsPartial({
           vault,
           user: user1.publicKey,
@@ -637,6 +1168,13 @@ describe("vault-core", () => {
       expect(finalUserBalance.toString()).to.equal(
         (initialUserBalance + BigInt(5000 * 10 ** 9)).toString()
       );
+
+      // Lifetime counters track gross flows, not the current balance
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.lifetimeDeposited.toString()).to.equal(
+        initialVaultBalance.toString()
+      );
+      expect(vaultAccount.lifetimeWithdrawn.toNumber()).to.equal(5000 * 10 ** 9);
     });
 
     it("Withdraws all shares and closes account (full withdraw)", async () => {
@@ -644,7 +1182,7 @@ describe("vault-core", () => {
       const totalShares = vaultAccountBefore.totalShares;
 
       await program.methods
-        .withdraw(totalShares)
+        .withdraw(totalShares, new anchor.BN(0))
         .accountsPartial({
           vault,
           user: user1.publicKey,
@@ -658,6 +1196,13 @@ describe("vault-core", () => {
       const vaultAccount = await program.account.vault.fetch(vault);
       expect(vaultAccount.totalShares.toNumber()).to.equal(0);
 
+      // The emptied position stays open until explicitly closed
+      await program.methods
+        .closePosition()
+        .accounts({ vault, user: user1.publicKey })
+        .signers([user1])
+        .rpc();
+
       // Verify user position account is closed
       try {
         const [userPositionPDA] = await getUserPositionPDA(
@@ -677,13 +1222,44 @@ describe("vault-core", () => {
       }
     });
 
+    it("Emergency withdraws the full principal while paused", async () => {
+      await program.methods
+        .setPaused(true)
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      const balanceBefore = (await getAccount(connection, userTokenAccount)).amount;
+      await program.methods
+        .emergencyWithdraw()
+        .accountsPartial({
+          vault,
+          user: user1.publicKey,
+          userTokenAccount,
+          vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      const balanceAfter = (await getAccount(connection, userTokenAccount)).amount;
+      expect((balanceAfter - balanceBefore).toString()).to.equal(
+        BigInt(10000 * 10 ** 9).toString()
+      );
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.totalShares.toNumber()).to.equal(0);
+      const [userPositionPDA] = await getUserPositionPDA(vault, user1.publicKey);
+      const position = await program.account.userPosition.fetch(userPositionPDA);
+      expect(position.shares.toNumber()).to.equal(0);
+      expect(position.rewardsOwed.toString()).to.equal("0");
+    });
+
     it("Fails with insufficient shares", async () => {
       const vaultAccount = await program.account.vault.fetch(vault);
       const excessiveShares = vaultAccount.totalShares.add(new anchor.BN(1));
 
       try {
         await program.methods
-          .withdraw(excessiveShares)
+          .withdraw(excessiveShares, new anchor.BN(0))
           .accountsPartial({
             vault,
             user: user1.publicKey,
@@ -698,10 +1274,29 @@ describe("vault-core", () => {
       }
     });
 
+    it("Rejects withdrawals paying out less than min_tokens_out", async () => {
+      const shares = new anchor.BN(1000 * 10 ** 9);
+      try {
+        await program.methods
+          .withdraw(shares, shares.add(new anchor.BN(1)))
+          .accountsPartial({
+            vault,
+            user: user1.publicKey,
+            userTokenAccount,
+            vaultTokenAccount,
+          })
+          .signers([user1])
+          .rpc();
+        expect.fail("Should have failed with slippage exceeded");
+      } catch (e) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+    });
+
     it("Fails with zero shares", async () => {
       try {
         await program.methods
-          .withdraw(new anchor.BN(0))
+          .withdraw(new anchor.BN(0), new anchor.BN(0))
           .accountsPartial({
             vault,
             user: user1.publicKey,
@@ -715,23 +1310,61 @@ describe("vault-core", () => {
         expect(e.toString()).to.include("InvalidAmount");
       }
     });
-  });
 
-  describe("invariants", () => {
-    it("Maintains invariant: total_shares >= sum(user_shares) with multiple users", async () => {
-      // Initialize vault
-      const [vault] = await getVaultPDA(tokenMint1);
+    it("Can't pay out of another account approved to the vault authority", async () => {
+      // A victim's approval, e.g. left over for a keeper deposit
       const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
-      const vaultTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+      const victimTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user2,
+          tokenMint1,
+          user2.publicKey
+        )
+      ).address;
+      await mintTo(connection, authority, tokenMint1, victimTokenAccount, authority, 10 ** 12);
+      await approve(
         connection,
-        authority,
-        tokenMint1,
+        user2,
+        victimTokenAccount,
         vaultAuthority,
-        true
+        user2,
+        BigInt(10 ** 12)
       );
-      const vaultTokenAccount = vaultTokenAccountInfo.address;
+      const victimBalance = (await getAccount(connection, victimTokenAccount)).amount;
 
-      const rewardVault = await getAssociatedTokenAddress(
+      try {
+        await program.methods
+          .withdraw(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0))
+          .accountsPartial({
+            vault,
+            user: user1.publicKey,
+            userTokenAccount,
+            vaultTokenAccount: victimTokenAccount,
+          })
+          .signers([user1])
+          .rpc();
+        expect.fail("Should have failed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("ConstraintTokenOwner");
+      }
+      expect(
+        (await getAccount(connection, victimTokenAccount)).amount.toString()
+      ).to.equal(victimBalance.toString());
+    });
+  });
+
+  describe("close_vault", () => {
+    let vault: PublicKey;
+    let vaultTokenAccount: PublicKey;
+    let userTokenAccount: PublicKey;
+    let authorityTokenAccount: PublicKey;
+
+    beforeEach(async () => {
+      const [vaultPDA] = await getVaultPDA(tokenMint1);
+      vault = vaultPDA;
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      vaultTokenAccount = await getAssociatedTokenAddress(
         tokenMint1,
         vaultAuthority,
         true
@@ -743,486 +1376,755 @@ describe("vault-core", () => {
           authority: authority.publicKey,
           tokenMint: tokenMint1,
           rewardMint: tokenMint1,
-          //rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
         .signers([authority])
         .rpc();
 
-      // Create token accounts for both users
-      const user1TokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+      userTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user1,
+          tokenMint1,
+          user1.publicKey
+        )
+      ).address;
+      await mintTo(
         connection,
-        user1,
+        authority,
         tokenMint1,
-        user1.publicKey,
-        false
+        userTokenAccount,
+        authority,
+        1000000 * 10 ** 9
       );
-      const user1TokenAccount = user1TokenAccountInfo.address;
-      const user2TokenAccountInfo = await getOrCreateAssociatedTokenAccount(
-        connection,
-        user2,
-        tokenMint1,
-        user2.publicKey,
-        false
-      );
-      const user2TokenAccount = user2TokenAccountInfo.address;
-      await Promise.all([
-        mintTo(
-          connection,
-          authority,
-          tokenMint1,
-          user1TokenAccount,
-          authority,
-          1000000 * 10 ** 9
-        ),
-        mintTo(
+      authorityTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
           connection,
           authority,
           tokenMint1,
-          user2TokenAccount,
-          authority,
-          1000000 * 10 ** 9
-        ),
-      ]);
-
-      const [user1Position] = await getUserPositionPDA(vault, user1.publicKey);
-      const [user2Position] = await getUserPositionPDA(vault, user2.publicKey);
+          authority.publicKey
+        )
+      ).address;
 
-      // Multiple deposits and withdrawals
       await program.methods
-        .deposit(new anchor.BN(1000 * 10 ** 9))
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
         .accounts({
           vault: vault,
           user: user1.publicKey,
-          userTokenAccount: user1TokenAccount,
+          userTokenAccount: userTokenAccount,
           vaultTokenAccount: vaultTokenAccount,
         })
         .signers([user1])
         .rpc();
+    });
+
+    it("Fails while shares are outstanding", async () => {
+      try {
+        await program.methods
+          .closeVault()
+          .accountsPartial({
+            vault,
+            authority: authority.publicKey,
+            vaultTokenAccount,
+            authorityTokenAccount,
+            authorityRewardTokenAccount: authorityTokenAccount,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - vault not empty");
+      } catch (e: any) {
+        expect(e.toString()).to.include("VaultNotEmpty");
+      }
+    });
 
+    it("Fails while withdrawn positions are still owed rewards", async () => {
+      await mintTo(
+        connection,
+        authority,
+        tokenMint1,
+        authorityTokenAccount,
+        authority,
+        1000 * 10 ** 9
+      );
       await program.methods
-        .deposit(new anchor.BN(2000 * 10 ** 9))
+        .fundRewards(new anchor.BN(1000 * 10 ** 9), new anchor.BN(10 ** 9))
         .accounts({
-          vault: vault,
-          user: user2.publicKey,
-          userTokenAccount: user2TokenAccount,
-          vaultTokenAccount: vaultTokenAccount,
+          vault,
+          funder: authority.publicKey,
+          funderTokenAccount: authorityTokenAccount,
+          rewardVault: vaultTokenAccount,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
-        .signers([user2])
+        .signers([authority])
         .rpc();
+      await new Promise((resolve) => setTimeout(resolve, 2000));
 
-      // Verify invariant
+      // Withdrawing settles the position's rewards into `rewards_owed` unclaimed
       const vaultAccount = await program.account.vault.fetch(vault);
-      const user1PositionAccount = await program.account.userPosition.fetch(
-        user1Position
-      );
-      const user2PositionAccount = await program.account.userPosition.fetch(
-        user2Position
-      );
+      await program.methods
+        .withdraw(vaultAccount.totalShares, new anchor.BN(0))
+        .accountsPartial({
+          vault,
+          user: user1.publicKey,
+          userTokenAccount,
+          vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      expect(
+        (await program.account.vault.fetch(vault)).unpaidRewards.toNumber()
+      ).to.be.greaterThan(0);
 
-      const totalUserShares =
-        user1PositionAccount.shares.toNumber() +
-        user2PositionAccount.shares.toNumber();
-      expect(vaultAccount.totalShares.toNumber()).to.be.at.least(
-        totalUserShares
-      );
-      expect(vaultAccount.totalShares.toNumber()).to.equal(totalUserShares);
+      try {
+        await program.methods
+          .closeVault()
+          .accountsPartial({
+            vault,
+            authority: authority.publicKey,
+            vaultTokenAccount,
+            authorityTokenAccount,
+            authorityRewardTokenAccount: authorityTokenAccount,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - rewards still owed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("RewardsStillOwed");
+      }
     });
-  });
 
-  describe("rewards", () => {
-    let vault: PublicKey;
-    let vaultAuthority: PublicKey;
-    let vaultTokenAccount: PublicKey;
-    let rewardVault: PublicKey;
-    let rewardMint: PublicKey;
-    let user1TokenAccount: PublicKey;
-    let user2TokenAccount: PublicKey;
-    let user1RewardAccount: PublicKey;
+    it("Sweeps residual tokens and closes all vault accounts", async () => {
+      const vaultAccount = await program.account.vault.fetch(vault);
+      await program.methods
+        .withdraw(vaultAccount.totalShares, new anchor.BN(0))
+        .accountsPartial({
+          vault,
+          user: user1.publicKey,
+          userTokenAccount,
+          vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
 
-    beforeEach(async () => {
-      // Create reward mint (can be same as staked token)
-      rewardMint = await createMint(
+      // Leave some dust behind in the vault token account
+      await mintTo(
         connection,
         authority,
-        authority.publicKey,
-        null,
-        9
+        tokenMint1,
+        vaultTokenAccount,
+        authority,
+        42
       );
 
-      // Initialize vault
-      const [vaultPDA] = await getVaultPDA(tokenMint1);
-      vault = vaultPDA;
-      const [vaultAuthorityPDA] = await getVaultAuthorityPDA(tokenMint1);
-      vaultAuthority = vaultAuthorityPDA;
+      await program.methods
+        .closeVault()
+        .accountsPartial({
+          vault,
+          authority: authority.publicKey,
+          vaultTokenAccount,
+          authorityTokenAccount,
+          authorityRewardTokenAccount: authorityTokenAccount,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
 
-      const vaultTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
-        connection,
-        authority,
-        tokenMint1,
-        vaultAuthority,
-        true
+      expect(await connection.getAccountInfo(vault)).to.be.null;
+      expect(await connection.getAccountInfo(vaultTokenAccount)).to.be.null;
+      const [vaultEntry] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault_entry"), vault.toBuffer()],
+        program.programId
       );
-      vaultTokenAccount = vaultTokenAccountInfo.address;
+      expect(await connection.getAccountInfo(vaultEntry)).to.be.null;
+      const authorityBalance = (
+        await getAccount(connection, authorityTokenAccount)
+      ).amount;
+      expect(authorityBalance.toString()).to.equal("42");
+    });
+  });
 
-      const rewardVaultInfo = await getOrCreateAssociatedTokenAccount(
-        connection,
-        authority,
-        rewardMint,
-        vaultAuthority,
-        true
-      );
-      rewardVault = rewardVaultInfo.address;
+  describe("management_fee", () => {
+    let vault: PublicKey;
+    let feePosition: PublicKey;
+    const deposit = new anchor.BN(1000 * 10 ** 9);
+
+    beforeEach(async () => {
+      const [vaultPDA] = await getVaultPDA(tokenMint1);
+      vault = vaultPDA;
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      [feePosition] = await getUserPositionPDA(vault, authority.publicKey);
 
       await program.methods
         .initializeVault()
         .accounts({
           authority: authority.publicKey,
           tokenMint: tokenMint1,
-          rewardMint: rewardMint,
-          //rewardVault: rewardVault,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
         .signers([authority])
         .rpc();
 
-      // Create user token accounts and mint tokens
-      const user1TokenAccountInfo = await getOrCreateAssociatedTokenAccount(
-        connection,
-        user1,
-        tokenMint1,
-        user1.publicKey,
-        false
-      );
-      user1TokenAccount = user1TokenAccountInfo.address;
-
-      const user2TokenAccountInfo = await getOrCreateAssociatedTokenAccount(
-        connection,
-        user2,
-        tokenMint1,
-        user2.publicKey,
-        false
-      );
-      user2TokenAccount = user2TokenAccountInfo.address;
-
-      await Promise.all([
-        mintTo(
-          connection,
-          authority,
-          tokenMint1,
-          user1TokenAccount,
-          authority,
-          1000000 * 10 ** 9
-        ),
-        mintTo(
+      const userTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
           connection,
-          authority,
+          user1,
           tokenMint1,
-          user2TokenAccount,
-          authority,
-          1000000 * 10 ** 9
-        ),
-      ]);
-
-      // Create user reward token account
-      const user1RewardAccountInfo = await getOrCreateAssociatedTokenAccount(
-        connection,
-        user1,
-        rewardMint,
-        user1.publicKey,
-        false
-      );
-      user1RewardAccount = user1RewardAccountInfo.address;
-    });
-
-    it("Funds rewards and sets reward rate", async () => {
-      // Fund reward vault with tokens
-      const fundAmount = 1000000 * 10 ** 9; // 1M tokens
-      const rewardRate = 1000 * 10 ** 9; // 1000 tokens per second
-
-      // Create funder token account and mint tokens
-      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
-        connection,
-        authority,
-        rewardMint,
-        authority.publicKey,
-        false
-      );
+          user1.publicKey
+        )
+      ).address;
       await mintTo(
         connection,
         authority,
-        rewardMint,
-        funderRewardAccount.address,
+        tokenMint1,
+        userTokenAccount,
         authority,
-        fundAmount
+        1000000 * 10 ** 9
       );
 
       await program.methods
-        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
+        .deposit(deposit, new anchor.BN(0), [])
         .accounts({
           vault: vault,
-          funder: authority.publicKey,
-          funderTokenAccount: funderRewardAccount.address,
-          rewardVault: rewardVault,
+          user: user1.publicKey,
+          userTokenAccount: userTokenAccount,
+          vaultTokenAccount: await getAssociatedTokenAddress(
+            tokenMint1,
+            vaultAuthority,
+            true
+          ),
         })
+        .signers([user1])
+        .rpc();
+
+      await program.methods
+        .setManagementFee(500, authority.publicKey)
+        .accounts({ vault, authority: authority.publicKey })
         .signers([authority])
         .rpc();
+    });
 
-      const vaultAccount = await program.account.vault.fetch(vault);
-      expect(vaultAccount.rewardRate.toNumber()).to.equal(rewardRate);
+    it("Mints accrued fee shares to the fee recipient's position", async () => {
+      await new Promise((resolve) => setTimeout(resolve, 2000));
 
-      const rewardVaultBalance = await getAccount(connection, rewardVault);
-      expect(rewardVaultBalance.amount.toString()).to.equal(
-        fundAmount.toString()
+      await program.methods
+        .collectManagementFee()
+        .accountsPartial({ vault, feePosition, payer: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      const vaultAccount = await program.account.vault.fetch(vault);
+      const position = await program.account.userPosition.fetch(feePosition);
+      expect(position.user.toString()).to.equal(authority.publicKey.toString());
+      expect(position.shares.toNumber()).to.be.greaterThan(0);
+      expect(vaultAccount.totalShares.toString()).to.equal(
+        deposit.add(position.shares).toString()
       );
     });
 
-    it("Distributes rewards to multiple users proportionally", async () => {
-      // Fund rewards
-      const fundAmount = 1000000 * 10 ** 9;
-      const rewardRate = 100 * 10 ** 9; // 100 tokens per second
+    it("Requires collecting before the fee rate changes", async () => {
+      await new Promise((resolve) => setTimeout(resolve, 2000));
 
-      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
-        connection,
-        authority,
-        rewardMint,
-        authority.publicKey,
-        false
-      );
-      await mintTo(
-        connection,
-        authority,
-        rewardMint,
-        funderRewardAccount.address,
-        authority,
-        fundAmount
+      try {
+        await program.methods
+          .setManagementFee(1000, authority.publicKey)
+          .accounts({ vault, authority: authority.publicKey })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - fee not collected");
+      } catch (e: any) {
+        expect(e.toString()).to.include("ManagementFeeNotCollected");
+      }
+    });
+  });
+
+  describe("allowlist", () => {
+    let vault: PublicKey;
+    let vaultTokenAccount: PublicKey;
+
+    beforeEach(async () => {
+      const [vaultPDA] = await getVaultPDA(tokenMint1);
+      vault = vaultPDA;
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      vaultTokenAccount = await getAssociatedTokenAddress(
+        tokenMint1,
+        vaultAuthority,
+        true
       );
 
       await program.methods
-        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
+        .initializeVault()
         .accounts({
-          vault: vault,
-          funder: authority.publicKey,
-          funderTokenAccount: funderRewardAccount.address,
-          rewardVault: rewardVault,
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
         .signers([authority])
         .rpc();
 
-      // User1 deposits 1000 tokens
-      const deposit1 = new anchor.BN(1000 * 10 ** 9);
+      // Two-member tree: [user1, authority]
+      const root = node(
+        leaf(user1.publicKey.toBuffer()),
+        leaf(authority.publicKey.toBuffer())
+      );
       await program.methods
-        .deposit(deposit1)
-        .accounts({
-          vault: vault,
-          user: user1.publicKey,
-          userTokenAccount: user1TokenAccount,
-          vaultTokenAccount: vaultTokenAccount,
-        })
-        .signers([user1])
+        .setAllowlistRoot([...root])
+        .accounts({ vault, authority: authority.publicKey })
+        .signers([authority])
         .rpc();
+    });
 
-      // Wait a bit (simulate time passing)
-      await new Promise((resolve) => setTimeout(resolve, 2000));
+    async function fundedTokenAccount(owner: Keypair): Promise<PublicKey> {
+      const account = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          owner,
+          tokenMint1,
+          owner.publicKey
+        )
+      ).address;
+      await mintTo(connection, authority, tokenMint1, account, authority, 1000);
+      return account;
+    }
+
+    it("Accepts deposits with a valid proof", async () => {
+      const userTokenAccount = await fundedTokenAccount(user1);
 
-      // User2 deposits 2000 tokens
-      const deposit2 = new anchor.BN(2000 * 10 ** 9);
       await program.methods
-        .deposit(deposit2)
+        .deposit(new anchor.BN(1000), new anchor.BN(0), [
+          [...leaf(authority.publicKey.toBuffer())],
+        ])
         .accounts({
-          vault: vault,
-          user: user2.publicKey,
-          userTokenAccount: user2TokenAccount,
-          vaultTokenAccount: vaultTokenAccount,
+          vault,
+          user: user1.publicKey,
+          userTokenAccount,
+          vaultTokenAccount,
         })
-        .signers([user2])
+        .signers([user1])
         .rpc();
 
-      // Wait more time
-      await new Promise((resolve) => setTimeout(resolve, 2000));
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.totalShares.toNumber()).to.equal(1000);
+    });
 
-      const user2RewardAccount = await getOrCreateAssociatedTokenAccount(
-        connection,
-        user2,
-        rewardMint,
-        user2.publicKey,
-        false
-      );
+    it("Rejects depositors outside the tree", async () => {
+      const userTokenAccount = await fundedTokenAccount(user2);
+
+      try {
+        await program.methods
+          .deposit(new anchor.BN(1000), new anchor.BN(0), [
+          [...leaf(authority.publicKey.toBuffer())],
+        ])
+          .accounts({
+            vault,
+            user: user2.publicKey,
+            userTokenAccount,
+            vaultTokenAccount,
+          })
+          .signers([user2])
+          .rpc();
+        expect.fail("Should have failed - not allowlisted");
+      } catch (e: any) {
+        expect(e.toString()).to.include("NotAllowlisted");
+      }
+    });
+  });
+
+  describe("reward drops", () => {
+    let vault: PublicKey;
+    let rewardDrop: PublicKey;
+    let dropTokenAccount: PublicKey;
+    let user1RewardAccount: PublicKey;
+    let root: Buffer;
+    const u64 = (n: number) => new anchor.BN(n).toArrayLike(Buffer, "le", 8);
+    const user1Leaf = () => leaf(u64(0), user1.publicKey.toBuffer(), u64(100));
+    const user2Leaf = () => leaf(u64(1), user2.publicKey.toBuffer(), u64(200));
+
+    beforeEach(async () => {
+      [vault] = await getVaultPDA(tokenMint1);
 
-      // User1 claims rewards
       await program.methods
-        .claimRewards()
-        .accountsPartial({
-          vault: vault,
-          user: user1.publicKey,
-          userRewardTokenAccount: user1RewardAccount,
-          rewardVault: rewardVault,
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
-        .postInstructions([
-          await program.methods
-            .claimRewards()
-            .accountsPartial({
-              vault,
-              user: user2.publicKey,
-              userRewardTokenAccount: user2RewardAccount.address,
-              rewardVault,
-            })
-            .instruction(),
-        ])
-        .signers([user1, user2])
+        .signers([authority])
         .rpc();
 
-      // User1 should have received more rewards (was in longer and had earlier deposit)
-      const user1RewardBalance = await getAccount(
+      const authorityTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          authority,
+          tokenMint1,
+          authority.publicKey
+        )
+      ).address;
+      await mintTo(
         connection,
-        user1RewardAccount
+        authority,
+        tokenMint1,
+        authorityTokenAccount,
+        authority,
+        300
       );
+      user1RewardAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          user1,
+          tokenMint1,
+          user1.publicKey
+        )
+      ).address;
 
-      const user2RewardBalance = await getAccount(
-        connection,
-        user2RewardAccount.address
+      [rewardDrop] = PublicKey.findProgramAddressSync(
+        [Buffer.from("drop"), vault.toBuffer(), u64(0)],
+        program.programId
       );
-      expect(Number(user1RewardBalance.amount)).to.be.greaterThan(0);
-      expect(Number(user2RewardBalance.amount)).to.be.greaterThan(0);
-      expect(Number(user1RewardBalance.amount)).to.be.greaterThan(
-        Number(user2RewardBalance.amount)
+      dropTokenAccount = await getAssociatedTokenAddress(
+        tokenMint1,
+        rewardDrop,
+        true
       );
-    });
 
-    it("Handles zero reward rate correctly", async () => {
-      // Deposit without funding rewards (reward_rate = 0)
-      const deposit = new anchor.BN(1000 * 10 ** 9);
+      root = node(user1Leaf(), user2Leaf());
       await program.methods
-        .deposit(deposit)
-        .accounts({
-          vault: vault,
-          user: user1.publicKey,
-          userTokenAccount: user1TokenAccount,
-          vaultTokenAccount: vaultTokenAccount,
+        .createRewardDrop([...root], new anchor.BN(300))
+        .accountsPartial({
+          vault,
+          authority: authority.publicKey,
+          rewardDrop,
+          rewardMint: tokenMint1,
+          dropTokenAccount,
+          authorityTokenAccount,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
-        .signers([user1])
+        .signers([authority])
         .rpc();
+    });
 
-      // Wait some time
-      await new Promise((resolve) => setTimeout(resolve, 1000));
-
-      // Claim should succeed but yield zero rewards
+    async function claim(amount: number) {
       await program.methods
-        .claimRewards()
+        .claimDrop(new anchor.BN(0), new anchor.BN(amount), [
+          [...user2Leaf()],
+        ])
         .accountsPartial({
-          vault: vault,
+          rewardDrop,
           user: user1.publicKey,
+          dropTokenAccount,
           userRewardTokenAccount: user1RewardAccount,
-          rewardVault: rewardVault,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
         .signers([user1])
         .rpc();
+    }
 
-      const user1RewardBalance = await getAccount(
-        connection,
-        user1RewardAccount
-      );
-      expect(Number(user1RewardBalance.amount)).to.equal(0);
+    it("Pays out a leaf once and rejects a second claim", async () => {
+      await claim(100);
+
+      const balance = await getAccount(connection, user1RewardAccount);
+      expect(balance.amount.toString()).to.equal("100");
+      const drop = await program.account.rewardDrop.fetch(rewardDrop);
+      expect(drop.claimedAmount.toNumber()).to.equal(100);
+
+      try {
+        await claim(100);
+        expect.fail("Should have failed - already claimed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("DropAlreadyClaimed");
+      }
     });
 
-    it("Settles rewards on deposit and withdraw", async () => {
-      // Fund rewards
-      const fundAmount = 1000000 * 10 ** 9;
-      const rewardRate = 100 * 10 ** 9;
+    it("Rejects a claim for a different amount than the leaf", async () => {
+      try {
+        await claim(200);
+        expect.fail("Should have failed - wrong amount");
+      } catch (e: any) {
+        expect(e.toString()).to.include("InvalidDropProof");
+      }
+    });
+  });
 
-      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
-        connection,
-        authority,
-        rewardMint,
-        authority.publicKey,
-        false
+  describe("native SOL", () => {
+    it("Wraps SOL on deposit and unwraps it on withdraw", async () => {
+      // One native-mint vault per deployment, so create it only once
+      const [vault] = await getVaultPDA(NATIVE_MINT);
+      if (!(await connection.getAccountInfo(vault))) {
+        await program.methods
+          .initializeVault()
+          .accounts({
+            authority: authority.publicKey,
+            tokenMint: NATIVE_MINT,
+            rewardMint: NATIVE_MINT,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([authority])
+          .rpc();
+      }
+      const [vaultAuthority] = await getVaultAuthorityPDA(NATIVE_MINT);
+      const vaultTokenAccount = await getAssociatedTokenAddress(
+        NATIVE_MINT,
+        vaultAuthority,
+        true
       );
-      await mintTo(
-        connection,
-        authority,
-        rewardMint,
-        funderRewardAccount.address,
-        authority,
-        fundAmount
+      const userWsolAccount = await getAssociatedTokenAddress(
+        NATIVE_MINT,
+        user1.publicKey
       );
+      const amount = new anchor.BN(anchor.web3.LAMPORTS_PER_SOL);
+      const vaultBalanceBefore = (await getAccount(connection, vaultTokenAccount))
+        .amount;
 
       await program.methods
-        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
-        .accounts({
-          vault: vault,
-          funder: authority.publicKey,
-          funderTokenAccount: funderRewardAccount.address,
-          rewardVault: rewardVault,
+        .depositSol(amount, new anchor.BN(0), [])
+        .accountsPartial({
+          user: user1.publicKey,
+          userWsolAccount,
+          deposit: {
+            vault,
+            user: user1.publicKey,
+            userTokenAccount: userWsolAccount,
+            vaultTokenAccount,
+          },
         })
-        .signers([authority])
+        .signers([user1])
         .rpc();
 
-      // Initial deposit
+      const vaultBalanceAfter = (await getAccount(connection, vaultTokenAccount))
+        .amount;
+      expect((vaultBalanceAfter - vaultBalanceBefore).toString()).to.equal(
+        amount.toString()
+      );
+
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      const position = await program.account.userPosition.fetch(userPosition);
+
+      // The unwrapped amount is floored like a plain withdraw
+      try {
+        await program.methods
+          .withdrawSol(position.shares, new anchor.BN("18446744073709551615"))
+          .accountsPartial({
+            user: user1.publicKey,
+            userWsolAccount,
+            withdraw: {
+              vault,
+              user: user1.publicKey,
+              userTokenAccount: userWsolAccount,
+              vaultTokenAccount,
+            },
+          })
+          .signers([user1])
+          .rpc();
+        expect.fail("Should have failed with slippage exceeded");
+      } catch (e) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+
+      const lamportsBefore = await connection.getBalance(user1.publicKey);
+
       await program.methods
-        .deposit(new anchor.BN(1000 * 10 ** 9))
-        .accounts({
-          vault: vault,
+        .withdrawSol(position.shares, new anchor.BN(0))
+        .accountsPartial({
           user: user1.publicKey,
-          userTokenAccount: user1TokenAccount,
-          vaultTokenAccount: vaultTokenAccount,
+          userWsolAccount,
+          withdraw: {
+            vault,
+            user: user1.publicKey,
+            userTokenAccount: userWsolAccount,
+            vaultTokenAccount,
+          },
         })
         .signers([user1])
         .rpc();
 
-      // Wait for rewards to accrue
-      await new Promise((resolve) => setTimeout(resolve, 1000));
-
-      // Additional deposit should settle existing rewards
+      // WSOL account is closed and the SOL is back in the wallet
+      expect(await connection.getAccountInfo(userWsolAccount)).to.be.null;
+      const lamportsAfter = await connection.getBalance(user1.publicKey);
+      expect(lamportsAfter - lamportsBefore).to.be.greaterThan(
+        0.99 * anchor.web3.LAMPORTS_PER_SOL
+      );
+    });
+  });
+
+  describe("dual stake", () => {
+    it("Deposits and withdraws both legs at the configured ratio", async () => {
       await program.methods
-        .deposit(new anchor.BN(500 * 10 ** 9))
+        .initializeVault()
         .accounts({
-          vault: vault,
-          user: user1.publicKey,
-          userTokenAccount: user1TokenAccount,
-          vaultTokenAccount: vaultTokenAccount,
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
-        .signers([user1])
+        .signers([authority])
         .rpc();
+      const [vault] = await getVaultPDA(tokenMint1);
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      const vaultTokenAccount = await getAssociatedTokenAddress(
+        tokenMint1,
+        vaultAuthority,
+        true
+      );
+      const vaultSecondTokenAccount = await getAssociatedTokenAddress(
+        tokenMint2,
+        vaultAuthority,
+        true
+      );
 
-      // Withdraw should also settle rewards
-      await new Promise((resolve) => setTimeout(resolve, 1000));
-
+      // 1 unit of the second leg for every 2 of the first
       await program.methods
-        .withdraw(new anchor.BN(500 * 10 ** 9))
+        .configureDualStake(new anchor.BN(2), new anchor.BN(1))
         .accountsPartial({
           vault,
-          user: user1.publicKey,
-          userTokenAccount: user1TokenAccount,
-          vaultTokenAccount,
+          authority: authority.publicKey,
+          secondMint: tokenMint2,
+          vaultAuthority,
+        })
+        .signers([authority])
+        .rpc();
+
+      const userTokenAccount = await createAccount(
+        connection,
+        user1,
+        tokenMint1,
+        user1.publicKey
+      );
+      const userSecondTokenAccount = await createAccount(
+        connection,
+        user1,
+        tokenMint2,
+        user1.publicKey
+      );
+      await mintTo(connection, authority, tokenMint1, userTokenAccount, authority, 1000);
+      await mintTo(connection, authority, tokenMint2, userSecondTokenAccount, authority, 1000);
+
+      try {
+        await program.methods
+          .deposit(new anchor.BN(100), new anchor.BN(0), [])
+          .accountsPartial({
+            vault,
+            user: user1.publicKey,
+            userTokenAccount,
+            vaultTokenAccount,
+          })
+          .signers([user1])
+          .rpc();
+        expect.fail("Should have failed - single-leg deposit");
+      } catch (e: any) {
+        expect(e.toString()).to.include("DualStakeRequired");
+      }
+
+      await program.methods
+        .depositPair(new anchor.BN(101), new anchor.BN(0), [])
+        .accountsPartial({
+          userSecondTokenAccount,
+          vaultSecondTokenAccount,
+          deposit: {
+            vault,
+            user: user1.publicKey,
+            userTokenAccount,
+            vaultTokenAccount,
+          },
         })
         .signers([user1])
         .rpc();
 
-      // Claim remaining rewards
+      // Second leg rounds up in the vault's favour
+      expect((await getAccount(connection, vaultSecondTokenAccount)).amount.toString()).to.equal("51");
+
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      const position = await program.account.userPosition.fetch(userPosition);
+      try {
+        await program.methods
+          .withdrawPair(position.shares, new anchor.BN(102))
+          .accountsPartial({
+            userSecondTokenAccount,
+            vaultSecondTokenAccount,
+            withdraw: {
+              vault,
+              user: user1.publicKey,
+              userTokenAccount,
+              vaultTokenAccount,
+            },
+          })
+          .signers([user1])
+          .rpc();
+        expect.fail("Should have failed with slippage exceeded");
+      } catch (e: any) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+
       await program.methods
-        .claimRewards()
+        .withdrawPair(position.shares, new anchor.BN(101))
         .accountsPartial({
-          vault: vault,
-          user: user1.publicKey,
-          userRewardTokenAccount: user1RewardAccount,
-          rewardVault: rewardVault,
+          userSecondTokenAccount,
+          vaultSecondTokenAccount,
+          withdraw: {
+            vault,
+            user: user1.publicKey,
+            userTokenAccount,
+            vaultTokenAccount,
+          },
         })
         .signers([user1])
         .rpc();
 
-      const user1RewardBalance = await getAccount(connection, user1RewardAccount);
-      expect(Number(user1RewardBalance.amount)).to.be.greaterThan(0);
+      expect((await getAccount(connection, userTokenAccount)).amount.toString()).to.equal("1000");
+      expect((await getAccount(connection, userSecondTokenAccount)).amount.toString()).to.equal("1000");
     });
+  });
 
-    it("Handles full withdrawal with rewards", async () => {
-      // Fund rewards
-      const fundAmount = 1000000 * 10 ** 9;
-      const rewardRate = 100 * 10 ** 9;
+  describe("token-2022 rewards", () => {
+    it("Funds and pays out a Token-2022 reward mint", async () => {
+      const rewardMint = await createMint(
+        connection,
+        authority,
+        authority.publicKey,
+        null,
+        6,
+        undefined,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      );
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint,
+          rewardTokenProgram: TOKEN_2022_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+      const [vault] = await getVaultPDA(tokenMint1);
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      const vaultTokenAccount = await getAssociatedTokenAddress(
+        tokenMint1,
+        vaultAuthority,
+        true
+      );
+      const rewardVault = getAssociatedTokenAddressSync(
+        rewardMint,
+        vaultAuthority,
+        true,
+        TOKEN_2022_PROGRAM_ID
+      );
 
       const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
         connection,
         authority,
         rewardMint,
         authority.publicKey,
-        false
+        false,
+        undefined,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
       );
       await mintTo(
         connection,
@@ -1230,84 +2132,76 @@ describe("vault-core", () => {
         rewardMint,
         funderRewardAccount.address,
         authority,
-        fundAmount
+        1_000_000,
+        [],
+        undefined,
+        TOKEN_2022_PROGRAM_ID
       );
-
       await program.methods
-        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
+        .fundRewards(new anchor.BN(1_000_000), new anchor.BN(1000))
         .accounts({
-          vault: vault,
+          vault,
           funder: authority.publicKey,
           funderTokenAccount: funderRewardAccount.address,
-          rewardVault: rewardVault,
+          rewardVault,
+          rewardTokenProgram: TOKEN_2022_PROGRAM_ID,
         })
         .signers([authority])
         .rpc();
 
-      // Deposit
-      const deposit = new anchor.BN(1000 * 10 ** 9);
+      const userTokenAccount = await createAccount(
+        connection,
+        user1,
+        tokenMint1,
+        user1.publicKey
+      );
+      await mintTo(connection, authority, tokenMint1, userTokenAccount, authority, 1000);
       await program.methods
-        .deposit(deposit)
+        .deposit(new anchor.BN(1000), new anchor.BN(0), [])
         .accounts({
-          vault: vault,
-          user: user1.publicKey,
-          userTokenAccount: user1TokenAccount,
-          vaultTokenAccount: vaultTokenAccount,
-        })
-        .signers([user1])
-        .rpc();
-
-      // Wait for rewards
-      await new Promise((resolve) => setTimeout(resolve, 1000));
-
-      // Full withdrawal should settle rewards before closing
-      const vaultAccount = await program.account.vault.fetch(vault);
-      await program.methods
-        .withdraw(vaultAccount.totalShares)
-        .accountsPartial({
           vault,
           user: user1.publicKey,
-          userTokenAccount: user1TokenAccount,
+          userTokenAccount,
           vaultTokenAccount,
         })
         .signers([user1])
         .rpc();
+      await new Promise((resolve) => setTimeout(resolve, 2000));
 
-      // Claim rewards
+      // The user's Token-2022 reward ATA is created by the claim itself
+      const userRewardTokenAccount = getAssociatedTokenAddressSync(
+        rewardMint,
+        user1.publicKey,
+        false,
+        TOKEN_2022_PROGRAM_ID
+      );
       await program.methods
         .claimRewards()
         .accountsPartial({
-          vault: vault,
+          vault,
           user: user1.publicKey,
-          userRewardTokenAccount: user1RewardAccount,
-          rewardVault: rewardVault,
+          userRewardTokenAccount,
+          rewardVault,
+          rewardTokenProgram: TOKEN_2022_PROGRAM_ID,
         })
         .signers([user1])
         .rpc();
 
-      const user1RewardBalance = await getAccount(
+      const claimed = await getAccount(
         connection,
-        user1RewardAccount
+        userRewardTokenAccount,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
       );
-      expect(Number(user1RewardBalance.amount)).to.be.greaterThan(0);
+      expect(Number(claimed.amount)).to.be.greaterThan(0);
     });
   });
 
-  describe("flash_loan", () => {
-    let vault: PublicKey;
-    let vaultAuthority: PublicKey;
-    let vaultTokenAccount: PublicKey;
-    let feeTreasury: Keypair;
-    let feeTreasuryTokenAccount: PublicKey;
-    let borrower: Keypair;
-    let borrowerTokenAccount: PublicKey;
-
-    beforeEach(async () => {
+  describe("invariants", () => {
+    it("Maintains invariant: total_shares >= sum(user_shares) with multiple users", async () => {
       // Initialize vault
-      const [vaultPDA] = await getVaultPDA(tokenMint1);
-      vault = vaultPDA;
-      const [vaultAuthorityPDA] = await getVaultAuthorityPDA(tokenMint1);
-      vaultAuthority = vaultAuthorityPDA;
+      const [vault] = await getVaultPDA(tokenMint1);
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
       const vaultTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
         connection,
         authority,
@@ -1315,7 +2209,7 @@ describe("vault-core", () => {
         vaultAuthority,
         true
       );
-      vaultTokenAccount = vaultTokenAccountInfo.address;
+      const vaultTokenAccount = vaultTokenAccountInfo.address;
 
       const rewardVault = await getAssociatedTokenAddress(
         tokenMint1,
@@ -1329,420 +2223,3110 @@ describe("vault-core", () => {
           authority: authority.publicKey,
           tokenMint: tokenMint1,
           rewardMint: tokenMint1,
+          //rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
         })
         .signers([authority])
         .rpc();
 
-      // Create fee treasury
-      feeTreasury = Keypair.generate();
-      await connection.requestAirdrop(
-        feeTreasury.publicKey,
-        2 * anchor.web3.LAMPORTS_PER_SOL
-      );
-      await new Promise((resolve) => setTimeout(resolve, 1000));
-
-      feeTreasuryTokenAccount = await getAssociatedTokenAddress(
+      // Create token accounts for both users
+      const user1TokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        user1,
         tokenMint1,
-        feeTreasury.publicKey,
+        user1.publicKey,
         false
       );
-      await getOrCreateAssociatedTokenAccount(
+      const user1TokenAccount = user1TokenAccountInfo.address;
+      const user2TokenAccountInfo = await getOrCreateAssociatedTokenAccount(
         connection,
-        feeTreasury,
+        user2,
         tokenMint1,
-        feeTreasury.publicKey,
+        user2.publicKey,
         false
       );
+      const user2TokenAccount = user2TokenAccountInfo.address;
+      await Promise.all([
+        mintTo(
+          connection,
+          authority,
+          tokenMint1,
+          user1TokenAccount,
+          authority,
+          1000000 * 10 ** 9
+        ),
+        mintTo(
+          connection,
+          authority,
+          tokenMint1,
+          user2TokenAccount,
+          authority,
+          1000000 * 10 ** 9
+        ),
+      ]);
 
-      // Create borrower
-      borrower = Keypair.generate();
-      await connection.requestAirdrop(
-        borrower.publicKey,
-        2 * anchor.web3.LAMPORTS_PER_SOL
-      );
-      await new Promise((resolve) => setTimeout(resolve, 1000));
+      const [user1Position] = await getUserPositionPDA(vault, user1.publicKey);
+      const [user2Position] = await getUserPositionPDA(vault, user2.publicKey);
 
-      borrowerTokenAccount = await getAssociatedTokenAddress(
-        tokenMint1,
-        borrower.publicKey,
-        false
-      );
-      await getOrCreateAssociatedTokenAccount(
-        connection,
-        borrower,
-        tokenMint1,
-        borrower.publicKey,
-        false
-      );
+      // Multiple deposits and withdrawals
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
 
-      // Deposit tokens into vault
+      await program.methods
+        .deposit(new anchor.BN(2000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user2.publicKey,
+          userTokenAccount: user2TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user2])
+        .rpc();
+
+      // Verify invariant
+      const vaultAccount = await program.account.vault.fetch(vault);
+      const user1PositionAccount = await program.account.userPosition.fetch(
+        user1Position
+      );
+      const user2PositionAccount = await program.account.userPosition.fetch(
+        user2Position
+      );
+
+      const totalUserShares =
+        user1PositionAccount.shares.toNumber() +
+        user2PositionAccount.shares.toNumber();
+      expect(vaultAccount.totalShares.toNumber()).to.be.at.least(
+        totalUserShares
+      );
+      expect(vaultAccount.totalShares.toNumber()).to.equal(totalUserShares);
+
+      // The on-chain crank agrees, including the per-position checks
+      await program.methods
+        .assertInvariants()
+        .accounts({ vault, vaultTokenAccount, rewardVault })
+        .remainingAccounts(
+          [user1Position, user2Position].map((pubkey) => ({
+            pubkey,
+            isWritable: false,
+            isSigner: false,
+          }))
+        )
+        .rpc();
+    });
+
+    it("Holds when emissions outrun funding", async () => {
+      const rewardMint = await createMint(connection, authority, authority.publicKey, null, 9);
+      const [vault] = await getVaultPDA(tokenMint1);
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      const vaultTokenAccount = await getAssociatedTokenAddress(tokenMint1, vaultAuthority, true);
+      const rewardVault = await getAssociatedTokenAddress(rewardMint, vaultAuthority, true);
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const funderRewardAccount = (
+        await getOrCreateAssociatedTokenAccount(connection, authority, rewardMint, authority.publicKey)
+      ).address;
+      await mintTo(connection, authority, rewardMint, funderRewardAccount, authority, 10 ** 9);
+      await program.methods
+        .fundRewards(new anchor.BN(10 ** 9), new anchor.BN(100 * 10 ** 9))
+        .accounts({
+          vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount,
+          rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const userTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(connection, user1, tokenMint1, user1.publicKey)
+      ).address;
+      await mintTo(connection, authority, tokenMint1, userTokenAccount, authority, 2000 * 10 ** 9);
+      const deposit = () =>
+        program.methods
+          .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+          .accounts({ vault, user: user1.publicKey, userTokenAccount, vaultTokenAccount })
+          .signers([user1])
+          .rpc();
+      await deposit();
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+      // Accrues well past the single funded token
+      await deposit();
+
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.rewardsAccrued.gt(vaultAccount.rewardsFunded)).to.be.true;
+      await program.methods
+        .assertInvariants()
+        .accounts({ vault, vaultTokenAccount, rewardVault })
+        .rpc();
+    });
+
+    it("Fails when the vault token account no longer holds the tracked balance", async () => {
+      // Single-mint vault: rewards sit in the vault token account next to the stake
+      const [vault] = await getVaultPDA(tokenMint1);
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      const vaultTokenAccount = await getAssociatedTokenAddress(tokenMint1, vaultAuthority, true);
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const userTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(connection, user1, tokenMint1, user1.publicKey)
+      ).address;
+      await mintTo(connection, authority, tokenMint1, userTokenAccount, authority, 2000 * 10 ** 9);
+      await program.methods
+        .deposit(new anchor.BN(100 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({ vault, user: user1.publicKey, userTokenAccount, vaultTokenAccount })
+        .signers([user1])
+        .rpc();
+      // Anyone may top up the reward budget
+      await program.methods
+        .fundRewards(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0))
+        .accounts({
+          vault,
+          funder: user1.publicKey,
+          funderTokenAccount: userTokenAccount,
+          rewardVault: vaultTokenAccount,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+
+      await program.methods
+        .assertInvariants()
+        .accounts({ vault, vaultTokenAccount, rewardVault: vaultTokenAccount })
+        .rpc();
+
+      // Share pricing counts the reward tokens as stake, so redeeming every share
+      // walks off with them and leaves the reward budget unbacked
+      const vaultAccount = await program.account.vault.fetch(vault);
+      await program.methods
+        .withdraw(vaultAccount.totalShares, new anchor.BN(0))
+        .accountsPartial({ vault, user: user1.publicKey, userTokenAccount, vaultTokenAccount })
+        .signers([user1])
+        .rpc();
+
+      try {
+        await program.methods
+          .assertInvariants()
+          .accounts({ vault, vaultTokenAccount, rewardVault: vaultTokenAccount })
+          .rpc();
+        expect.fail("Should have failed - tracked balance unbacked");
+      } catch (e: any) {
+        expect(e.toString()).to.include("InvariantViolated");
+        expect((e.logs ?? []).join("\n")).to.include("tracked balance exceeds vault token account");
+      }
+    });
+  });
+
+  describe("rewards", () => {
+    let vault: PublicKey;
+    let vaultAuthority: PublicKey;
+    let vaultTokenAccount: PublicKey;
+    let rewardVault: PublicKey;
+    let rewardMint: PublicKey;
+    let user1TokenAccount: PublicKey;
+    let user2TokenAccount: PublicKey;
+    let user1RewardAccount: PublicKey;
+
+    beforeEach(async () => {
+      // Create reward mint (can be same as staked token)
+      rewardMint = await createMint(
+        connection,
+        authority,
+        authority.publicKey,
+        null,
+        9
+      );
+
+      // Initialize vault
+      const [vaultPDA] = await getVaultPDA(tokenMint1);
+      vault = vaultPDA;
+      const [vaultAuthorityPDA] = await getVaultAuthorityPDA(tokenMint1);
+      vaultAuthority = vaultAuthorityPDA;
+
+      const vaultTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMint1,
+        vaultAuthority,
+        true
+      );
+      vaultTokenAccount = vaultTokenAccountInfo.address;
+
+      const rewardVaultInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        vaultAuthority,
+        true
+      );
+      rewardVault = rewardVaultInfo.address;
+
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint: rewardMint,
+          //rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      // Create user token accounts and mint tokens
+      const user1TokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        user1,
+        tokenMint1,
+        user1.publicKey,
+        false
+      );
+      user1TokenAccount = user1TokenAccountInfo.address;
+
+      const user2TokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        user2,
+        tokenMint1,
+        user2.publicKey,
+        false
+      );
+      user2TokenAccount = user2TokenAccountInfo.address;
+
+      await Promise.all([
+        mintTo(
+          connection,
+          authority,
+          tokenMint1,
+          user1TokenAccount,
+          authority,
+          1000000 * 10 ** 9
+        ),
+        mintTo(
+          connection,
+          authority,
+          tokenMint1,
+          user2TokenAccount,
+          authority,
+          1000000 * 10 ** 9
+        ),
+      ]);
+
+      // Create user reward token account
+      const user1RewardAccountInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        user1,
+        rewardMint,
+        user1.publicKey,
+        false
+      );
+      user1RewardAccount = user1RewardAccountInfo.address;
+    });
+
+    it("Funds rewards and sets reward rate", async () => {
+      // Fund reward vault with tokens
+      const fundAmount = 1000000 * 10 ** 9; // 1M tokens
+      const rewardRate = 1000 * 10 ** 9; // 1000 tokens per second
+
+      // Create funder token account and mint tokens
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        fundAmount
+      );
+
+      await program.methods
+        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.rewardRate.toNumber()).to.equal(rewardRate);
+
+      const rewardVaultBalance = await getAccount(connection, rewardVault);
+      expect(rewardVaultBalance.amount.toString()).to.equal(
+        fundAmount.toString()
+      );
+    });
+
+    it("Distributes rewards to multiple users proportionally", async () => {
+      // Fund rewards
+      const fundAmount = 1000000 * 10 ** 9;
+      const rewardRate = 100 * 10 ** 9; // 100 tokens per second
+
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        fundAmount
+      );
+
+      await program.methods
+        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      // User1 deposits 1000 tokens
+      const deposit1 = new anchor.BN(1000 * 10 ** 9);
+      await program.methods
+        .deposit(deposit1, new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      // Wait a bit (simulate time passing)
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      // User2 deposits 2000 tokens
+      const deposit2 = new anchor.BN(2000 * 10 ** 9);
+      await program.methods
+        .deposit(deposit2, new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user2.publicKey,
+          userTokenAccount: user2TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user2])
+        .rpc();
+
+      // Wait more time
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      const user2RewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        user2,
+        rewardMint,
+        user2.publicKey,
+        false
+      );
+
+      // User1 claims rewards
+      await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault: vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .postInstructions([
+          await program.methods
+            .claimRewards()
+            .accountsPartial({
+              vault,
+              user: user2.publicKey,
+              userRewardTokenAccount: user2RewardAccount.address,
+              rewardVault,
+              rewardTokenProgram: TOKEN_PROGRAM_ID,
+            })
+            .instruction(),
+        ])
+        .signers([user1, user2])
+        .rpc();
+
+      // User1 should have received more rewards (was in longer and had earlier deposit)
+      const user1RewardBalance = await getAccount(
+        connection,
+        user1RewardAccount
+      );
+
+      const user2RewardBalance = await getAccount(
+        connection,
+        user2RewardAccount.address
+      );
+      expect(Number(user1RewardBalance.amount)).to.be.greaterThan(0);
+      expect(Number(user2RewardBalance.amount)).to.be.greaterThan(0);
+      expect(Number(user1RewardBalance.amount)).to.be.greaterThan(
+        Number(user2RewardBalance.amount)
+      );
+    });
+
+    it("Handles zero reward rate correctly", async () => {
+      // Deposit without funding rewards (reward_rate = 0)
+      const deposit = new anchor.BN(1000 * 10 ** 9);
+      await program.methods
+        .deposit(deposit, new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      // Wait some time
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      // Claim should succeed but yield zero rewards
+      await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault: vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+
+      const user1RewardBalance = await getAccount(
+        connection,
+        user1RewardAccount
+      );
+      expect(Number(user1RewardBalance.amount)).to.equal(0);
+    });
+
+    it("Creates the reward ATA on a first claim", async () => {
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000 * 10 ** 9
+      );
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user2.publicKey,
+          userTokenAccount: user2TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user2])
+        .rpc();
+      await program.methods
+        .fundRewards(new anchor.BN(1000000 * 10 ** 9), new anchor.BN(100 * 10 ** 9))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      const user2RewardAta = await getAssociatedTokenAddress(
+        rewardMint,
+        user2.publicKey
+      );
+      expect(await connection.getAccountInfo(user2RewardAta)).to.be.null;
+
+      await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault: vault,
+          user: user2.publicKey,
+          userRewardTokenAccount: user2RewardAta,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user2])
+        .rpc();
+
+      const balance = (await getAccount(connection, user2RewardAta)).amount;
+      expect(balance > BigInt(0)).to.be.true;
+    });
+
+    it("Claims rewards to a recipient account chosen by the owner", async () => {
+      const treasury = Keypair.generate();
+      const treasuryRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        treasury.publicKey,
+        false
+      );
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000 * 10 ** 9
+      );
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .fundRewards(new anchor.BN(1000000 * 10 ** 9), new anchor.BN(100 * 10 ** 9))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      const userBefore = (await getAccount(connection, user1RewardAccount)).amount;
+      await program.methods
+        .claimRewardsTo()
+        .accountsPartial({
+          vault: vault,
+          user: user1.publicKey,
+          recipientTokenAccount: treasuryRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+
+      const treasuryBalance = (
+        await getAccount(connection, treasuryRewardAccount.address)
+      ).amount;
+      expect(treasuryBalance > BigInt(0)).to.be.true;
+      expect(
+        (await getAccount(connection, user1RewardAccount)).amount.toString()
+      ).to.equal(userBefore.toString());
+    });
+
+    it("Carries sub-token reward dust across claims", async () => {
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000
+      );
+      // Odd share count so acc_reward_per_share never divides evenly
+      await program.methods
+        .deposit(new anchor.BN(3 * 10 ** 9 + 1), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .fundRewards(new anchor.BN(1000000), new anchor.BN(7))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault: vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      const position = await program.account.userPosition.fetch(userPosition);
+      const owedScaled = position.rewardsOwed;
+      // The unpaid remainder stays owed to the position rather than being burned
+      expect(owedScaled.gtn(0)).to.be.true;
+      expect(owedScaled.lt(new anchor.BN(10).pow(new anchor.BN(12)))).to.be.true;
+    });
+
+    it("Fresh stakes forfeit rewards under a zero loyalty floor", async () => {
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000
+      );
+      // Nothing is paid until a stake is a year old
+      await program.methods
+        .setLoyaltyBoost(0, 365 * 24 * 60 * 60)
+        .accounts({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .fundRewards(new anchor.BN(1000000), new anchor.BN(100))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault: vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      const position = await program.account.userPosition.fetch(userPosition);
+      expect(position.stakeWeightedTs.toNumber()).to.be.greaterThan(0);
+      expect(
+        Number((await getAccount(connection, user1RewardAccount)).amount)
+      ).to.equal(0);
+      // The forfeited rewards went back to the unallocated budget
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.rewardsAccrued.toNumber()).to.be.lessThan(100);
+    });
+
+    it("Halves the reward rate on schedule", async () => {
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000
+      );
+      await program.methods
+        .setRewardHalving(1)
+        .accounts({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .fundRewards(new anchor.BN(1000000), new anchor.BN(1024))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+      await program.methods.updateRewards().accounts({ vault }).rpc();
+
+      const vaultAccount = await program.account.vault.fetch(vault);
+      // At least two halvings have passed; each second accrued at the rate in force
+      expect(vaultAccount.rewardRate.toNumber()).to.be.at.most(256);
+      expect(vaultAccount.rewardsAccrued.toNumber()).to.be.lessThan(1024 * 3);
+      expect(vaultAccount.nextHalvingTs.toNumber()).to.be.greaterThan(
+        Math.floor(Date.now() / 1000) - 1
+      );
+    });
+
+    it("Claims rewards straight into the reward mint's vault", async () => {
+      // The target vault stakes this vault's reward mint
+      const [targetVault] = await getVaultPDA(rewardMint);
+      const [targetAuthority] = await getVaultAuthorityPDA(rewardMint);
+      const targetTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          authority,
+          rewardMint,
+          targetAuthority,
+          true
+        )
+      ).address;
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: rewardMint,
+          rewardMint: rewardMint,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000 * 10 ** 9
+      );
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .fundRewards(new anchor.BN(1000000 * 10 ** 9), new anchor.BN(10 ** 9))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      const claimAndStake = (minSharesOut: anchor.BN) =>
+        program.methods
+          .claimAndStake(minSharesOut, [])
+          .accountsPartial({
+            vault,
+            userPosition,
+            rewardVault,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
+            target: {
+              vault: targetVault,
+              user: user1.publicKey,
+              userTokenAccount: user1RewardAccount,
+              vaultTokenAccount: targetTokenAccount,
+            },
+          })
+          .signers([user1])
+          .rpc();
+
+      // The redeposit honours the caller's share floor
+      try {
+        await claimAndStake(new anchor.BN("18446744073709551615"));
+        expect.fail("Should have failed with slippage exceeded");
+      } catch (e) {
+        expect(e.toString()).to.include("SlippageExceeded");
+      }
+
+      await claimAndStake(new anchor.BN(1));
+
+      // Nothing left in the user's wallet; the claim became target-vault shares
+      expect(Number((await getAccount(connection, user1RewardAccount)).amount)).to.equal(0);
+      const [targetPosition] = await getUserPositionPDA(targetVault, user1.publicKey);
+      const staked = await program.account.userPosition.fetch(targetPosition);
+      expect(staked.shares.toNumber()).to.be.greaterThan(0);
+      const target = await program.account.vault.fetch(targetVault);
+      expect(target.totalShares.toString()).to.equal(staked.shares.toString());
+    });
+
+    it("Settles rewards on deposit and withdraw", async () => {
+      // Fund rewards
+      const fundAmount = 1000000 * 10 ** 9;
+      const rewardRate = 100 * 10 ** 9;
+
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        fundAmount
+      );
+
+      await program.methods
+        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      // Initial deposit
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      // Wait for rewards to accrue
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      // Additional deposit should settle existing rewards
+      await program.methods
+        .deposit(new anchor.BN(500 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      // Withdraw should also settle rewards
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      await program.methods
+        .withdraw(new anchor.BN(500 * 10 ** 9), new anchor.BN(0))
+        .accountsPartial({
+          vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      // Claim remaining rewards
+      await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault: vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+
+      const user1RewardBalance = await getAccount(connection, user1RewardAccount);
+      expect(Number(user1RewardBalance.amount)).to.be.greaterThan(0);
+    });
+
+    it("Handles full withdrawal with rewards", async () => {
+      // Fund rewards
+      const fundAmount = 1000000 * 10 ** 9;
+      const rewardRate = 100 * 10 ** 9;
+
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        fundAmount
+      );
+
+      await program.methods
+        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      // Deposit
+      const deposit = new anchor.BN(1000 * 10 ** 9);
+      await program.methods
+        .deposit(deposit, new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      // Wait for rewards
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      // Full withdrawal should settle rewards before closing
+      const vaultAccount = await program.account.vault.fetch(vault);
+      await program.methods
+        .withdraw(vaultAccount.totalShares, new anchor.BN(0))
+        .accountsPartial({
+          vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      // Claim rewards
+      await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault: vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+
+      const user1RewardBalance = await getAccount(
+        connection,
+        user1RewardAccount
+      );
+      expect(Number(user1RewardBalance.amount)).to.be.greaterThan(0);
+    });
+
+    it("Closed positions cannot be revived in the same transaction to double-claim", async () => {
+      const fundAmount = 1000000 * 10 ** 9;
+      const rewardRate = 100 * 10 ** 9;
+
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        fundAmount
+      );
+
+      await program.methods
+        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const deposit = new anchor.BN(1000 * 10 ** 9);
+      await program.methods
+        .deposit(deposit, new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      const claimIx = await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault: vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .instruction();
+
+      // withdraw all → claim → close → refund rent to the PDA → claim again
+      const rent = await connection.getMinimumBalanceForRentExemption(
+        program.account.userPosition.size
+      );
+      const tx = new anchor.web3.Transaction().add(
+        await program.methods
+          .withdraw(deposit, new anchor.BN(0))
+          .accountsPartial({
+            vault,
+            user: user1.publicKey,
+            userTokenAccount: user1TokenAccount,
+            vaultTokenAccount,
+          })
+          .instruction(),
+        claimIx,
+        await program.methods
+          .closePosition()
+          .accounts({ vault, user: user1.publicKey })
+          .instruction(),
+        SystemProgram.transfer({
+          fromPubkey: user1.publicKey,
+          toPubkey: userPosition,
+          lamports: rent,
+        }),
+        claimIx
+      );
+
+      try {
+        await provider.sendAndConfirm(tx, [user1]);
+        expect.fail("Should have failed - position was closed");
+      } catch (e: any) {
+        // The closed PDA is system-owned again, so the second claim can't load it
+        const logs = (e.logs ?? []).join("\n");
+        expect(e.toString() + logs).to.match(/AccountOwnedByWrongProgram|0xbbf/);
+      }
+
+      // Nothing from the failed transaction landed
+      const user1RewardBalance = await getAccount(connection, user1RewardAccount);
+      expect(Number(user1RewardBalance.amount)).to.equal(0);
+      const position = await program.account.userPosition.fetch(userPosition);
+      expect(position.shares.toString()).to.equal(deposit.toString());
+    });
+
+    it("Reclaims unallocated rewards while keeping accrued rewards claimable", async () => {
+      const fundAmount = 1000000 * 10 ** 9;
+      const rewardRate = 100 * 10 ** 9;
+
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        fundAmount
+      );
+
+      await program.methods
+        .fundRewards(new anchor.BN(fundAmount), new anchor.BN(rewardRate))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      await program.methods
+        .withdrawUnallocatedRewards()
+        .accountsPartial({
+          vault,
+          authority: authority.publicKey,
+          rewardVault,
+          authorityRewardTokenAccount: funderRewardAccount.address,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.rewardRate.toNumber()).to.equal(0);
+      expect(vaultAccount.rewardsFunded.toString()).to.equal(
+        vaultAccount.rewardsAccrued.toString()
+      );
+
+      // Only the accrued portion is left behind for stakers
+      const reclaimed = Number(
+        (await getAccount(connection, funderRewardAccount.address)).amount
+      );
+      const remaining = Number((await getAccount(connection, rewardVault)).amount);
+      expect(reclaimed).to.be.greaterThan(0);
+      expect(remaining).to.equal(vaultAccount.rewardsAccrued.toNumber());
+
+      await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault: vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+
+      const user1RewardBalance = await getAccount(connection, user1RewardAccount);
+      expect(Number(user1RewardBalance.amount)).to.be.greaterThan(0);
+      expect(Number(user1RewardBalance.amount)).to.be.at.most(remaining);
+    });
+
+    it("Keeper pushes rewards to opted-in positions and earns a tip", async () => {
+      const keeper = Keypair.generate();
+      await connection.confirmTransaction(
+        await connection.requestAirdrop(
+          keeper.publicKey,
+          anchor.web3.LAMPORTS_PER_SOL
+        ),
+        "confirmed"
+      );
+      const keeperRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        keeper,
+        rewardMint,
+        keeper.publicKey,
+        false
+      );
+
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000 * 10 ** 9
+      );
+      await program.methods
+        .fundRewards(new anchor.BN(1000000 * 10 ** 9), new anchor.BN(100 * 10 ** 9))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const tipBps = 100; // 1%
+      await program.methods
+        .setKeeper(keeper.publicKey, tipBps)
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      // Not opted in yet
+      try {
+        await program.methods
+          .keeperClaimRewards()
+          .accountsPartial({
+            vault,
+            user: user1.publicKey,
+            userRewardTokenAccount: user1RewardAccount,
+            keeper: keeper.publicKey,
+            keeperRewardTokenAccount: keeperRewardAccount.address,
+            rewardVault,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([keeper])
+          .rpc();
+        expect.fail("Should have failed - position not opted in");
+      } catch (e: any) {
+        expect(e.toString()).to.include("KeeperClaimsDisabled");
+      }
+
+      await program.methods
+        .setKeeperClaims(true)
+        .accountsPartial({ vault, user: user1.publicKey })
+        .signers([user1])
+        .rpc();
+
+      await program.methods
+        .keeperClaimRewards()
+        .accountsPartial({
+          vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          keeper: keeper.publicKey,
+          keeperRewardTokenAccount: keeperRewardAccount.address,
+          rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([keeper])
+        .rpc();
+
+      const userReward = Number(
+        (await getAccount(connection, user1RewardAccount)).amount
+      );
+      const keeperTip = Number(
+        (await getAccount(connection, keeperRewardAccount.address)).amount
+      );
+      expect(userReward).to.be.greaterThan(0);
+      expect(keeperTip).to.be.greaterThan(0);
+      expect(keeperTip).to.be.at.most(
+        Math.ceil(((userReward + keeperTip) * tipBps) / 10000)
+      );
+    });
+
+    it("Anyone can crank update_rewards to refresh the accumulator", async () => {
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000 * 10 ** 9
+      );
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .fundRewards(new anchor.BN(1000000 * 10 ** 9), new anchor.BN(100 * 10 ** 9))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+      const before = await program.account.vault.fetch(vault);
+
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      await program.methods
+        .updateRewards()
+        .accounts({ vault })
+        .rpc();
+
+      const after = await program.account.vault.fetch(vault);
+      expect(after.accRewardPerShare.gt(before.accRewardPerShare)).to.be.true;
+      expect(after.lastUpdateTs.gt(before.lastUpdateTs)).to.be.true;
+      expect(after.totalShares.toString()).to.equal(before.totalShares.toString());
+    });
+
+    it("Records share-price snapshots in the stats ring buffer", async () => {
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      await program.methods.initializeVaultStats().accounts({ vault }).rpc();
+      await program.methods
+        .snapshot()
+        .accountsPartial({ vault, vaultTokenAccount, vaultAuthority })
+        .rpc();
+
+      const [vaultStats] = PublicKey.findProgramAddressSync(
+        [Buffer.from("stats"), vault.toBuffer()],
+        program.programId
+      );
+      const stats = await program.account.vaultStats.fetch(vaultStats);
+      expect(stats.len).to.equal(1);
+      expect(stats.head).to.equal(1);
+      expect(stats.snapshots[0].trackedBalance.toString()).to.equal(
+        (1000 * 10 ** 9).toString()
+      );
+      expect(stats.snapshots[0].totalShares.toString()).to.equal(
+        (1000 * 10 ** 9).toString()
+      );
+
+      try {
+        await program.methods
+          .snapshot()
+          .accountsPartial({ vault, vaultTokenAccount, vaultAuthority })
+          .rpc();
+        expect.fail("Should have failed - interval not elapsed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("SnapshotTooSoon");
+      }
+    });
+
+    it("Delegate claims to the owner's account but cannot act for others", async () => {
+      const delegate = Keypair.generate();
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000 * 10 ** 9
+      );
+      await program.methods
+        .fundRewards(new anchor.BN(1000000 * 10 ** 9), new anchor.BN(100 * 10 ** 9))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      try {
+        await program.methods
+          .delegateClaimRewards()
+          .accountsPartial({
+            vault,
+            user: user1.publicKey,
+            userRewardTokenAccount: user1RewardAccount,
+            delegate: delegate.publicKey,
+            rewardVault,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([delegate])
+          .rpc();
+        expect.fail("Should have failed - no delegate set");
+      } catch (e: any) {
+        expect(e.toString()).to.include("UnauthorizedDelegate");
+      }
+
+      await program.methods
+        .setPositionDelegate(delegate.publicKey)
+        .accountsPartial({ vault, user: user1.publicKey })
+        .signers([user1])
+        .rpc();
+
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      expect(
+        (await program.account.userPosition.fetch(userPosition)).delegate.toString()
+      ).to.equal(delegate.publicKey.toString());
+
+      const before = (await getAccount(connection, user1RewardAccount)).amount;
+      await program.methods
+        .delegateClaimRewards()
+        .accountsPartial({
+          vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: user1RewardAccount,
+          delegate: delegate.publicKey,
+          rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([delegate])
+        .rpc();
+      const after = (await getAccount(connection, user1RewardAccount)).amount;
+      expect(after > before).to.be.true;
+    });
+
+    // Emit far more than the reward vault holds so a delegate claim falls short
+    const setUpShortfall = async (funded: number) => {
+      const delegate = Keypair.generate();
+      const funderRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        rewardMint,
+        funderRewardAccount.address,
+        authority,
+        1000000 * 10 ** 9
+      );
+      await program.methods
+        .fundRewards(new anchor.BN(funded), new anchor.BN(100 * 10 ** 9))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount.address,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .setPositionDelegate(delegate.publicKey)
+        .accountsPartial({ vault, user: user1.publicKey })
+        .signers([user1])
+        .rpc();
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      const claim = () =>
+        program.methods
+          .delegateClaimRewards()
+          .accountsPartial({
+            vault,
+            user: user1.publicKey,
+            userRewardTokenAccount: user1RewardAccount,
+            delegate: delegate.publicKey,
+            rewardVault,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([delegate])
+          .rpc();
+      return { funderRewardAccount: funderRewardAccount.address, claim };
+    };
+
+    it("Delegate claim pays what the reward vault holds and keeps the rest owed", async () => {
+      const { claim } = await setUpShortfall(10 ** 9);
+
+      const before = (await getAccount(connection, user1RewardAccount)).amount;
+      await claim();
+      const after = (await getAccount(connection, user1RewardAccount)).amount;
+      expect((after - before).toString()).to.equal((10 ** 9).toString());
+      expect((await getAccount(connection, rewardVault)).amount.toString()).to.equal("0");
+
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      const position = await program.account.userPosition.fetch(userPosition);
+      expect(position.rewardsOwed.gte(new anchor.BN(10).pow(new anchor.BN(12)))).to.be.true;
+      expect((await program.account.vault.fetch(vault)).unpaidRewards.toNumber()).to.be.greaterThan(0);
+    });
+
+    it("A later claim settles the IOU once the reward vault is topped up", async () => {
+      const { funderRewardAccount, claim } = await setUpShortfall(10 ** 9);
+      await claim();
+      const [userPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      const owed = (await program.account.userPosition.fetch(userPosition)).rewardsOwed.div(
+        new anchor.BN(10).pow(new anchor.BN(12))
+      );
+
+      // Top up without changing the rate
+      await program.methods
+        .fundRewards(new anchor.BN(100000 * 10 ** 9), new anchor.BN(0))
+        .accounts({
+          vault: vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount,
+          rewardVault: rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      const before = (await getAccount(connection, user1RewardAccount)).amount;
+      await claim();
+      const after = (await getAccount(connection, user1RewardAccount)).amount;
+      expect(new anchor.BN((after - before).toString()).gte(owed)).to.be.true;
+
+      const position = await program.account.userPosition.fetch(userPosition);
+      expect(position.rewardsOwed.lt(new anchor.BN(10).pow(new anchor.BN(12)))).to.be.true;
+      expect((await program.account.vault.fetch(vault)).unpaidRewards.toString()).to.equal("0");
+    });
+
+    it("Delegate compounds rewards into shares on a single-mint vault", async () => {
+      const delegate = Keypair.generate();
+      const mint = await createMint(
+        connection,
+        authority,
+        authority.publicKey,
+        null,
+        9
+      );
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: mint,
+          rewardMint: mint,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+      const [singleVault] = await getVaultPDA(mint);
+      const [singleAuthority] = await getVaultAuthorityPDA(mint);
+      const singleVaultToken = await getAssociatedTokenAddress(
+        mint,
+        singleAuthority,
+        true
+      );
+
+      const userAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        user1,
+        mint,
+        user1.publicKey,
+        false
+      );
+      const funderAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        mint,
+        authority.publicKey,
+        false
+      );
+      await mintTo(connection, authority, mint, userAccount.address, authority, 1000 * 10 ** 9);
+      await mintTo(connection, authority, mint, funderAccount.address, authority, 1000000 * 10 ** 9);
+
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: singleVault,
+          user: user1.publicKey,
+          userTokenAccount: userAccount.address,
+          vaultTokenAccount: singleVaultToken,
+        })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .fundRewards(new anchor.BN(1000000 * 10 ** 9), new anchor.BN(100 * 10 ** 9))
+        .accounts({
+          vault: singleVault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderAccount.address,
+          rewardVault: singleVaultToken,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+      await program.methods
+        .setPositionDelegate(delegate.publicKey)
+        .accountsPartial({ vault: singleVault, user: user1.publicKey })
+        .signers([user1])
+        .rpc();
+
+      await new Promise((resolve) => setTimeout(resolve, 2000));
+
+      const [userPosition] = await getUserPositionPDA(singleVault, user1.publicKey);
+      const sharesBefore = (await program.account.userPosition.fetch(userPosition))
+        .shares;
+      await program.methods
+        .compoundRewards()
+        .accountsPartial({
+          vault: singleVault,
+          user: user1.publicKey,
+          authority: delegate.publicKey,
+          vaultTokenAccount: singleVaultToken,
+          rewardVault: singleVaultToken,
+        })
+        .signers([delegate])
+        .rpc();
+      const sharesAfter = (await program.account.userPosition.fetch(userPosition))
+        .shares;
+      expect(sharesAfter.gt(sharesBefore)).to.be.true;
+    });
+
+    it("Rejects reward pushes from an unregistered keeper", async () => {
+      const impostor = Keypair.generate();
+      const impostorRewardAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        rewardMint,
+        impostor.publicKey,
+        false
+      );
+
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: user1TokenAccount,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+      await program.methods
+        .setKeeperClaims(true)
+        .accountsPartial({ vault, user: user1.publicKey })
+        .signers([user1])
+        .rpc();
+
+      try {
+        await program.methods
+          .keeperClaimRewards()
+          .accountsPartial({
+            vault,
+            user: user1.publicKey,
+            userRewardTokenAccount: user1RewardAccount,
+            keeper: impostor.publicKey,
+            keeperRewardTokenAccount: impostorRewardAccount.address,
+            rewardVault,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([impostor])
+          .rpc();
+        expect.fail("Should have failed - keeper not registered");
+      } catch (e: any) {
+        expect(e.toString()).to.include("UnauthorizedKeeper");
+      }
+    });
+  });
+
+  describe("flash_loan", () => {
+    let vault: PublicKey;
+    let vaultAuthority: PublicKey;
+    let vaultTokenAccount: PublicKey;
+    let feeTreasury: Keypair;
+    let feeTreasuryTokenAccount: PublicKey;
+    let borrower: Keypair;
+    let borrowerTokenAccount: PublicKey;
+
+    beforeEach(async () => {
+      // Initialize vault
+      const [vaultPDA] = await getVaultPDA(tokenMint1);
+      vault = vaultPDA;
+      const [vaultAuthorityPDA] = await getVaultAuthorityPDA(tokenMint1);
+      vaultAuthority = vaultAuthorityPDA;
+      const vaultTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMint1,
+        vaultAuthority,
+        true
+      );
+      vaultTokenAccount = vaultTokenAccountInfo.address;
+
+      const rewardVault = await getAssociatedTokenAddress(
+        tokenMint1,
+        vaultAuthority,
+        true
+      );
+
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      // Create fee treasury
+      feeTreasury = Keypair.generate();
+      await connection.requestAirdrop(
+        feeTreasury.publicKey,
+        2 * anchor.web3.LAMPORTS_PER_SOL
+      );
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      feeTreasuryTokenAccount = await getAssociatedTokenAddress(
+        tokenMint1,
+        feeTreasury.publicKey,
+        false
+      );
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        feeTreasury,
+        tokenMint1,
+        feeTreasury.publicKey,
+        false
+      );
+
+      // Create borrower
+      borrower = Keypair.generate();
+      await connection.requestAirdrop(
+        borrower.publicKey,
+        2 * anchor.web3.LAMPORTS_PER_SOL
+      );
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+
+      borrowerTokenAccount = await getAssociatedTokenAddress(
+        tokenMint1,
+        borrower.publicKey,
+        false
+      );
+      await getOrCreateAssociatedTokenAccount(
+        connection,
+        borrower,
+        tokenMint1,
+        borrower.publicKey,
+        false
+      );
+
+      // Deposit tokens into vault
       const userTokenAccount = await getOrCreateAssociatedTokenAccount(
         connection,
-        user1,
-        tokenMint1,
-        user1.publicKey,
-        false
+        user1,
+        tokenMint1,
+        user1.publicKey,
+        false
+      );
+      await mintTo(
+        connection,
+        authority,
+        tokenMint1,
+        userTokenAccount.address,
+        authority,
+        1000000 * 10 ** 9
+      );
+
+      await program.methods
+        .deposit(new anchor.BN(100000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault: vault,
+          user: user1.publicKey,
+          userTokenAccount: userTokenAccount.address,
+          vaultTokenAccount: vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      // Configure flash loan
+      await program.methods
+        .updateFlashLoanConfig(
+          9, // 0.09% fee (9 basis points)
+          feeTreasury.publicKey,
+          false, // allowlist disabled
+          []
+        )
+        .accountsPartial({
+          vault: vault,
+          authority: authority.publicKey,
+        })
+        .signers([authority])
+        .rpc();
+    });
+
+    it("Successful flash loan with proper repayment", async () => {
+      const loanAmount = new anchor.BN(10000 * 10 ** 9); // 10k tokens
+      const feeBps = 9; // 0.09%
+      const fee = (loanAmount.toNumber() * feeBps) / 10000;
+
+      // Pre-fund borrower with enough tokens to cover the fee
+      // This simulates a real scenario where borrower has funds or uses loan proceeds to generate profit
+      await mintTo(
+        connection,
+        authority,
+        tokenMint1,
+        borrowerTokenAccount,
+        authority,
+        fee + 100 // fee + small buffer
+      );
+
+      // Record initial balances
+      const initialVaultBalance = (await getAccount(connection, vaultTokenAccount)).amount;
+      const initialBorrowerBalance = (await getAccount(connection, borrowerTokenAccount)).amount;
+      const initialFeeTreasuryBalance = (await getAccount(connection, feeTreasuryTokenAccount)).amount;
+
+      // The mock borrower's on_flash_loan repays loan + fee from the borrower's account
+      await program.methods
+        .flashLoan(loanAmount)
+        .accounts({
+          vault: vault,
+          vaultTokenAccount: vaultTokenAccount,
+          borrower: borrower.publicKey,
+          borrowerTokenAccount: borrowerTokenAccount,
+          callbackProgram: mockFlashBorrower.programId,
+        })
+        .signers([borrower])
+        .rpc();
+
+      // Verify final balances
+      const finalVaultBalance = new anchor.BN(
+        (await getAccount(connection, vaultTokenAccount)).amount.toString()
+      );
+      const finalBorrowerBalance = new anchor.BN(
+        (await getAccount(connection, borrowerTokenAccount)).amount.toString()
+      );
+      const finalFeeTreasuryBalance = new anchor.BN(
+        (await getAccount(connection, feeTreasuryTokenAccount)).amount.toString()
+      );
+
+      // Convert initial balances to BN for comparison
+      const initialVaultBalanceBN = new anchor.BN(
+        initialVaultBalance.toString()
+      );
+      const initialBorrowerBalanceBN = new anchor.BN(
+        initialBorrowerBalance.toString()
+      );
+      const initialFeeTreasuryBalanceBN = new anchor.BN(
+        initialFeeTreasuryBalance.toString()
+      );
+
+      // Vault should have: initial - loan + repayment = initial + fee
+      // (The fee waits in the vault token account for collect_fees)
+      expect(finalVaultBalance.toString()).to.equal(
+        initialVaultBalanceBN.add(new anchor.BN(fee)).toString()
+      );
+
+      // Borrower should have: initial + loan - repayment = initial - fee
+      expect(finalBorrowerBalance.toString()).to.equal(
+        initialBorrowerBalanceBN.sub(new anchor.BN(fee)).toString()
+      );
+
+      // Nothing reaches the treasury until the fees are collected
+      expect(finalFeeTreasuryBalance.toString()).to.equal(
+        initialFeeTreasuryBalanceBN.toString()
+      );
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.pendingProtocolFees.toNumber()).to.equal(fee);
+
+      await program.methods
+        .collectFees()
+        .accountsPartial({ vault, feeTreasuryTokenAccount, protocolFeeTokenAccount: null })
+        .rpc();
+      expect(
+        (await getAccount(connection, feeTreasuryTokenAccount)).amount.toString()
+      ).to.equal(initialFeeTreasuryBalanceBN.add(new anchor.BN(fee)).toString());
+      expect(
+        (await getAccount(connection, vaultTokenAccount)).amount.toString()
+      ).to.equal(initialVaultBalanceBN.toString());
+      expect(
+        (await program.account.vault.fetch(vault)).pendingProtocolFees.toNumber()
+      ).to.equal(0);
+    });
+
+    it("Rejects a second flash loan against the vault in the same transaction", async () => {
+      const loanAmount = new anchor.BN(1000 * 10 ** 9);
+      await mintTo(connection, authority, tokenMint1, borrowerTokenAccount, authority, 10 ** 9);
+
+      const loan = () =>
+        program.methods.flashLoan(loanAmount).accounts({
+          vault: vault,
+          vaultTokenAccount: vaultTokenAccount,
+          borrower: borrower.publicKey,
+          borrowerTokenAccount: borrowerTokenAccount,
+          callbackProgram: mockFlashBorrower.programId,
+        });
+
+      try {
+        await loan()
+          .postInstructions([await loan().instruction()])
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("TooManyFlashLoans");
+      }
+    });
+
+    it("Rejects flash loans taken through CPI", async () => {
+      await mintTo(connection, authority, tokenMint1, borrowerTokenAccount, authority, 10 ** 9);
+
+      // Two loans from one outer instruction would both be invisible to the
+      // instructions sysvar, so the first is refused
+      try {
+        await mockFlashBorrower.methods
+          .borrowTwice(new anchor.BN(1000 * 10 ** 9))
+          .accounts({
+            vault,
+            vaultTokenAccount,
+            rewardVault: vaultTokenAccount,
+            vaultAuthority,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount,
+            callbackProgram: mockFlashBorrower.programId,
+          })
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("FlashLoanViaCpi");
+      }
+    });
+
+    it("Only lends out of the vault's own token account", async () => {
+      // Another account approved to the vault authority can't stand in for the vault's
+      const [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      const victim = Keypair.generate();
+      const victimTokenAccount = await createAccount(
+        connection,
+        authority,
+        tokenMint1,
+        victim.publicKey
+      );
+      await mintTo(connection, authority, tokenMint1, victimTokenAccount, authority, 10 ** 12);
+      await approve(
+        connection,
+        authority,
+        victimTokenAccount,
+        vaultAuthority,
+        victim,
+        BigInt(10 ** 12)
+      );
+
+      try {
+        await program.methods
+          .flashLoan(new anchor.BN(10 ** 12))
+          .accounts({
+            vault,
+            vaultTokenAccount: victimTokenAccount,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount,
+            callbackProgram: mockFlashBorrower.programId,
+          })
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("ConstraintTokenOwner");
+      }
+    });
+
+    it("Lends to an allowlisted callback's borrower PDA without a signature", async () => {
+      const [borrowerPda] = PublicKey.findProgramAddressSync(
+        [Buffer.from("flash_borrower")],
+        mockFlashBorrower.programId
+      );
+      const pdaTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(
+          connection,
+          authority,
+          tokenMint1,
+          borrowerPda,
+          true
+        )
+      ).address;
+      await mintTo(connection, authority, tokenMint1, pdaTokenAccount, authority, 10 ** 9);
+
+      const loan = () =>
+        program.methods.flashLoan(new anchor.BN(1000 * 10 ** 9)).accountsPartial({
+          vault: vault,
+          vaultTokenAccount: vaultTokenAccount,
+          borrower: borrowerPda,
+          borrowerTokenAccount: pdaTokenAccount,
+          callbackProgram: mockFlashBorrower.programId,
+        });
+
+      // Off until the vault authority opts in
+      try {
+        await loan().rpc();
+        expect.fail("Should have failed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("CallbackNotAllowlisted");
+      }
+
+      await program.methods
+        .updateFlashLoanConfig(null, null, null, [mockFlashBorrower.programId])
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      try {
+        await loan().rpc();
+        expect.fail("Should have failed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("PdaBorrowerNotAllowed");
+      }
+
+      await program.methods
+        .setPdaBorrowersEnabled(true)
+        .accounts({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      const before = (await program.account.vault.fetch(vault)).pendingProtocolFees;
+      await loan().rpc();
+      const after = (await program.account.vault.fetch(vault)).pendingProtocolFees;
+      // 0.09% of the loan, repaid by the PDA and held for collect_fees
+      expect(after.sub(before).toNumber()).to.equal(900000000);
+    });
+
+    it("Fails with insufficient repayment", async () => {
+      // This test requires a callback program that under-repays
+      // For now, we'll test the configuration and error cases we can test
+      const loanAmount = new anchor.BN(10000 * 10 ** 9);
+
+      // Try to call flash_loan without proper callback setup
+      // This should fail because we don't have a real callback program
+      // that repays correctly
+      try {
+        await program.methods
+          .flashLoan(loanAmount)
+          .accounts({
+            vault: vault,
+            vaultTokenAccount: vaultTokenAccount,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount: borrowerTokenAccount,
+            callbackProgram: SystemProgram.programId, // Wrong program
+          })
+          .remainingAccounts([])
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed");
+      } catch (e: any) {
+        // Should fail for various reasons (no callback, wrong program, etc.)
+        expect(e.toString()).to.not.be.undefined;
+      }
+    });
+
+    it("Fails with zero amount", async () => {
+      try {
+        await program.methods
+          .flashLoan(new anchor.BN(0))
+          .accounts({
+            vault: vault,
+            vaultTokenAccount: vaultTokenAccount,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount: borrowerTokenAccount,
+            callbackProgram: SystemProgram.programId,
+          })
+          .remainingAccounts([])
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed with zero amount");
+      } catch (e: any) {
+        expect(e.toString()).to.include("InvalidFlashLoanAmount");
+      }
+    });
+
+    it("Fails when flash loan not configured", async () => {
+      // Create a new vault without flash loan config
+      const [newVault] = await getVaultPDA(tokenMint2);
+      const [newVaultAuthority] = await getVaultAuthorityPDA(tokenMint2);
+      const newVaultTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
+        connection,
+        authority,
+        tokenMint2,
+        newVaultAuthority,
+        true
+      );
+
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint2,
+          rewardMint: tokenMint2,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      try {
+        await program.methods
+          .flashLoan(new anchor.BN(1000 * 10 ** 9))
+          .accounts({
+            vault: newVault,
+            vaultTokenAccount: newVaultTokenAccountInfo.address,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount: borrowerTokenAccount,
+            callbackProgram: SystemProgram.programId,
+          })
+          .remainingAccounts([])
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed - flash loan not configured");
+      } catch (e: any) {
+        expect(e.toString()).to.include("FlashLoanNotConfigured");
+      }
+    });
+
+    it("Fails with insufficient vault balance", async () => {
+      const excessiveAmount = new anchor.BN(1000000 * 10 ** 9); // More than vault has
+
+      try {
+        await program.methods
+          .flashLoan(excessiveAmount)
+          .accounts({
+            vault: vault,
+            vaultTokenAccount: vaultTokenAccount,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount: borrowerTokenAccount,
+            callbackProgram: SystemProgram.programId,
+          })
+          .remainingAccounts([])
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed - insufficient balance");
+      } catch (e: any) {
+        expect(e.toString()).to.include("InsufficientVaultBalance");
+      }
+    });
+
+    it("Tests allowlist enforcement", async () => {
+      // Enable allowlist
+      const allowedProgram = Keypair.generate().publicKey;
+      await program.methods
+        .updateFlashLoanConfig(
+          null,
+          null,
+          true, // enable allowlist
+          [allowedProgram]
+        )
+        .accountsPartial({
+          vault: vault,
+          authority: authority.publicKey,
+        })
+        .signers([authority])
+        .rpc();
+
+      // Try to use a non-allowlisted program
+      try {
+        await program.methods
+          .flashLoan(new anchor.BN(1000 * 10 ** 9))
+          .accounts({
+            vault: vault,
+            vaultTokenAccount: vaultTokenAccount,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount: borrowerTokenAccount,
+            callbackProgram: SystemProgram.programId, // Not in allowlist
+          })
+          .remainingAccounts([])
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed - callback not allowlisted");
+      } catch (e: any) {
+        expect(e.toString()).to.include("CallbackNotAllowlisted");
+      }
+
+      // Try with allowlisted program (should pass allowlist check, but fail on other things)
+      try {
+        await program.methods
+          .flashLoan(new anchor.BN(1000 * 10 ** 9))
+          .accounts({
+            vault: vault,
+            vaultTokenAccount: vaultTokenAccount,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount: borrowerTokenAccount,
+            callbackProgram: allowedProgram,
+          })
+          .remainingAccounts([])
+          .signers([borrower])
+          .rpc();
+        // Should fail on callback execution, not allowlist
+      } catch (e: any) {
+        // Should not be allowlist error
+        expect(e.toString()).to.not.include("CallbackNotAllowlisted");
+      }
+    });
+
+    it("Validates fee calculation", async () => {
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.flashFeeBps).to.equal(9);
+
+      // Test various amounts
+      const testAmounts = [
+        1000 * 10 ** 9,
+        10000 * 10 ** 9,
+        100000 * 10 ** 9,
+      ];
+
+      for (const amount of testAmounts) {
+        const fee = (amount * 9) / 10000;
+        expect(fee).to.be.greaterThan(0);
+        // Fee should be approximately 0.09% of amount
+        const expectedFee = Math.floor((amount * 9) / 10000);
+        expect(fee).to.equal(expectedFee);
+      }
+    });
+
+    it("Validates fee treasury token account", async () => {
+      // Try with wrong mint
+      const wrongMintTokenAccount = await getOrCreateAssociatedTokenAccount(
+        connection,
+        feeTreasury,
+        tokenMint2,
+        feeTreasury.publicKey,
+        true
       );
-      await mintTo(
-        connection,
-        authority,
+
+      try {
+        await program.methods
+          .collectFees()
+          .accountsPartial({
+            vault: vault,
+            feeTreasuryTokenAccount: wrongMintTokenAccount.address,
+            protocolFeeTokenAccount: null,
+          })
+          .rpc();
+        expect.fail("Should have failed - wrong mint");
+      } catch (e: any) {
+        expect(e.toString()).to.include("InvalidFeeTreasury");
+      }
+    });
+
+    it("Pauses flash loans without affecting deposits", async () => {
+      await program.methods
+        .setFlashLoansEnabled(false)
+        .accountsPartial({ vault: vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      try {
+        await program.methods
+          .flashLoan(new anchor.BN(1000 * 10 ** 9))
+          .accounts({
+            vault: vault,
+            vaultTokenAccount: vaultTokenAccount,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount: borrowerTokenAccount,
+            callbackProgram: TOKEN_PROGRAM_ID,
+          })
+          .remainingAccounts([])
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed - flash loans disabled");
+      } catch (e: any) {
+        expect(e.toString()).to.include("FlashLoansDisabled");
+      }
+
+      // Staking keeps working while flash loans are paused
+      const userTokenAccount = await getAssociatedTokenAddress(
         tokenMint1,
-        userTokenAccount.address,
-        authority,
-        1000000 * 10 ** 9
+        user1.publicKey
       );
-
       await program.methods
-        .deposit(new anchor.BN(100000 * 10 ** 9))
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
         .accounts({
           vault: vault,
           user: user1.publicKey,
-          userTokenAccount: userTokenAccount.address,
+          userTokenAccount: userTokenAccount,
           vaultTokenAccount: vaultTokenAccount,
         })
         .signers([user1])
         .rpc();
 
-      // Configure flash loan
       await program.methods
-        .updateFlashLoanConfig(
-          9, // 0.09% fee (9 basis points)
-          feeTreasury.publicKey,
-          false, // allowlist disabled
-          []
-        )
-        .accountsPartial({
-          vault: vault,
-          authority: authority.publicKey,
-        })
+        .setFlashLoansEnabled(true)
+        .accountsPartial({ vault: vault, authority: authority.publicKey })
         .signers([authority])
         .rpc();
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.flashLoansEnabled).to.equal(1);
     });
 
-    it("Successful flash loan with proper repayment", async () => {
-      const loanAmount = new anchor.BN(10000 * 10 ** 9); // 10k tokens
-      const feeBps = 9; // 0.09%
-      const fee = (loanAmount.toNumber() * feeBps) / 10000;
-      const repayAmount = loanAmount.toNumber() + fee;
+    it("Gates pausing and fee changes on delegated roles", async () => {
+      const guardian = Keypair.generate();
+      const feeManager = Keypair.generate();
 
-      // Pre-fund borrower with enough tokens to cover the fee
-      // This simulates a real scenario where borrower has funds or uses loan proceeds to generate profit
-      await mintTo(
-        connection,
-        authority,
-        tokenMint1,
-        borrowerTokenAccount,
-        authority,
-        fee + 100 // fee + small buffer
-      );
+      try {
+        await program.methods
+          .setPaused(true)
+          .accountsPartial({ vault, authority: guardian.publicKey })
+          .signers([guardian])
+          .rpc();
+        expect.fail("Should have failed - not the guardian yet");
+      } catch (e: any) {
+        expect(e.toString()).to.include("MissingRole");
+      }
 
-      // Record initial balances
-      const initialVaultBalance = (await getAccount(connection, vaultTokenAccount)).amount;
-      const initialBorrowerBalance = (await getAccount(connection, borrowerTokenAccount)).amount;
-      const initialFeeTreasuryBalance = (await getAccount(connection, feeTreasuryTokenAccount)).amount;
+      await program.methods
+        .setRole({ guardian: {} }, guardian.publicKey)
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      await program.methods
+        .setRole({ feeManager: {} }, feeManager.publicKey)
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
 
-      // Build callback instruction data for SPL Token Transfer
-      // Transfer instruction discriminator is 3, followed by amount (u64, little-endian)
-      const transferInstructionDiscriminator = 3;
-      const repayAmountBN = new anchor.BN(repayAmount);
-      const callbackIxData = Buffer.concat([
-        Buffer.from([transferInstructionDiscriminator]),
-        repayAmountBN.toArrayLike(Buffer, "le", 8),
-      ]);
+      await program.methods
+        .setPaused(true)
+        .accountsPartial({ vault, authority: guardian.publicKey })
+        .signers([guardian])
+        .rpc();
+      try {
+        await program.methods
+          .flashLoan(new anchor.BN(1000))
+          .accounts({
+            vault: vault,
+            vaultTokenAccount: vaultTokenAccount,
+            borrower: borrower.publicKey,
+            borrowerTokenAccount: borrowerTokenAccount,
+            callbackProgram: TOKEN_PROGRAM_ID,
+          })
+          .signers([borrower])
+          .rpc();
+        expect.fail("Should have failed - vault paused");
+      } catch (e: any) {
+        expect(e.toString()).to.include("VaultPaused");
+      }
 
-      // Execute flash loan with callback that repays the loan + fee
+      // The guardian can't touch fees, the fee manager can
+      try {
+        await program.methods
+          .updateFlashLoanConfig(20, null, null, null)
+          .accountsPartial({ vault, authority: guardian.publicKey })
+          .signers([guardian])
+          .rpc();
+        expect.fail("Should have failed - guardian is not fee manager");
+      } catch (e: any) {
+        expect(e.toString()).to.include("MissingRole");
+      }
       await program.methods
-        .flashLoan(loanAmount, callbackIxData)
-        .accounts({
-          vault: vault,
-          vaultTokenAccount: vaultTokenAccount,
-          borrower: borrower.publicKey,
-          borrowerTokenAccount: borrowerTokenAccount,
-          feeTreasuryTokenAccount: feeTreasuryTokenAccount,
-          callbackProgram: TOKEN_PROGRAM_ID, // Using token program as callback
-        })
-        .remainingAccounts([
-          {
-            pubkey: borrowerTokenAccount,
-            isSigner: false,
-            isWritable: true,
-          },
-          {
-            pubkey: vaultTokenAccount,
-            isSigner: false,
-            isWritable: true,
-          },
-          {
-            pubkey: borrower.publicKey,
-            isSigner: true,
-            isWritable: false,
-          },
-        ])
-        .signers([borrower])
+        .updateFlashLoanConfig(20, null, null, null)
+        .accountsPartial({ vault, authority: feeManager.publicKey })
+        .signers([feeManager])
         .rpc();
+      expect((await program.account.vault.fetch(vault)).flashFeeBps).to.equal(20);
 
-      // Verify final balances
-      const finalVaultBalance = new anchor.BN(
-        (await getAccount(connection, vaultTokenAccount)).amount.toString()
-      );
-      const finalBorrowerBalance = new anchor.BN(
-        (await getAccount(connection, borrowerTokenAccount)).amount.toString()
-      );
-      const finalFeeTreasuryBalance = new anchor.BN(
-        (await getAccount(connection, feeTreasuryTokenAccount)).amount.toString()
-      );
+      // Revoked roles stop working
+      await program.methods
+        .setRole({ guardian: {} }, PublicKey.default)
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      try {
+        await program.methods
+          .setPaused(false)
+          .accountsPartial({ vault, authority: guardian.publicKey })
+          .signers([guardian])
+          .rpc();
+        expect.fail("Should have failed - guardian revoked");
+      } catch (e: any) {
+        expect(e.toString()).to.include("MissingRole");
+      }
+      await program.methods
+        .setPaused(false)
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+    });
 
-      // Convert initial balances to BN for comparison
-      const initialVaultBalanceBN = new anchor.BN(
-        initialVaultBalance.toString()
-      );
-      const initialBorrowerBalanceBN = new anchor.BN(
-        initialBorrowerBalance.toString()
-      );
-      const initialFeeTreasuryBalanceBN = new anchor.BN(
-        initialFeeTreasuryBalance.toString()
+    it("Timelocks fee changes behind propose and execute", async () => {
+      const [pendingChange] = PublicKey.findProgramAddressSync(
+        [Buffer.from("pending_config"), vault.toBuffer()],
+        program.programId
       );
+      await program.methods
+        .setConfigTimelock(new anchor.BN(2))
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
 
-      // Vault should have: initial - loan + repayment - fee transfer = initial
-      // (The fee is transferred to treasury, so vault returns to original balance)
-      expect(finalVaultBalance.toString()).to.equal(
-        initialVaultBalanceBN.toString()
-      );
+      try {
+        await program.methods
+          .updateFlashLoanConfig(50, null, null, null)
+          .accountsPartial({ vault, authority: authority.publicKey })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - config is timelocked");
+      } catch (e: any) {
+        expect(e.toString()).to.include("ConfigTimelocked");
+      }
 
-      // Borrower should have: initial + loan - repayment = initial - fee
-      expect(finalBorrowerBalance.toString()).to.equal(
-        initialBorrowerBalanceBN.sub(new anchor.BN(fee)).toString()
-      );
+      await program.methods
+        .proposeConfigChange({
+          flashFeeBps: 50,
+          feeTreasury: null,
+          callbackAllowlistEnabled: null,
+          callbackAllowlist: null,
+          managementFeeBps: null,
+          feeRecipient: null,
+          configTimelockSecs: null,
+        })
+        .accountsPartial({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
 
-      // Fee treasury should have received the fee
-      expect(finalFeeTreasuryBalance.toString()).to.equal(
-        initialFeeTreasuryBalanceBN.add(new anchor.BN(fee)).toString()
+      try {
+        await program.methods
+          .executeConfigChange()
+          .accountsPartial({ vault, pendingChange, authority: authority.publicKey })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - timelock not elapsed");
+      } catch (e: any) {
+        expect(e.toString()).to.include("TimelockNotElapsed");
+      }
+      expect((await program.account.vault.fetch(vault)).flashFeeBps).to.equal(9);
+
+      await new Promise((resolve) => setTimeout(resolve, 3000));
+      await program.methods
+        .executeConfigChange()
+        .accountsPartial({ vault, pendingChange, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      expect((await program.account.vault.fetch(vault)).flashFeeBps).to.equal(50);
+      expect(await connection.getAccountInfo(pendingChange)).to.be.null;
+    });
+
+    it("Adds and removes single callback allowlist entries", async () => {
+      const programA = Keypair.generate().publicKey;
+      const programB = Keypair.generate().publicKey;
+
+      for (const p of [programA, programB]) {
+        await program.methods
+          .addCallbackProgram(p)
+          .accountsPartial({ vault: vault, authority: authority.publicKey })
+          .signers([authority])
+          .rpc();
+      }
+
+      try {
+        await program.methods
+          .addCallbackProgram(programA)
+          .accountsPartial({ vault: vault, authority: authority.publicKey })
+          .signers([authority])
+          .rpc();
+        expect.fail("Should have failed - duplicate entry");
+      } catch (e: any) {
+        expect(e.toString()).to.include("CallbackAlreadyAllowlisted");
+      }
+
+      await program.methods
+        .removeCallbackProgram(programA)
+        .accountsPartial({ vault: vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      const allowlist = await getCallbackAllowlist(vault);
+      expect(allowlist.map((p) => p.toString())).to.deep.equal(
+        [programB.toString()]
       );
     });
 
-    it("Fails with insufficient repayment", async () => {
-      // This test requires a callback program that under-repays
-      // For now, we'll test the configuration and error cases we can test
-      const loanAmount = new anchor.BN(10000 * 10 ** 9);
+    it("Expands the callback allowlist beyond the initial capacity", async () => {
+      const initialCapacity = 10;
+      const entries = Array.from({ length: initialCapacity }, () =>
+        Keypair.generate().publicKey
+      );
+      await program.methods
+        .updateFlashLoanConfig(null, null, true, entries)
+        .accountsPartial({ vault: vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
 
-      // Try to call flash_loan without proper callback setup
-      // This should fail because we don't have a real callback program
-      // that repays correctly
+      const extraProgram = Keypair.generate().publicKey;
       try {
-        const callbackIxData = Buffer.alloc(0);
         await program.methods
-          .flashLoan(loanAmount, Buffer.from(callbackIxData))
-          .accounts({
-            vault: vault,
-            vaultTokenAccount: vaultTokenAccount,
-            borrower: borrower.publicKey,
-            borrowerTokenAccount: borrowerTokenAccount,
-            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
-            callbackProgram: SystemProgram.programId, // Wrong program
-          })
-          .remainingAccounts([])
-          .signers([borrower])
+          .addCallbackProgram(extraProgram)
+          .accountsPartial({ vault: vault, authority: authority.publicKey })
+          .signers([authority])
           .rpc();
-        expect.fail("Should have failed");
+        expect.fail("Should have failed - allowlist full");
       } catch (e: any) {
-        // Should fail for various reasons (no callback, wrong program, etc.)
-        expect(e.toString()).to.not.be.undefined;
+        expect(e.toString()).to.include("CallbackAllowlistFull");
       }
+
+      const sizeBefore = (await connection.getAccountInfo(vault)).data.length;
+      await program.methods
+        .expandCallbackAllowlist(5)
+        .accountsPartial({ vault: vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+      const sizeAfter = (await connection.getAccountInfo(vault)).data.length;
+      expect(sizeAfter - sizeBefore).to.equal(5 * 32);
+
+      await program.methods
+        .addCallbackProgram(extraProgram)
+        .accountsPartial({ vault: vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      const allowlist = await getCallbackAllowlist(vault);
+      expect(allowlist.length).to.equal(initialCapacity + 1);
     });
 
-    it("Fails with zero amount", async () => {
+    it("Rejects a reward vault that does not belong to the vault", async () => {
+      // The reward vault is snapshotted around the callback, so it must be the real one
+      const foreignRewardVault = await getOrCreateAssociatedTokenAccount(
+        connection,
+        borrower,
+        tokenMint1,
+        Keypair.generate().publicKey,
+        true
+      );
+
       try {
         await program.methods
-          .flashLoan(new anchor.BN(0), Buffer.from([]))
-          .accounts({
+          .flashLoan(new anchor.BN(1000 * 10 ** 9))
+          .accountsPartial({
             vault: vault,
             vaultTokenAccount: vaultTokenAccount,
+            rewardVault: foreignRewardVault.address,
             borrower: borrower.publicKey,
             borrowerTokenAccount: borrowerTokenAccount,
-            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
             callbackProgram: SystemProgram.programId,
           })
           .remainingAccounts([])
           .signers([borrower])
           .rpc();
-        expect.fail("Should have failed with zero amount");
+        expect.fail("Should have failed - wrong reward vault");
       } catch (e: any) {
-        expect(e.toString()).to.include("InvalidFlashLoanAmount");
+        expect(e.toString()).to.include("RewardVaultMismatch");
       }
     });
+  });
 
-    it("Fails when flash loan not configured", async () => {
-      // Create a new vault without flash loan config
-      const [newVault] = await getVaultPDA(tokenMint2);
-      const [newVaultAuthority] = await getVaultAuthorityPDA(tokenMint2);
-      const newVaultTokenAccountInfo = await getOrCreateAssociatedTokenAccount(
-        connection,
-        authority,
-        tokenMint2,
-        newVaultAuthority,
-        true
-      );
+  describe("strategies", () => {
+    const strategyProgram = TOKEN_PROGRAM_ID;
+    let vault: PublicKey;
+    let vaultAuthority: PublicKey;
+    let vaultTokenAccount: PublicKey;
+    let userTokenAccount: PublicKey;
+    let depositAccount: PublicKey;
+    let strategy: PublicKey;
 
+    beforeEach(async () => {
+      [vault] = await getVaultPDA(tokenMint1);
+      [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      vaultTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(connection, authority, tokenMint1, vaultAuthority, true)
+      ).address;
       await program.methods
         .initializeVault()
         .accounts({
           authority: authority.publicKey,
-          tokenMint: tokenMint2,
-          rewardMint: tokenMint2,
+          tokenMint: tokenMint1,
+          rewardMint: tokenMint1,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      userTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(connection, user1, tokenMint1, user1.publicKey)
+      ).address;
+      await mintTo(connection, authority, tokenMint1, userTokenAccount, authority, 2000 * 10 ** 9);
+      await program.methods
+        .deposit(new anchor.BN(1000 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({
+          vault,
+          user: user1.publicKey,
+          userTokenAccount,
+          vaultTokenAccount,
+        })
+        .signers([user1])
+        .rpc();
+
+      [strategy] = PublicKey.findProgramAddressSync(
+        [Buffer.from("strategy"), vault.toBuffer(), strategyProgram.toBuffer()],
+        program.programId
+      );
+      // Stand-in for a yield program's custody account, held by the strategy PDA
+      depositAccount = (
+        await getOrCreateAssociatedTokenAccount(connection, authority, tokenMint1, strategy, true)
+      ).address;
+      await program.methods
+        .addStrategy(depositAccount, 5000)
+        .accounts({ vault, strategyProgram, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+    });
+
+    // SPL token `Transfer`, run by the token program standing in as the strategy
+    const transferIx = (amount: anchor.BN) =>
+      Buffer.concat([Buffer.from([3]), amount.toArrayLike(Buffer, "le", 8)]);
+    const harvest = (from: PublicKey, owner: PublicKey, amount: anchor.BN) =>
+      program.methods
+        .harvestStrategy(transferIx(amount))
+        .accountsPartial({
+          vault,
+          strategy,
+          strategyProgram,
+          vaultTokenAccount,
+          vaultAuthority,
+          depositAccount: null,
+          authority: authority.publicKey,
+        })
+        .remainingAccounts([
+          { pubkey: from, isSigner: false, isWritable: true },
+          { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+          { pubkey: owner, isSigner: false, isWritable: false },
+        ])
+        .signers([authority])
+        .rpc();
+
+    const invest = (amount: anchor.BN) =>
+      program.methods
+        .invest(amount, Buffer.from([]))
+        .accountsPartial({
+          vault,
+          strategy,
+          strategyProgram,
+          vaultTokenAccount,
+          vaultAuthority,
+          depositAccount,
+          authority: authority.publicKey,
         })
         .signers([authority])
         .rpc();
 
+    it("Invests idle funds up to the strategy's allocation", async () => {
+      await invest(new anchor.BN(500 * 10 ** 9));
+
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.totalDeployed.toString()).to.equal((500 * 10 ** 9).toString());
+      const strategyAccount = await program.account.strategy.fetch(strategy);
+      expect(strategyAccount.deployed.toString()).to.equal((500 * 10 ** 9).toString());
+      expect((await getAccount(connection, depositAccount)).amount.toString()).to.equal(
+        (500 * 10 ** 9).toString()
+      );
+
       try {
-        await program.methods
-          .flashLoan(new anchor.BN(1000 * 10 ** 9), Buffer.from([]))
-          .accounts({
-            vault: newVault,
-            vaultTokenAccount: newVaultTokenAccountInfo.address,
-            borrower: borrower.publicKey,
-            borrowerTokenAccount: borrowerTokenAccount,
-            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
-            callbackProgram: SystemProgram.programId,
-          })
-          .remainingAccounts([])
-          .signers([borrower])
-          .rpc();
-        expect.fail("Should have failed - flash loan not configured");
+        await invest(new anchor.BN(1));
+        expect.fail("Should have failed - allocation exceeded");
       } catch (e: any) {
-        expect(e.toString()).to.include("FlashLoanNotConfigured");
+        expect(e.toString()).to.include("StrategyAllocationExceeded");
       }
     });
 
-    it("Fails with insufficient vault balance", async () => {
-      const excessiveAmount = new anchor.BN(1000000 * 10 ** 9); // More than vault has
+    it("Books harvested yield from the vault's balance increase", async () => {
+      await invest(new anchor.BN(500 * 10 ** 9));
+      // Yield earned by the strategy's custody
+      await mintTo(connection, authority, tokenMint1, depositAccount, authority, 50 * 10 ** 9);
+
+      const before = (await getAccount(connection, vaultTokenAccount)).amount;
+      await harvest(depositAccount, strategy, new anchor.BN(50 * 10 ** 9));
+      const after = (await getAccount(connection, vaultTokenAccount)).amount;
+      expect((after - before).toString()).to.equal((50 * 10 ** 9).toString());
+
+      const strategyAccount = await program.account.strategy.fetch(strategy);
+      expect(strategyAccount.totalProfit.toString()).to.equal((50 * 10 ** 9).toString());
+      expect(strategyAccount.deployed.toString()).to.equal((500 * 10 ** 9).toString());
+    });
 
+    it("Never signs a strategy CPI with the vault authority", async () => {
+      const other = (
+        await getOrCreateAssociatedTokenAccount(connection, authority, tokenMint1, authority.publicKey)
+      ).address;
       try {
         await program.methods
-          .flashLoan(excessiveAmount, Buffer.from([]))
-          .accounts({
-            vault: vault,
-            vaultTokenAccount: vaultTokenAccount,
-            borrower: borrower.publicKey,
-            borrowerTokenAccount: borrowerTokenAccount,
-            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
-            callbackProgram: SystemProgram.programId,
+          .harvestStrategy(transferIx(new anchor.BN(1)))
+          .accountsPartial({
+            vault,
+            strategy,
+            strategyProgram,
+            vaultTokenAccount,
+            vaultAuthority,
+            depositAccount: null,
+            authority: authority.publicKey,
           })
-          .remainingAccounts([])
-          .signers([borrower])
+          .remainingAccounts([
+            { pubkey: vaultTokenAccount, isSigner: false, isWritable: true },
+            { pubkey: other, isSigner: false, isWritable: true },
+            { pubkey: vaultAuthority, isSigner: false, isWritable: false },
+          ])
+          .signers([authority])
           .rpc();
-        expect.fail("Should have failed - insufficient balance");
+        expect.fail("Should have failed - vault authority did not sign");
       } catch (e: any) {
-        expect(e.toString()).to.include("InsufficientVaultBalance");
+        expect(e.toString()).to.not.include("Should have failed");
       }
     });
 
-    it("Tests allowlist enforcement", async () => {
-      // Enable allowlist
-      const allowedProgram = Keypair.generate().publicKey;
+    it("Guardian retires a strategy and leaves the vault withdrawal-only", async () => {
+      await invest(new anchor.BN(500 * 10 ** 9));
+
+      const guardian = Keypair.generate();
       await program.methods
-        .updateFlashLoanConfig(
-          null,
-          null,
-          true, // enable allowlist
-          [allowedProgram]
-        )
+        .setRole({ guardian: {} }, guardian.publicKey)
+        .accounts({ vault, authority: authority.publicKey })
+        .signers([authority])
+        .rpc();
+
+      // An unreachable strategy is written off entirely
+      await program.methods
+        .emergencyDivestAll(Buffer.from([]))
         .accountsPartial({
-          vault: vault,
-          authority: authority.publicKey,
+          vault,
+          strategy,
+          strategyProgram,
+          vaultTokenAccount,
+          vaultAuthority,
+          authority: guardian.publicKey,
         })
-        .signers([authority])
+        .signers([guardian])
         .rpc();
 
-      // Try to use a non-allowlisted program
+      const vaultAccount = await program.account.vault.fetch(vault);
+      expect(vaultAccount.paused).to.equal(1);
+      expect(vaultAccount.totalDeployed.toString()).to.equal("0");
+      const strategyAccount = await program.account.strategy.fetch(strategy);
+      expect(strategyAccount.retired).to.equal(true);
+      expect(strategyAccount.totalLoss.toString()).to.equal((500 * 10 ** 9).toString());
+
       try {
         await program.methods
-          .flashLoan(new anchor.BN(1000 * 10 ** 9), Buffer.from([]))
-          .accounts({
-            vault: vault,
-            vaultTokenAccount: vaultTokenAccount,
-            borrower: borrower.publicKey,
-            borrowerTokenAccount: borrowerTokenAccount,
-            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
-            callbackProgram: SystemProgram.programId, // Not in allowlist
-          })
-          .remainingAccounts([])
-          .signers([borrower])
+          .deposit(new anchor.BN(10 * 10 ** 9), new anchor.BN(0), [])
+          .accounts({ vault, user: user1.publicKey, userTokenAccount, vaultTokenAccount })
+          .signers([user1])
           .rpc();
-        expect.fail("Should have failed - callback not allowlisted");
+        expect.fail("Should have failed - vault paused");
       } catch (e: any) {
-        expect(e.toString()).to.include("CallbackNotAllowlisted");
+        expect(e.toString()).to.include("VaultPaused");
       }
+    });
+  });
 
-      // Try with allowlisted program (should pass allowlist check, but fail on other things)
+  describe("vault generations", () => {
+    let vault: PublicKey;
+    let vaultAuthority: PublicKey;
+    let vaultTokenAccount: PublicKey;
+    let vault2: PublicKey;
+    let vault2Authority: PublicKey;
+    let vault2TokenAccount: PublicKey;
+    let rewardMint: PublicKey;
+    let rewardVault: PublicKey;
+    let userTokenAccount: PublicKey;
+
+    beforeEach(async () => {
+      rewardMint = await createMint(connection, authority, authority.publicKey, null, 9);
+      [vault] = await getVaultPDA(tokenMint1);
+      [vaultAuthority] = await getVaultAuthorityPDA(tokenMint1);
+      vaultTokenAccount = await getAssociatedTokenAddress(tokenMint1, vaultAuthority, true);
+      rewardVault = await getAssociatedTokenAddress(rewardMint, vaultAuthority, true);
+      await program.methods
+        .initializeVault()
+        .accounts({
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          rewardMint,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      // Generation 1 adds its byte to the vault and authority seeds
+      [vault2] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), tokenMint1.toBuffer(), Buffer.from([1])],
+        program.programId
+      );
+      [vault2Authority] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), tokenMint1.toBuffer(), Buffer.from("authority"), Buffer.from([1])],
+        program.programId
+      );
+      vault2TokenAccount = await getAssociatedTokenAddress(tokenMint1, vault2Authority, true);
+      await program.methods
+        .initializeVaultGeneration(1)
+        .accountsPartial({
+          vault: vault2,
+          authority: authority.publicKey,
+          tokenMint: tokenMint1,
+          vaultTokenAccount: vault2TokenAccount,
+          vaultAuthority: vault2Authority,
+          rewardMint,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+
+      userTokenAccount = (
+        await getOrCreateAssociatedTokenAccount(connection, user1, tokenMint1, user1.publicKey)
+      ).address;
+      await mintTo(connection, authority, tokenMint1, userTokenAccount, authority, 1000 * 10 ** 9);
+      await program.methods
+        .deposit(new anchor.BN(500 * 10 ** 9), new anchor.BN(0), [])
+        .accounts({ vault, user: user1.publicKey, userTokenAccount, vaultTokenAccount })
+        .signers([user1])
+        .rpc();
+    });
+
+    const migrate = (minSharesOut: anchor.BN) =>
+      program.methods
+        .migratePositionToVault(minSharesOut, [])
+        .accountsPartial({
+          src: {
+            vault,
+            user: user1.publicKey,
+            userTokenAccount,
+            vaultTokenAccount,
+            vaultAuthority,
+          },
+          dst: {
+            vault: vault2,
+            user: user1.publicKey,
+            userTokenAccount,
+            vaultTokenAccount: vault2TokenAccount,
+            vaultAuthority: vault2Authority,
+          },
+        })
+        .signers([user1])
+        .rpc();
+
+    it("Refuses generation 0, which belongs to the original vault", async () => {
+      const [vault0] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), tokenMint1.toBuffer(), Buffer.from([0])],
+        program.programId
+      );
+      const [vault0Authority] = PublicKey.findProgramAddressSync(
+        [Buffer.from("vault"), tokenMint1.toBuffer(), Buffer.from("authority"), Buffer.from([0])],
+        program.programId
+      );
       try {
         await program.methods
-          .flashLoan(new anchor.BN(1000 * 10 ** 9), Buffer.from([]))
-          .accounts({
-            vault: vault,
-            vaultTokenAccount: vaultTokenAccount,
-            borrower: borrower.publicKey,
-            borrowerTokenAccount: borrowerTokenAccount,
-            feeTreasuryTokenAccount: feeTreasuryTokenAccount,
-            callbackProgram: allowedProgram,
+          .initializeVaultGeneration(0)
+          .accountsPartial({
+            vault: vault0,
+            authority: authority.publicKey,
+            tokenMint: tokenMint1,
+            vaultTokenAccount: await getAssociatedTokenAddress(tokenMint1, vault0Authority, true),
+            vaultAuthority: vault0Authority,
+            rewardMint,
+            rewardTokenProgram: TOKEN_PROGRAM_ID,
           })
-          .remainingAccounts([])
-          .signers([borrower])
+          .signers([authority])
           .rpc();
-        // Should fail on callback execution, not allowlist
+        expect.fail("Should have failed - generation 0");
       } catch (e: any) {
-        // Should not be allowlist error
-        expect(e.toString()).to.not.include("CallbackNotAllowlisted");
+        expect(e.toString()).to.include("InvalidGeneration");
       }
     });
 
-    it("Validates fee calculation", async () => {
-      const vaultAccount = await program.account.vault.fetch(vault);
-      expect(vaultAccount.flashFeeBps).to.equal(9);
-
-      // Test various amounts
-      const testAmounts = [
-        1000 * 10 ** 9,
-        10000 * 10 ** 9,
-        100000 * 10 ** 9,
-      ];
+    it("Moves a whole position to the next generation, keeping its rewards claimable", async () => {
+      const funderRewardAccount = (
+        await getOrCreateAssociatedTokenAccount(connection, authority, rewardMint, authority.publicKey)
+      ).address;
+      await mintTo(connection, authority, rewardMint, funderRewardAccount, authority, 1000 * 10 ** 9);
+      await program.methods
+        .fundRewards(new anchor.BN(1000 * 10 ** 9), new anchor.BN(10 ** 9))
+        .accounts({
+          vault,
+          funder: authority.publicKey,
+          funderTokenAccount: funderRewardAccount,
+          rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([authority])
+        .rpc();
+      await new Promise((resolve) => setTimeout(resolve, 2000));
 
-      for (const amount of testAmounts) {
-        const fee = (amount * 9) / 10000;
-        expect(fee).to.be.greaterThan(0);
-        // Fee should be approximately 0.09% of amount
-        const expectedFee = Math.floor((amount * 9) / 10000);
-        expect(fee).to.equal(expectedFee);
-      }
-    });
+      await migrate(new anchor.BN(0));
 
-    it("Validates fee treasury token account", async () => {
-      // Try with wrong mint
-      const wrongMintTokenAccount = await getOrCreateAssociatedTokenAccount(
-        connection,
-        feeTreasury,
-        tokenMint2,
-        feeTreasury.publicKey,
-        true
+      expect((await getAccount(connection, vaultTokenAccount)).amount.toString()).to.equal("0");
+      expect((await getAccount(connection, vault2TokenAccount)).amount.toString()).to.equal(
+        (500 * 10 ** 9).toString()
       );
+      expect((await getAccount(connection, userTokenAccount)).amount.toString()).to.equal(
+        (500 * 10 ** 9).toString()
+      );
+      const [srcPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      const [dstPosition] = await getUserPositionPDA(vault2, user1.publicKey);
+      const src = await program.account.userPosition.fetch(srcPosition);
+      expect(src.shares.toString()).to.equal("0");
+      expect(src.rewardsOwed.gtn(0)).to.be.true;
+      const dst = await program.account.userPosition.fetch(dstPosition);
+      expect(dst.shares.gtn(0)).to.be.true;
+      expect(dst.vault.toString()).to.equal(vault2.toString());
+
+      // The rewards earned in the old vault are still paid out by it
+      const userRewardAccount = (
+        await getOrCreateAssociatedTokenAccount(connection, user1, rewardMint, user1.publicKey)
+      ).address;
+      await program.methods
+        .claimRewards()
+        .accountsPartial({
+          vault,
+          user: user1.publicKey,
+          userRewardTokenAccount: userRewardAccount,
+          rewardVault,
+          rewardTokenProgram: TOKEN_PROGRAM_ID,
+        })
+        .signers([user1])
+        .rpc();
+      expect((await getAccount(connection, userRewardAccount)).amount > BigInt(0)).to.be.true;
+    });
 
+    it("Fails when the destination mints fewer than min_shares_out", async () => {
       try {
-        await program.methods
-          .flashLoan(new anchor.BN(1000 * 10 ** 9), Buffer.from([]))
-          .accounts({
-            vault: vault,
-            vaultTokenAccount: vaultTokenAccount,
-            borrower: borrower.publicKey,
-            borrowerTokenAccount: borrowerTokenAccount,
-            feeTreasuryTokenAccount: wrongMintTokenAccount.address,
-            callbackProgram: SystemProgram.programId,
-          })
-          .remainingAccounts([])
-          .signers([borrower])
-          .rpc();
-        expect.fail("Should have failed - wrong mint");
+        await migrate(new anchor.BN("1000000000000000000"));
+        expect.fail("Should have failed - slippage");
       } catch (e: any) {
-        expect(e.toString()).to.include("InvalidFeeTreasury");
+        expect(e.toString()).to.include("SlippageExceeded");
       }
+      const [srcPosition] = await getUserPositionPDA(vault, user1.publicKey);
+      expect((await program.account.userPosition.fetch(srcPosition)).shares.gtn(0)).to.be.true;
     });
   });
 });
@@ -0,0 +1,276 @@
+//! Scenario replay: mainnet-fork style rehearsals for the workspace programs.
+//!
+//! Snapshots the accounts named in a scenario file from a live cluster, loads them
+//! (plus the programs, deployed or locally built) into LiteSVM, replays the candidate
+//! transactions in order and prints what each one changed. Nothing is ever sent to
+//! the cluster.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use clap::Parser;
+use litesvm::LiteSVM;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use solana_rpc_client::rpc_client::RpcClient;
+use solana_sdk::{
+    account::Account, bpf_loader_upgradeable, clock::Clock, pubkey::Pubkey, sysvar,
+    transaction::VersionedTransaction,
+};
+
+/// Offset of the ELF inside an upgradeable loader ProgramData account
+/// (enum tag + slot + Option<authority>)
+const PROGRAM_DATA_ELF_OFFSET: usize = 4 + 8 + 1 + 32;
+
+/// Anchor accounts we can name in the report, by program
+const KNOWN_ACCOUNTS: &[&str] = &[
+    "Vault",
+    "UserPosition",
+    "Governance",
+    "ProtocolConfig",
+    "Pool",
+    "RouterConfig",
+];
+
+#[derive(Parser)]
+#[command(about = "Replay candidate transactions against a snapshot of live accounts")]
+struct Cli {
+    /// Scenario file (JSON), see scenarios/example.json
+    scenario: PathBuf,
+
+    /// Cluster to snapshot from
+    #[arg(long, default_value = "https://api.mainnet-beta.solana.com")]
+    rpc: String,
+
+    /// Print program logs for every transaction, not just failed ones
+    #[arg(long)]
+    logs: bool,
+}
+
+#[derive(Deserialize)]
+struct Scenario {
+    /// Accounts to copy from the cluster (vaults, pools, oracles, token accounts, ...)
+    accounts: Vec<String>,
+    #[serde(default)]
+    programs: Vec<ProgramSource>,
+    /// Base64 bincode `VersionedTransaction`s, replayed in order. Signatures and
+    /// blockhashes aren't checked, so unsigned transactions built by an operator work.
+    transactions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ProgramSource {
+    id: String,
+    /// Local build to rehearse with (e.g. target/deploy/vault_core.so). When absent
+    /// the currently deployed ELF is pulled from the cluster.
+    so: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let scenario: Scenario = serde_json::from_slice(
+        &std::fs::read(&cli.scenario)
+            .with_context(|| format!("reading {}", cli.scenario.display()))?,
+    )
+    .context("parsing scenario")?;
+    let base_dir = cli.scenario.parent().unwrap_or(Path::new("."));
+
+    let rpc = RpcClient::new(cli.rpc.clone());
+    let mut svm = LiteSVM::new()
+        .with_sigverify(false)
+        .with_blockhash_check(false);
+
+    // Run at the cluster's clock so time-based accounting (rewards, fees) matches
+    let clock_account = rpc.get_account(&sysvar::clock::ID).context("fetching clock")?;
+    let clock: Clock = bincode::deserialize(&clock_account.data)?;
+    svm.set_sysvar(&clock);
+
+    for program in &scenario.programs {
+        let id = parse_pubkey(&program.id)?;
+        let elf = match &program.so {
+            Some(path) => std::fs::read(base_dir.join(path))
+                .with_context(|| format!("reading {}", path.display()))?,
+            None => fetch_deployed_elf(&rpc, &id)?,
+        };
+        svm.add_program(id, &elf);
+    }
+
+    let tracked: Vec<Pubkey> = scenario
+        .accounts
+        .iter()
+        .map(|s| parse_pubkey(s))
+        .collect::<Result<_>>()?;
+    let snapshot = rpc
+        .get_multiple_accounts(&tracked)
+        .context("snapshotting accounts")?;
+    for (key, account) in tracked.iter().zip(snapshot) {
+        let account = account.ok_or_else(|| anyhow!("account {key} not found on cluster"))?;
+        svm.set_account(*key, account)
+            .map_err(|e| anyhow!("loading {key}: {e:?}"))?;
+    }
+    println!(
+        "Loaded {} accounts at slot {} (unix {})",
+        tracked.len(),
+        clock.slot,
+        clock.unix_timestamp
+    );
+
+    let names = known_discriminators();
+    for (i, encoded) in scenario.transactions.iter().enumerate() {
+        let tx: VersionedTransaction = bincode::deserialize(&BASE64.decode(encoded)?)
+            .with_context(|| format!("decoding transaction #{i}"))?;
+
+        // Diff every tracked account plus whatever the transaction writes
+        let mut watched = tracked.clone();
+        let message = &tx.message;
+        for (idx, key) in message.static_account_keys().iter().enumerate() {
+            if message.is_maybe_writable(idx, None) && !watched.contains(key) {
+                watched.push(*key);
+            }
+        }
+        let before: BTreeMap<Pubkey, Option<Account>> =
+            watched.iter().map(|k| (*k, svm.get_account(k))).collect();
+
+        println!("\n== Transaction #{i} ==");
+        match svm.send_transaction(tx) {
+            Ok(meta) => {
+                println!("ok ({} CU)", meta.compute_units_consumed);
+                if cli.logs {
+                    print_logs(&meta.logs);
+                }
+            }
+            Err(failed) => {
+                println!("FAILED: {:?}", failed.err);
+                print_logs(&failed.meta.logs);
+                continue;
+            }
+        }
+
+        for (key, old) in &before {
+            report_diff(key, old.as_ref(), svm.get_account(key).as_ref(), &names);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_pubkey(s: &str) -> Result<Pubkey> {
+    s.parse().map_err(|e| anyhow!("invalid pubkey {s}: {e}"))
+}
+
+fn fetch_deployed_elf(rpc: &RpcClient, program_id: &Pubkey) -> Result<Vec<u8>> {
+    let (program_data, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::ID);
+    let account = rpc
+        .get_account(&program_data)
+        .with_context(|| format!("fetching program data for {program_id}"))?;
+    if account.data.len() <= PROGRAM_DATA_ELF_OFFSET {
+        bail!("{program_id} has no deployed ELF");
+    }
+    Ok(account.data[PROGRAM_DATA_ELF_OFFSET..].to_vec())
+}
+
+fn known_discriminators() -> BTreeMap<[u8; 8], &'static str> {
+    KNOWN_ACCOUNTS
+        .iter()
+        .map(|name| {
+            let hash = Sha256::digest(format!("account:{name}").as_bytes());
+            let mut disc = [0u8; 8];
+            disc.copy_from_slice(&hash[..8]);
+            (disc, *name)
+        })
+        .collect()
+}
+
+fn account_label(account: &Account, names: &BTreeMap<[u8; 8], &'static str>) -> &'static str {
+    account
+        .data
+        .get(..8)
+        .and_then(|d| names.get(<&[u8; 8]>::try_from(d).ok()?))
+        .copied()
+        .unwrap_or("account")
+}
+
+fn report_diff(
+    key: &Pubkey,
+    before: Option<&Account>,
+    after: Option<&Account>,
+    names: &BTreeMap<[u8; 8], &'static str>,
+) {
+    match (before, after) {
+        (None, None) => {}
+        (None, Some(new)) => println!(
+            "  + {} {key}: created ({} bytes, {} lamports, owner {})",
+            account_label(new, names),
+            new.data.len(),
+            new.lamports,
+            new.owner
+        ),
+        (Some(old), None) => println!(
+            "  - {} {key}: closed ({} lamports released)",
+            account_label(old, names),
+            old.lamports
+        ),
+        (Some(old), Some(new)) => {
+            if old == new {
+                return;
+            }
+            println!("  ~ {} {key}", account_label(new, names));
+            if old.lamports != new.lamports {
+                println!(
+                    "      lamports: {} -> {} ({:+})",
+                    old.lamports,
+                    new.lamports,
+                    new.lamports as i128 - old.lamports as i128
+                );
+            }
+            if old.owner != new.owner {
+                println!("      owner: {} -> {}", old.owner, new.owner);
+            }
+            if old.data.len() != new.data.len() {
+                println!("      size: {} -> {}", old.data.len(), new.data.len());
+            }
+            for (start, end) in changed_ranges(&old.data, &new.data) {
+                println!(
+                    "      data[{start}..{end}]: {} -> {}",
+                    hex(old.data.get(start..end).unwrap_or(&[])),
+                    hex(new.data.get(start..end).unwrap_or(&[]))
+                );
+            }
+        }
+    }
+}
+
+/// Contiguous byte ranges that differ (bytes past the shorter buffer count as changed)
+fn changed_ranges(old: &[u8], new: &[u8]) -> Vec<(usize, usize)> {
+    let len = old.len().max(new.len());
+    let mut ranges = Vec::new();
+    let mut start = None;
+    for i in 0..len {
+        let differs = old.get(i) != new.get(i);
+        match (differs, start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                ranges.push((s, i));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        ranges.push((s, len));
+    }
+    ranges
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn print_logs(logs: &[String]) {
+    for line in logs {
+        println!("    | {line}");
+    }
+}